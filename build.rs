@@ -2,5 +2,8 @@ fn main() {
     let mut config = prost_build::Config::new();
     config.bytes(&["."]);
     config.type_attribute(".", "#[derive(PartialOrd)]");
-    config.out_dir("src/pb").compile_protos(&["abi.proto"], &["protos"]).unwrap();
-}
\ No newline at end of file
+    tonic_build::configure()
+        .out_dir("src/pb")
+        .compile_with_config(config, &["abi.proto"], &["protos"])
+        .unwrap();
+}
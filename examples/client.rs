@@ -1,10 +1,9 @@
 use anyhow::Result;
-use async_prost::AsyncProstStream;
-use futures::prelude::*;
-use kv_store::{CommandRequest, CommandResponse};
+use kv_store::{CommandRequest, ProstClientStream};
 use tokio::net::TcpStream;
 use tracing::info;
 
+// Talks to examples/server.rs, which expects a Hello at the start of the connection
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
@@ -13,31 +12,16 @@ async fn main() -> Result<()> {
     // Connect to the server
     let stream = TcpStream::connect(addr).await?;
 
-    // Use AsyncProstStream to handle TCP Frame
-    let mut client = AsyncProstStream::<_, CommandResponse, CommandRequest, _>::from(stream).for_async();
+    // ProstClientStream sends the Hello with the first command and handles the frames
+    let mut client = ProstClientStream::new(stream);
 
     // Generate a HSET command
     let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
 
     // Send HSET command
-    client.send(cmd).await?;
-    if let Some(Ok(data)) = client.next().await {
-        info!("Got response {:?}", data);
-    }
+    let data = client.execute(cmd).await?;
+    info!("Got response {:?}", data);
+    info!("Negotiated {:?}", client.negotiated());
 
     Ok(())
 }
-
-// use kv_store::command_request::RequestData;
-
-// if let Some(cr) = cmd.request_data {
-//     println!("{:?}", cr);
-//     if let RequestData::Hset(hset) = cr {
-//         println!("{:?}", hset.pair);
-//         if let Some(t) = hset.pair {
-//             println!("{:?}",t.value);
-//             println!("{:?}",t.key);
-//         }
-//         println!("{:?}", hset.table);
-//     }
-// }
\ No newline at end of file
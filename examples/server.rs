@@ -1,7 +1,5 @@
 use anyhow::Result;
-use async_prost::AsyncProstStream;
-use futures::prelude::*;
-use kv_store::{CommandRequest, CommandResponse, MemTable, Service, ServiceInner};
+use kv_store::{ConnectionContext, MemTable, ProstServerStream, Service, ServiceInner};
 use tokio::net::TcpListener;
 use tracing::info;
 
@@ -15,14 +13,13 @@ async fn main() -> Result<()> {
     loop {
         let (stream, addr) = listener.accept().await?;
         info!("Client {:?} connected", addr);
-        let svc = service.clone();
+        // the Hello handshake comes first, a client that doesn't send one is refused
+        let stream = ProstServerStream::new(stream, service.clone())
+            .with_context(ConnectionContext::new(Some(addr)));
         tokio::spawn(async move {
-            let mut stream = AsyncProstStream::<_, CommandRequest, CommandResponse, _>::from(stream).for_async();
-            while let Some(Ok(cmd)) = stream.next().await {
-                let res = svc.execute(cmd);
-                stream.send(res).await.unwrap();
+            if let Err(e) = stream.process().await {
+                info!("Client {:?} disconnected: {:?}", addr, e);
             }
-            info!("Client {:?} disconnected", addr);
         });
     }
-}
\ No newline at end of file
+}
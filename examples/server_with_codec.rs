@@ -1,6 +1,6 @@
 use anyhow::Result;
 use futures::prelude::*;
-use kv_store::{CommandRequest, MemTable, RocksDB, Service, ServiceInner};
+use kv_store::{CommandRequest, ConnectionContext, MemTable, RocksDB, Service, ServiceInner};
 use prost::Message;
 use tokio::net::TcpListener;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
@@ -11,7 +11,7 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
     let service: Service<RocksDB> = ServiceInner::new(RocksDB::new("/tmp/kvserver"))
-        .fn_before_send(|res| match res.message.as_ref() {
+        .fn_before_send(|res, _ctx| match res.message.as_ref() {
             "" => res.message = "altered. Original message is empty.".into(),
             s => res.message = format!("altered: {}", s),
         })
@@ -24,12 +24,13 @@ async fn main() -> Result<()> {
         let (stream, addr) = listener.accept().await?;
         info!("Client {:?} connected", addr);
         let svc = service.clone();
+        let ctx = ConnectionContext::new(Some(addr));
         tokio::spawn(async move {
             let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
             while let Some(Ok(mut buf)) = stream.next().await {
                 let cmd = CommandRequest::decode(&buf[..]).unwrap();
                 info!("Got a new command: {:?}", cmd);
-                let res = svc.execute(cmd);
+                let res = svc.execute(cmd, &ctx);
                 buf.clear();
                 res.encode(&mut buf).unwrap();
                 stream.send(buf.freeze()).await.unwrap();
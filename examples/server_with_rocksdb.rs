@@ -1,7 +1,7 @@
 use anyhow::Result;
 use async_prost::AsyncProstStream;
 use futures::prelude::*;
-use kv_store::{CommandRequest, CommandResponse, RocksDB, Service, ServiceInner};
+use kv_store::{CommandRequest, CommandResponse, ConnectionContext, RocksDB, Service, ServiceInner};
 use tokio::net::TcpListener;
 use tracing::info;
 
@@ -10,7 +10,7 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     // let service: Service = ServiceInner::new(MemTable::new()).into();
     let service: Service<RocksDB> = ServiceInner::new(RocksDB::new("/tmp/kvserver"))
-        .fn_before_send(|res| match res.message.as_ref() {
+        .fn_before_send(|res, _ctx| match res.message.as_ref() {
             "" => res.message = "altered. Original message is empty.".into(),
             s => res.message = format!("altered: {}", s),
         })
@@ -22,11 +22,12 @@ async fn main() -> Result<()> {
         let (stream, addr) = listener.accept().await?;
         info!("Client {:?} connected", addr);
         let svc = service.clone();
+        let ctx = ConnectionContext::new(Some(addr));
         tokio::spawn(async move {
             let mut stream =
                 AsyncProstStream::<_, CommandRequest, CommandResponse, _>::from(stream).for_async();
             while let Some(Ok(cmd)) = stream.next().await {
-                let res = svc.execute(cmd);
+                let res = svc.execute(cmd, &ctx);
                 stream.send(res).await.unwrap();
             }
             info!("Client {:?} disconnected", addr);
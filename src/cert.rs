@@ -0,0 +1,266 @@
+// Certificate management behind `kvs cert`: a CA, server certs issued with chosen SANs and
+// client certs with chosen CN and roles. Keys are ED25519 like examples/gen_cert.rs.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, SanType, PKCS_ED25519,
+};
+use time::{Duration, OffsetDateTime};
+
+use crate::KvError;
+
+/// Roles of a client are carried as URI SANs with this prefix, see PeerIdentity::roles
+pub const ROLE_URI_PREFIX: &str = "urn:kv:role:";
+
+/// PEM encoded certificate and its private key
+#[derive(Debug, Clone)]
+pub struct CertPem {
+    pub cert: String,
+    pub key: String,
+}
+
+/// A CA which issues the server and client certs
+pub struct CertAuthority {
+    inner: Certificate,
+    pem: CertPem,
+}
+
+impl CertAuthority {
+    /// Create a self signed CA valid for days
+    pub fn new(org: &str, cn: &str, days: u32) -> Result<Self, KvError> {
+        let mut params = params(org, cn, days);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+
+        let inner = Certificate::from_params(params).map_err(cert_error)?;
+        let pem = CertPem {
+            cert: inner.serialize_pem().map_err(cert_error)?,
+            key: inner.serialize_private_key_pem(),
+        };
+        Ok(Self { inner, pem })
+    }
+
+    /// Load an existing CA from its PEM cert and key
+    pub fn load(cert: &str, key: &str) -> Result<Self, KvError> {
+        let key_pair =
+            KeyPair::from_pem(key).map_err(|_| KvError::CertifcateParseError("CA", "key"))?;
+        let params = CertificateParams::from_ca_cert_pem(cert, key_pair)
+            .map_err(|_| KvError::CertifcateParseError("CA", "cert"))?;
+        let inner = Certificate::from_params(params).map_err(cert_error)?;
+        let pem = CertPem {
+            cert: cert.to_string(),
+            key: key.to_string(),
+        };
+        Ok(Self { inner, pem })
+    }
+
+    pub fn pem(&self) -> &CertPem {
+        &self.pem
+    }
+
+    /// Issue a server cert for the SANs, each one is a DNS name or an IP address
+    pub fn issue_server(
+        &self,
+        org: &str,
+        cn: &str,
+        sans: &[String],
+        days: u32,
+    ) -> Result<CertPem, KvError> {
+        if sans.is_empty() {
+            return Err(KvError::Internal("Server cert needs at least one SAN".into()));
+        }
+
+        let mut params = params(org, cn, days);
+        params.subject_alt_names = sans
+            .iter()
+            .map(|san| match san.parse::<IpAddr>() {
+                Ok(ip) => SanType::IpAddress(ip),
+                Err(_) => SanType::DnsName(san.clone()),
+            })
+            .collect();
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        self.issue(params)
+    }
+
+    /// Issue a client cert, the CN and roles are the identity the server sees
+    pub fn issue_client(
+        &self,
+        org: &str,
+        cn: &str,
+        roles: &[String],
+        days: u32,
+    ) -> Result<CertPem, KvError> {
+        let mut params = params(org, cn, days);
+        params.subject_alt_names = roles
+            .iter()
+            .map(|role| SanType::URI(format!("{}{}", ROLE_URI_PREFIX, role)))
+            .collect();
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        self.issue(params)
+    }
+
+    fn issue(&self, params: CertificateParams) -> Result<CertPem, KvError> {
+        let cert = Certificate::from_params(params).map_err(cert_error)?;
+        Ok(CertPem {
+            cert: cert.serialize_pem_with_signer(&self.inner).map_err(cert_error)?,
+            key: cert.serialize_private_key_pem(),
+        })
+    }
+}
+
+impl CertPem {
+    /// Write {name}.cert and {name}.key into dir, the key is only readable by the owner.
+    /// Existing files are kept unless force is set
+    pub fn write(
+        &self,
+        dir: impl AsRef<Path>,
+        name: &str,
+        force: bool,
+    ) -> Result<(PathBuf, PathBuf), KvError> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        let cert = dir.join(format!("{}.cert", name));
+        let key = dir.join(format!("{}.key", name));
+        // don't leave a cert without its key behind
+        if let Some(path) = [&cert, &key].into_iter().find(|p| !force && p.exists()) {
+            let msg = format!("{} already exists", path.display());
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, msg).into());
+        }
+        write_file(&cert, &self.cert, 0o644, force)?;
+        write_file(&key, &self.key, 0o600, force)?;
+        Ok((cert, key))
+    }
+}
+
+fn params(org: &str, cn: &str, days: u32) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.alg = &PKCS_ED25519;
+
+    let mut name = DistinguishedName::new();
+    name.push(DnType::OrganizationName, org);
+    name.push(DnType::CommonName, cn);
+    params.distinguished_name = name;
+
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - Duration::minutes(5);
+    params.not_after = now + Duration::days(days as i64);
+    params
+}
+
+fn write_file(path: &Path, content: &str, mode: u32, force: bool) -> Result<(), KvError> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mut options = OpenOptions::new();
+    options.write(true).mode(mode);
+    match force {
+        true => options.create(true).truncate(true),
+        false => options.create_new(true),
+    };
+    let mut file = options.open(path)?;
+    // mode only applies to new files
+    file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+fn cert_error(e: rcgen::RcgenError) -> KvError {
+    KvError::Internal(format!("Failed to generate certificate: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::{PeerIdentity, TlsClientConnector, TlsServerAcceptor};
+    use anyhow::Result;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::{internal::pemfile, Session};
+
+    #[tokio::test]
+    async fn issued_certs_should_work_with_tls() -> Result<()> {
+        let ca = CertAuthority::new("Acme Inc.", "Acme CA", 10)?;
+        let sans = vec!["kvserver.acme.inc".to_string(), "127.0.0.1".to_string()];
+        let server = ca.issue_server("Acme Inc.", "Acme KV server", &sans, 10)?;
+
+        // a CA loaded from the PEM issues certs chained to the same CA
+        let ca = CertAuthority::load(&ca.pem().cert, &ca.pem().key)?;
+        let roles = vec!["admin".to_string(), "reader".to_string()];
+        let client = ca.issue_client("Acme Inc.", "awesome-device-id", &roles, 1)?;
+
+        let acceptor = TlsServerAcceptor::new(&server.cert, &server.key, Some(&ca.pem().cert))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            let certs = stream.get_ref().1.get_peer_certificates().unwrap();
+            PeerIdentity::from_der(&certs[0].0).unwrap()
+        });
+
+        let identity = Some((client.cert.as_str(), client.key.as_str()));
+        let connector =
+            TlsClientConnector::new_private("kvserver.acme.inc", identity, &ca.pem().cert)?;
+        let mut stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        stream.write_all(b"hello").await?;
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+
+        let identity = server.await?;
+        assert_eq!(identity.common_name.as_deref(), Some("awesome-device-id"));
+        assert_eq!(identity.roles, roles);
+
+        Ok(())
+    }
+
+    #[test]
+    fn server_cert_should_have_sans() -> Result<()> {
+        let ca = CertAuthority::new("Acme Inc.", "Acme CA", 10)?;
+        assert!(ca.issue_server("Acme Inc.", "Acme KV server", &[], 10).is_err());
+
+        let sans = vec!["kvserver.acme.inc".to_string(), "::1".to_string()];
+        let server = ca.issue_server("Acme Inc.", "Acme KV server", &sans, 10)?;
+        let der = pemfile::certs(&mut server.cert.as_bytes())
+            .unwrap()
+            .remove(0);
+        let identity = PeerIdentity::from_der(&der.0)?;
+        assert_eq!(identity.sans, sans);
+        assert!(identity.roles.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cert_pem_write_should_not_overwrite_by_default() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let ca = CertAuthority::new("Acme Inc.", "Acme CA", 10)?;
+
+        let (cert, key) = ca.pem().write(dir.path(), "ca", false)?;
+        assert_eq!(std::fs::read_to_string(&cert)?, ca.pem().cert);
+        assert_eq!(std::fs::metadata(&key)?.permissions().mode() & 0o777, 0o600);
+
+        let other = CertAuthority::new("Acme Inc.", "Other CA", 10)?;
+        assert!(other.pem().write(dir.path(), "ca", false).is_err());
+        assert_eq!(std::fs::read_to_string(&cert)?, ca.pem().cert);
+
+        other.pem().write(dir.path(), "ca", true)?;
+        assert_eq!(std::fs::read_to_string(&key)?, other.pem().key);
+
+        Ok(())
+    }
+}
@@ -1,30 +1,149 @@
 use anyhow::Result;
-use kv_store::{CommandRequest, ProstClientStream, TlsClientConnector};
+use clap::{Parser, Subcommand};
+use kv_store::{
+    export_remote, export_store, import_remote, import_store, CommandRequest, ProstClientStream,
+    RecordFormat, RecordReader, RecordWriter, RocksDB, SledDb, Storage, TlsClientConnector,
+};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
 use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
 use tracing::info;
 
+#[derive(Debug, Parser)]
+#[command(name = "kvc", about = "KV client")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Address of the native TLS listener of the server
+    #[arg(long, default_value = "127.0.0.1:9527")]
+    server: String,
+    /// Domain name in the certificate of the server
+    #[arg(long, default_value = "kvserver.acme.inc")]
+    domain: String,
+    /// CA certificate file to verify the server, the bundled fixture CA is used when not set
+    #[arg(long)]
+    ca: Option<PathBuf>,
+    /// Client certificate file, when the server verifies client certs
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    /// Private key file of the client certificate
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Export tables as JSON Lines or CSV, one record per kvpair
+    Export {
+        /// Table to export, repeated for more tables. All tables when not set
+        #[arg(long = "table")]
+        tables: Vec<String>,
+        /// Where the records are written, stdout when not set
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        opts: RecordOpts,
+    },
+    /// Import the records of an export in batches of Hmset
+    Import {
+        /// Where the records are read, stdin when not set
+        #[arg(long, short)]
+        input: Option<PathBuf>,
+        /// Number of kvpairs in one Hmset
+        #[arg(long, default_value = "500")]
+        batch_size: usize,
+        #[command(flatten)]
+        opts: RecordOpts,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct RecordOpts {
+    /// Format of the records: jsonl or csv
+    #[arg(long, default_value = "jsonl")]
+    format: RecordFormat,
+    /// Work offline on the sled database at this path instead of the server
+    #[arg(long, conflicts_with = "rocksdb")]
+    sled: Option<PathBuf>,
+    /// Work offline on the rocksdb database at this path instead of the server
+    #[arg(long)]
+    rocksdb: Option<PathBuf>,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
-    let addr = "127.0.0.1:9527";
+    let args = Args::parse();
+    match &args.command {
+        Some(Command::Export { tables, output, opts }) => {
+            let output: Box<dyn Write> = match output {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(io::stdout().lock()),
+            };
+            let mut writer = RecordWriter::new(output, opts.format)?;
+            let count = match (&opts.sled, &opts.rocksdb) {
+                (Some(path), _) => export_store(&SledDb::new(path), tables, &mut writer)?,
+                (_, Some(path)) => export_store(&RocksDB::new(path), tables, &mut writer)?,
+                _ => export_remote(&mut connect(&args).await?, tables, &mut writer).await?,
+            };
+            info!("Exported {} records", count);
+        }
+        Some(Command::Import { input, batch_size, opts }) => {
+            let input: Box<dyn Read> = match input {
+                Some(path) => Box::new(File::open(path)?),
+                None => Box::new(io::stdin()),
+            };
+            let records = RecordReader::new(input, opts.format)?;
+            let count = match (&opts.sled, &opts.rocksdb) {
+                (Some(path), _) => import_offline(SledDb::new(path), records, *batch_size)?,
+                (_, Some(path)) => import_offline(RocksDB::new(path), records, *batch_size)?,
+                _ => import_remote(&mut connect(&args).await?, records, *batch_size).await?,
+            };
+            info!("Imported {} records", count);
+        }
+        None => {
+            let mut client = connect(&args).await?;
 
-    // 以后用配置替换
-    let ca_cert = include_str!("../fixtures/ca.cert");
+            // 生成一个 HSET 命令
+            let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
 
-    // 连接服务器
-    let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(ca_cert))?;
-    let stream = TcpStream::connect(addr).await?;
-    let stream = connector.connect(stream).await?;
+            // 发送 HSET 命令
+            let data = client.execute(cmd).await?;
+            info!("Got response {:?}", data);
+        }
+    }
 
-    let mut client = ProstClientStream::new(stream);
+    Ok(())
+}
 
-    // 生成一个 HSET 命令
-    let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
+// The records are imported before the store is dropped, which flushes it
+fn import_offline(
+    store: impl Storage,
+    records: RecordReader<Box<dyn Read>>,
+    batch_size: usize,
+) -> Result<u64> {
+    Ok(import_store(&store, records, batch_size)?)
+}
 
-    // 发送 HSET 命令
-    let data = client.execute(cmd).await?;
-    info!("Got response {:?}", data);
+async fn connect(args: &Args) -> Result<ProstClientStream<TlsStream<TcpStream>>> {
+    let ca = match &args.ca {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => include_str!("../fixtures/ca.cert").to_string(),
+    };
+    let identity = match (&args.cert, &args.key) {
+        (Some(cert), Some(key)) => {
+            Some((std::fs::read_to_string(cert)?, std::fs::read_to_string(key)?))
+        }
+        _ => None,
+    };
+    let identity = identity.as_ref().map(|(cert, key)| (cert.as_str(), key.as_str()));
 
-    Ok(())
-}
\ No newline at end of file
+    // 连接服务器
+    let connector = TlsClientConnector::new_private(&args.domain, identity, &ca)?;
+    let stream = TcpStream::connect(&args.server).await?;
+    let stream = connector.connect(stream).await?;
+    Ok(ProstClientStream::new(stream))
+}
@@ -8,8 +8,12 @@ pub enum KvError {
 
     #[error("Cannot parse command: `{0}`")]
     InvalidCommand(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
     #[error("Cannot convert value {:0} to {1}")]
     ConvertError(Value, &'static str),
+    #[error("Wrong type of value for table: {0}, key: {1}, expect {2}")]
+    WrongType(String, String, &'static str),
     #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {3}")]
     StorageError(&'static str, String, String, String),
 
@@ -21,16 +25,51 @@ pub enum KvError {
     SledError(#[from] sled::Error),
     #[error("Failed to access rocksdb")]
     RocksDBError(#[from] rocksdb::Error),
+    #[error("Corrupt record: {0}")]
+    CorruptRecord(String),
+    #[error("Encryption error: {0}")]
+    EncryptionError(String),
 
     #[error("Failed to parse certifcate: {0}, {1}")]
     CertifcateParseError(&'static str, &'static str),
+    #[error("Invalid certificate revocation list: {0}")]
+    InvalidCrl(String),
+    #[error("Certificate of {0} does not match any pinned public key")]
+    CertificatePinMismatch(String),
 
     #[error("Frame is larger than max size!")]
     FrameError,
+    #[error("Incompatible protocol: {0}")]
+    IncompatibleProtocol(String),
+
+    #[error("Replica is read only, send writes to the primary {0}")]
+    ReadOnlyReplica(String),
+    #[error("Sequence number {0} is no longer in the log, the oldest is {1}")]
+    SeqCompacted(u64, u64),
+    #[error("Invalid record at line {0}: {1}")]
+    InvalidRecord(u64, String),
+    #[error("Migration error: {0}")]
+    MigrationError(String),
+    #[error("Backup error: {0}")]
+    BackupError(String),
+    #[error("Replication error: {0}")]
+    ReplicationError(String),
+    #[error("Not the leader of the raft cluster, the leader is {0}")]
+    NotLeader(String),
+    #[error("Raft error: {0}")]
+    RaftError(String),
 
     #[error("TLS Error")]
     TLSError(#[from] tokio_rustls::rustls::TLSError),
 
+    #[error("Yamux Connection error")]
+    YamuxConnectionError(#[from] yamux::ConnectionError),
+
+    #[error("QUIC connection error")]
+    QuicConnectionError(#[from] quinn::ConnectionError),
+    #[error("QUIC connect error")]
+    QuicConnectError(#[from] quinn::ConnectError),
+
     #[error("I/O Error")]
     IoError(#[from] std::io::Error),
 
@@ -0,0 +1,593 @@
+// Logical export and import of the tables, one record per kvpair with the type of its value, so
+// the data could be read by other tools or loaded into a store of another backend. Records are
+// imported in batches of Hmset, either straight into a Storage or over the network.
+
+use std::io::{BufRead, BufReader, Lines, Read, Write};
+use std::iter::Peekable;
+use std::str::FromStr;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use http::StatusCode;
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::command_request::RequestData;
+use crate::*;
+
+// Columns of the CSV format, written as its header
+const CSV_HEADER: [&str; 4] = ["table", "key", "type", "value"];
+
+/// A record of the export, a kvpair and the table it's in
+pub type ExportRecord = (String, Kvpair);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// One JSON object per line: {"table", "key", "type", "value"}
+    JsonLines,
+    /// table,key,type,value with a header, binary values are base64 encoded
+    Csv,
+}
+
+impl FromStr for RecordFormat {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" | "json" => Ok(Self::JsonLines),
+            "csv" => Ok(Self::Csv),
+            _ => Err(KvError::InvalidCommand(format!("Unknown record format {}", s))),
+        }
+    }
+}
+
+/// Writes the records in a RecordFormat
+pub struct RecordWriter<W: Write> {
+    inner: WriterInner<W>,
+}
+
+enum WriterInner<W: Write> {
+    JsonLines(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(writer: W, format: RecordFormat) -> Result<Self, KvError> {
+        let inner = match format {
+            RecordFormat::JsonLines => WriterInner::JsonLines(writer),
+            RecordFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                writer.write_record(CSV_HEADER).map_err(csv_io_error)?;
+                WriterInner::Csv(Box::new(writer))
+            }
+        };
+        Ok(Self { inner })
+    }
+
+    pub fn write(&mut self, table: &str, pair: &Kvpair) -> Result<(), KvError> {
+        let (ty, text) = to_text(pair.value.as_ref());
+        match &mut self.inner {
+            WriterInner::JsonLines(writer) => {
+                let value = match pair.value.as_ref().and_then(|v| v.value.as_ref()) {
+                    Some(value::Value::Integer(i)) => json!(i),
+                    Some(value::Value::Float(f)) if f.is_finite() => json!(f),
+                    Some(value::Value::Bool(b)) => json!(b),
+                    None => serde_json::Value::Null,
+                    // strings, base64 of binary and the floats JSON can't hold
+                    _ => json!(text),
+                };
+                let record = json!({"table": table, "key": pair.key, "type": ty, "value": value});
+                serde_json::to_writer(&mut *writer, &record)
+                    .map_err(|e| KvError::IoError(e.into()))?;
+                writer.write_all(b"\n")?;
+            }
+            WriterInner::Csv(writer) => writer
+                .write_record([table, pair.key.as_str(), ty, text.as_str()])
+                .map_err(csv_io_error)?,
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), KvError> {
+        match &mut self.inner {
+            WriterInner::JsonLines(writer) => writer.flush()?,
+            WriterInner::Csv(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Reads the records written by RecordWriter
+pub struct RecordReader<R: Read> {
+    inner: ReaderInner<R>,
+}
+
+enum ReaderInner<R: Read> {
+    JsonLines(Lines<BufReader<R>>, u64),
+    Csv(csv::StringRecordsIntoIter<R>),
+}
+
+impl<R: Read> RecordReader<R> {
+    pub fn new(reader: R, format: RecordFormat) -> Result<Self, KvError> {
+        let inner = match format {
+            RecordFormat::JsonLines => ReaderInner::JsonLines(BufReader::new(reader).lines(), 0),
+            RecordFormat::Csv => {
+                let mut reader = csv::Reader::from_reader(reader);
+                let header = reader
+                    .headers()
+                    .map_err(|e| KvError::InvalidRecord(1, e.to_string()))?;
+                if header.iter().ne(CSV_HEADER) {
+                    let msg = format!("expect the header {}", CSV_HEADER.join(","));
+                    return Err(KvError::InvalidRecord(1, msg));
+                }
+                ReaderInner::Csv(reader.into_records())
+            }
+        };
+        Ok(Self { inner })
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = Result<ExportRecord, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            ReaderInner::JsonLines(lines, line) => loop {
+                *line += 1;
+                let data = match lines.next()? {
+                    Ok(data) => data,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                if !data.trim().is_empty() {
+                    return Some(parse_json(&data).map_err(|e| KvError::InvalidRecord(*line, e)));
+                }
+            },
+            ReaderInner::Csv(records) => {
+                let record = records.next()?;
+                let result = record
+                    .map_err(|e| {
+                        let line = e.position().map_or(0, |v| v.line());
+                        KvError::InvalidRecord(line, e.to_string())
+                    })
+                    .and_then(|record| {
+                        let line = record.position().map_or(0, |v| v.line());
+                        parse_csv(&record).map_err(|e| KvError::InvalidRecord(line, e))
+                    });
+                Some(result)
+            }
+        }
+    }
+}
+
+/// Groups consecutive records of the same table into Hmset commands of up to size pairs
+pub struct Batches<I: Iterator> {
+    records: Peekable<I>,
+    size: usize,
+}
+
+impl<I> Batches<I>
+where
+    I: Iterator<Item = Result<ExportRecord, KvError>>,
+{
+    pub fn new(records: I, size: usize) -> Self {
+        Self {
+            records: records.peekable(),
+            size: size.max(1),
+        }
+    }
+}
+
+impl<I> Iterator for Batches<I>
+where
+    I: Iterator<Item = Result<ExportRecord, KvError>>,
+{
+    type Item = Result<CommandRequest, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (table, pair) = match self.records.next()? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e)),
+        };
+        let mut pairs = vec![pair];
+        while pairs.len() < self.size {
+            // an error is returned by the next call, after this batch
+            match self.records.peek() {
+                Some(Ok((next, _))) if *next == table => {
+                    if let Some(Ok((_, pair))) = self.records.next() {
+                        pairs.push(pair);
+                    }
+                }
+                _ => break,
+            }
+        }
+        Some(Ok(CommandRequest::new_hmset(table, pairs)))
+    }
+}
+
+/// Write the kvpairs of the tables sorted by key, all tables when none is given. The elements
+/// of the lists, sets and sorted sets in a table follow it. Returns the number of records written
+pub fn export_store(
+    store: &impl Storage,
+    tables: &[String],
+    writer: &mut RecordWriter<impl Write>,
+) -> Result<u64, KvError> {
+    let tables = match tables {
+        [] => {
+            let mut tables = store.get_tables()?;
+            tables.retain(|v| !is_internal_table(v));
+            tables.sort();
+            tables
+        }
+        tables => tables.to_vec(),
+    };
+
+    let mut count = 0;
+    for table in tables {
+        let pairs = store.get_all(&table)?;
+        count += pairs.len() as u64;
+        for elements in write_pairs(writer, &table, pairs)? {
+            let pairs = store.get_all(&elements)?;
+            count += pairs.len() as u64;
+            write_pairs(writer, &elements, pairs)?;
+        }
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Set the records into the store in batches of Hmset. Returns the number of records imported
+pub fn import_store(
+    store: &impl Storage,
+    records: impl Iterator<Item = Result<ExportRecord, KvError>>,
+    batch_size: usize,
+) -> Result<u64, KvError> {
+    let mut count = 0;
+    for cmd in Batches::new(records, batch_size) {
+        let cmd = cmd?;
+        let pairs = hmset_len(&cmd);
+        check(dispatch(cmd, store))?;
+        count += pairs;
+    }
+    Ok(count)
+}
+
+/// Export the tables of a server, all tables when none is given, see export_store
+pub async fn export_remote<S>(
+    client: &mut ProstClientStream<S>,
+    tables: &[String],
+    writer: &mut RecordWriter<impl Write>,
+) -> Result<u64, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let tables: Vec<String> = match tables {
+        [] => {
+            let res = check(client.execute(CommandRequest::new_list_tables()).await?)?;
+            res.values
+                .into_iter()
+                .map(|v| match v.value {
+                    Some(value::Value::String(table)) => Ok(table),
+                    _ => Err(KvError::ConvertError(v, "table name")),
+                })
+                .collect::<Result<_, _>>()?
+        }
+        tables => tables.to_vec(),
+    };
+
+    let mut count = 0;
+    for table in tables {
+        let res = check(client.execute(CommandRequest::new_hget_all(&table)).await?)?;
+        count += res.pairs.len() as u64;
+        for elements in write_pairs(writer, &table, res.pairs)? {
+            let res = check(client.execute(CommandRequest::new_hget_all(&elements)).await?)?;
+            count += res.pairs.len() as u64;
+            write_pairs(writer, &elements, res.pairs)?;
+        }
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Send the records to a server in batches of Hmset, see import_store
+pub async fn import_remote<S>(
+    client: &mut ProstClientStream<S>,
+    records: impl Iterator<Item = Result<ExportRecord, KvError>>,
+    batch_size: usize,
+) -> Result<u64, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut count = 0;
+    for cmd in Batches::new(records, batch_size) {
+        let cmd = cmd?;
+        let pairs = hmset_len(&cmd);
+        check(client.execute(cmd).await?)?;
+        count += pairs;
+    }
+    Ok(count)
+}
+
+// Write the kvpairs sorted by key, returns the tables with the elements of the collections
+fn write_pairs(
+    writer: &mut RecordWriter<impl Write>,
+    table: &str,
+    mut pairs: Vec<Kvpair>,
+) -> Result<Vec<String>, KvError> {
+    pairs.sort_by(|a, b| a.key.cmp(&b.key));
+    let mut collections = Vec::new();
+    for pair in &pairs {
+        writer.write(table, pair)?;
+        if pair.value.as_ref().and_then(as_collection).is_some() {
+            collections.push(elements_table(table, &pair.key));
+        }
+    }
+    Ok(collections)
+}
+
+fn hmset_len(cmd: &CommandRequest) -> u64 {
+    match &cmd.request_data {
+        Some(RequestData::Hmset(v)) => v.pairs.len() as u64,
+        _ => 0,
+    }
+}
+
+fn check(res: CommandResponse) -> Result<CommandResponse, KvError> {
+    if res.status != StatusCode::OK.as_u16() as u32 {
+        let msg = format!("{} ({})", res.message, res.status);
+        return Err(KvError::Internal(msg));
+    }
+    Ok(res)
+}
+
+// The type of the value and the value as text, which is how CSV holds it
+fn to_text(value: Option<&Value>) -> (&'static str, String) {
+    match value.and_then(|v| v.value.as_ref()) {
+        Some(value::Value::String(s)) => ("string", s.clone()),
+        Some(value::Value::Binary(buf)) => ("binary", BASE64.encode(buf)),
+        Some(value::Value::Integer(i)) => ("integer", i.to_string()),
+        Some(value::Value::Float(f)) => ("float", f.to_string()),
+        Some(value::Value::Bool(b)) => ("bool", b.to_string()),
+        Some(value::Value::Json(text)) => ("json", text.clone()),
+        Some(value::Value::Collection(c)) => (
+            "collection",
+            format!("{} {} {} {}", c.kind, c.len, c.head, c.tail),
+        ),
+        None => ("null", String::new()),
+    }
+}
+
+fn from_text(ty: &str, text: &str) -> Result<Value, String> {
+    let invalid = |e: &dyn std::fmt::Display| format!("invalid {} {:?}: {}", ty, text, e);
+    match ty {
+        "string" => Ok(text.into()),
+        "binary" => BASE64
+            .decode(text)
+            .map(|buf| Bytes::from(buf).into())
+            .map_err(|e| invalid(&e)),
+        "integer" => text.parse::<i64>().map(Into::into).map_err(|e| invalid(&e)),
+        "float" => text.parse::<f64>().map(Into::into).map_err(|e| invalid(&e)),
+        "bool" => text.parse::<bool>().map(Into::into).map_err(|e| invalid(&e)),
+        "json" => serde_json::from_str::<serde_json::Value>(text)
+            .map(|_| Value {
+                value: Some(value::Value::Json(text.into())),
+            })
+            .map_err(|e| invalid(&e)),
+        "collection" => match text.split(' ').collect::<Vec<_>>()[..] {
+            [kind, len, head, tail] => Ok(Collection {
+                kind: kind.into(),
+                len: len.parse().map_err(|e| invalid(&e))?,
+                head: head.parse().map_err(|e| invalid(&e))?,
+                tail: tail.parse().map_err(|e| invalid(&e))?,
+            }
+            .into()),
+            _ => Err(invalid(&"expect kind, len, head and tail")),
+        },
+        "null" if text.is_empty() => Ok(Value::default()),
+        "null" => Err(invalid(&"expect no value")),
+        _ => Err(format!("unknown type {:?}", ty)),
+    }
+}
+
+fn parse_json(data: &str) -> Result<ExportRecord, String> {
+    use serde_json::Value as Json;
+
+    let record: Json = serde_json::from_str(data).map_err(|e| e.to_string())?;
+    let field = |name: &str| match &record[name] {
+        Json::String(s) => Ok(s.as_str()),
+        _ => Err(format!("expect a string {}", name)),
+    };
+    let (table, key, ty) = (field("table")?, field("key")?, field("type")?);
+
+    let value = match (ty, &record["value"]) {
+        (_, Json::String(s)) => from_text(ty, s)?,
+        ("integer", Json::Number(n)) => n
+            .as_i64()
+            .ok_or_else(|| format!("invalid integer {}", n))?
+            .into(),
+        ("float", Json::Number(n)) => n
+            .as_f64()
+            .ok_or_else(|| format!("invalid float {}", n))?
+            .into(),
+        ("bool", Json::Bool(b)) => (*b).into(),
+        ("null", Json::Null) => Value::default(),
+        (_, v) => return Err(format!("invalid {} {}", ty, v)),
+    };
+    Ok((table.into(), Kvpair::new(key, value)))
+}
+
+fn parse_csv(record: &csv::StringRecord) -> Result<ExportRecord, String> {
+    match (record.get(0), record.get(1), record.get(2), record.get(3)) {
+        (Some(table), Some(key), Some(ty), Some(text)) if record.len() == CSV_HEADER.len() => {
+            Ok((table.into(), Kvpair::new(key, from_text(ty, text)?)))
+        }
+        _ => Err(format!("expect {} fields", CSV_HEADER.len())),
+    }
+}
+
+fn csv_io_error(e: csv::Error) -> KvError {
+    KvError::IoError(e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<ExportRecord> {
+        vec![
+            ("t1".into(), Kvpair::new("a", "hello, \"world\"\n".into())),
+            ("t1".into(), Kvpair::new("b", b"\x00\xffbin".into())),
+            ("t1".into(), Kvpair::new("c", (-42).into())),
+            ("t2".into(), Kvpair::new("d", 0.1.into())),
+            ("t2".into(), Kvpair::new("e", f64::INFINITY.into())),
+            ("t2".into(), Kvpair::new("f", true.into())),
+            ("t2".into(), Kvpair::new("g", Value::default())),
+        ]
+    }
+
+    fn round_trip(format: RecordFormat) -> Result<Vec<u8>, KvError> {
+        let mut writer = RecordWriter::new(Vec::new(), format)?;
+        for (table, pair) in &records() {
+            writer.write(table, pair)?;
+        }
+        writer.flush()?;
+        let data = match writer.inner {
+            WriterInner::JsonLines(v) => v,
+            WriterInner::Csv(v) => (*v).into_inner().unwrap(),
+        };
+
+        let read: Result<Vec<_>, _> = RecordReader::new(data.as_slice(), format)?.collect();
+        assert_eq!(read?, records());
+        Ok(data)
+    }
+
+    #[test]
+    fn json_lines_should_round_trip() -> Result<(), KvError> {
+        let data = round_trip(RecordFormat::JsonLines)?;
+        let data = String::from_utf8(data).unwrap();
+        let lines: Vec<_> = data.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(
+            lines[2],
+            r#"{"key":"c","table":"t1","type":"integer","value":-42}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn csv_should_round_trip() -> Result<(), KvError> {
+        let data = round_trip(RecordFormat::Csv)?;
+        let data = String::from_utf8(data).unwrap();
+        assert!(data.starts_with("table,key,type,value\n"));
+        assert!(data.contains("t1,b,binary,AP9iaW4=\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_records_should_be_reported_with_line() {
+        let data = "{\"table\":\"t1\",\"key\":\"a\",\"type\":\"string\",\"value\":\"v\"}\n\n{}\n";
+        let read: Vec<_> = RecordReader::new(data.as_bytes(), RecordFormat::JsonLines)
+            .unwrap()
+            .collect();
+        assert!(read[0].is_ok());
+        assert!(matches!(read[1], Err(KvError::InvalidRecord(3, _))));
+
+        let data = "table,key,type,value\nt1,a,integer,abc\n";
+        let read: Vec<_> = RecordReader::new(data.as_bytes(), RecordFormat::Csv)
+            .unwrap()
+            .collect();
+        assert!(matches!(&read[0], Err(KvError::InvalidRecord(2, msg)) if msg.contains("integer")));
+
+        let result = RecordReader::new("k,v\n".as_bytes(), RecordFormat::Csv);
+        assert!(matches!(result, Err(KvError::InvalidRecord(1, _))));
+    }
+
+    #[test]
+    fn batches_should_split_by_table_and_size() {
+        let records = records().into_iter().map(Ok);
+        let batches: Vec<_> = Batches::new(records, 3)
+            .map(|v| {
+                let cmd = v.unwrap();
+                (cmd.table().unwrap().to_string(), hmset_len(&cmd))
+            })
+            .collect();
+        let expected = [("t1", 3), ("t2", 3), ("t2", 1)];
+        let expected: Vec<_> = expected.iter().map(|(t, n)| (t.to_string(), *n)).collect();
+        assert_eq!(batches, expected);
+    }
+
+    #[test]
+    fn export_and_import_should_copy_tables() -> Result<(), KvError> {
+        let store = MemTable::new();
+        import_store(&store, records().into_iter().map(Ok), 2)?;
+
+        let mut writer = RecordWriter::new(Vec::new(), RecordFormat::Csv)?;
+        assert_eq!(export_store(&store, &[], &mut writer)?, 7);
+        let data = match writer.inner {
+            WriterInner::Csv(v) => (*v).into_inner().unwrap(),
+            _ => unreachable!(),
+        };
+
+        let copy = MemTable::new();
+        let reader = RecordReader::new(data.as_slice(), RecordFormat::Csv)?;
+        assert_eq!(import_store(&copy, reader, 100)?, 7);
+        let mut pairs = copy.get_all("t2")?;
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        let expected: Vec<_> = records().into_iter().skip(3).map(|v| v.1).collect();
+        assert_eq!(pairs, expected);
+
+        let mut writer = RecordWriter::new(Vec::new(), RecordFormat::JsonLines)?;
+        assert_eq!(export_store(&copy, &["t1".into()], &mut writer)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn export_should_carry_the_elements_of_collections() -> Result<(), KvError> {
+        let store = MemTable::new();
+        store.lpush("t1", "l", vec!["a".into(), "b".into()])?;
+        store.zadd("t1", "z", vec![("m".into(), 1.0)])?;
+
+        let mut writer = RecordWriter::new(Vec::new(), RecordFormat::Csv)?;
+        assert_eq!(export_store(&store, &["t1".into()], &mut writer)?, 5);
+        let data = match writer.inner {
+            WriterInner::Csv(v) => (*v).into_inner().unwrap(),
+            _ => unreachable!(),
+        };
+
+        let copy = MemTable::new();
+        let reader = RecordReader::new(data.as_slice(), RecordFormat::Csv)?;
+        assert_eq!(import_store(&copy, reader, 100)?, 5);
+        assert_eq!(copy.lrange("t1", "l", 0, -1)?, vec!["b".into(), "a".into()]);
+        assert_eq!(copy.zrange("t1", "z", 0, -1)?, vec![("m".to_string(), 1.0)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_and_import_should_work_over_the_network() -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let server = service.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(ProstServerStream::new(stream, server.clone()).process());
+            }
+        });
+
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let imported = import_remote(&mut client, records().into_iter().map(Ok), 2).await?;
+        assert_eq!(imported, 7);
+
+        let mut writer = RecordWriter::new(Vec::new(), RecordFormat::JsonLines)?;
+        assert_eq!(export_remote(&mut client, &[], &mut writer).await?, 7);
+        let data = match writer.inner {
+            WriterInner::JsonLines(v) => v,
+            _ => unreachable!(),
+        };
+        let read: Result<Vec<_>, _> =
+            RecordReader::new(data.as_slice(), RecordFormat::JsonLines)?.collect();
+        assert_eq!(read?, records());
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+// gRPC gateway, implements the KvService generated from abi.proto on top of Service
+
+use crate::command_request::RequestData;
+use crate::{
+    kv_service_server::{KvService, KvServiceServer},
+    ChangeEvent, CommandRequest, CommandResponse, ConnectionContext, Hgetall, KvError, Kvpair,
+    ScanRequest, Service, Storage, SubscribeRequest, TlsServerAcceptor, Watch,
+};
+use futures::{stream::BoxStream, StreamExt};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream};
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+// How many Kvpair a scan could buffer before the client consumes them
+const SCAN_BUFFER: usize = 128;
+
+/// Build the gRPC server of the gateway, sharing the Service with other listeners
+pub fn grpc_server<Store>(service: Service<Store>) -> KvServiceServer<Service<Store>>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    KvServiceServer::new(service)
+}
+
+/// Serve the gRPC gateway over TLS, the client certs are verified when the acceptor has a
+/// client CA
+pub async fn serve_grpc_tls<Store>(
+    listener: TcpListener,
+    tls: TlsServerAcceptor,
+    service: Service<Store>,
+) -> Result<(), tonic::transport::Error>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let incoming = ReceiverStream::new(super::tls_incoming(listener, tls))
+        .map(|(stream, _)| Ok::<_, io::Error>(GatewayStream(stream)));
+    Server::builder()
+        .add_service(grpc_server(service))
+        .serve_with_incoming(incoming)
+        .await
+}
+
+// A TLS connection of the gateway, tonic only knows the TLS streams of its own rustls. The
+// peer address is the one of the TCP connection
+struct GatewayStream(TlsStream<TcpStream>);
+
+impl Connected for GatewayStream {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.0.get_ref().0.connect_info()
+    }
+}
+
+impl AsyncRead for GatewayStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for GatewayStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[tonic::async_trait]
+impl<Store> KvService for Service<Store>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    async fn execute(
+        &self,
+        request: Request<CommandRequest>,
+    ) -> Result<Response<CommandResponse>, Status> {
+        let ctx = ConnectionContext::new(request.remote_addr());
+        Ok(Response::new(Service::execute(self, request.into_inner(), &ctx)))
+    }
+
+    type ScanStream = BoxStream<'static, Result<Kvpair, Status>>;
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let ctx = ConnectionContext::new(request.remote_addr());
+        let ScanRequest { table, filter } = request.into_inner();
+        // a scan reads what Hgetall does, the hooks see it as one
+        let cmd = CommandRequest {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table.clone(),
+                filter: filter.clone(),
+            })),
+        };
+        self.admit(&cmd, &ctx).map_err(refused)?;
+
+        let (tx, rx) = mpsc::channel(SCAN_BUFFER);
+        let service = self.clone();
+
+        // Storage iterators are blocking and not Send, so drive them on a blocking thread
+        tokio::task::spawn_blocking(move || {
+            let status = |e: KvError| match e {
+                KvError::InvalidCommand(_) => Status::invalid_argument(e.to_string()),
+                e => Status::internal(e.to_string()),
+            };
+            match service.scan(&table, filter) {
+                Ok(iter) => {
+                    // the stream ends with the first kvpair which can't be read
+                    for pair in iter {
+                        let failed = pair.is_err();
+                        if tx.blocking_send(pair.map_err(status)).is_err() || failed {
+                            break;
+                        }
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(status(e)));
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx).boxed()))
+    }
+
+    type SubscribeStream = BoxStream<'static, Result<CommandRequest, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let ctx = ConnectionContext::new(request.remote_addr());
+        let table = request.into_inner().table;
+        // a subscription streams the commands of the table, the hooks see it as a Watch of it
+        let cmd = CommandRequest {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.clone(),
+                ..Default::default()
+            })),
+        };
+        self.admit(&cmd, &ctx).map_err(refused)?;
+
+        let stream = BroadcastStream::new(Service::subscribe(self)).filter_map(move |log| {
+            let item = match log.map(|v| v.command.clone()) {
+                Ok(Some(cmd)) if cmd.table() == Some(table.as_str()) => Some(Ok(cmd)),
+                Ok(_) => None,
+                Err(e) => Some(Err(Status::data_loss(e.to_string()))),
+            };
+            futures::future::ready(item)
+        });
+
+        Ok(Response::new(stream.boxed()))
+    }
+
+    type WatchChangesStream = BoxStream<'static, Result<ChangeEvent, Status>>;
+
+    async fn watch_changes(
+        &self,
+        request: Request<Watch>,
+    ) -> Result<Response<Self::WatchChangesStream>, Status> {
+        let ctx = ConnectionContext::new(request.remote_addr());
+        let cmd = CommandRequest {
+            request_data: Some(RequestData::Watch(request.into_inner())),
+        };
+        let watcher = Service::watch(self, cmd, &ctx).map_err(|e| match e {
+            KvError::SeqCompacted(_, _) => Status::out_of_range(e.to_string()),
+            KvError::PermissionDenied(_) => Status::permission_denied(e.to_string()),
+            e => Status::invalid_argument(e.to_string()),
+        })?;
+
+        // the stream ends after the first error, the client watches again from the last seq
+        let stream = futures::stream::unfold(Some(watcher), |watcher| async move {
+            let mut watcher = watcher?;
+            match watcher.next().await {
+                Ok(event) => Some((Ok(event), Some(watcher))),
+                Err(e) => Some((Err(Status::data_loss(e.to_string())), None)),
+            }
+        });
+
+        Ok(Response::new(stream.boxed()))
+    }
+}
+
+// Status of a streaming request refused by the hooks of the service or for an internal table
+fn refused(e: KvError) -> Status {
+    match e {
+        KvError::PermissionDenied(_) => Status::permission_denied(e.to_string()),
+        e => Status::invalid_argument(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, kv_service_client::KvServiceClient, MemTable, ScanFilter, ServiceInner,
+        TlsClientConnector, Value,
+    };
+    use anyhow::Result;
+    use std::net::SocketAddr;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Channel, Endpoint, Uri};
+    use tower::service_fn;
+
+    const CA_CERT: &str = include_str!("../../fixtures/ca.cert");
+    const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
+    const SERVER_KEY: &str = include_str!("../../fixtures/server.key");
+
+    #[tokio::test]
+    async fn grpc_execute_should_work() -> Result<()> {
+        let (addr, _) = start_server().await?;
+        let mut client = connect(addr).await?;
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(cmd).await?.into_inner();
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res.into_inner(), &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn grpc_scan_should_work() -> Result<()> {
+        let (addr, service) = start_server().await?;
+        let ctx = ConnectionContext::default();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &ctx);
+        service.execute(CommandRequest::new_hset("t1", "k2", "v2".into()), &ctx);
+        service.execute(CommandRequest::new_hset("t2", "k3", "v3".into()), &ctx);
+        let mut client = connect(addr).await?;
+
+        let req = ScanRequest {
+            table: "t1".into(),
+            ..Default::default()
+        };
+        let stream = client.scan(req).await?.into_inner();
+        let mut pairs: Vec<Kvpair> = stream.map(|v| v.unwrap()).collect().await;
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            pairs,
+            vec![Kvpair::new("k1", "v1".into()), Kvpair::new("k2", "v2".into())]
+        );
+
+        // filtered on the server
+        let req = ScanRequest {
+            table: "t1".into(),
+            filter: Some(ScanFilter {
+                key_glob: "*2".into(),
+                ..Default::default()
+            }),
+        };
+        let stream = client.scan(req).await?.into_inner();
+        let pairs: Vec<Kvpair> = stream.map(|v| v.unwrap()).collect().await;
+        assert_eq!(pairs, vec![Kvpair::new("k2", "v2".into())]);
+
+        let req = ScanRequest {
+            table: "t1".into(),
+            filter: Some(ScanFilter {
+                count_only: true,
+                ..Default::default()
+            }),
+        };
+        let mut stream = client.scan(req).await?.into_inner();
+        let status = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn grpc_subscribe_should_work() -> Result<()> {
+        let (addr, service) = start_server().await?;
+        let mut client = connect(addr).await?;
+
+        let req = SubscribeRequest { table: "t1".into() };
+        let mut stream = client.subscribe(req).await?.into_inner();
+
+        let cmd1 = CommandRequest::new_hset("t2", "k1", "v1".into());
+        let cmd2 = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let cmd3 = CommandRequest::new_hget("t1", "k1");
+        let cmd4 = CommandRequest::new_hdel("t1", "k1");
+        for cmd in [cmd1, cmd2.clone(), cmd3, cmd4.clone()] {
+            service.execute(cmd, &ConnectionContext::default());
+        }
+
+        assert_eq!(stream.next().await.unwrap()?, cmd2);
+        assert_eq!(stream.next().await.unwrap()?, cmd4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn grpc_streams_should_be_authorized() -> Result<()> {
+        fn no_secrets(cmd: &CommandRequest, _: &ConnectionContext) -> Result<(), KvError> {
+            match cmd.table() {
+                Some("secrets") => Err(KvError::PermissionDenied("no secrets".into())),
+                _ => Ok(()),
+            }
+        }
+        let service = ServiceInner::new(MemTable::new()).fn_authorize(no_secrets);
+        let (addr, _) = start_server_with(service.into()).await?;
+        let mut client = connect(addr).await?;
+
+        let req = ScanRequest {
+            table: "secrets".into(),
+            ..Default::default()
+        };
+        let status = client.scan(req).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let req = SubscribeRequest {
+            table: "secrets".into(),
+        };
+        let status = client.subscribe(req).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        // the internal tables can't be streamed either
+        let req = SubscribeRequest {
+            table: "\0replication".into(),
+        };
+        let status = client.subscribe(req).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn grpc_over_tls_should_work() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let tls = TlsServerAcceptor::new(SERVER_CERT, SERVER_KEY, None)?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(serve_grpc_tls(listener, tls, service));
+
+        let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(CA_CERT))?;
+        let channel = Endpoint::from_static("http://kvserver.acme.inc")
+            .connect_with_connector(service_fn(move |_: Uri| {
+                let connector = connector.clone();
+                async move { connector.connect(TcpStream::connect(addr).await?).await }
+            }))
+            .await?;
+        let mut client = KvServiceClient::new(channel);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_res_ok(client.execute(cmd).await?.into_inner(), &[Value::default()], &[]);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res.into_inner(), &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    async fn start_server() -> Result<(SocketAddr, Service)> {
+        start_server_with(ServiceInner::new(MemTable::new()).into()).await
+    }
+
+    async fn start_server_with(service: Service) -> Result<(SocketAddr, Service)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = Server::builder()
+            .add_service(grpc_server(service.clone()))
+            .serve_with_incoming(TcpListenerStream::new(listener));
+        tokio::spawn(server);
+
+        Ok((addr, service))
+    }
+
+    async fn connect(addr: SocketAddr) -> Result<KvServiceClient<Channel>> {
+        Ok(KvServiceClient::connect(format!("http://{}", addr)).await?)
+    }
+}
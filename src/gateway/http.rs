@@ -0,0 +1,495 @@
+// HTTP/JSON gateway, maps REST routes onto CommandRequest and reuses Service::execute
+
+use crate::{
+    CommandRequest, CommandResponse, ConnectionContext, KvError, Kvpair, RaftNode, RaftRole,
+    RaftStatus, ReplicationStats, ScanFilter, Service, Storage, TlsServerAcceptor, Value,
+};
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Extension, Json, Router,
+};
+use hyper::server::conn::http1;
+use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tracing::warn;
+
+/// Build the HTTP router of the gateway:
+///
+/// - `GET    /tables/{t}`          -> Hgetall, filtered by the query: `glob`, `regex`, `type`,
+///   `min` and `max` as JSON encoded values, `path`, `keys_only` and `count`
+/// - `GET    /tables/{t}/keys/{k}` -> Hget
+/// - `PUT    /tables/{t}/keys/{k}` -> Hset, body is the JSON encoded value
+/// - `DELETE /tables/{t}/keys/{k}` -> Hdel
+/// - `GET    /stats`               -> replication sequence numbers and lag
+///
+/// Serve it with `into_make_service_with_connect_info::<SocketAddr>()` to have the peer address
+/// in the ConnectionContext.
+pub fn http_router<Store>(service: Service<Store>) -> Router
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/tables/:table", get(hget_all::<Store>))
+        .route(
+            "/tables/:table/keys/:key",
+            get(hget::<Store>).put(hset::<Store>).delete(hdel::<Store>),
+        )
+        .route("/stats", get(stats::<Store>))
+        .with_state(service)
+}
+
+/// Routes to manage a raft cluster, merged with http_router on a node of the cluster:
+///
+/// - `GET    /cluster`              -> status of the node
+/// - `PUT    /cluster/members/{id}` -> add the node, body is its raft address as a JSON string
+/// - `DELETE /cluster/members/{id}` -> remove the node
+///
+/// Membership changes are only accepted by the leader. Anyone reaching the routes could change
+/// the members, serve them only with `serve_http_tls` and an acceptor which has a client CA.
+pub fn cluster_router<Store>(node: RaftNode<Store>) -> Router
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/cluster", get(cluster_status::<Store>))
+        .route(
+            "/cluster/members/:id",
+            put(add_member::<Store>).delete(remove_member::<Store>),
+        )
+        .with_state(node)
+}
+
+/// Serve the router over TLS, the client certs are verified when the acceptor has a client CA.
+/// The peer address is in the ConnectionContext, as with `into_make_service_with_connect_info`
+pub async fn serve_http_tls(listener: TcpListener, tls: TlsServerAcceptor, router: Router) {
+    let mut incoming = super::tls_incoming(listener, tls);
+    while let Some((stream, addr)) = incoming.recv().await {
+        let router = router.clone().layer(Extension(ConnectInfo(addr)));
+        let service = TowerToHyperService::new(router);
+        tokio::spawn(async move {
+            let conn = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+            if let Err(e) = conn.await {
+                warn!("HTTP connection with {:?} failed: {:?}", addr, e);
+            }
+        });
+    }
+}
+
+async fn hget_all<Store: Storage>(
+    State(service): State<Service<Store>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Path(table): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let cmd = match scan_filter(query) {
+        Ok(Some(filter)) => CommandRequest::new_hget_all_filtered(table, filter),
+        Ok(None) => CommandRequest::new_hget_all(table),
+        Err(e) => return reply(e.into()),
+    };
+    reply(service.execute(cmd, &context(peer)))
+}
+
+async fn hget<Store: Storage>(
+    State(service): State<Service<Store>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Path((table, key)): Path<(String, String)>,
+) -> Response {
+    reply(service.execute(CommandRequest::new_hget(table, key), &context(peer)))
+}
+
+async fn hset<Store: Storage>(
+    State(service): State<Service<Store>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Path((table, key)): Path<(String, String)>,
+    body: Bytes,
+) -> Response {
+    let value = serde_json::from_slice::<serde_json::Value>(&body)
+        .map_err(|e| KvError::InvalidCommand(format!("Invalid JSON body: {}", e)))
+        .and_then(Value::try_from);
+
+    match value {
+        Ok(value) => {
+            let cmd = CommandRequest::new_hset(table, key, value);
+            reply(service.execute(cmd, &context(peer)))
+        }
+        Err(e) => reply(e.into()),
+    }
+}
+
+async fn hdel<Store: Storage>(
+    State(service): State<Service<Store>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Path((table, key)): Path<(String, String)>,
+) -> Response {
+    reply(service.execute(CommandRequest::new_hdel(table, key), &context(peer)))
+}
+
+async fn stats<Store: Storage>(State(service): State<Service<Store>>) -> Response {
+    Json(stats_to_json(service.replication_stats())).into_response()
+}
+
+async fn cluster_status<Store>(State(node): State<RaftNode<Store>>) -> Response
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    Json(status_to_json(node.status())).into_response()
+}
+
+async fn add_member<Store>(
+    State(node): State<RaftNode<Store>>,
+    Path(id): Path<u64>,
+    body: Bytes,
+) -> Response
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let result = match serde_json::from_slice::<String>(&body) {
+        Ok(addr) => node.add_member(id, addr).await,
+        Err(e) => Err(KvError::InvalidCommand(format!("Invalid raft address: {}", e))),
+    };
+    member_changed(node, result)
+}
+
+async fn remove_member<Store>(State(node): State<RaftNode<Store>>, Path(id): Path<u64>) -> Response
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let result = node.remove_member(id).await;
+    member_changed(node, result)
+}
+
+fn member_changed<Store>(node: RaftNode<Store>, result: Result<(), KvError>) -> Response
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    match result {
+        Ok(()) => Json(status_to_json(node.status())).into_response(),
+        Err(e) => reply(e.into()),
+    }
+}
+
+// The filter of Hgetall from the query of the URL, None when the query sets none. Other
+// parameters, like a cache buster, are left to whoever added them
+fn scan_filter(mut query: HashMap<String, String>) -> Result<Option<ScanFilter>, KvError> {
+    let value = |text: Option<String>| match text {
+        Some(text) => serde_json::from_str::<serde_json::Value>(&text)
+            .map_err(|e| KvError::InvalidCommand(format!("Invalid JSON value {:?}: {}", text, e)))
+            .and_then(Value::try_from)
+            .map(Some),
+        None => Ok(None),
+    };
+    // a flag without a value is set
+    let flag = |text: Option<String>| matches!(text.as_deref(), Some("" | "1" | "true"));
+    let filter = ScanFilter {
+        key_glob: query.remove("glob").unwrap_or_default(),
+        key_regex: query.remove("regex").unwrap_or_default(),
+        value_type: query.remove("type").unwrap_or_default(),
+        min: value(query.remove("min"))?,
+        max: value(query.remove("max"))?,
+        path: query.remove("path").unwrap_or_default(),
+        keys_only: flag(query.remove("keys_only")),
+        count_only: flag(query.remove("count")),
+    };
+    match filter == ScanFilter::default() {
+        true => Ok(None),
+        false => Ok(Some(filter)),
+    }
+}
+
+// Every HTTP request is a connection of its own
+fn context(peer: Option<ConnectInfo<SocketAddr>>) -> ConnectionContext {
+    ConnectionContext::new(peer.map(|ConnectInfo(addr)| addr))
+}
+
+// Status code of HTTP response is the one in CommandResponse, since it reuses HTTP codes already
+fn reply(res: CommandResponse) -> Response {
+    let status = StatusCode::from_u16(res.status as _).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let values: Vec<serde_json::Value> = res.values.into_iter().map(Into::into).collect();
+    let pairs: Vec<serde_json::Value> = res.pairs.into_iter().map(pair_to_json).collect();
+    let body = json!({
+        "status": res.status,
+        "message": res.message,
+        "values": values,
+        "pairs": pairs,
+    });
+
+    (status, Json(body)).into_response()
+}
+
+fn stats_to_json(stats: ReplicationStats) -> serde_json::Value {
+    let followers: Vec<serde_json::Value> = stats
+        .followers
+        .into_iter()
+        .map(|v| {
+            json!({
+                "peer": v.peer.map(|addr| addr.to_string()),
+                "sent_seq": v.sent_seq,
+                "lag": v.lag,
+            })
+        })
+        .collect();
+    let primary = stats.primary.map(|v| {
+        json!({
+            "addr": v.addr,
+            "connected": v.connected,
+            "applied_seq": v.applied_seq,
+            "primary_seq": v.primary_seq,
+            "lag": v.lag,
+        })
+    });
+
+    json!({
+        "role": if primary.is_some() { "follower" } else { "primary" },
+        "log_id": stats.log_id,
+        "seq": stats.seq,
+        "followers": followers,
+        "primary": primary,
+    })
+}
+
+fn status_to_json(status: RaftStatus) -> serde_json::Value {
+    let role = match status.role {
+        RaftRole::Follower => "follower",
+        RaftRole::Candidate => "candidate",
+        RaftRole::Leader => "leader",
+    };
+    let members: Vec<serde_json::Value> = status
+        .members
+        .into_iter()
+        .map(|v| json!({"id": v.id, "addr": v.addr}))
+        .collect();
+
+    json!({
+        "id": status.id,
+        "role": role,
+        "term": status.term,
+        "leader": status.leader,
+        "commit_index": status.commit_index,
+        "applied_index": status.applied_index,
+        "snapshot_index": status.snapshot_index,
+        "members": members,
+    })
+}
+
+fn pair_to_json(pair: Kvpair) -> serde_json::Value {
+    json!({
+        "key": pair.key,
+        "value": serde_json::Value::from(pair.value.unwrap_or_default()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CertAuthority, MemTable, MemoryNetwork, RaftConfig, RaftPeer, ServiceInner,
+        TlsClientConnector,
+    };
+    use axum::{body::Body, http::Request};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn http_put_then_get_should_work() {
+        let router = new_router();
+
+        let (status, body) = call(&router, "PUT", "/tables/t1/keys/k1", r#""v1""#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["values"], json!([null]));
+
+        let (status, body) = call(&router, "GET", "/tables/t1/keys/k1", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["values"], json!(["v1"]));
+    }
+
+    #[tokio::test]
+    async fn http_get_all_and_delete_should_work() {
+        let router = new_router();
+        call(&router, "PUT", "/tables/t1/keys/k1", "10").await;
+        call(&router, "PUT", "/tables/t1/keys/k2", r#"{"binary": "aGVsbG8="}"#).await;
+
+        let (status, body) = call(&router, "GET", "/tables/t1", "").await;
+        assert_eq!(status, StatusCode::OK);
+        let mut pairs = body["pairs"].as_array().unwrap().clone();
+        pairs.sort_by_key(|p| p["key"].as_str().unwrap().to_string());
+        assert_eq!(
+            pairs,
+            vec![
+                json!({"key": "k1", "value": 10}),
+                json!({"key": "k2", "value": {"binary": "aGVsbG8="}}),
+            ]
+        );
+
+        let (status, body) = call(&router, "DELETE", "/tables/t1/keys/k1", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["values"], json!([10]));
+    }
+
+    #[tokio::test]
+    async fn http_get_all_should_filter_by_query() {
+        let router = new_router();
+        for (key, value) in [("u1", "10"), ("u2", "25"), ("admin", "30"), ("u3", r#""x""#)] {
+            call(&router, "PUT", &format!("/tables/t1/keys/{}", key), value).await;
+        }
+
+        let (status, body) = call(&router, "GET", "/tables/t1?glob=u*&min=20", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["pairs"], json!([{"key": "u2", "value": 25}]));
+
+        let (_, body) = call(&router, "GET", "/tables/t1?type=integer&count", "").await;
+        assert_eq!(body["values"], json!([3]));
+
+        // unknown parameters are ignored
+        let (status, body) = call(&router, "GET", "/tables/t1?limit=1", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["pairs"].as_array().unwrap().len(), 4);
+        let (_, body) = call(&router, "GET", "/tables/t1?glob=u*&min=20&_=1700000000", "").await;
+        assert_eq!(body["pairs"], json!([{"key": "u2", "value": 25}]));
+    }
+
+    #[tokio::test]
+    async fn http_status_should_come_from_command_response() {
+        let router = new_router();
+
+        let (status, body) = call(&router, "GET", "/tables/t1/keys/nope", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body["message"].as_str().unwrap().contains("Not found"));
+
+        let (status, _) = call(&router, "PUT", "/tables/t1/keys/k1", "[1, 2]").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn http_stats_should_show_replication() {
+        let router = new_router();
+        call(&router, "PUT", "/tables/t1/keys/k1", "10").await;
+
+        let (status, body) = call(&router, "GET", "/stats", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["role"], json!("primary"));
+        assert_eq!(body["seq"], json!(1));
+        assert_eq!(body["followers"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn http_cluster_should_show_status_and_change_members() {
+        let network = MemoryNetwork::new();
+        let peers = vec![RaftPeer {
+            id: 1,
+            addr: "node1".into(),
+        }];
+        let config = RaftConfig {
+            tick_interval: std::time::Duration::from_millis(10),
+            propose_timeout: std::time::Duration::from_millis(200),
+            ..RaftConfig::new(1, "node1")
+        };
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let inbox = network.join("node1");
+        let node = RaftNode::start(config, peers, service, network, inbox).unwrap();
+        let router = cluster_router(node);
+
+        // a single node cluster elects itself
+        let mut body = serde_json::Value::Null;
+        for _ in 0..100 {
+            body = call(&router, "GET", "/cluster", "").await.1;
+            if body["role"] == json!("leader") {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(body["role"], json!("leader"));
+        assert_eq!(body["members"], json!([{"id": 1, "addr": "node1"}]));
+
+        let (status, _) = call(&router, "PUT", "/cluster/members/2", "node2").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // node2 isn't running, the change can't be committed by a majority
+        let (status, body) = call(&router, "PUT", "/cluster/members/2", r#""node2""#).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body["message"].as_str().unwrap().contains("timeout"));
+        let (_, body) = call(&router, "GET", "/cluster", "").await;
+        assert_eq!(body["members"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn http_over_tls_should_verify_client_certs() {
+        let ca = CertAuthority::new("Acme", "Acme CA", 1).unwrap();
+        let sans = ["kvserver.acme.inc".to_string()];
+        let server = ca.issue_server("Acme", "kvserver", &sans, 1).unwrap();
+        let client = ca.issue_client("Acme", "client", &[], 1).unwrap();
+        let ca = ca.pem().cert.clone();
+        let tls = TlsServerAcceptor::new(&server.cert, &server.key, Some(&ca)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_http_tls(listener, tls, new_router()));
+
+        let identity = Some((client.cert.as_str(), client.key.as_str()));
+        let res = get_over_tls(addr, identity, &ca, "/stats").await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"));
+
+        // a client without a cert of the CA is refused
+        let res = get_over_tls(addr, None, &ca, "/stats").await;
+        assert!(!matches!(res, Ok(v) if v.starts_with("HTTP/1.1 200")));
+    }
+
+    #[test]
+    fn value_json_roundtrip_should_work() {
+        let values: Vec<Value> = vec![
+            "hello".into(),
+            42.into(),
+            1.5.into(),
+            true.into(),
+            b"data".into(),
+            Value::default(),
+        ];
+        for v in values {
+            let json: serde_json::Value = v.clone().into();
+            assert_eq!(Value::try_from(json).unwrap(), v);
+        }
+    }
+
+    fn new_router() -> Router {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        http_router(service)
+    }
+
+    async fn get_over_tls(
+        addr: SocketAddr,
+        identity: Option<(&str, &str)>,
+        ca: &str,
+        uri: &str,
+    ) -> Result<String, KvError> {
+        let connector = TlsClientConnector::new("kvserver.acme.inc", identity, Some(ca))?;
+        let mut stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        let req = format!("GET {} HTTP/1.1\r\nHost: kvserver\r\nConnection: close\r\n\r\n", uri);
+        stream.write_all(req.as_bytes()).await?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await?;
+        Ok(res)
+    }
+
+    async fn call(
+        router: &Router,
+        method: &str,
+        uri: &str,
+        body: &'static str,
+    ) -> (StatusCode, serde_json::Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+}
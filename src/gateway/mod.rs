@@ -0,0 +1,48 @@
+mod grpc;
+mod http;
+
+pub use self::grpc::{grpc_server, serve_grpc_tls};
+pub use self::http::{cluster_router, http_router, serve_http_tls};
+
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tracing::warn;
+
+use crate::TlsServerAcceptor;
+
+// The connections of a gateway which complete the TLS handshake, each handshake runs in a task
+// of its own so that a slow client doesn't hold up the others
+fn tls_incoming(
+    listener: TcpListener,
+    tls: TlsServerAcceptor,
+) -> mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            // A failed accept (e.g. out of file descriptors) only drops that connection
+            let (stream, addr) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Accept of the gateway failed: {:?}", e);
+                    continue;
+                }
+            };
+            // the gateway is stopped
+            if tx.is_closed() {
+                break;
+            }
+            let (tls, tx) = (tls.clone(), tx.clone());
+            tokio::spawn(async move {
+                match tls.accept(stream).await {
+                    Ok(stream) => {
+                        let _ = tx.send((stream, addr)).await;
+                    }
+                    Err(e) => warn!("TLS handshake with gateway client {:?} failed: {:?}", addr, e),
+                }
+            });
+        }
+    });
+    rx
+}
@@ -3,10 +3,18 @@ mod storage;
 mod error;
 mod service;
 mod network;
+mod gateway;
+mod cert;
+mod raft;
+mod export;
 
 pub use pb::abi::*;
 pub use error::KvError;
 pub use storage::*;
 pub use service::*;
 pub use network::*;
+pub use gateway::*;
+pub use cert::*;
+pub use raft::*;
+pub use export::*;
 
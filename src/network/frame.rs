@@ -1,4 +1,7 @@
-use crate::{CommandRequest, CommandResponse, KvError};
+use crate::{
+    ChangeEvent, CommandRequest, CommandResponse, Hello, HelloResponse, KvError, RaftMessage,
+    ReplicaEntry,
+};
 use bytes::{Buf, BufMut, BytesMut};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use prost::Message;
@@ -18,15 +21,41 @@ const COMPRESSION_LIMIT: usize = 1436;
 // compress flag bit shows whether it's compressed or not
 const COMPRESSION_BIT: usize = 1 << 31;
 
+// the largest frame the 31 bit length info could carry
+pub const MAX_FRAME_SIZE: usize = MAX_FRAME - 1;
+
+// How frames are encoded and read, negotiated by the Hello handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameOptions {
+    // whether a large payload could be compressed with gzip
+    pub gzip: bool,
+    // max size of a frame
+    pub max_size: usize,
+}
+
+impl Default for FrameOptions {
+    fn default() -> Self {
+        Self {
+            gzip: true,
+            max_size: MAX_FRAME_SIZE,
+        }
+    }
+}
+
 pub trait FrameCoder
 where
     Self: Message + Sized + Default,
 {
     // Encode a Message to a frame
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
+        self.encode_frame_with(buf, &FrameOptions::default())
+    }
+
+    // Encode a Message to a frame with the negotiated options
+    fn encode_frame_with(&self, buf: &mut BytesMut, opts: &FrameOptions) -> Result<(), KvError> {
         let size = self.encoded_len();
 
-        if size >= MAX_FRAME {
+        if size >= MAX_FRAME || size > opts.max_size {
             return Err(KvError::FrameError);
         }
 
@@ -34,7 +63,7 @@ where
         buf.put_u32(size as _);
 
         // if need to be compressed
-        if size > COMPRESSION_LIMIT {
+        if opts.gzip && size > COMPRESSION_LIMIT {
             let mut buf1 = Vec::with_capacity(size);
             self.encode(&mut buf1)?;
 
@@ -87,6 +116,11 @@ where
 
 impl FrameCoder for CommandRequest {}
 impl FrameCoder for CommandResponse {}
+impl FrameCoder for Hello {}
+impl FrameCoder for HelloResponse {}
+impl FrameCoder for ReplicaEntry {}
+impl FrameCoder for RaftMessage {}
+impl FrameCoder for ChangeEvent {}
 
 fn decode_header(header: usize) -> (usize, bool) {
     let len = header & !COMPRESSION_BIT;
@@ -95,11 +129,26 @@ fn decode_header(header: usize) -> (usize, bool) {
 }
 
 pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<(), KvError>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    read_frame_with(stream, buf, MAX_FRAME_SIZE).await
+}
+
+// Read a frame, refuse it if it's larger than max_size
+pub async fn read_frame_with<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    max_size: usize,
+) -> Result<(), KvError>
 where
     S: AsyncRead + Unpin + Send,
 {
     let header = stream.read_u32().await? as usize;
     let (len, _compressed) = decode_header(header);
+    if len > max_size {
+        return Err(KvError::FrameError);
+    }
 
     // create enough space in memory, at least a frame.
     buf.reserve(LEN_LEN + len);
@@ -199,6 +248,40 @@ mod tests {
         assert_eq!(cmd, cmd1);
     }
 
+    #[test]
+    fn frame_options_should_limit_compression_and_size() {
+        let value: Value = Bytes::from(vec![0u8; COMPRESSION_LIMIT + 1]).into();
+        let res: CommandResponse = value.into();
+
+        let mut buf = BytesMut::new();
+        let opts = FrameOptions {
+            gzip: false,
+            ..Default::default()
+        };
+        res.encode_frame_with(&mut buf, &opts).unwrap();
+        assert!(!is_compressed(&buf));
+        assert_eq!(CommandResponse::decode_frame(&mut buf).unwrap(), res);
+
+        let opts = FrameOptions {
+            max_size: COMPRESSION_LIMIT,
+            ..Default::default()
+        };
+        assert!(res.encode_frame_with(&mut buf, &opts).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_frame_with_should_refuse_large_frame() {
+        let mut buf = BytesMut::new();
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        cmd.encode_frame(&mut buf).unwrap();
+        let len = buf.len() - LEN_LEN;
+        let mut stream = DummyStream { buf };
+
+        let mut data = BytesMut::new();
+        let result = read_frame_with(&mut stream, &mut data, len - 1).await;
+        assert!(matches!(result, Err(KvError::FrameError)));
+    }
+
     fn is_compressed(data: &[u8]) -> bool {
         if let &[v] = &data[..1] {
             v >> 7 == 1
@@ -0,0 +1,194 @@
+// Hello handshake at the start of a connection: the client sends its protocol version and
+// capabilities, the server replies with the set both sides support, or refuses a client it
+// can't talk to with a clear error.
+
+use http::StatusCode;
+
+use crate::network::frame::{FrameOptions, MAX_FRAME_SIZE};
+use crate::{CommandResponse, Hello, HelloResponse, KvError, SUPPORTED_COMMANDS};
+
+// Protocol version spoken by this build
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// The oldest protocol version this build still speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// Compression codecs supported by FrameCoder
+const COMPRESSIONS: &[&str] = &["gzip"];
+
+// Commands a client of this build could send
+const CLIENT_COMMANDS: &[&str] = &[
+    "hget", "hgetall", "hmget", "hset", "hmset", "hdel", "hmdel", "hexist", "hmexist",
+    "backup", "list_tables", "lpush", "lpop", "lrange", "sadd", "srem", "smembers", "zadd",
+    "zrange", "zrangebyscore", "json_get", "json_set", "json_append", "json_incr",
+    "create_index", "drop_index", "hfind", "replicate", "watch",
+];
+
+// Commands served by ProstServerStream itself rather than Service::execute
+const STREAM_COMMANDS: &[&str] = &["replicate", "watch"];
+
+impl Hello {
+    // What a client of this build supports
+    pub fn client() -> Self {
+        Self::new(CLIENT_COMMANDS)
+    }
+
+    // What a server of this build supports
+    pub fn server() -> Self {
+        Self::new(&[SUPPORTED_COMMANDS, STREAM_COMMANDS].concat())
+    }
+
+    fn new(commands: &[&str]) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            commands: commands.iter().map(|v| v.to_string()).collect(),
+            compressions: COMPRESSIONS.iter().map(|v| v.to_string()).collect(),
+            max_frame_size: MAX_FRAME_SIZE as u32,
+        }
+    }
+
+    // The version and capabilities supported by both self and the peer
+    pub fn negotiate(&self, peer: &Hello) -> Result<Hello, KvError> {
+        // a CommandRequest from a client that doesn't send Hello may decode as one without version
+        if peer.version == 0 {
+            return Err(missing_hello());
+        }
+        let version = self.version.min(peer.version);
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(KvError::IncompatibleProtocol(format!(
+                "protocol version {} is not supported, expect {}..={}",
+                peer.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )));
+        }
+
+        let commands = intersect(&self.commands, &peer.commands);
+        if commands.is_empty() {
+            return Err(KvError::IncompatibleProtocol(
+                "no command is supported by both sides".into(),
+            ));
+        }
+
+        if peer.max_frame_size == 0 {
+            return Err(KvError::IncompatibleProtocol("max frame size is not set".into()));
+        }
+
+        Ok(Hello {
+            version,
+            commands,
+            compressions: intersect(&self.compressions, &peer.compressions),
+            max_frame_size: self.max_frame_size.min(peer.max_frame_size),
+        })
+    }
+
+    // Whether the command is in the negotiated set
+    pub fn supports(&self, command: &str) -> bool {
+        self.commands.iter().any(|v| v == command)
+    }
+
+    // How frames are encoded and read after the negotiation
+    pub fn frame_options(&self) -> FrameOptions {
+        FrameOptions {
+            gzip: self.compressions.iter().any(|v| v == "gzip"),
+            max_size: self.max_frame_size as usize,
+        }
+    }
+}
+
+impl HelloResponse {
+    // Turn the response back to the negotiated Hello, or the reason of the refusal
+    pub fn into_result(self) -> Result<Hello, KvError> {
+        match (self.status, self.hello) {
+            (200, Some(hello)) => Ok(hello),
+            _ => Err(KvError::IncompatibleProtocol(self.message)),
+        }
+    }
+}
+
+impl From<Hello> for HelloResponse {
+    fn from(hello: Hello) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            message: String::new(),
+            hello: Some(hello),
+        }
+    }
+}
+
+impl From<KvError> for HelloResponse {
+    fn from(err: KvError) -> Self {
+        let res = CommandResponse::from(err);
+        Self {
+            status: res.status,
+            message: res.message,
+            hello: None,
+        }
+    }
+}
+
+// The refusal of a client that sends a command before Hello
+pub(crate) fn missing_hello() -> KvError {
+    KvError::IncompatibleProtocol(
+        "missing Hello, send a Hello with the protocol version before the first command".into(),
+    )
+}
+
+fn intersect(ours: &[String], theirs: &[String]) -> Vec<String> {
+    ours.iter().filter(|v| theirs.contains(v)).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_should_pick_common_capabilities() {
+        let client = Hello {
+            version: PROTOCOL_VERSION + 1,
+            compressions: vec![],
+            max_frame_size: 1024,
+            ..Hello::client()
+        };
+        let hello = Hello::server().negotiate(&client).unwrap();
+
+        assert_eq!(hello.version, PROTOCOL_VERSION);
+        assert_eq!(hello.commands, [SUPPORTED_COMMANDS, STREAM_COMMANDS].concat());
+        assert!(hello.supports("hget"));
+        assert!(hello.supports("hmget"));
+        assert!(!hello.supports("hscan"));
+        assert_eq!(
+            hello.frame_options(),
+            FrameOptions {
+                gzip: false,
+                max_size: 1024
+            }
+        );
+
+        // the client checks the negotiated set in the same way
+        assert_eq!(client.negotiate(&hello).unwrap(), hello);
+    }
+
+    #[test]
+    fn negotiate_should_refuse_incompatible_peer() {
+        let server = Hello::server();
+
+        let legacy = Hello {
+            version: 0,
+            ..Hello::client()
+        };
+        let err = server.negotiate(&legacy).unwrap_err();
+        assert!(err.to_string().contains("missing Hello"));
+
+        let other = Hello {
+            commands: vec!["publish".into()],
+            ..Hello::client()
+        };
+        assert!(server.negotiate(&other).is_err());
+
+        let res: HelloResponse = server.negotiate(&other).unwrap_err().into();
+        assert_eq!(res.status, StatusCode::UPGRADE_REQUIRED.as_u16() as u32);
+        assert!(matches!(
+            res.into_result(),
+            Err(KvError::IncompatibleProtocol(_))
+        ));
+    }
+}
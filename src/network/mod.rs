@@ -1,22 +1,47 @@
-use crate::{CommandRequest, CommandResponse, KvError, Service};
+use crate::{ChangeEvent, CommandRequest, CommandResponse, ConnectionContext, Hello};
+use crate::{HelloResponse, KvError, RaftNode, ReplicaEntry, ReplicaSource, Service, Value};
+use crate::Watcher;
 use bytes::BytesMut;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tracing::info;
+use tracing::{info, warn};
 
 mod frame;
+mod hello;
+mod multiplex;
+mod quic;
+mod raft;
+mod replica;
+mod shard;
 mod tls;
 mod stream;
-
-pub use frame::{read_frame, FrameCoder};
+mod unix;
+
+pub use frame::{read_frame, read_frame_with, FrameCoder, FrameOptions, MAX_FRAME_SIZE};
+pub use hello::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+pub use multiplex::YamuxCtrl;
+pub use quic::*;
+pub use raft::{serve_raft, TcpTransport};
+pub use replica::follow;
+pub use shard::{HashRing, ShardBy, ShardedClient};
 pub use tls::*;
+pub use unix::*;
 
 pub struct ProstServerStream<S> {
     inner: S,
     service: Service,
+    ctx: ConnectionContext,
+    // negotiated with the client by the Hello handshake
+    hello: Hello,
+    // orders the writes in the cluster mode
+    raft: Option<RaftNode>,
 }
 
 pub struct ProstClientStream<S> {
     inner: S,
+    // what the client announces in the Hello handshake
+    hello: Hello,
+    // negotiated with the server, None until the first command is sent
+    negotiated: Option<Hello>,
 }
 
 impl<S> ProstServerStream<S>
@@ -27,31 +52,108 @@ where
         Self {
             inner: stream,
             service,
+            ctx: ConnectionContext::new(None),
+            hello: Hello::server(),
+            raft: None,
         }
     }
 
+    // Replace the context of the connection, e.g. with the peer address and TLS identity
+    pub fn with_context(mut self, ctx: ConnectionContext) -> Self {
+        self.ctx = ctx;
+        self
+    }
+
+    // Execute the commands through the raft node of the cluster
+    pub fn with_raft(mut self, raft: RaftNode) -> Self {
+        self.raft = Some(raft);
+        self
+    }
+
     pub async fn process(mut self) -> Result<(), KvError> {
+        self.handshake().await?;
+
         while let Ok(cmd) = self.recv().await {
             info!("Got a new command: {:?}", cmd);
-            let res = self.service.execute(cmd);
+            let res = match cmd.command() {
+                Some(name) if !self.hello.supports(name) => {
+                    KvError::InvalidCommand(format!("Command {} is not negotiated", name)).into()
+                }
+                // a follower takes over the connection until it is closed
+                Some("replicate") => match self.service.replicate(cmd, &self.ctx) {
+                    Ok(source) => return self.replicate(source).await,
+                    Err(e) => e.into(),
+                },
+                // so does a watcher
+                Some("watch") => match self.service.watch(cmd, &self.ctx) {
+                    Ok(watcher) => return self.watch(watcher).await,
+                    Err(e) => e.into(),
+                },
+                _ => match &self.raft {
+                    Some(raft) => raft.execute(cmd, &self.ctx).await,
+                    None => self.service.execute(cmd, &self.ctx),
+                },
+            };
             self.send(res).await?;
         }
         Ok(())
     }
 
-    async fn send(&mut self, msg: CommandResponse) -> Result<(), KvError> {
+    // The first frame of a connection must be a Hello, an incompatible client is refused
+    async fn handshake(&mut self) -> Result<(), KvError> {
         let mut buf = BytesMut::new();
-        msg.encode_frame(&mut buf)?;
-        let encoded = buf.freeze();
-        self.inner.write_all(&encoded[..]).await?;
-        Ok(())
+        read_frame_with(&mut self.inner, &mut buf, self.hello.max_frame_size as _).await?;
+        let negotiated = match Hello::decode_frame(&mut buf) {
+            Ok(hello) => self.hello.negotiate(&hello),
+            Err(_) => Err(hello::missing_hello()),
+        };
+
+        let res: HelloResponse = match negotiated {
+            Ok(hello) => {
+                self.hello = hello.clone();
+                hello.into()
+            }
+            Err(e) => {
+                warn!("Refuse client {:?}: {:?}", self.ctx.peer_addr, e);
+                e.into()
+            }
+        };
+        let refused = res.hello.is_none();
+        send_frame(&mut self.inner, &res, &FrameOptions::default()).await?;
+
+        match refused {
+            true => Err(KvError::IncompatibleProtocol(res.message)),
+            false => Ok(()),
+        }
+    }
+
+    // Acknowledge the Replicate command, then stream the entries until the follower is gone
+    async fn replicate(mut self, mut source: ReplicaSource) -> Result<(), KvError> {
+        info!("Follower {:?} connected", self.ctx.peer_addr);
+        self.send(Value::default().into()).await?;
+        let opts = self.hello.frame_options();
+        loop {
+            let entry = source.next().await?;
+            send_frame(&mut self.inner, &entry, &opts).await?;
+        }
+    }
+
+    // Acknowledge the Watch command with the seq it starts after, then stream the changes
+    async fn watch(mut self, mut watcher: Watcher) -> Result<(), KvError> {
+        self.send(Value::from(watcher.seq() as i64).into()).await?;
+        let opts = self.hello.frame_options();
+        loop {
+            let event = watcher.next().await?;
+            send_frame(&mut self.inner, &event, &opts).await?;
+        }
+    }
+
+    async fn send(&mut self, msg: CommandResponse) -> Result<(), KvError> {
+        send_frame(&mut self.inner, &msg, &self.hello.frame_options()).await
     }
 
     async fn recv(&mut self) -> Result<CommandRequest, KvError> {
-        let mut buf = BytesMut::new();
-        let stream = &mut self.inner;
-        read_frame(stream, &mut buf).await?;
-        CommandRequest::decode_frame(&mut buf)
+        recv_frame(&mut self.inner, self.hello.max_frame_size as _).await
     }
 }
 
@@ -60,30 +162,103 @@ where
     S: AsyncRead + AsyncWrite + Unpin + Send,
 {
     pub fn new(stream: S) -> Self {
-        Self { inner: stream }
+        Self {
+            inner: stream,
+            hello: Hello::client(),
+            negotiated: None,
+        }
+    }
+
+    // Replace what the client announces, e.g. to accept smaller frames only
+    pub fn with_hello(mut self, hello: Hello) -> Self {
+        self.hello = hello;
+        self
+    }
+
+    // The protocol negotiated with the server, None until the first command is sent
+    pub fn negotiated(&self) -> Option<&Hello> {
+        self.negotiated.as_ref()
     }
 
     pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
-        self.send(cmd).await?;
-        Ok(self.recv().await?)
+        match &self.negotiated {
+            Some(hello) => {
+                if let Some(name) = cmd.command().filter(|v| !hello.supports(v)) {
+                    let msg = format!("Command {} is not supported by the server", name);
+                    return Err(KvError::InvalidCommand(msg));
+                }
+                self.send(cmd).await?;
+            }
+            None => {
+                // Hello goes out with the first command so the handshake takes no extra round
+                // trip. The command isn't compressed since the codecs are not negotiated yet
+                let opts = FrameOptions {
+                    gzip: false,
+                    ..Default::default()
+                };
+                send_frame(&mut self.inner, &self.hello, &opts).await?;
+                send_frame(&mut self.inner, &cmd, &opts).await?;
+
+                let max_size = self.hello.max_frame_size as _;
+                let res: HelloResponse = recv_frame(&mut self.inner, max_size).await?;
+                self.negotiated = Some(self.hello.negotiate(&res.into_result()?)?);
+            }
+        }
+        self.recv().await
+    }
+
+    // Read what the primary streams after it acknowledged a Replicate command
+    pub async fn next_replica_entry(&mut self) -> Result<ReplicaEntry, KvError> {
+        let max_size = self.frame_options().max_size;
+        recv_frame(&mut self.inner, max_size).await
+    }
+
+    // Read what the server streams after it acknowledged a Watch command
+    pub async fn next_change(&mut self) -> Result<ChangeEvent, KvError> {
+        let max_size = self.frame_options().max_size;
+        recv_frame(&mut self.inner, max_size).await
     }
 
     async fn send(&mut self, msg: CommandRequest) -> Result<(), KvError> {
-        let mut buf = BytesMut::new();
-        msg.encode_frame(&mut buf)?;
-        let encoded = buf.freeze();
-        self.inner.write_all(&encoded[..]).await?;
-        Ok(())
+        let opts = self.frame_options();
+        send_frame(&mut self.inner, &msg, &opts).await
     }
 
     async fn recv(&mut self) -> Result<CommandResponse, KvError> {
-        let mut buf = BytesMut::new();
-        let stream = &mut self.inner;
-        read_frame(stream, &mut buf).await?;
-        CommandResponse::decode_frame(&mut buf)
+        let max_size = self.frame_options().max_size;
+        recv_frame(&mut self.inner, max_size).await
+    }
+
+    fn frame_options(&self) -> FrameOptions {
+        match &self.negotiated {
+            Some(hello) => hello.frame_options(),
+            None => self.hello.frame_options(),
+        }
     }
 }
 
+async fn send_frame<S, M>(stream: &mut S, msg: &M, opts: &FrameOptions) -> Result<(), KvError>
+where
+    S: AsyncWrite + Unpin + Send,
+    M: FrameCoder,
+{
+    let mut buf = BytesMut::new();
+    msg.encode_frame_with(&mut buf, opts)?;
+    let encoded = buf.freeze();
+    stream.write_all(&encoded[..]).await?;
+    Ok(())
+}
+
+async fn recv_frame<S, M>(stream: &mut S, max_size: usize) -> Result<M, KvError>
+where
+    S: AsyncRead + Unpin + Send,
+    M: FrameCoder,
+{
+    let mut buf = BytesMut::new();
+    read_frame_with(stream, &mut buf, max_size).await?;
+    M::decode_frame(&mut buf)
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
@@ -91,7 +266,8 @@ mod tests {
     use std::net::SocketAddr;
     use tokio::net::{TcpListener, TcpStream};
 
-    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
+    use crate::command_request::RequestData;
+    use crate::{assert_res_ok, Hmget, MemTable, ServiceInner, Value};
 
     use super::*;
 
@@ -141,6 +317,104 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn hello_should_negotiate_capabilities() -> Result<()> {
+        let addr = start_server().await?;
+
+        // a newer client without compression talks to this server in its version
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 1,
+            commands: vec!["hget".into(), "hset".into()],
+            compressions: vec![],
+            ..Hello::client()
+        };
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream).with_hello(hello);
+        assert!(client.negotiated().is_none());
+
+        let v: Value = Bytes::from(vec![0u8; 16384]).into();
+        let res = client.execute(CommandRequest::new_hset("t3", "k3", v.clone())).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+        let res = client.execute(CommandRequest::new_hget("t3", "k3")).await?;
+        assert_res_ok(res, &[v], &[]);
+
+        let negotiated = client.negotiated().unwrap();
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert!(negotiated.compressions.is_empty());
+        assert!(negotiated.supports("hget"));
+
+        // commands out of the negotiated set are refused before being sent
+        let cmd = CommandRequest {
+            request_data: Some(RequestData::Hmget(Hmget {
+                table: "t3".into(),
+                keys: vec!["k3".into()],
+            })),
+        };
+        let result = client.execute(cmd).await;
+        assert!(matches!(result, Err(KvError::InvalidCommand(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incompatible_client_should_be_refused() -> Result<()> {
+        let addr = start_server().await?;
+
+        let hello = Hello {
+            version: MIN_PROTOCOL_VERSION - 1,
+            ..Hello::client()
+        };
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream).with_hello(hello);
+        let result = client.execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert!(matches!(result, Err(KvError::IncompatibleProtocol(_))));
+
+        // a client without Hello reads the refusal as a CommandResponse, whether its command
+        // can't be decoded as a Hello or decodes as one without version
+        for cmd in [CommandRequest::new_hget("t1", "k1"), CommandRequest::new_hdel("t1", "k1")] {
+            let mut stream = TcpStream::connect(addr).await?;
+            send_frame(&mut stream, &cmd, &FrameOptions::default()).await?;
+            let res: CommandResponse = recv_frame(&mut stream, MAX_FRAME_SIZE).await?;
+            assert_eq!(res.status, 426);
+            assert!(res.message.contains("missing Hello"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_should_stream_changes_over_the_network() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let server = service.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(ProstServerStream::new(stream, server.clone()).process());
+            }
+        });
+
+        let ctx = ConnectionContext::default();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &ctx);
+
+        let mut client = ProstClientStream::new(TcpStream::connect(addr).await?);
+        let res = client.execute(CommandRequest::new_watch_key("t1", "k1", 1)).await?;
+        assert_res_ok(res, &[1i64.into()], &[]);
+
+        service.execute(CommandRequest::new_hset("t1", "k2", "v2".into()), &ctx);
+        service.execute(CommandRequest::new_hdel("t1", "k1"), &ctx);
+
+        let event = client.next_change().await?;
+        assert_eq!((event.seq, event.op.as_str()), (1, "set"));
+        assert_eq!(event.new_value, Some("v1".into()));
+        let event = client.next_change().await?;
+        assert_eq!((event.seq, event.op.as_str()), (3, "del"));
+        assert_eq!(event.old_value, Some("v1".into()));
+
+        Ok(())
+    }
+
     async fn start_server() -> Result<SocketAddr> {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
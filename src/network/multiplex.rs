@@ -0,0 +1,162 @@
+use std::marker::PhantomData;
+
+use futures::{future, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tracing::warn;
+use yamux::{Config, Connection, ConnectionError, Control, Mode, WindowUpdateMode};
+
+use crate::{ConnectionContext, KvError, ProstClientStream, ProstServerStream, Service};
+
+/// yamux control over one TCP/TLS connection, which could carry many logical streams.
+/// The connection is driven by a background task as long as the peer keeps it open.
+pub struct YamuxCtrl<S> {
+    ctrl: Control,
+    _conn: PhantomData<S>,
+}
+
+impl<S> YamuxCtrl<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Client side, use `open_stream` to get a new ProstClientStream on the connection
+    pub fn new_client(stream: S, config: Option<Config>) -> Self {
+        Self::new(stream, config, Mode::Client, |_stream| future::ready(Ok(())))
+    }
+
+    /// Server side, every sub-stream opened by the client is served by its own ProstServerStream,
+    /// they share the context of the underlying connection
+    pub fn new_server(
+        stream: S,
+        config: Option<Config>,
+        service: Service,
+        ctx: ConnectionContext,
+    ) -> Self {
+        Self::new(stream, config, Mode::Server, move |stream| {
+            let server =
+                ProstServerStream::new(stream.compat(), service.clone()).with_context(ctx.clone());
+            tokio::spawn(async move {
+                if let Err(e) = server.process().await {
+                    warn!("Failed to process sub-stream: {:?}", e);
+                }
+            });
+            future::ready(Ok(()))
+        })
+    }
+
+    fn new<F, Fut>(stream: S, config: Option<Config>, mode: Mode, f: F) -> Self
+    where
+        F: FnMut(yamux::Stream) -> Fut + Send + 'static,
+        Fut: future::Future<Output = Result<(), ConnectionError>> + Send + 'static,
+    {
+        let mut config = config.unwrap_or_default();
+        config.set_window_update_mode(WindowUpdateMode::OnRead);
+
+        let conn = Connection::new(stream.compat(), config, mode);
+        let ctrl = conn.control();
+
+        // Keep the connection running, it handles the inbound sub-streams for server side
+        tokio::spawn(yamux::into_stream(conn).try_for_each_concurrent(None, f));
+
+        Self {
+            ctrl,
+            _conn: PhantomData,
+        }
+    }
+
+    /// Open a new logical stream on the connection
+    pub async fn open_stream(
+        &mut self,
+    ) -> Result<ProstClientStream<Compat<yamux::Stream>>, KvError> {
+        let stream = self.ctrl.open_stream().await?;
+        Ok(ProstClientStream::new(stream.compat()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, CommandRequest, MemTable, ServiceInner, TlsClientConnector,
+        TlsServerAcceptor, Value,
+    };
+    use anyhow::Result;
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    const CA_CERT: &str = include_str!("../../fixtures/ca.cert");
+    const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
+    const SERVER_KEY: &str = include_str!("../../fixtures/server.key");
+
+    #[tokio::test]
+    async fn yamux_ctrl_client_server_should_work() -> Result<()> {
+        let addr = start_server(None).await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut ctrl = YamuxCtrl::new_client(stream, None);
+
+        let mut client = ctrl.open_stream().await?;
+        let res = client.execute(CommandRequest::new_hset("t1", "k1", "v1".into())).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        // another logical stream on the same connection sees the same service
+        let mut client = ctrl.open_stream().await?;
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn yamux_ctrl_over_tls_with_concurrent_streams_should_work() -> Result<()> {
+        let acceptor = TlsServerAcceptor::new(SERVER_CERT, SERVER_KEY, None)?;
+        let addr = start_server(Some(acceptor)).await?;
+
+        let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(CA_CERT))?;
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector.connect(stream).await?;
+        let mut ctrl = YamuxCtrl::new_client(stream, None);
+
+        let mut handles = Vec::new();
+        for i in 0..10 {
+            let mut client = ctrl.open_stream().await?;
+            handles.push(tokio::spawn(async move {
+                let key = format!("k{}", i);
+                let cmd = CommandRequest::new_hset("t1", key.clone(), (i as i64).into());
+                client.execute(cmd).await.unwrap();
+                client.execute(CommandRequest::new_hget("t1", key)).await.unwrap()
+            }));
+        }
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_res_ok(handle.await?, &[(i as i64).into()], &[]);
+        }
+
+        Ok(())
+    }
+
+    async fn start_server(acceptor: Option<TlsServerAcceptor>) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                match &acceptor {
+                    Some(acceptor) => {
+                        let stream = acceptor.accept(stream).await.unwrap();
+                        let ctx = ConnectionContext::new(None).with_tls(&stream);
+                        YamuxCtrl::new_server(stream, None, service.clone(), ctx);
+                    }
+                    None => {
+                        let ctx = ConnectionContext::new(None);
+                        YamuxCtrl::new_server(stream, None, service.clone(), ctx);
+                    }
+                }
+            }
+        });
+
+        Ok(addr)
+    }
+}
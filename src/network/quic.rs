@@ -0,0 +1,468 @@
+// QUIC transport, each request/response pair uses its own bidirectional stream,
+// so a lost packet only blocks the request it belongs to.
+//
+// quinn is built on rustls 0.21 while tokio-rustls here is on rustls 0.19, so the configs
+// are built from the same PEM contents TlsServerAcceptor/TlsClientConnector take.
+
+use std::io::{BufRead, Cursor};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use quinn::{Endpoint, RecvStream, SendStream};
+use rustls::server::{AllowAnyAuthenticatedClient, UnparsedCertRevocationList};
+use rustls::{Certificate, PrivateKey, RootCertStore};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{info, warn};
+use x509_parser::prelude::parse_x509_crl;
+
+use crate::network::tls::{check_next_update, decode_spki_pins, spki_sha256, ALPN_KV};
+use crate::{
+    CommandRequest, CommandResponse, ConnectionContext, KvError, ProstClientStream,
+    ProstServerStream, Service,
+};
+
+/// QUIC server endpoint, configured with the same certs as TlsServerAcceptor
+pub struct QuicServerEndpoint {
+    inner: Endpoint,
+}
+
+/// QUIC client endpoint, configured with the same certs as TlsClientConnector
+pub struct QuicClientConnector {
+    inner: Endpoint,
+    domain: Arc<String>,
+    // SHA-256 of the pinned server public keys (SPKI), not checked when empty
+    pins: Arc<Vec<Vec<u8>>>,
+}
+
+/// An established QUIC connection to the server
+#[derive(Clone)]
+pub struct QuicClientConnection {
+    inner: quinn::Connection,
+}
+
+/// A bidirectional QUIC stream as one AsyncRead + AsyncWrite
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl QuicServerEndpoint {
+    /// Load server cert/client CA cert/CRL and bind the UDP socket
+    pub fn bind(
+        addr: SocketAddr,
+        cert: &str,
+        key: &str,
+        client_ca: Option<&str>,
+        crl: Option<&str>,
+    ) -> Result<Self, KvError> {
+        let certs = load_certs(cert)?;
+        let key = load_key(key)?;
+
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match (client_ca, crl) {
+            (None, None) => builder.with_no_client_auth(),
+            (None, Some(_)) => return Err(KvError::InvalidCrl("CRL needs a client CA".into())),
+            (Some(cert), crl) => {
+                let mut verifier = AllowAnyAuthenticatedClient::new(load_roots(cert)?);
+                if let Some(crl) = crl {
+                    verifier = verifier
+                        .with_crls(load_crls(crl)?)
+                        .map_err(|e| KvError::InvalidCrl(format!("{:?}", e)))?;
+                }
+                builder.with_client_cert_verifier(verifier.boxed())
+            }
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|_| KvError::CertifcateParseError("server", "cert"))?;
+        config.alpn_protocols = vec![Vec::from(ALPN_KV)];
+
+        let config = quinn::ServerConfig::with_crypto(Arc::new(config));
+        let inner = Endpoint::server(config, addr)?;
+        Ok(Self { inner })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, KvError> {
+        Ok(self.inner.local_addr()?)
+    }
+
+    /// Accept connections, every bidirectional stream is served by its own ProstServerStream
+    pub async fn serve(self, service: Service) -> Result<(), KvError> {
+        while let Some(connecting) = self.inner.accept().await {
+            let service = service.clone();
+            tokio::spawn(async move {
+                let conn = match connecting.await {
+                    Ok(conn) => conn,
+                    Err(e) => return warn!("Failed to establish QUIC connection: {:?}", e),
+                };
+                info!("Client {:?} connected", conn.remote_address());
+                let ctx = quic_context(&conn);
+
+                while let Ok((send, recv)) = conn.accept_bi().await {
+                    let stream = ProstServerStream::new(QuicStream { send, recv }, service.clone())
+                        .with_context(ctx.clone());
+                    tokio::spawn(async move { stream.process().await });
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl QuicClientConnector {
+    /// Load client cert/server CA cert, native root certs are trusted as well
+    pub fn new(
+        domain: impl Into<String>,
+        identity: Option<(&str, &str)>,
+        server_ca: Option<&str>,
+    ) -> Result<Self, KvError> {
+        Self::build(domain, identity, server_ca, true)
+    }
+
+    /// Trust server_ca only, without the native root certs, for private deployments
+    pub fn new_private(
+        domain: impl Into<String>,
+        identity: Option<(&str, &str)>,
+        server_ca: &str,
+    ) -> Result<Self, KvError> {
+        Self::build(domain, identity, Some(server_ca), false)
+    }
+
+    /// Pin the server public keys, base64 SPKI SHA-256 as spki_pin makes them. A cert in the
+    /// chain of the server must match one of them after the handshake
+    pub fn with_spki_pins<'a>(
+        mut self,
+        pins: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, KvError> {
+        self.pins = Arc::new(decode_spki_pins(pins)?);
+        Ok(self)
+    }
+
+    fn build(
+        domain: impl Into<String>,
+        identity: Option<(&str, &str)>,
+        server_ca: Option<&str>,
+        native_roots: bool,
+    ) -> Result<Self, KvError> {
+        let mut roots = RootCertStore::empty();
+        if native_roots {
+            let mut native = NativeRoots(&mut roots);
+            if let Err(e) = rustls_native_certs::build_native_certs(&mut native) {
+                warn!("Failed to load native root certs: {:?}", e);
+            }
+        }
+        if let Some(cert) = server_ca {
+            let certs = load_certs(cert)?;
+            if certs.is_empty() {
+                return Err(KvError::CertifcateParseError("CA", "cert"));
+            }
+            for cert in certs {
+                roots
+                    .add(&cert)
+                    .map_err(|_| KvError::CertifcateParseError("CA", "cert"))?;
+            }
+        }
+
+        let builder = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let mut config = match identity {
+            None => builder.with_no_client_auth(),
+            Some((cert, key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                .map_err(|_| KvError::CertifcateParseError("client", "cert"))?,
+        };
+        config.alpn_protocols = vec![Vec::from(ALPN_KV)];
+
+        let mut inner = Endpoint::client("0.0.0.0:0".parse().unwrap())?;
+        inner.set_default_client_config(quinn::ClientConfig::new(Arc::new(config)));
+
+        Ok(Self {
+            inner,
+            domain: Arc::new(domain.into()),
+            pins: Arc::new(Vec::new()),
+        })
+    }
+
+    /// Establish a QUIC connection, streams are opened on it per request
+    pub async fn connect(&self, addr: SocketAddr) -> Result<QuicClientConnection, KvError> {
+        let conn = self.inner.connect(addr, self.domain.as_str())?.await?;
+        if let Err(e) = self.check_pins(&conn) {
+            conn.close(0u32.into(), b"server public key is not pinned");
+            return Err(e);
+        }
+        Ok(QuicClientConnection { inner: conn })
+    }
+
+    // The chain is verified by the CA already, check its public keys are the pinned ones
+    fn check_pins(&self, conn: &quinn::Connection) -> Result<(), KvError> {
+        if self.pins.is_empty() {
+            return Ok(());
+        }
+
+        let certs = conn
+            .peer_identity()
+            .and_then(|v| v.downcast::<Vec<Certificate>>().ok());
+        let pinned = certs
+            .iter()
+            .flat_map(|v| v.iter())
+            .filter_map(|cert| spki_sha256(&cert.0))
+            .any(|digest| self.pins.contains(&digest));
+        match pinned {
+            true => Ok(()),
+            false => Err(KvError::CertificatePinMismatch(self.domain.to_string())),
+        }
+    }
+}
+
+impl QuicClientConnection {
+    /// Open a new bidirectional stream on the connection
+    pub async fn open_stream(&self) -> Result<ProstClientStream<QuicStream>, KvError> {
+        let (send, recv) = self.inner.open_bi().await?;
+        Ok(ProstClientStream::new(QuicStream { send, recv }))
+    }
+
+    /// Execute a command on its own stream, which is finished after the response arrives
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let mut stream = self.open_stream().await?;
+        stream.execute(cmd).await
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.get_mut().send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+// Context of a QUIC connection, shared by all its streams
+fn quic_context(conn: &quinn::Connection) -> ConnectionContext {
+    let certs = conn
+        .peer_identity()
+        .and_then(|v| v.downcast::<Vec<Certificate>>().ok());
+    let alpn = conn
+        .handshake_data()
+        .and_then(|v| v.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|v| v.protocol);
+    let cert = certs.as_ref().and_then(|v| v.first()).map(|c| c.0.as_slice());
+
+    ConnectionContext::new(Some(conn.remote_address())).with_peer(cert, alpn)
+}
+
+// Collect the native root certs into a rustls 0.21 RootCertStore
+struct NativeRoots<'a>(&'a mut RootCertStore);
+
+impl rustls_native_certs::RootStoreBuilder for NativeRoots<'_> {
+    fn load_der(&mut self, der: Vec<u8>) -> Result<(), std::io::Error> {
+        // Native stores may contain certs webpki can't parse, skip them
+        let _ = self.0.add(&Certificate(der));
+        Ok(())
+    }
+
+    fn load_pem_file(&mut self, rd: &mut dyn BufRead) -> Result<(), std::io::Error> {
+        for der in rustls_pemfile::certs(rd)? {
+            self.load_der(der)?;
+        }
+        Ok(())
+    }
+}
+
+fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
+    let mut cert = Cursor::new(cert);
+    let certs = rustls_pemfile::certs(&mut cert)
+        .map_err(|_| KvError::CertifcateParseError("server", "cert"))?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+// rustls checks the signatures of the CRLs but not their next_update, that's checked here
+fn load_crls(crl: &str) -> Result<Vec<UnparsedCertRevocationList>, KvError> {
+    let mut crl = Cursor::new(crl);
+    let crls = rustls_pemfile::crls(&mut crl).map_err(|e| KvError::InvalidCrl(e.to_string()))?;
+    if crls.is_empty() {
+        return Err(KvError::InvalidCrl("no X509 CRL found".into()));
+    }
+    for der in &crls {
+        let (_, list) = parse_x509_crl(der).map_err(|e| KvError::InvalidCrl(e.to_string()))?;
+        check_next_update(&list)?;
+    }
+    Ok(crls.into_iter().map(UnparsedCertRevocationList).collect())
+}
+
+fn load_roots(cert: &str) -> Result<RootCertStore, KvError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(cert)? {
+        roots
+            .add(&cert)
+            .map_err(|_| KvError::CertifcateParseError("CA", "cert"))?;
+    }
+    Ok(roots)
+}
+
+fn load_key(key: &str) -> Result<PrivateKey, KvError> {
+    let mut cursor = Cursor::new(key);
+    let item = rustls_pemfile::read_one(&mut cursor)
+        .map_err(|_| KvError::CertifcateParseError("private", "key"))?;
+
+    match item {
+        Some(rustls_pemfile::Item::PKCS8Key(key))
+        | Some(rustls_pemfile::Item::RSAKey(key))
+        | Some(rustls_pemfile::Item::ECKey(key)) => Ok(PrivateKey(key)),
+        _ => Err(KvError::CertifcateParseError("private", "key")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ok, spki_pin, MemTable, ServiceInner, Value};
+    use anyhow::Result;
+    use rcgen::{
+        date_time_ymd, BasicConstraints, CertificateParams, CertificateRevocationList,
+        CertificateRevocationListParams, IsCa, KeyIdMethod, PKCS_ED25519,
+    };
+
+    const CA_CERT: &str = include_str!("../../fixtures/ca.cert");
+    const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
+    const SERVER_KEY: &str = include_str!("../../fixtures/server.key");
+
+    #[tokio::test]
+    async fn quic_client_server_should_work() -> Result<()> {
+        let addr = start_server()?;
+
+        let connector = QuicClientConnector::new("kvserver.acme.inc", None, Some(CA_CERT))?;
+        let conn = connector.connect(addr).await?;
+
+        let res = conn.execute(CommandRequest::new_hset("t1", "k1", "v1".into())).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = conn.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quic_concurrent_requests_should_work() -> Result<()> {
+        let addr = start_server()?;
+
+        let connector = QuicClientConnector::new("kvserver.acme.inc", None, Some(CA_CERT))?;
+        let conn = connector.connect(addr).await?;
+
+        let mut handles = Vec::new();
+        for i in 0..10i64 {
+            let conn = conn.clone();
+            handles.push(tokio::spawn(async move {
+                let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+                conn.execute(cmd).await.unwrap();
+                conn.execute(CommandRequest::new_hget("t1", format!("k{}", i)))
+                    .await
+                    .unwrap()
+            }));
+        }
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_res_ok(handle.await?, &[(i as i64).into()], &[]);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quic_with_bad_domain_should_not_work() -> Result<()> {
+        let addr = start_server()?;
+
+        let connector = QuicClientConnector::new("kvserver1.acme.inc", None, Some(CA_CERT))?;
+        assert!(connector.connect(addr).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quic_private_connector_with_spki_pins_should_work() -> Result<()> {
+        let addr = start_server()?;
+
+        let pin = spki_pin(SERVER_CERT)?;
+        let connector = QuicClientConnector::new_private("kvserver.acme.inc", None, CA_CERT)?
+            .with_spki_pins([pin.as_str()])?;
+        let conn = connector.connect(addr).await?;
+        let res = conn.execute(CommandRequest::new_hset("t1", "k1", "v1".into())).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+        let res = conn.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        // the CA verifies the server cert, but its public key isn't the pinned one
+        let pin = spki_pin(CA_CERT)?;
+        let connector = QuicClientConnector::new_private("kvserver.acme.inc", None, CA_CERT)?
+            .with_spki_pins([pin.as_str()])?;
+        let result = connector.connect(addr).await;
+        assert!(matches!(result, Err(KvError::CertificatePinMismatch(_))));
+
+        assert!(QuicClientConnector::new_private("kvserver.acme.inc", None, "").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn quic_crl_past_next_update_should_be_refused() -> Result<()> {
+        let mut params = CertificateParams::default();
+        params.alg = &PKCS_ED25519;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params)?;
+        let crl = CertificateRevocationListParams {
+            this_update: date_time_ymd(2024, 1, 1),
+            next_update: date_time_ymd(2024, 6, 1),
+            crl_number: 1.into(),
+            issuing_distribution_point: None,
+            revoked_certs: vec![],
+            alg: &PKCS_ED25519,
+            key_identifier_method: KeyIdMethod::Sha256,
+        };
+        let crl = CertificateRevocationList::from_params(crl)?.serialize_pem_with_signer(&ca)?;
+
+        let addr = "127.0.0.1:0".parse()?;
+        let ca = ca.serialize_pem()?;
+        let result =
+            QuicServerEndpoint::bind(addr, SERVER_CERT, SERVER_KEY, Some(&ca), Some(&crl));
+        assert!(matches!(result, Err(KvError::InvalidCrl(e)) if e.contains("expired")));
+
+        Ok(())
+    }
+
+    fn start_server() -> Result<SocketAddr> {
+        let addr = "127.0.0.1:0".parse()?;
+        let endpoint = QuicServerEndpoint::bind(addr, SERVER_CERT, SERVER_KEY, None, None)?;
+        let addr = endpoint.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(endpoint.serve(service));
+
+        Ok(addr)
+    }
+}
@@ -0,0 +1,172 @@
+// Raft messages between the nodes of a cluster over TCP, optionally with TLS. Each peer has one
+// connection, a message failed to send is dropped and raft sends it again on the next heartbeat.
+
+use dashmap::DashMap;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use super::{recv_frame, send_frame, FrameOptions, MAX_FRAME_SIZE};
+use crate::{KvError, RaftMessage, RaftTransport, TlsClientConnector, TlsServerAcceptor};
+
+/// Sends the raft messages to the address of the peers
+#[derive(Default)]
+pub struct TcpTransport {
+    tls: Option<TlsClientConnector>,
+    peers: DashMap<String, mpsc::UnboundedSender<RaftMessage>>,
+}
+
+type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+type Reader = Box<dyn AsyncRead + Unpin + Send>;
+
+impl TcpTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect to the peers with TLS, they should serve_raft with a TlsServerAcceptor
+    pub fn with_tls(mut self, connector: TlsClientConnector) -> Self {
+        self.tls = Some(connector);
+        self
+    }
+
+    // Messages to a peer are sent in order by a task of its own
+    fn spawn_peer(&self, addr: String) -> mpsc::UnboundedSender<RaftMessage> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<RaftMessage>();
+        let tls = self.tls.clone();
+        tokio::spawn(async move {
+            let mut stream: Option<Writer> = None;
+            while let Some(msg) = rx.recv().await {
+                if stream.is_none() {
+                    stream = match connect(&addr, &tls).await {
+                        Ok(stream) => Some(stream),
+                        Err(e) => {
+                            debug!("Failed to connect to raft peer {}: {:?}", addr, e);
+                            continue;
+                        }
+                    };
+                }
+                if let Some(writer) = stream.as_mut() {
+                    if let Err(e) = send_frame(writer, &msg, &FrameOptions::default()).await {
+                        debug!("Failed to send to raft peer {}: {:?}", addr, e);
+                        stream = None;
+                    }
+                }
+            }
+        });
+        tx
+    }
+}
+
+impl RaftTransport for TcpTransport {
+    fn send(&self, addr: &str, msg: RaftMessage) {
+        let peer = self
+            .peers
+            .entry(addr.to_string())
+            .or_insert_with(|| self.spawn_peer(addr.to_string()))
+            .clone();
+        // the task of the peer never stops while the transport is alive
+        let _ = peer.send(msg);
+    }
+}
+
+/// Receive the raft messages sent by TcpTransport into inbox, which is passed to RaftNode::start
+pub async fn serve_raft(
+    listener: TcpListener,
+    tls: Option<TlsServerAcceptor>,
+    inbox: mpsc::UnboundedSender<RaftMessage>,
+) -> Result<(), KvError> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let (tls, inbox) = (tls.clone(), inbox.clone());
+        tokio::spawn(async move {
+            let mut stream: Reader = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => Box::new(stream),
+                    Err(e) => {
+                        warn!("TLS handshake with raft peer {:?} failed: {:?}", addr, e);
+                        return;
+                    }
+                },
+                None => Box::new(stream),
+            };
+            while let Ok(msg) = recv_frame::<_, RaftMessage>(&mut stream, MAX_FRAME_SIZE).await {
+                if inbox.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+async fn connect(addr: &str, tls: &Option<TlsClientConnector>) -> Result<Writer, KvError> {
+    let stream = TcpStream::connect(addr).await?;
+    match tls {
+        Some(tls) => Ok(Box::new(tls.connect(stream).await?)),
+        None => Ok(Box::new(stream)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, CommandRequest, ConnectionContext, MemTable, RaftConfig, RaftNode,
+        RaftPeer, RaftRole, Service, ServiceInner, Value,
+    };
+    use anyhow::Result;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn raft_cluster_should_work_over_loopback() -> Result<()> {
+        let mut listeners = Vec::new();
+        let mut peers = Vec::new();
+        for id in 1..=3 {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?.to_string();
+            peers.push(RaftPeer { id, addr });
+            listeners.push(listener);
+        }
+
+        let mut nodes = Vec::new();
+        for (peer, listener) in peers.iter().zip(listeners) {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(serve_raft(listener, None, tx));
+            let config = RaftConfig {
+                tick_interval: Duration::from_millis(10),
+                ..RaftConfig::new(peer.id, &peer.addr)
+            };
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            let transport = TcpTransport::new();
+            nodes.push(RaftNode::start(config, peers.clone(), service, transport, rx)?);
+        }
+
+        let ctx = ConnectionContext::default();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut applied = None;
+        for _ in 0..500 {
+            if let Some(leader) = nodes.iter().find(|v| v.status().role == RaftRole::Leader) {
+                let res = leader.execute(cmd.clone(), &ctx).await;
+                assert_res_ok(res, &[Value::default()], &[]);
+                applied = Some(leader.status().applied_index);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let applied = applied.expect("no leader is elected");
+
+        for _ in 0..500 {
+            if nodes.iter().all(|v| v.status().applied_index >= applied) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for node in &nodes {
+            let res = node.execute(CommandRequest::new_hget("t1", "k1"), &ctx).await;
+            assert_res_ok(res, &["v1".into()], &[]);
+        }
+
+        Ok(())
+    }
+}
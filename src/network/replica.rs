@@ -0,0 +1,129 @@
+// Follower side of the replication: keep a connection to the primary, apply what it streams and
+// resume from the next sequence number after a disconnect.
+
+use std::future::Future;
+use std::time::Duration;
+
+use http::StatusCode;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{info, warn};
+
+use crate::{KvError, ProstClientStream, Service};
+
+// How long to wait before reconnecting to the primary
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Replicate from the primary into the service forever. connect opens a new stream to the
+/// primary, e.g. a TLS connection with the client certificate of the follower
+pub async fn follow<S, F, Fut>(service: Service, mut connect: F)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<S, KvError>>,
+{
+    loop {
+        let result = match connect().await {
+            Ok(stream) => follow_stream(&service, stream).await,
+            Err(e) => Err(e),
+        };
+        service.set_primary_connected(false);
+        if let Err(e) = result {
+            warn!("Replication from the primary stopped: {:?}", e);
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+async fn follow_stream<S>(service: &Service, stream: S) -> Result<(), KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut client = ProstClientStream::new(stream);
+    let cmd = service.replicate_request()?;
+    info!("Replicate from the primary with {:?}", cmd);
+    let res = client.execute(cmd).await?;
+    if res.status != StatusCode::OK.as_u16() as u32 {
+        return Err(KvError::ReplicationError(res.message));
+    }
+
+    service.set_primary_connected(true);
+    loop {
+        let entry = client.next_replica_entry().await?;
+        service.apply_replica(entry)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ok, CommandRequest, ConnectionContext, Kvpair, MemTable};
+    use crate::{ProstServerStream, ServiceInner};
+    use anyhow::Result;
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn follower_should_replicate_over_the_network() -> Result<()> {
+        let primary: Service = ServiceInner::new(MemTable::new()).into();
+        let addr = start_server(primary.clone()).await?;
+        let ctx = ConnectionContext::default();
+        primary.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &ctx);
+
+        let follower: Service = ServiceInner::new(MemTable::new())
+            .follower_of(addr.to_string())
+            .into();
+        let connect = move || async move { Ok(TcpStream::connect(addr).await?) };
+        tokio::spawn(follow(follower.clone(), connect));
+
+        primary.execute(CommandRequest::new_hset("t1", "k2", "v2".into()), &ctx);
+        wait_for(&follower, 2).await;
+
+        let res = follower.execute(CommandRequest::new_hget("t1", "k1"), &ctx);
+        assert_res_ok(res, &["v1".into()], &[]);
+        let res = follower.execute(CommandRequest::new_hget("t1", "k2"), &ctx);
+        assert_res_ok(res, &["v2".into()], &[]);
+
+        let stats = follower.replication_stats().primary.unwrap();
+        assert!(stats.connected);
+        assert_eq!(stats.lag, 0);
+        let stats = primary.replication_stats();
+        assert_eq!(stats.followers.len(), 1);
+        assert_eq!(stats.followers[0].sent_seq, 2);
+
+        primary.execute(CommandRequest::new_hdel("t1", "k1"), &ctx);
+        wait_for(&follower, 3).await;
+        let res = follower.execute(CommandRequest::new_hget_all("t1"), &ctx);
+        assert_res_ok(res, &[], &[Kvpair::new("k2", "v2".into())]);
+
+        // clients write to the primary only
+        let res = follower.execute(CommandRequest::new_hdel("t1", "k2"), &ctx);
+        assert_eq!(res.status, 421);
+
+        Ok(())
+    }
+
+    async fn wait_for(follower: &Service, seq: u64) {
+        for _ in 0..100 {
+            if follower.replication_stats().primary.unwrap().applied_seq >= seq {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("follower didn't apply seq {}", seq);
+    }
+
+    async fn start_server(service: Service) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server = ProstServerStream::new(stream, service.clone());
+                tokio::spawn(server.process());
+            }
+        });
+
+        Ok(addr)
+    }
+}
@@ -0,0 +1,519 @@
+// Client side sharding: commands are routed to the kvs nodes by a consistent hash ring, so adding
+// or removing a node only moves the keys between it and its neighbours on the ring.
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+
+use futures::future::join_all;
+use http::StatusCode;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::command_request::RequestData;
+use crate::{
+    value, CommandRequest, CommandResponse, Hfind, KvError, Kvpair, ProstClientStream, Value,
+};
+
+// Points of each node on the ring, more points spread the keys more evenly
+const VIRTUAL_NODES: usize = 160;
+
+/// A consistent hash ring of node addresses
+#[derive(Debug, Clone, Default)]
+pub struct HashRing {
+    ring: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    pub fn new<T: Into<String>>(nodes: impl IntoIterator<Item = T>) -> Self {
+        let mut ring = Self::default();
+        for node in nodes {
+            ring.add(node);
+        }
+        ring
+    }
+
+    pub fn add(&mut self, node: impl Into<String>) {
+        let node = node.into();
+        for i in 0..VIRTUAL_NODES {
+            self.ring.insert(hash(&[node.as_bytes(), &i.to_be_bytes()]), node.clone());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.ring.retain(|_, v| v != node);
+    }
+
+    /// The node owning the key: the first point on the ring at or after the hash of the key
+    pub fn get(&self, key: &[u8]) -> Option<&str> {
+        let hash = hash(&[key]);
+        self.ring
+            .range(hash..)
+            .chain(self.ring.iter())
+            .next()
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The nodes on the ring, sorted
+    pub fn nodes(&self) -> Vec<&str> {
+        let mut nodes: Vec<&str> = self.ring.values().map(|v| v.as_str()).collect();
+        nodes.sort_unstable();
+        nodes.dedup();
+        nodes
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> u64 {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// How commands are spread over the nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShardBy {
+    /// A table lives on one node, HGETALL is served by that node alone
+    #[default]
+    Table,
+    /// Each key of a table is placed on its own, HGETALL asks every node and merges the pairs
+    Key,
+}
+
+/// A client of several kvs nodes. connect opens a stream to the address of a node, e.g. a TLS
+/// connection, and is called again after a connection failed
+pub struct ShardedClient<S, F> {
+    ring: HashRing,
+    shard_by: ShardBy,
+    connect: F,
+    conns: HashMap<String, ProstClientStream<S>>,
+}
+
+impl<S, F, Fut> ShardedClient<S, F>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    F: Fn(&str) -> Fut,
+    Fut: Future<Output = Result<S, KvError>>,
+{
+    pub fn new<T: Into<String>>(nodes: impl IntoIterator<Item = T>, connect: F) -> Self {
+        Self {
+            ring: HashRing::new(nodes),
+            shard_by: ShardBy::default(),
+            connect,
+            conns: HashMap::new(),
+        }
+    }
+
+    pub fn with_shard_by(mut self, shard_by: ShardBy) -> Self {
+        self.shard_by = shard_by;
+        self
+    }
+
+    /// Add a node, the keys it now owns should be copied to it from their previous nodes
+    pub fn add_node(&mut self, node: impl Into<String>) {
+        self.ring.add(node);
+    }
+
+    pub fn remove_node(&mut self, node: &str) {
+        self.ring.remove(node);
+        self.conns.remove(node);
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// The node a key of the table is placed on
+    pub fn node_for(&self, table: &str, key: &str) -> Option<&str> {
+        match self.shard_by {
+            ShardBy::Table => self.ring.get(table.as_bytes()),
+            // the separator keeps ("ab", "c") and ("a", "bc") apart
+            ShardBy::Key => self.ring.get(&[table.as_bytes(), &[0], key.as_bytes()].concat()),
+        }
+    }
+
+    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let table = match cmd.table() {
+            Some(table) => table.to_string(),
+            None => {
+                let msg = format!("Command {:?} can't be sharded", cmd.command());
+                return Err(KvError::InvalidCommand(msg));
+            }
+        };
+        let data = cmd.request_data.clone().unwrap();
+        if let RequestData::Watch(_) = data {
+            return Err(KvError::InvalidCommand("Watch can't be sharded".into()));
+        }
+
+        let key = match (&data, self.shard_by) {
+            (_, ShardBy::Table) => Some(""),
+            (RequestData::Hget(v), _) => Some(v.key.as_str()),
+            (RequestData::Hset(v), _) => Some(v.pair.as_ref().map_or("", |v| v.key.as_str())),
+            (RequestData::Hdel(v), _) => Some(v.key.as_str()),
+            (RequestData::Hexist(v), _) => Some(v.key.as_str()),
+            // the elements of a collection are on the node of its key
+            (RequestData::Lpush(v), _) => Some(v.key.as_str()),
+            (RequestData::Lpop(v), _) => Some(v.key.as_str()),
+            (RequestData::Lrange(v), _) => Some(v.key.as_str()),
+            (RequestData::Sadd(v), _) => Some(v.key.as_str()),
+            (RequestData::Srem(v), _) => Some(v.key.as_str()),
+            (RequestData::Smembers(v), _) => Some(v.key.as_str()),
+            (RequestData::Zadd(v), _) => Some(v.key.as_str()),
+            (RequestData::Zrange(v), _) => Some(v.key.as_str()),
+            (RequestData::Zrangebyscore(v), _) => Some(v.key.as_str()),
+            (RequestData::JsonGet(v), _) => Some(v.key.as_str()),
+            (RequestData::JsonSet(v), _) => Some(v.key.as_str()),
+            (RequestData::JsonAppend(v), _) => Some(v.key.as_str()),
+            (RequestData::JsonIncr(v), _) => Some(v.key.as_str()),
+            _ => None,
+        };
+        if let Some(key) = key {
+            let node = self.node(&table, key)?;
+            let mut res = self.run(vec![(node, cmd)]).await?;
+            return Ok(res.remove(0));
+        }
+
+        match data {
+            // every node counts its own keys
+            RequestData::Hgetall(v) if v.filter.as_ref().is_some_and(|v| v.count_only) => {
+                let responses = self.broadcast(cmd).await?;
+                sum_counts(responses)
+            }
+            // the keys found on every node come back sorted by key
+            RequestData::Hgetall(_) => {
+                let responses = self.broadcast(cmd).await?;
+                merge_pairs(responses)
+            }
+            // and in the order of the index, which the nodes send along
+            RequestData::Hfind(v) => {
+                let cmd = CommandRequest {
+                    request_data: Some(RequestData::Hfind(Hfind {
+                        with_order: true,
+                        ..v
+                    })),
+                };
+                let responses = self.broadcast(cmd).await?;
+                merge_found(responses)
+            }
+            // every node indexes its own keys
+            RequestData::CreateIndex(_) | RequestData::DropIndex(_) => {
+                let responses = self.broadcast(cmd).await?;
+                sum_counts(responses)
+            }
+            RequestData::Hmget(v) => {
+                self.split(&table, v.keys, |table, keys| CommandRequest::new_hmget(table, keys))
+                    .await
+            }
+            RequestData::Hmdel(v) => {
+                self.split(&table, v.keys, |table, keys| CommandRequest::new_hmdel(table, keys))
+                    .await
+            }
+            RequestData::Hmexist(v) => {
+                self.split(&table, v.keys, |table, keys| CommandRequest::new_hmexist(table, keys))
+                    .await
+            }
+            RequestData::Hmset(v) => {
+                self.split(&table, v.pairs, |table, pairs| CommandRequest::new_hmset(table, pairs))
+                    .await
+            }
+            _ => unreachable!("single key commands are routed above"),
+        }
+    }
+
+    fn node(&self, table: &str, key: &str) -> Result<String, KvError> {
+        self.node_for(table, key)
+            .map(|v| v.to_string())
+            .ok_or_else(|| KvError::Internal("No node in the ring".into()))
+    }
+
+    async fn broadcast(&mut self, cmd: CommandRequest) -> Result<Vec<CommandResponse>, KvError> {
+        let batches = self
+            .ring
+            .nodes()
+            .into_iter()
+            .map(|node| (node.to_string(), cmd.clone()))
+            .collect();
+        self.run(batches).await
+    }
+
+    // Send the items of a multi-key command to their nodes, then put the values back in the
+    // order of the items
+    async fn split<T: ShardItem>(
+        &mut self,
+        table: &str,
+        items: Vec<T>,
+        new: impl Fn(&str, Vec<T>) -> CommandRequest,
+    ) -> Result<CommandResponse, KvError> {
+        let total = items.len();
+        let mut groups: BTreeMap<String, (Vec<usize>, Vec<T>)> = BTreeMap::new();
+        for (i, item) in items.into_iter().enumerate() {
+            let node = self.node(table, item.key())?;
+            let group = groups.entry(node).or_default();
+            group.0.push(i);
+            group.1.push(item);
+        }
+
+        let mut positions = Vec::with_capacity(groups.len());
+        let mut batches = Vec::with_capacity(groups.len());
+        for (node, (indexes, items)) in groups {
+            positions.push(indexes);
+            batches.push((node, new(table, items)));
+        }
+
+        let responses = self.run(batches).await?;
+        let mut values = vec![Value::default(); total];
+        for (indexes, res) in positions.into_iter().zip(responses) {
+            if res.status != StatusCode::OK.as_u16() as u32 {
+                return Ok(res);
+            }
+            for (i, value) in indexes.into_iter().zip(res.values) {
+                values[i] = value;
+            }
+        }
+        Ok(values.into())
+    }
+
+    // Execute the commands on their nodes concurrently, the responses are in the same order
+    async fn run(
+        &mut self,
+        batches: Vec<(String, CommandRequest)>,
+    ) -> Result<Vec<CommandResponse>, KvError> {
+        for (node, _) in &batches {
+            if !self.conns.contains_key(node) {
+                let stream = (self.connect)(node).await?;
+                self.conns.insert(node.clone(), ProstClientStream::new(stream));
+            }
+        }
+
+        let mut cmds: HashMap<String, CommandRequest> = HashMap::new();
+        let order: Vec<String> = batches.iter().map(|(node, _)| node.clone()).collect();
+        cmds.extend(batches);
+        let futures = self.conns.iter_mut().filter_map(|(node, conn)| {
+            let cmd = cmds.remove(node)?;
+            Some(async move { (node.clone(), conn.execute(cmd).await) })
+        });
+        let results: Vec<(String, Result<CommandResponse, KvError>)> = join_all(futures).await;
+
+        let mut responses = HashMap::with_capacity(results.len());
+        let mut error = None;
+        for (node, result) in results {
+            match result {
+                Ok(res) => {
+                    responses.insert(node, res);
+                }
+                Err(e) => {
+                    // the stream is broken, connect again on the next command
+                    self.conns.remove(&node);
+                    error = Some(e);
+                }
+            }
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+        Ok(order
+            .iter()
+            .map(|node| responses.remove(node).expect("every batch is executed"))
+            .collect())
+    }
+}
+
+// Items of a multi-key command, by the key they're placed with
+trait ShardItem {
+    fn key(&self) -> &str;
+}
+
+impl ShardItem for String {
+    fn key(&self) -> &str {
+        self
+    }
+}
+
+impl ShardItem for Kvpair {
+    fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+fn merge_pairs(responses: Vec<CommandResponse>) -> Result<CommandResponse, KvError> {
+    let mut pairs = Vec::new();
+    for res in responses {
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return Ok(res);
+        }
+        pairs.extend(res.pairs);
+    }
+    pairs.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(pairs.into())
+}
+
+// The kvpairs found by Hfind on every node, by their encoded indexed values then by key
+fn merge_found(responses: Vec<CommandResponse>) -> Result<CommandResponse, KvError> {
+    let mut found = Vec::new();
+    for res in responses {
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return Ok(res);
+        }
+        if res.values.len() != res.pairs.len() {
+            return Err(KvError::Internal("Hfind came back without the order".into()));
+        }
+        for (order, pair) in res.values.into_iter().zip(res.pairs) {
+            let order = match order.value {
+                Some(value::Value::String(order)) => order,
+                _ => return Err(KvError::Internal("Hfind came back without the order".into())),
+            };
+            found.push((order, pair));
+        }
+    }
+    found.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.key.cmp(&b.1.key)));
+    let pairs: Vec<Kvpair> = found.into_iter().map(|(_, pair)| pair).collect();
+    Ok(pairs.into())
+}
+
+fn sum_counts(responses: Vec<CommandResponse>) -> Result<CommandResponse, KvError> {
+    let mut sum = 0;
+    for res in responses {
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return Ok(res);
+        }
+        if let Some(Value {
+            value: Some(value::Value::Integer(count)),
+        }) = res.values.first()
+        {
+            sum += count;
+        }
+    }
+    Ok(Value::from(sum).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ok, MemTable, ProstServerStream, ScanFilter, Service, ServiceInner};
+    use anyhow::Result;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn hash_ring_should_move_few_keys_when_nodes_change() {
+        let mut ring = HashRing::new(["n1", "n2", "n3"]);
+        assert_eq!(ring.nodes(), ["n1", "n2", "n3"]);
+
+        let keys: Vec<String> = (0..3000).map(|i| format!("key{}", i)).collect();
+        let before: Vec<String> = keys
+            .iter()
+            .map(|k| ring.get(k.as_bytes()).unwrap().to_string())
+            .collect();
+        for node in ["n1", "n2", "n3"] {
+            let count = before.iter().filter(|v| *v == node).count();
+            assert!(count > 600, "{} owns {} keys only", node, count);
+        }
+
+        // only the keys taken by the new node move
+        ring.add("n4");
+        let mut moved = 0;
+        for (key, node) in keys.iter().zip(&before) {
+            let now = ring.get(key.as_bytes()).unwrap();
+            if now != node {
+                assert_eq!(now, "n4");
+                moved += 1;
+            }
+        }
+        assert!(moved > 400 && moved < 1200, "{} keys moved", moved);
+
+        // and they go back when it's removed
+        ring.remove("n4");
+        for (key, node) in keys.iter().zip(&before) {
+            assert_eq!(ring.get(key.as_bytes()).unwrap(), node);
+        }
+    }
+
+    #[tokio::test]
+    async fn sharded_client_should_split_and_merge_commands() -> Result<()> {
+        let mut nodes = Vec::new();
+        let mut services = Vec::new();
+        for _ in 0..3 {
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            nodes.push(start_server(service.clone()).await?);
+            services.push(service);
+        }
+        let connect = |addr: &str| {
+            let addr = addr.to_string();
+            async move { Ok(TcpStream::connect(addr).await?) }
+        };
+        let mut client = ShardedClient::new(nodes.clone(), connect).with_shard_by(ShardBy::Key);
+
+        let pairs: Vec<Kvpair> = (0..20)
+            .map(|i| Kvpair::new(format!("k{:02}", i), i64::from(i).into()))
+            .collect();
+        let res = client.execute(CommandRequest::new_hmset("t1", pairs.clone())).await?;
+        let empty = vec![Value::default(); 20];
+        assert_res_ok(res, &empty, &[]);
+
+        // every node gets a share of the keys
+        for service in &services {
+            let res = service.execute(CommandRequest::new_hget_all("t1"), &Default::default());
+            assert!(!res.pairs.is_empty());
+        }
+
+        let keys = vec!["k19".into(), "k20".into(), "k03".into()];
+        let res = client.execute(CommandRequest::new_hmget("t1", keys)).await?;
+        assert_res_ok(res, &[19i64.into(), Value::default(), 3i64.into()], &[]);
+
+        let res = client.execute(CommandRequest::new_hget("t1", "k07")).await?;
+        assert_res_ok(res, &[7i64.into()], &[]);
+
+        let res = client.execute(CommandRequest::new_hget_all("t1")).await?;
+        assert_res_ok(res, &[], &pairs);
+
+        let filter = ScanFilter {
+            min: Some(15.into()),
+            count_only: true,
+            ..Default::default()
+        };
+        let res = client.execute(CommandRequest::new_hget_all_filtered("t1", filter)).await?;
+        assert_res_ok(res, &[5.into()], &[]);
+
+        // found on every node, in the order of the values rather than the keys
+        let reversed: Vec<Kvpair> = (0..20)
+            .map(|i| Kvpair::new(format!("k{:02}", i), i64::from(20 - i).into()))
+            .collect();
+        client.execute(CommandRequest::new_hmset("t3", reversed.clone())).await?;
+        let res = client.execute(CommandRequest::new_create_index("t3", "v", "")).await?;
+        assert_res_ok(res, &[20.into()], &[]);
+        let cmd = CommandRequest::new_hfind_range("t3", "v", Some(5.into()), Some(8.into()));
+        let res = client.execute(cmd).await?;
+        let expected: Vec<Kvpair> = reversed[12..16].iter().rev().cloned().collect();
+        assert_eq!(res.pairs, expected);
+        assert!(res.values.is_empty());
+
+        // a table stays on one node when sharded by table
+        let mut client = ShardedClient::new(nodes.clone(), connect);
+        let node = client.node_for("t2", "").unwrap().to_string();
+        client.execute(CommandRequest::new_hmset("t2", pairs.clone())).await?;
+        let i = nodes.iter().position(|v| *v == node).unwrap();
+        let res = services[i].execute(CommandRequest::new_hget_all("t2"), &Default::default());
+        assert_res_ok(res, &[], &pairs);
+
+        client.remove_node(&node);
+        assert_eq!(client.ring().nodes().len(), 2);
+        assert_ne!(client.node_for("t2", "").unwrap(), node);
+
+        Ok(())
+    }
+
+    async fn start_server(service: Service) -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server = ProstServerStream::new(stream, service.clone());
+                tokio::spawn(server.process());
+            }
+        });
+
+        Ok(addr.to_string())
+    }
+}
@@ -1,24 +1,54 @@
+use std::collections::HashSet;
 use std::io::Cursor;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_rustls::rustls::{internal::pemfile, Certificate, ClientConfig, ServerConfig};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use tokio_rustls::rustls::{internal::pemfile, Certificate, ClientConfig, ServerConfig, Session};
 use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore};
-use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::rustls::{ClientCertVerified, ClientCertVerifier, DistinguishedNames, TLSError};
+use tokio_rustls::webpki::{DNSName, DNSNameRef};
 use tokio_rustls::TlsConnector;
 use tokio_rustls::{
     client::TlsStream as ClientTlsStream, server::TlsStream as ServerTlsStream, TlsAcceptor,
 };
+use x509_parser::prelude::{
+    parse_x509_certificate, parse_x509_crl, ASN1Time, CertificateRevocationList, Pem,
+};
 
 use crate::KvError;
 
 /// KV Server 自己的 ALPN (Application-Layer Protocol Negotiation)
-const ALPN_KV: &str = "kv";
+pub(crate) const ALPN_KV: &str = "kv";
 
 /// 存放 TLS ServerConfig 并提供方法 accept 把底层的协议转换成 TLS
+/// ServerConfig 可以被原子地替换，新的握手使用新的配置，已有的连接不受影响
 #[derive(Clone)]
 pub struct TlsServerAcceptor {
-    inner: Arc<ServerConfig>,
+    inner: Arc<RwLock<Arc<ServerConfig>>>,
+    files: Option<Arc<CertFiles>>,
+}
+
+/// 证书文件的路径，用于重新加载
+#[derive(Debug, Clone)]
+struct CertFiles {
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+    crl: Option<PathBuf>,
+}
+
+/// 在 CA 验证之外，拒绝被 CRL 吊销的客户端证书
+struct CrlClientVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    /// 被吊销的证书：(签发者 DER 编码的名字, 序列号)
+    revoked: HashSet<(Vec<u8>, Vec<u8>)>,
 }
 
 /// 存放 TLS Client 并提供方法 connect 把底层的协议转换成 TLS
@@ -26,6 +56,8 @@ pub struct TlsServerAcceptor {
 pub struct TlsClientConnector {
     pub config: Arc<ClientConfig>,
     pub domain: Arc<String>,
+    /// 固定的服务器公钥 (SPKI) 的 SHA-256，为空时不检查
+    pins: Arc<Vec<Vec<u8>>>,
 }
 
 impl TlsClientConnector {
@@ -34,6 +66,34 @@ impl TlsClientConnector {
         domain: impl Into<String>,
         identity: Option<(&str, &str)>,
         server_ca: Option<&str>,
+    ) -> Result<Self, KvError> {
+        Self::build(domain, identity, server_ca, true)
+    }
+
+    /// 不加载本地信任的根证书，只信任 server_ca，用于私有部署
+    pub fn new_private(
+        domain: impl Into<String>,
+        identity: Option<(&str, &str)>,
+        server_ca: &str,
+    ) -> Result<Self, KvError> {
+        Self::build(domain, identity, Some(server_ca), false)
+    }
+
+    /// 固定服务器的公钥，pin 是 base64 编码的 SPKI SHA-256（见 spki_pin）
+    /// 握手后服务器的证书链中至少要有一个证书的公钥匹配
+    pub fn with_spki_pins<'a>(
+        mut self,
+        pins: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, KvError> {
+        self.pins = Arc::new(decode_spki_pins(pins)?);
+        Ok(self)
+    }
+
+    fn build(
+        domain: impl Into<String>,
+        identity: Option<(&str, &str)>,
+        server_ca: Option<&str>,
+        native_roots: bool,
     ) -> Result<Self, KvError> {
         let mut config = ClientConfig::new();
 
@@ -45,21 +105,27 @@ impl TlsClientConnector {
         }
 
         // 加载本地信任的根证书链
-        config.root_store = match rustls_native_certs::load_native_certs() {
-            Ok(store) | Err((Some(store), _)) => store,
-            Err((None, error)) => return Err(error.into()),
-        };
+        if native_roots {
+            config.root_store = match rustls_native_certs::load_native_certs() {
+                Ok(store) | Err((Some(store), _)) => store,
+                Err((None, error)) => return Err(error.into()),
+            };
+        }
 
         // 如果有签署服务器的 CA 证书，则加载它，这样服务器证书不在根证书链
         // 但是这个 CA 证书能验证它，也可以
         if let Some(cert) = server_ca {
             let mut buf = Cursor::new(cert);
-            config.root_store.add_pem_file(&mut buf).unwrap();
+            match config.root_store.add_pem_file(&mut buf) {
+                Ok((valid, _)) if valid > 0 => {}
+                _ => return Err(KvError::CertifcateParseError("CA", "cert")),
+            }
         }
 
         Ok(Self {
             config: Arc::new(config),
             domain: Arc::new(domain.into()),
+            pins: Arc::new(Vec::new()),
         })
     }
 
@@ -74,53 +140,326 @@ impl TlsClientConnector {
         let stream = TlsConnector::from(self.config.clone())
             .connect(dns, stream)
             .await?;
+        self.check_pins(&stream)?;
 
         Ok(stream)
     }
+
+    // 证书链已经由 CA 验证过，这里再检查公钥是否是固定的那些
+    fn check_pins<S>(&self, stream: &ClientTlsStream<S>) -> Result<(), KvError> {
+        if self.pins.is_empty() {
+            return Ok(());
+        }
+
+        let certs = stream.get_ref().1.get_peer_certificates().unwrap_or_default();
+        let pinned = certs
+            .iter()
+            .filter_map(|cert| spki_sha256(&cert.0))
+            .any(|digest| self.pins.contains(&digest));
+        match pinned {
+            true => Ok(()),
+            false => Err(KvError::CertificatePinMismatch(self.domain.to_string())),
+        }
+    }
+}
+
+/// 计算 PEM 证书（第一个）的 SPKI pin，即 base64 编码的公钥 SHA-256
+pub fn spki_pin(cert: &str) -> Result<String, KvError> {
+    let certs = load_certs(cert)?;
+    let digest = certs
+        .first()
+        .and_then(|cert| spki_sha256(&cert.0))
+        .ok_or(KvError::CertifcateParseError("server", "cert"))?;
+    Ok(STANDARD.encode(digest))
+}
+
+pub(crate) fn spki_sha256(der: &[u8]) -> Option<Vec<u8>> {
+    let (_, cert) = parse_x509_certificate(der).ok()?;
+    Some(Sha256::digest(cert.public_key().raw).to_vec())
+}
+
+/// 解码 base64 编码的 SPKI pin，每个都必须是 SHA-256
+pub(crate) fn decode_spki_pins<'a>(
+    pins: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<Vec<u8>>, KvError> {
+    pins.into_iter()
+        .map(|pin| match STANDARD.decode(pin) {
+            Ok(digest) if digest.len() == 32 => Ok(digest),
+            _ => Err(KvError::CertifcateParseError("SPKI", "pin")),
+        })
+        .collect()
+}
+
+/// 过了 next_update 的 CRL 可能漏掉之后吊销的证书，不再使用
+pub(crate) fn check_next_update(list: &CertificateRevocationList) -> Result<(), KvError> {
+    match list.next_update() {
+        Some(next) if next < ASN1Time::now() => Err(KvError::InvalidCrl(format!(
+            "CRL of {} expired at {}",
+            list.issuer(),
+            next
+        ))),
+        _ => Ok(()),
+    }
 }
 
 impl TlsServerAcceptor {
     /// 加载 server cert/CA cert，生成 ServerConfig
     pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self, KvError> {
-        let certs = load_certs(cert)?;
-        let key = load_key(key)?;
-
-        let mut config = match client_ca {
-            None => ServerConfig::new(NoClientAuth::new()),
-            Some(cert) => {
-                // 如果客户端证书是某个 CA 证书签发的，则把这个 CA 证书加载到信任链中
-                let mut cert = Cursor::new(cert);
-                let mut client_root_cert_store = RootCertStore::empty();
-                client_root_cert_store
-                    .add_pem_file(&mut cert)
-                    .map_err(|_| KvError::CertifcateParseError("CA", "cert"))?;
-
-                let client_auth = AllowAnyAuthenticatedClient::new(client_root_cert_store);
-                ServerConfig::new(client_auth)
-            }
-        };
+        let config = server_config(cert, key, client_ca, None)?;
 
-        // 加载服务器证书
-        config
-            .set_single_cert(certs, key)
-            .map_err(|_| KvError::CertifcateParseError("server", "cert"))?;
-        config.set_protocols(&[Vec::from(&ALPN_KV[..])]);
+        Ok(Self {
+            inner: Arc::new(RwLock::new(Arc::new(config))),
+            files: None,
+        })
+    }
+
+    /// 加载 server cert/CA cert 以及 CA 签发的 CRL，被吊销的客户端证书会被拒绝
+    pub fn with_crl(cert: &str, key: &str, client_ca: &str, crl: &str) -> Result<Self, KvError> {
+        let config = server_config(cert, key, Some(client_ca), Some(crl))?;
 
         Ok(Self {
-            inner: Arc::new(config),
+            inner: Arc::new(RwLock::new(Arc::new(config))),
+            files: None,
         })
     }
 
+    /// 从文件加载 server cert/CA cert/CRL，之后可以通过 reload/watch 重新加载
+    pub fn from_files(
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+        client_ca: Option<impl AsRef<Path>>,
+        crl: Option<impl AsRef<Path>>,
+    ) -> Result<Self, KvError> {
+        let files = CertFiles {
+            cert: cert.as_ref().to_path_buf(),
+            key: key.as_ref().to_path_buf(),
+            client_ca: client_ca.map(|p| p.as_ref().to_path_buf()),
+            crl: crl.map(|p| p.as_ref().to_path_buf()),
+        };
+        let config = files.load()?;
+
+        Ok(Self {
+            inner: Arc::new(RwLock::new(Arc::new(config))),
+            files: Some(Arc::new(files)),
+        })
+    }
+
+    /// 重新读取证书文件并替换 ServerConfig；失败时保留旧的配置
+    pub fn reload(&self) -> Result<(), KvError> {
+        let files = self
+            .files
+            .as_ref()
+            .ok_or_else(|| KvError::Internal("TLS acceptor is not loaded from files".into()))?;
+        let config = files.load()?;
+        *self.inner.write().unwrap() = Arc::new(config);
+        info!("TLS certificates reloaded from {:?}", files);
+        Ok(())
+    }
+
+    /// 每隔 interval 检查证书文件是否变化，收到 SIGHUP 时也重新加载
+    pub fn watch(&self, interval: Duration) -> Result<JoinHandle<()>, KvError> {
+        let files = self
+            .files
+            .clone()
+            .ok_or_else(|| KvError::Internal("TLS acceptor is not loaded from files".into()))?;
+        let mut hangup = signal(SignalKind::hangup())?;
+        let acceptor = self.clone();
+
+        Ok(tokio::spawn(async move {
+            let mut last = files.modified();
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        let current = files.modified();
+                        if current == last {
+                            continue;
+                        }
+                        last = current;
+                    }
+                    Some(_) = hangup.recv() => info!("Got SIGHUP, reload TLS certificates"),
+                }
+
+                if let Err(e) = acceptor.reload() {
+                    warn!("Failed to reload TLS certificates, keep the old ones: {:?}", e);
+                }
+            }
+        }))
+    }
+
     /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
     pub async fn accept<S>(&self, stream: S) -> Result<ServerTlsStream<S>, KvError>
         where
             S: AsyncRead + AsyncWrite + Unpin + Send,
     {
-        let acceptor = TlsAcceptor::from(self.inner.clone());
+        let config = self.inner.read().unwrap().clone();
+        let acceptor = TlsAcceptor::from(config);
         Ok(acceptor.accept(stream).await?)
     }
 }
 
+impl CertFiles {
+    fn load(&self) -> Result<ServerConfig, KvError> {
+        let cert = std::fs::read_to_string(&self.cert)?;
+        let key = std::fs::read_to_string(&self.key)?;
+        let client_ca = match &self.client_ca {
+            Some(path) => Some(std::fs::read_to_string(path)?),
+            None => None,
+        };
+        let crl = match &self.crl {
+            Some(path) => Some(std::fs::read_to_string(path)?),
+            None => None,
+        };
+        server_config(&cert, &key, client_ca.as_deref(), crl.as_deref())
+    }
+
+    // 文件的修改时间，文件不存在（比如正在被替换）时为 None
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let paths = [
+            Some(&self.cert),
+            Some(&self.key),
+            self.client_ca.as_ref(),
+            self.crl.as_ref(),
+        ];
+        paths
+            .into_iter()
+            .flatten()
+            .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+impl CrlClientVerifier {
+    fn new(
+        inner: Arc<dyn ClientCertVerifier>,
+        client_ca: &str,
+        crl: &str,
+    ) -> Result<Self, KvError> {
+        let cas = load_certs(client_ca)?;
+        let mut revoked = HashSet::new();
+        let mut count = 0;
+
+        for pem in Pem::iter_from_buffer(crl.as_bytes()) {
+            let pem = pem.map_err(|e| KvError::InvalidCrl(e.to_string()))?;
+            if pem.label != "X509 CRL" {
+                continue;
+            }
+            let (_, list) = parse_x509_crl(&pem.contents)
+                .map_err(|e| KvError::InvalidCrl(e.to_string()))?;
+
+            // CRL 必须由信任的 client CA 签发，否则可以随意吊销证书
+            let issuer = list.issuer().as_raw();
+            let signed = cas.iter().any(|ca| match parse_x509_certificate(&ca.0) {
+                Ok((_, ca)) => {
+                    ca.subject().as_raw() == issuer
+                        && list.verify_signature(ca.public_key()).is_ok()
+                }
+                Err(_) => false,
+            });
+            if !signed {
+                return Err(KvError::InvalidCrl(format!(
+                    "{} is not signed by the client CA",
+                    list.issuer()
+                )));
+            }
+            check_next_update(&list)?;
+
+            for cert in list.iter_revoked_certificates() {
+                revoked.insert((issuer.to_vec(), cert.raw_serial().to_vec()));
+            }
+            count += 1;
+        }
+
+        if count == 0 {
+            return Err(KvError::InvalidCrl("no X509 CRL found".into()));
+        }
+
+        Ok(Self { inner, revoked })
+    }
+}
+
+impl ClientCertVerifier for CrlClientVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self, sni: Option<&DNSName>) -> Option<bool> {
+        self.inner.client_auth_mandatory(sni)
+    }
+
+    fn client_auth_root_subjects(&self, sni: Option<&DNSName>) -> Option<DistinguishedNames> {
+        self.inner.client_auth_root_subjects(sni)
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+        sni: Option<&DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        let verified = self.inner.verify_client_cert(presented_certs, sni)?;
+
+        for cert in presented_certs {
+            let (_, cert) = parse_x509_certificate(&cert.0)
+                .map_err(|_| TLSError::General("invalid client certificate".into()))?;
+            let id = (cert.issuer().as_raw().to_vec(), cert.raw_serial().to_vec());
+            if self.revoked.contains(&id) {
+                warn!("Reject revoked client certificate {}", cert.subject());
+                return Err(TLSError::General(format!(
+                    "client certificate {} is revoked",
+                    cert.raw_serial_as_string()
+                )));
+            }
+        }
+
+        Ok(verified)
+    }
+}
+
+fn server_config(
+    cert: &str,
+    key: &str,
+    client_ca: Option<&str>,
+    crl: Option<&str>,
+) -> Result<ServerConfig, KvError> {
+    let certs = load_certs(cert)?;
+    if certs.is_empty() {
+        return Err(KvError::CertifcateParseError("server", "cert"));
+    }
+    let key = load_key(key)?;
+
+    let mut config = match (client_ca, crl) {
+        (None, None) => ServerConfig::new(NoClientAuth::new()),
+        (None, Some(_)) => {
+            return Err(KvError::InvalidCrl("CRL needs a client CA".into()));
+        }
+        (Some(cert), crl) => {
+            // 如果客户端证书是某个 CA 证书签发的，则把这个 CA 证书加载到信任链中
+            let mut ca = Cursor::new(cert);
+            let mut client_root_cert_store = RootCertStore::empty();
+            client_root_cert_store
+                .add_pem_file(&mut ca)
+                .map_err(|_| KvError::CertifcateParseError("CA", "cert"))?;
+
+            let client_auth = AllowAnyAuthenticatedClient::new(client_root_cert_store);
+            match crl {
+                Some(crl) => {
+                    let verifier = CrlClientVerifier::new(client_auth, cert, crl)?;
+                    ServerConfig::new(Arc::new(verifier))
+                }
+                None => ServerConfig::new(client_auth),
+            }
+        }
+    };
+
+    // 加载服务器证书
+    config
+        .set_single_cert(certs, key)
+        .map_err(|_| KvError::CertifcateParseError("server", "cert"))?;
+    config.set_protocols(&[Vec::from(&ALPN_KV[..])]);
+
+    Ok(config)
+}
+
 fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
     let mut cert = Cursor::new(cert);
     pemfile::certs(&mut cert).map_err(|_| KvError::CertifcateParseError("server", "cert"))
@@ -155,6 +494,12 @@ mod tests {
 
     use super::*;
     use anyhow::Result;
+    use rcgen::{
+        date_time_ymd, BasicConstraints, CertificateParams, CertificateRevocationList,
+        CertificateRevocationListParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+        KeyIdMethod, RevocationReason, RevokedCertParams, PKCS_ED25519,
+    };
+    use time::OffsetDateTime;
     use tokio::{
         io::{AsyncReadExt, AsyncWriteExt},
         net::{TcpListener, TcpStream},
@@ -165,6 +510,7 @@ mod tests {
     const CLIENT_KEY: &str = include_str!("../../fixtures/client.key");
     const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
     const SERVER_KEY: &str = include_str!("../../fixtures/server.key");
+    const DOMAIN: &str = "kvserver.acme.inc";
 
     #[tokio::test]
     async fn tls_should_work() -> Result<()> {
@@ -214,18 +560,269 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn tls_reload_should_keep_old_config_on_failure() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (cert, key) = (dir.path().join("server.cert"), dir.path().join("server.key"));
+        std::fs::write(&cert, SERVER_CERT)?;
+        std::fs::write(&key, SERVER_KEY)?;
+        let acceptor = TlsServerAcceptor::from_files(&cert, &key, None::<&Path>, None::<&Path>)?;
+
+        // a valid reload swaps the config
+        let before = acceptor.inner.read().unwrap().clone();
+        acceptor.reload()?;
+        assert!(!Arc::ptr_eq(&before, &acceptor.inner.read().unwrap()));
+
+        // a broken cert file fails the reload, and the old config is still used
+        let before = acceptor.inner.read().unwrap().clone();
+        std::fs::write(&cert, "not a cert")?;
+        assert!(acceptor.reload().is_err());
+        assert!(Arc::ptr_eq(&before, &acceptor.inner.read().unwrap()));
+
+        let addr = start_server_with(acceptor).await?;
+        let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(CA_CERT))?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(stream).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_watch_should_reload_changed_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (cert, key) = (dir.path().join("server.cert"), dir.path().join("server.key"));
+        std::fs::write(&cert, SERVER_CERT)?;
+        std::fs::write(&key, SERVER_KEY)?;
+        let acceptor = TlsServerAcceptor::from_files(&cert, &key, None::<&Path>, None::<&Path>)?;
+        let handle = acceptor.watch(Duration::from_millis(10))?;
+
+        let before = acceptor.inner.read().unwrap().clone();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::remove_file(&cert)?;
+        std::fs::write(&cert, SERVER_CERT)?;
+
+        let mut reloaded = false;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if !Arc::ptr_eq(&before, &acceptor.inner.read().unwrap()) {
+                reloaded = true;
+                break;
+            }
+        }
+        handle.abort();
+        assert!(reloaded);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_crl_should_reject_revoked_client_cert() -> Result<()> {
+        let ca = generate_ca("Acme CA");
+        let ca_cert = ca.serialize_pem()?;
+        let (server_cert, server_key) = generate_cert(&ca, "Acme KV server", &[DOMAIN], 1, false);
+        let good = generate_cert(&ca, "good-device-id", &[], 2, true);
+        let revoked = generate_cert(&ca, "revoked-device-id", &[], 3, true);
+        let crl = generate_crl(&ca, &[3]);
+
+        let acceptor = TlsServerAcceptor::with_crl(&server_cert, &server_key, &ca_cert, &crl)?;
+        let addr = start_server_with(acceptor).await?;
+
+        let identity = Some((good.0.as_str(), good.1.as_str()));
+        let connector = TlsClientConnector::new_private(DOMAIN, identity, &ca_cert)?;
+        assert!(echo(&connector, addr).await.is_ok());
+
+        let identity = Some((revoked.0.as_str(), revoked.1.as_str()));
+        let connector = TlsClientConnector::new_private(DOMAIN, identity, &ca_cert)?;
+        assert!(echo(&connector, addr).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tls_crl_should_be_signed_by_client_ca() -> Result<()> {
+        let ca = generate_ca("Acme CA");
+        let (server_cert, server_key) = generate_cert(&ca, "Acme KV server", &[DOMAIN], 1, false);
+        let crl = generate_crl(&generate_ca("Evil CA"), &[1]);
+
+        let ca_cert = ca.serialize_pem()?;
+        let result = TlsServerAcceptor::with_crl(&server_cert, &server_key, &ca_cert, &crl);
+        assert!(matches!(result, Err(KvError::InvalidCrl(_))));
+
+        let result = TlsServerAcceptor::with_crl(&server_cert, &server_key, &ca_cert, "");
+        assert!(matches!(result, Err(KvError::InvalidCrl(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn tls_crl_past_next_update_should_be_refused() -> Result<()> {
+        let ca = generate_ca("Acme CA");
+        let ca_cert = ca.serialize_pem()?;
+        let (server_cert, server_key) = generate_cert(&ca, "Acme KV server", &[DOMAIN], 1, false);
+
+        let crl = generate_crl_until(&ca, &[2], date_time_ymd(2024, 6, 1));
+        let result = TlsServerAcceptor::with_crl(&server_cert, &server_key, &ca_cert, &crl);
+        assert!(matches!(result, Err(KvError::InvalidCrl(e)) if e.contains("expired")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_spki_pins_should_work() -> Result<()> {
+        let ca = generate_ca("Acme CA");
+        let ca_cert = ca.serialize_pem()?;
+        let (server_cert, server_key) = generate_cert(&ca, "Acme KV server", &[DOMAIN], 1, false);
+        let (other_cert, _) = generate_cert(&ca, "Acme KV server", &[DOMAIN], 2, false);
+
+        let acceptor = TlsServerAcceptor::new(&server_cert, &server_key, None)?;
+        let addr = start_server_with(acceptor).await?;
+
+        let pin = spki_pin(&server_cert)?;
+        let connector = TlsClientConnector::new_private(DOMAIN, None, &ca_cert)?
+            .with_spki_pins([pin.as_str()])?;
+        assert!(echo(&connector, addr).await.is_ok());
+
+        // 同一个 CA 签发的另一个证书通过了 CA 验证，但公钥不匹配
+        let pin = spki_pin(&other_cert)?;
+        let connector = TlsClientConnector::new_private(DOMAIN, None, &ca_cert)?
+            .with_spki_pins([pin.as_str()])?;
+        let result = echo(&connector, addr).await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<KvError>(),
+            Some(KvError::CertificatePinMismatch(_))
+        ));
+
+        let result = TlsClientConnector::new_private(DOMAIN, None, &ca_cert)?
+            .with_spki_pins(["not a pin"]);
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_private_connector_should_only_trust_server_ca() -> Result<()> {
+        let ca = generate_ca("Acme CA");
+        let (server_cert, server_key) = generate_cert(&ca, "Acme KV server", &[DOMAIN], 1, false);
+        let other_ca = generate_ca("Other CA").serialize_pem()?;
+
+        let acceptor = TlsServerAcceptor::new(&server_cert, &server_key, None)?;
+        let addr = start_server_with(acceptor).await?;
+
+        let connector = TlsClientConnector::new_private(DOMAIN, None, &other_ca)?;
+        assert_eq!(connector.config.root_store.len(), 1);
+        assert!(echo(&connector, addr).await.is_err());
+
+        let connector = TlsClientConnector::new_private(DOMAIN, None, &ca.serialize_pem()?)?;
+        assert!(echo(&connector, addr).await.is_ok());
+
+        assert!(TlsClientConnector::new_private(DOMAIN, None, "").is_err());
+
+        Ok(())
+    }
+
+    async fn echo(connector: &TlsClientConnector, addr: SocketAddr) -> Result<()> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(stream).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+        Ok(())
+    }
+
+    // 和 examples/gen_cert.rs 一样，生成 ED25519 的 CA 以及它签发的证书
+    fn generate_ca(cn: &str) -> rcgen::Certificate {
+        let mut params = CertificateParams::default();
+        params.alg = &PKCS_ED25519;
+        params.distinguished_name = distinguished_name(cn);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    fn generate_cert(
+        ca: &rcgen::Certificate,
+        cn: &str,
+        domains: &[&str],
+        serial: u64,
+        is_client: bool,
+    ) -> (String, String) {
+        let domains: Vec<_> = domains.iter().map(|d| d.to_string()).collect();
+        let mut params = CertificateParams::new(domains);
+        params.alg = &PKCS_ED25519;
+        params.distinguished_name = distinguished_name(cn);
+        params.serial_number = Some(serial.into());
+        params.extended_key_usages = match is_client {
+            true => vec![ExtendedKeyUsagePurpose::ClientAuth],
+            false => vec![ExtendedKeyUsagePurpose::ServerAuth],
+        };
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let pem = cert.serialize_pem_with_signer(ca).unwrap();
+        (pem, cert.serialize_private_key_pem())
+    }
+
+    fn generate_crl(ca: &rcgen::Certificate, serials: &[u64]) -> String {
+        generate_crl_until(ca, serials, date_time_ymd(4096, 1, 1))
+    }
+
+    fn generate_crl_until(
+        ca: &rcgen::Certificate,
+        serials: &[u64],
+        next_update: OffsetDateTime,
+    ) -> String {
+        let revoked_certs = serials
+            .iter()
+            .map(|serial| RevokedCertParams {
+                serial_number: (*serial).into(),
+                revocation_time: date_time_ymd(2024, 1, 1),
+                reason_code: Some(RevocationReason::KeyCompromise),
+                invalidity_date: None,
+            })
+            .collect();
+        let params = CertificateRevocationListParams {
+            this_update: date_time_ymd(2024, 1, 1),
+            next_update,
+            crl_number: 1.into(),
+            issuing_distribution_point: None,
+            revoked_certs,
+            alg: &PKCS_ED25519,
+            key_identifier_method: KeyIdMethod::Sha256,
+        };
+        let crl = CertificateRevocationList::from_params(params).unwrap();
+        crl.serialize_pem_with_signer(ca).unwrap()
+    }
+
+    fn distinguished_name(cn: &str) -> DistinguishedName {
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CountryName, "CN");
+        name.push(DnType::OrganizationName, "Acme Inc.");
+        name.push(DnType::CommonName, cn);
+        name
+    }
+
     async fn start_server(ca: Option<&str>) -> Result<SocketAddr> {
         let acceptor = TlsServerAcceptor::new(SERVER_CERT, SERVER_KEY, ca)?;
+        start_server_with(acceptor).await
+    }
 
+    async fn start_server_with(acceptor: TlsServerAcceptor) -> Result<SocketAddr> {
         let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = echo.local_addr().unwrap();
 
         tokio::spawn(async move {
-            let (stream, _) = echo.accept().await.unwrap();
-            let mut stream = acceptor.accept(stream).await.unwrap();
-            let mut buf = [0; 12];
-            stream.read_exact(&mut buf).await.unwrap();
-            stream.write_all(&buf).await.unwrap();
+            // 握手失败的连接（比如证书被吊销）不影响后面的连接
+            while let Ok((stream, _)) = echo.accept().await {
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                let mut buf = [0; 12];
+                if stream.read_exact(&mut buf).await.is_ok() {
+                    stream.write_all(&buf).await.unwrap();
+                }
+            }
         });
 
         Ok(addr)
@@ -0,0 +1,186 @@
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use tokio::net::{unix::UCred, UnixListener, UnixStream};
+
+use crate::{KvError, ProstClientStream};
+
+/// Unix domain socket listener for sidecar processes on the same host.
+/// Access control relies on the permission bits of the socket file.
+pub struct UnixSocketListener {
+    inner: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocketListener {
+    /// Bind the socket at `path` and restrict it to `mode` (e.g. 0o660).
+    /// A stale socket left by a previous run is replaced, a socket another server is listening
+    /// on or any other file is an error.
+    pub fn bind(path: impl AsRef<Path>, mode: u32) -> Result<Self, KvError> {
+        let path = path.as_ref().to_path_buf();
+
+        if let Ok(meta) = fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                return Err(KvError::Internal(format!(
+                    "{} exists and is not a socket",
+                    path.display()
+                )));
+            }
+            if StdUnixStream::connect(&path).is_ok() {
+                return Err(KvError::Internal(format!(
+                    "{} is in use by another server",
+                    path.display()
+                )));
+            }
+        }
+
+        // The socket is bound in a 0700 directory and gets its mode there, then it's moved into
+        // place, so it's never reachable with the mode of the umask
+        let private = private_dir(&path)?;
+        let bound = private.join("kv.sock");
+        let result = UnixListener::bind(&bound).map_err(KvError::from).and_then(|inner| {
+            fs::set_permissions(&bound, fs::Permissions::from_mode(mode))?;
+            fs::rename(&bound, &path)?;
+            Ok(inner)
+        });
+        let _ = fs::remove_dir_all(&private);
+
+        Ok(Self {
+            inner: result?,
+            path,
+        })
+    }
+
+    /// Accept a connection, returns the stream and the credential of the peer process
+    pub async fn accept(&self) -> Result<(UnixStream, UCred), KvError> {
+        let (stream, _) = self.inner.accept().await?;
+        let cred = stream.peer_cred()?;
+        Ok((stream, cred))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+// An empty 0700 directory next to the socket, on the same file system so it can be renamed
+fn private_dir(path: &Path) -> Result<PathBuf, KvError> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| KvError::Internal(format!("{} is not a file", path.display())))?;
+    let mut dir_name = OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", process::id()));
+    let dir = path.with_file_name(dir_name);
+
+    // left by a run that crashed with the same pid, as in a container
+    if fs::symlink_metadata(&dir).is_ok() {
+        fs::remove_dir_all(&dir)?;
+    }
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl ProstClientStream<UnixStream> {
+    /// Connect to a kv server listening on a Unix domain socket
+    pub async fn connect_unix(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let stream = UnixStream::connect(path).await?;
+        Ok(Self::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, CommandRequest, MemTable, ProstServerStream, Service, ServiceInner, Value,
+    };
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn unix_socket_client_server_should_work() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("kv.sock");
+        start_server(&path, 0o600)?;
+
+        let mut client = ProstClientStream::connect_unix(&path).await?;
+        let res = client.execute(CommandRequest::new_hset("t1", "k1", "v1".into())).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unix_socket_should_apply_mode_and_replace_stale_socket() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("kv.sock");
+
+        // a stale socket from a previous run is replaced
+        let stale = std::os::unix::net::UnixListener::bind(&path)?;
+        drop(stale);
+        let listener = UnixSocketListener::bind(&path, 0o640)?;
+        let mode = fs::metadata(listener.path())?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+
+        // socket file is removed when listener is dropped
+        drop(listener);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unix_socket_should_not_replace_a_socket_in_use() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("kv.sock");
+        let listener = UnixSocketListener::bind(&path, 0o600)?;
+
+        assert!(UnixSocketListener::bind(&path, 0o600).is_err());
+        assert!(ProstClientStream::connect_unix(listener.path()).await.is_ok());
+        // nothing is left of the failed bind
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unix_socket_should_not_replace_regular_file() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("kv.sock");
+        fs::write(&path, b"data")?;
+
+        assert!(UnixSocketListener::bind(&path, 0o600).is_err());
+        assert_eq!(fs::read(&path)?, b"data");
+
+        Ok(())
+    }
+
+    fn start_server(path: &Path, mode: u32) -> Result<()> {
+        let listener = UnixSocketListener::bind(path, mode)?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server = ProstServerStream::new(stream, service.clone());
+                tokio::spawn(server.process());
+            }
+        });
+
+        Ok(())
+    }
+}
@@ -2,7 +2,7 @@
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9")]
+    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29")]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
@@ -28,6 +28,46 @@ pub mod command_request {
         Hexist(super::Hexist),
         #[prost(message, tag="9")]
         Hmexist(super::Hmexist),
+        #[prost(message, tag="10")]
+        Replicate(super::Replicate),
+        #[prost(message, tag="11")]
+        Watch(super::Watch),
+        #[prost(message, tag="12")]
+        Backup(super::Backup),
+        #[prost(message, tag="13")]
+        ListTables(super::ListTables),
+        #[prost(message, tag="14")]
+        Lpush(super::Lpush),
+        #[prost(message, tag="15")]
+        Lpop(super::Lpop),
+        #[prost(message, tag="16")]
+        Lrange(super::Lrange),
+        #[prost(message, tag="17")]
+        Sadd(super::Sadd),
+        #[prost(message, tag="18")]
+        Srem(super::Srem),
+        #[prost(message, tag="19")]
+        Smembers(super::Smembers),
+        #[prost(message, tag="20")]
+        Zadd(super::Zadd),
+        #[prost(message, tag="21")]
+        Zrange(super::Zrange),
+        #[prost(message, tag="22")]
+        Zrangebyscore(super::Zrangebyscore),
+        #[prost(message, tag="23")]
+        JsonGet(super::JsonGet),
+        #[prost(message, tag="24")]
+        JsonSet(super::JsonSet),
+        #[prost(message, tag="25")]
+        JsonAppend(super::JsonAppend),
+        #[prost(message, tag="26")]
+        JsonIncr(super::JsonIncr),
+        #[prost(message, tag="27")]
+        CreateIndex(super::CreateIndex),
+        #[prost(message, tag="28")]
+        DropIndex(super::DropIndex),
+        #[prost(message, tag="29")]
+        Hfind(super::Hfind),
     }
 }
 /// 服务器的响应
@@ -62,6 +102,38 @@ pub struct Hget {
 pub struct Hgetall {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
+    /// 只返回满足条件的 Kvpair，为空时返回所有的
+    #[prost(message, optional, tag="2")]
+    pub filter: ::core::option::Option<ScanFilter>,
+}
+/// 服务器遍历 table 时过滤和投影 Kvpair，设置了的条件都满足才返回
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ScanFilter {
+    /// key 的 glob，* 匹配任意个字符，? 匹配一个字符，[abc] 和 [!abc] 匹配或不匹配其中一个字符
+    #[prost(string, tag="1")]
+    pub key_glob: ::prost::alloc::string::String,
+    /// key 的正则表达式，匹配 key 的一部分即可
+    #[prost(string, tag="2")]
+    pub key_regex: ::prost::alloc::string::String,
+    /// value 的类型，即 Value 中 value 的字段名，如 "integer"、"json"
+    #[prost(string, tag="3")]
+    pub value_type: ::prost::alloc::string::String,
+    /// value 或 path 处的值在 min 和 max 之间（包含两端），按索引的顺序比较：
+    /// integer 和 float 按数字比较，其它类型只和同类型的比较；只设置 min 或 max 时另一端不限
+    #[prost(message, optional, tag="4")]
+    pub min: ::core::option::Option<Value>,
+    #[prost(message, optional, tag="5")]
+    pub max: ::core::option::Option<Value>,
+    /// 只返回 value 中 JSON 文档 path 处的值，没有这个值的 Kvpair 不返回
+    #[prost(string, tag="6")]
+    pub path: ::prost::alloc::string::String,
+    /// 只返回 key，value 为空
+    #[prost(bool, tag="7")]
+    pub keys_only: bool,
+    /// 只返回满足条件的 Kvpair 的个数
+    #[prost(bool, tag="8")]
+    pub count_only: bool,
 }
 /// 从 table 中获取一组 key，返回它们的 value
 #[derive(PartialOrd)]
@@ -76,7 +148,7 @@ pub struct Hmget {
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5")]
+    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5, 6, 7")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
@@ -94,8 +166,31 @@ pub mod value {
         Float(f64),
         #[prost(bool, tag="5")]
         Bool(bool),
+        /// key 上存的是 list、set 或 zset，只能用对应的命令操作
+        #[prost(message, tag="6")]
+        Collection(super::Collection),
+        /// JSON 文档的文本，可以用 JsonGet、JsonSet 等命令读写其中的一部分
+        #[prost(string, tag="7")]
+        Json(::prost::alloc::string::String),
     }
 }
+/// list、set、zset 的头，存在 key 上；元素存在单独的 table 中，每个元素一个 kvpair
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Collection {
+    /// "list"、"set" 或 "zset"
+    #[prost(string, tag="1")]
+    pub kind: ::prost::alloc::string::String,
+    /// 元素的个数
+    #[prost(uint64, tag="2")]
+    pub len: u64,
+    /// list 第一个元素的下标
+    #[prost(sint64, tag="3")]
+    pub head: i64,
+    /// list 最后一个元素之后的下标
+    #[prost(sint64, tag="4")]
+    pub tail: i64,
+}
 /// 返回的 kvpair
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -161,3 +256,962 @@ pub struct Hmexist {
     #[prost(string, repeated, tag="2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 列出所有 table 的名字，按字典序返回
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListTables {
+}
+/// 把一组 value 依次插入 list 的头部，key 不存在时创建 list，返回 list 的长度
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lpush {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="3")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 从 list 的头部弹出 count 个 value，count 为 0 时弹出一个
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lpop {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub count: u64,
+}
+/// 返回 list 中下标从 start 到 stop（包含 stop）的 value，负数表示从尾部数起
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lrange {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub start: i64,
+    #[prost(int64, tag="4")]
+    pub stop: i64,
+}
+/// 往 set 中加入一组 member，key 不存在时创建 set，返回新加入的个数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Sadd {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="3")]
+    pub members: ::prost::alloc::vec::Vec<Value>,
+}
+/// 从 set 中删除一组 member，返回删除的个数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Srem {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="3")]
+    pub members: ::prost::alloc::vec::Vec<Value>,
+}
+/// 返回 set 中所有的 member
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Smembers {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 往 zset 中加入一组 member，kvpair 的 key 是 member，value 是 score；
+/// 已有的 member 更新 score，返回新加入的个数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zadd {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="3")]
+    pub members: ::prost::alloc::vec::Vec<Kvpair>,
+}
+/// 按 score 排序，返回下标从 start 到 stop（包含 stop）的 member 和 score，负数表示从尾部数起
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zrange {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub start: i64,
+    #[prost(int64, tag="4")]
+    pub stop: i64,
+}
+/// 按 score 排序，返回 score 在 min 和 max 之间（包含两端）的 member 和 score
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zrangebyscore {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(double, tag="3")]
+    pub min: f64,
+    #[prost(double, tag="4")]
+    pub max: f64,
+}
+/// 读取 key 上 JSON 文档中 path 处的值，path 形如 $.user.email 或 $.items\[0\]，为空时是整个文档
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct JsonGet {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 把 JSON 文档中 path 处的值设为 value（JSON 文本），返回之前的值；
+/// key 不存在时只能设置整个文档
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct JsonSet {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub path: ::prost::alloc::string::String,
+    #[prost(string, tag="4")]
+    pub value: ::prost::alloc::string::String,
+}
+/// 往 JSON 文档中 path 处的数组末尾追加一组值（JSON 文本），返回数组的长度
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct JsonAppend {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub path: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="4")]
+    pub values: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 把 JSON 文档中 path 处的数字加上 by（integer 或 float），返回新的值
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct JsonIncr {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub path: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="4")]
+    pub by: ::core::option::Option<Value>,
+}
+/// 在 table 上创建名为 name 的二级索引，索引 value 本身，或 value 中 JSON 文档 path 处的值；
+/// 之后的写入会同时更新索引，返回已有的被索引的 key 的个数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CreateIndex {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub name: ::prost::alloc::string::String,
+    /// 为空时索引 value 本身
+    #[prost(string, tag="3")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 删除 table 上的索引，返回删除的索引项的个数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DropIndex {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub name: ::prost::alloc::string::String,
+}
+/// 用索引查找 table 中被索引的值等于 value，或在 min 和 max 之间（包含两端）的 kv pair，
+/// 按被索引的值排序；只设置 min 或 max 时另一端不限
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hfind {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub index: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+    #[prost(message, optional, tag="4")]
+    pub min: ::core::option::Option<Value>,
+    #[prost(message, optional, tag="5")]
+    pub max: ::core::option::Option<Value>,
+    /// 在 values 中按 pairs 的顺序返回它们被索引的值的编码，分片时按它合并各节点的结果
+    #[prost(bool, tag="6")]
+    pub with_order: bool,
+}
+/// 从节点向主节点请求复制写命令，主节点回应后持续发送 ReplicaEntry
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Replicate {
+    /// 从节点上次同步的复制日志 id，和主节点不一致时需要重新同步快照
+    #[prost(uint64, tag="1")]
+    pub log_id: u64,
+    /// 下一个需要的序号，0 表示从快照开始
+    #[prost(uint64, tag="2")]
+    pub next_seq: u64,
+}
+/// 订阅 table 的修改，服务器回应当前的序号后持续发送 ChangeEvent
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Watch {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    /// 只订阅这个 key，为空时订阅整个 table
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    /// 只订阅以此为前缀的 key
+    #[prost(string, tag="3")]
+    pub prefix: ::prost::alloc::string::String,
+    /// 从这个序号开始重放之前的修改，0 表示只订阅之后的修改
+    #[prost(uint64, tag="4")]
+    pub from_seq: u64,
+}
+/// 一个 key 的修改，同一条命令修改的多个 key 有相同的序号
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChangeEvent {
+    #[prost(uint64, tag="1")]
+    pub seq: u64,
+    #[prost(string, tag="2")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub key: ::prost::alloc::string::String,
+    /// 修改的类型，"set" 或 "del"；集合命令是命令名，如 "lpush"、"srem"，每个元素一个事件
+    #[prost(string, tag="4")]
+    pub op: ::prost::alloc::string::String,
+    /// 修改之前的值，之前不存在时为空；lpop 和 srem 是移除的元素，zadd 是之前的 score
+    #[prost(message, optional, tag="5")]
+    pub old_value: ::core::option::Option<Value>,
+    /// 修改之后的值，删除时为空；lpush 和 sadd 是加入的元素，zadd 是新的 score
+    #[prost(message, optional, tag="6")]
+    pub new_value: ::core::option::Option<Value>,
+    /// zadd 修改的 member
+    #[prost(string, tag="7")]
+    pub member: ::prost::alloc::string::String,
+}
+/// 把当前的数据备份到服务器备份目录下的 name 子目录中，返回备份的路径和序号
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Backup {
+    #[prost(string, tag="1")]
+    pub name: ::prost::alloc::string::String,
+}
+/// 主节点发给从节点的复制数据
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReplicaEntry {
+    /// 主节点当前最新的序号，用于计算复制延迟
+    #[prost(uint64, tag="1")]
+    pub head_seq: u64,
+    #[prost(oneof="replica_entry::Entry", tags="2, 3, 4, 5")]
+    pub entry: ::core::option::Option<replica_entry::Entry>,
+}
+/// Nested message and enum types in `ReplicaEntry`.
+pub mod replica_entry {
+    #[derive(PartialOrd)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Entry {
+        /// 一条写命令
+        #[prost(message, tag="2")]
+        Log(super::ReplicaLog),
+        /// 开始发送快照，从节点需要先清空自己的数据
+        #[prost(message, tag="3")]
+        SnapshotBegin(super::ReplicaSnapshot),
+        /// 快照中的一个 kvpair
+        #[prost(message, tag="4")]
+        Snapshot(super::Hset),
+        /// 快照发送完毕，之后的写命令从快照的序号之后开始
+        #[prost(message, tag="5")]
+        SnapshotEnd(super::ReplicaSnapshot),
+    }
+}
+/// 复制日志中带序号的写命令
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReplicaLog {
+    #[prost(uint64, tag="1")]
+    pub seq: u64,
+    #[prost(message, optional, tag="2")]
+    pub command: ::core::option::Option<CommandRequest>,
+    /// 命令执行前每个 key 的值，用于生成 ChangeEvent，不发给从节点；JSON 命令是之前的文档，
+    /// sadd、srem 和 zadd 是每个 member 之前的元素
+    #[prost(message, repeated, tag="3")]
+    pub old_values: ::prost::alloc::vec::Vec<Value>,
+    /// JSON 命令执行后的文档，用于生成 ChangeEvent，不发给从节点
+    #[prost(message, repeated, tag="4")]
+    pub new_values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 快照所属的复制日志和对应的序号
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReplicaSnapshot {
+    #[prost(uint64, tag="1")]
+    pub log_id: u64,
+    #[prost(uint64, tag="2")]
+    pub seq: u64,
+}
+/// 连接建立后客户端先发送 Hello，协商协议版本和能力
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hello {
+    /// 支持的最高协议版本
+    #[prost(uint32, tag="1")]
+    pub version: u32,
+    /// 支持的命令，即 CommandRequest 中 request_data 的字段名，如 "hget"
+    #[prost(string, repeated, tag="2")]
+    pub commands: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// 支持的压缩算法，如 "gzip"
+    #[prost(string, repeated, tag="3")]
+    pub compressions: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// 能接收的最大 frame（字节）
+    #[prost(uint32, tag="4")]
+    pub max_frame_size: u32,
+}
+/// 服务器对 Hello 的回应
+/// status/message 和 CommandResponse 的字段一致，不发送 Hello 的旧客户端也能看到错误信息
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HelloResponse {
+    /// 状态码；复用 HTTP 状态码，不兼容时为 426
+    #[prost(uint32, tag="1")]
+    pub status: u32,
+    /// 如果不兼容，message 里包含详细的信息
+    #[prost(string, tag="2")]
+    pub message: ::prost::alloc::string::String,
+    /// 协商后双方都支持的版本和能力
+    #[prost(message, optional, tag="3")]
+    pub hello: ::core::option::Option<Hello>,
+}
+/// Raft 日志中的一条记录，payload 为空时是新 leader 提交的空记录
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftEntry {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(uint64, tag="2")]
+    pub index: u64,
+    #[prost(oneof="raft_entry::Payload", tags="3, 4")]
+    pub payload: ::core::option::Option<raft_entry::Payload>,
+}
+/// Nested message and enum types in `RaftEntry`.
+pub mod raft_entry {
+    #[derive(PartialOrd)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Payload {
+        /// 写命令
+        #[prost(message, tag="3")]
+        Command(super::CommandRequest),
+        /// 新的成员列表，追加到日志时就生效
+        #[prost(message, tag="4")]
+        Membership(super::RaftMembership),
+    }
+}
+/// 集群的成员
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftMembership {
+    #[prost(message, repeated, tag="1")]
+    pub peers: ::prost::alloc::vec::Vec<RaftPeer>,
+}
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftPeer {
+    #[prost(uint64, tag="1")]
+    pub id: u64,
+    /// 接收 RaftMessage 的地址
+    #[prost(string, tag="2")]
+    pub addr: ::prost::alloc::string::String,
+}
+/// 节点之间的 Raft 消息
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftMessage {
+    #[prost(uint64, tag="1")]
+    pub from: u64,
+    /// 发送者的地址，新加入的节点可能还不知道发送者
+    #[prost(string, tag="2")]
+    pub from_addr: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub term: u64,
+    #[prost(oneof="raft_message::Body", tags="4, 5, 6, 7, 8, 9, 10, 11")]
+    pub body: ::core::option::Option<raft_message::Body>,
+}
+/// Nested message and enum types in `RaftMessage`.
+pub mod raft_message {
+    #[derive(PartialOrd)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Body {
+        #[prost(message, tag="4")]
+        Vote(super::VoteRequest),
+        #[prost(message, tag="5")]
+        VoteResponse(super::VoteResponse),
+        #[prost(message, tag="6")]
+        Append(super::AppendEntries),
+        #[prost(message, tag="7")]
+        AppendResponse(super::AppendResponse),
+        #[prost(message, tag="8")]
+        Snapshot(super::InstallSnapshot),
+        #[prost(message, tag="9")]
+        SnapshotResponse(super::SnapshotResponse),
+        /// follower 把客户端的写命令转发给 leader
+        #[prost(message, tag="10")]
+        Forward(super::ForwardRequest),
+        #[prost(message, tag="11")]
+        ForwardResponse(super::ForwardResponse),
+    }
+}
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct VoteRequest {
+    #[prost(uint64, tag="1")]
+    pub last_log_index: u64,
+    #[prost(uint64, tag="2")]
+    pub last_log_term: u64,
+}
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct VoteResponse {
+    #[prost(bool, tag="1")]
+    pub granted: bool,
+}
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AppendEntries {
+    #[prost(uint64, tag="1")]
+    pub prev_log_index: u64,
+    #[prost(uint64, tag="2")]
+    pub prev_log_term: u64,
+    #[prost(message, repeated, tag="3")]
+    pub entries: ::prost::alloc::vec::Vec<RaftEntry>,
+    #[prost(uint64, tag="4")]
+    pub leader_commit: u64,
+}
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AppendResponse {
+    #[prost(bool, tag="1")]
+    pub success: bool,
+    /// 成功时为已经匹配的最后一条记录，失败时为 leader 下次可以尝试的位置
+    #[prost(uint64, tag="2")]
+    pub match_index: u64,
+}
+/// 日志压缩后，leader 用快照同步落后太多的节点，快照分块发送
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InstallSnapshot {
+    #[prost(uint64, tag="1")]
+    pub last_index: u64,
+    #[prost(uint64, tag="2")]
+    pub last_term: u64,
+    #[prost(message, optional, tag="3")]
+    pub membership: ::core::option::Option<RaftMembership>,
+    /// 快照中从 offset 开始的一块 kvpair
+    #[prost(message, repeated, tag="4")]
+    pub pairs: ::prost::alloc::vec::Vec<Hset>,
+    #[prost(uint64, tag="5")]
+    pub offset: u64,
+    /// 是否是最后一块
+    #[prost(bool, tag="6")]
+    pub done: bool,
+}
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotResponse {
+    /// 已经安装的快照的最后一条记录，还没有安装或者安装失败时为 0
+    #[prost(uint64, tag="1")]
+    pub last_index: u64,
+    /// 正在接收的快照，以及收到的 kvpair 数量，安装失败时为 0，leader 从头重发
+    #[prost(uint64, tag="2")]
+    pub snapshot_index: u64,
+    #[prost(uint64, tag="3")]
+    pub received: u64,
+}
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ForwardRequest {
+    #[prost(uint64, tag="1")]
+    pub id: u64,
+    #[prost(message, optional, tag="2")]
+    pub command: ::core::option::Option<CommandRequest>,
+}
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ForwardResponse {
+    #[prost(uint64, tag="1")]
+    pub id: u64,
+    #[prost(message, optional, tag="2")]
+    pub response: ::core::option::Option<CommandResponse>,
+}
+/// 遍历一张表的所有 Kvpair
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ScanRequest {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    /// 只返回符合 filter 的 Kvpair，scan 不支持 count_only
+    #[prost(message, optional, tag="2")]
+    pub filter: ::core::option::Option<ScanFilter>,
+}
+/// 订阅一张表上执行的写命令
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SubscribeRequest {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// Generated client implementations.
+pub mod kv_service_client {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    /// gRPC 服务，和原生协议的监听共享同一个 Service
+    #[derive(Debug, Clone)]
+    pub struct KvServiceClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl KvServiceClient<tonic::transport::Channel> {
+        /// Attempt to create a new client by connecting to a given endpoint.
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: std::convert::TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> KvServiceClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::Error: Into<StdError>,
+        T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> KvServiceClient<InterceptedService<T, F>>
+        where
+            F: tonic::service::Interceptor,
+            T::ResponseBody: Default,
+            T: tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+                Response = http::Response<
+                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
+                >,
+            >,
+            <T as tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+            >>::Error: Into<StdError> + Send + Sync,
+        {
+            KvServiceClient::new(InterceptedService::new(inner, interceptor))
+        }
+        /// Compress requests with `gzip`.
+        ///
+        /// This requires the server to support it otherwise it might respond with an
+        /// error.
+        #[must_use]
+        pub fn send_gzip(mut self) -> Self {
+            self.inner = self.inner.send_gzip();
+            self
+        }
+        /// Enable decompressing responses with `gzip`.
+        #[must_use]
+        pub fn accept_gzip(mut self) -> Self {
+            self.inner = self.inner.accept_gzip();
+            self
+        }
+        /// 执行一个 CommandRequest，和原生协议相同
+        pub async fn execute(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CommandRequest>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Execute");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// 以流的方式返回一张表的所有 Kvpair
+        pub async fn scan(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ScanRequest>,
+        ) -> Result<
+            tonic::Response<tonic::codec::Streaming<super::Kvpair>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Scan");
+            self.inner.server_streaming(request.into_request(), path, codec).await
+        }
+        /// 以流的方式返回订阅之后这张表上执行的写命令
+        pub async fn subscribe(
+            &mut self,
+            request: impl tonic::IntoRequest<super::SubscribeRequest>,
+        ) -> Result<
+            tonic::Response<tonic::codec::Streaming<super::CommandRequest>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Subscribe");
+            self.inner.server_streaming(request.into_request(), path, codec).await
+        }
+        /// 以流的方式返回一张表、key 前缀或者 key 的变化，和 Watch 命令相同
+        pub async fn watch_changes(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Watch>,
+        ) -> Result<
+            tonic::Response<tonic::codec::Streaming<super::ChangeEvent>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/abi.KvService/WatchChanges",
+            );
+            self.inner.server_streaming(request.into_request(), path, codec).await
+        }
+    }
+}
+/// Generated server implementations.
+pub mod kv_service_server {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    ///Generated trait containing gRPC methods that should be implemented for use with KvServiceServer.
+    #[async_trait]
+    pub trait KvService: Send + Sync + 'static {
+        /// 执行一个 CommandRequest，和原生协议相同
+        async fn execute(
+            &self,
+            request: tonic::Request<super::CommandRequest>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        ///Server streaming response type for the Scan method.
+        type ScanStream: futures_core::Stream<
+                Item = Result<super::Kvpair, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        /// 以流的方式返回一张表的所有 Kvpair
+        async fn scan(
+            &self,
+            request: tonic::Request<super::ScanRequest>,
+        ) -> Result<tonic::Response<Self::ScanStream>, tonic::Status>;
+        ///Server streaming response type for the Subscribe method.
+        type SubscribeStream: futures_core::Stream<
+                Item = Result<super::CommandRequest, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        /// 以流的方式返回订阅之后这张表上执行的写命令
+        async fn subscribe(
+            &self,
+            request: tonic::Request<super::SubscribeRequest>,
+        ) -> Result<tonic::Response<Self::SubscribeStream>, tonic::Status>;
+        ///Server streaming response type for the WatchChanges method.
+        type WatchChangesStream: futures_core::Stream<
+                Item = Result<super::ChangeEvent, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        /// 以流的方式返回一张表、key 前缀或者 key 的变化，和 Watch 命令相同
+        async fn watch_changes(
+            &self,
+            request: tonic::Request<super::Watch>,
+        ) -> Result<tonic::Response<Self::WatchChangesStream>, tonic::Status>;
+    }
+    /// gRPC 服务，和原生协议的监听共享同一个 Service
+    #[derive(Debug)]
+    pub struct KvServiceServer<T: KvService> {
+        inner: _Inner<T>,
+        accept_compression_encodings: (),
+        send_compression_encodings: (),
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: KvService> KvServiceServer<T> {
+        pub fn new(inner: T) -> Self {
+            Self::from_arc(Arc::new(inner))
+        }
+        pub fn from_arc(inner: Arc<T>) -> Self {
+            let inner = _Inner(inner);
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+            }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for KvServiceServer<T>
+    where
+        T: KvService,
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(
+            &mut self,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/abi.KvService/Execute" => {
+                    #[allow(non_camel_case_types)]
+                    struct ExecuteSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::CommandRequest>
+                    for ExecuteSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CommandRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).execute(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ExecuteSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Scan" => {
+                    #[allow(non_camel_case_types)]
+                    struct ScanSvc<T: KvService>(pub Arc<T>);
+                    impl<
+                        T: KvService,
+                    > tonic::server::ServerStreamingService<super::ScanRequest>
+                    for ScanSvc<T> {
+                        type Response = super::Kvpair;
+                        type ResponseStream = T::ScanStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ScanRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).scan(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ScanSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Subscribe" => {
+                    #[allow(non_camel_case_types)]
+                    struct SubscribeSvc<T: KvService>(pub Arc<T>);
+                    impl<
+                        T: KvService,
+                    > tonic::server::ServerStreamingService<super::SubscribeRequest>
+                    for SubscribeSvc<T> {
+                        type Response = super::CommandRequest;
+                        type ResponseStream = T::SubscribeStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::SubscribeRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).subscribe(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SubscribeSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/WatchChanges" => {
+                    #[allow(non_camel_case_types)]
+                    struct WatchChangesSvc<T: KvService>(pub Arc<T>);
+                    impl<
+                        T: KvService,
+                    > tonic::server::ServerStreamingService<super::Watch>
+                    for WatchChangesSvc<T> {
+                        type Response = super::ChangeEvent;
+                        type ResponseStream = T::WatchChangesStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Watch>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).watch_changes(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = WatchChangesSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => {
+                    Box::pin(async move {
+                        Ok(
+                            http::Response::builder()
+                                .status(200)
+                                .header("grpc-status", "12")
+                                .header("content-type", "application/grpc")
+                                .body(empty_body())
+                                .unwrap(),
+                        )
+                    })
+                }
+            }
+        }
+    }
+    impl<T: KvService> Clone for KvServiceServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self {
+                inner,
+                accept_compression_encodings: self.accept_compression_encodings,
+                send_compression_encodings: self.send_compression_encodings,
+            }
+        }
+    }
+    impl<T: KvService> Clone for _Inner<T> {
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+    }
+    impl<T: std::fmt::Debug> std::fmt::Debug for _Inner<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    impl<T: KvService> tonic::transport::NamedService for KvServiceServer<T> {
+        const NAME: &'static str = "abi.KvService";
+    }
+}
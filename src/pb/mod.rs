@@ -2,6 +2,7 @@ pub mod abi;
 
 use crate::KvError;
 use abi::{command_request::RequestData, *};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use bytes::Bytes;
 use http::StatusCode;
 use prost::Message;
@@ -39,6 +40,17 @@ impl CommandRequest {
         Self {
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into(),
+                filter: None,
+            })),
+        }
+    }
+
+    // Get the kvpairs of the table matching the filter, filtered by the server
+    pub fn new_hget_all_filtered(table: impl Into<String>, filter: ScanFilter) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table.into(),
+                filter: Some(filter),
             })),
         }
     }
@@ -87,6 +99,392 @@ impl CommandRequest {
             })),
         }
     }
+
+    pub fn new_replicate(log_id: u64, next_seq: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Replicate(Replicate { log_id, next_seq })),
+        }
+    }
+
+    // List the names of all tables
+    pub fn new_list_tables() -> Self {
+        Self {
+            request_data: Some(RequestData::ListTables(ListTables {})),
+        }
+    }
+
+    // Push the values to the head of a list, the last value ends up first
+    pub fn new_lpush(table: impl Into<String>, key: impl Into<String>, values: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Lpush(Lpush {
+                table: table.into(),
+                key: key.into(),
+                values,
+            })),
+        }
+    }
+
+    pub fn new_lpop(table: impl Into<String>, key: impl Into<String>, count: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Lpop(Lpop {
+                table: table.into(),
+                key: key.into(),
+                count,
+            })),
+        }
+    }
+
+    pub fn new_lrange(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        start: i64,
+        stop: i64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Lrange(Lrange {
+                table: table.into(),
+                key: key.into(),
+                start,
+                stop,
+            })),
+        }
+    }
+
+    pub fn new_sadd(table: impl Into<String>, key: impl Into<String>, members: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Sadd(Sadd {
+                table: table.into(),
+                key: key.into(),
+                members,
+            })),
+        }
+    }
+
+    pub fn new_srem(table: impl Into<String>, key: impl Into<String>, members: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Srem(Srem {
+                table: table.into(),
+                key: key.into(),
+                members,
+            })),
+        }
+    }
+
+    pub fn new_smembers(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Smembers(Smembers {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    // Add the members with their scores to a sorted set
+    pub fn new_zadd(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        members: Vec<(String, f64)>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Zadd(Zadd {
+                table: table.into(),
+                key: key.into(),
+                members: members
+                    .into_iter()
+                    .map(|(member, score)| Kvpair::new(member, score.into()))
+                    .collect(),
+            })),
+        }
+    }
+
+    pub fn new_zrange(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        start: i64,
+        stop: i64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Zrange(Zrange {
+                table: table.into(),
+                key: key.into(),
+                start,
+                stop,
+            })),
+        }
+    }
+
+    pub fn new_zrangebyscore(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        min: f64,
+        max: f64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Zrangebyscore(Zrangebyscore {
+                table: table.into(),
+                key: key.into(),
+                min,
+                max,
+            })),
+        }
+    }
+
+    // Read the part of the JSON document at path, "$" for all of it
+    pub fn new_json_get(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        path: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::JsonGet(JsonGet {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+            })),
+        }
+    }
+
+    pub fn new_json_set(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        path: impl Into<String>,
+        value: &serde_json::Value,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::JsonSet(JsonSet {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+                value: value.to_string(),
+            })),
+        }
+    }
+
+    pub fn new_json_append(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        path: impl Into<String>,
+        values: &[serde_json::Value],
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::JsonAppend(JsonAppend {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+                values: values.iter().map(|v| v.to_string()).collect(),
+            })),
+        }
+    }
+
+    pub fn new_json_incr(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        path: impl Into<String>,
+        by: Value,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::JsonIncr(JsonIncr {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+                by: Some(by),
+            })),
+        }
+    }
+
+    // Index the values of the table, or the part of their JSON documents at path when it's set
+    pub fn new_create_index(
+        table: impl Into<String>,
+        name: impl Into<String>,
+        path: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::CreateIndex(CreateIndex {
+                table: table.into(),
+                name: name.into(),
+                path: path.into(),
+            })),
+        }
+    }
+
+    pub fn new_drop_index(table: impl Into<String>, name: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::DropIndex(DropIndex {
+                table: table.into(),
+                name: name.into(),
+            })),
+        }
+    }
+
+    // Find the kvpairs whose indexed value is value
+    pub fn new_hfind(table: impl Into<String>, index: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hfind(Hfind {
+                table: table.into(),
+                index: index.into(),
+                value: Some(value),
+                ..Default::default()
+            })),
+        }
+    }
+
+    // Find the kvpairs whose indexed value is between min and max, None for no bound
+    pub fn new_hfind_range(
+        table: impl Into<String>,
+        index: impl Into<String>,
+        min: Option<Value>,
+        max: Option<Value>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hfind(Hfind {
+                table: table.into(),
+                index: index.into(),
+                min,
+                max,
+                ..Default::default()
+            })),
+        }
+    }
+
+    // Back up the store into the backup directory of the server
+    pub fn new_backup(name: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Backup(Backup { name: name.into() })),
+        }
+    }
+
+    // Watch the changes of a table from from_seq, 0 for the changes from now on
+    pub fn new_watch(table: impl Into<String>, from_seq: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.into(),
+                from_seq,
+                ..Default::default()
+            })),
+        }
+    }
+
+    // Watch the changes of the keys with the prefix
+    pub fn new_watch_prefix(
+        table: impl Into<String>,
+        prefix: impl Into<String>,
+        from_seq: u64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.into(),
+                prefix: prefix.into(),
+                from_seq,
+                ..Default::default()
+            })),
+        }
+    }
+
+    // Watch the changes of a key
+    pub fn new_watch_key(table: impl Into<String>, key: impl Into<String>, from_seq: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.into(),
+                key: key.into(),
+                from_seq,
+                ..Default::default()
+            })),
+        }
+    }
+
+    // The table this command operates on
+    pub fn table(&self) -> Option<&str> {
+        match self.request_data.as_ref()? {
+            RequestData::Hget(v) => Some(&v.table),
+            RequestData::Hgetall(v) => Some(&v.table),
+            RequestData::Hmget(v) => Some(&v.table),
+            RequestData::Hset(v) => Some(&v.table),
+            RequestData::Hmset(v) => Some(&v.table),
+            RequestData::Hdel(v) => Some(&v.table),
+            RequestData::Hmdel(v) => Some(&v.table),
+            RequestData::Hexist(v) => Some(&v.table),
+            RequestData::Hmexist(v) => Some(&v.table),
+            RequestData::Replicate(_) => None,
+            RequestData::Watch(v) => Some(&v.table),
+            RequestData::Backup(_) => None,
+            RequestData::ListTables(_) => None,
+            RequestData::Lpush(v) => Some(&v.table),
+            RequestData::Lpop(v) => Some(&v.table),
+            RequestData::Lrange(v) => Some(&v.table),
+            RequestData::Sadd(v) => Some(&v.table),
+            RequestData::Srem(v) => Some(&v.table),
+            RequestData::Smembers(v) => Some(&v.table),
+            RequestData::Zadd(v) => Some(&v.table),
+            RequestData::Zrange(v) => Some(&v.table),
+            RequestData::Zrangebyscore(v) => Some(&v.table),
+            RequestData::JsonGet(v) => Some(&v.table),
+            RequestData::JsonSet(v) => Some(&v.table),
+            RequestData::JsonAppend(v) => Some(&v.table),
+            RequestData::JsonIncr(v) => Some(&v.table),
+            RequestData::CreateIndex(v) => Some(&v.table),
+            RequestData::DropIndex(v) => Some(&v.table),
+            RequestData::Hfind(v) => Some(&v.table),
+        }
+    }
+
+    // Name of the command, the same as its field name in request_data
+    pub fn command(&self) -> Option<&'static str> {
+        let name = match self.request_data.as_ref()? {
+            RequestData::Hget(_) => "hget",
+            RequestData::Hgetall(_) => "hgetall",
+            RequestData::Hmget(_) => "hmget",
+            RequestData::Hset(_) => "hset",
+            RequestData::Hmset(_) => "hmset",
+            RequestData::Hdel(_) => "hdel",
+            RequestData::Hmdel(_) => "hmdel",
+            RequestData::Hexist(_) => "hexist",
+            RequestData::Hmexist(_) => "hmexist",
+            RequestData::Replicate(_) => "replicate",
+            RequestData::Watch(_) => "watch",
+            RequestData::Backup(_) => "backup",
+            RequestData::ListTables(_) => "list_tables",
+            RequestData::Lpush(_) => "lpush",
+            RequestData::Lpop(_) => "lpop",
+            RequestData::Lrange(_) => "lrange",
+            RequestData::Sadd(_) => "sadd",
+            RequestData::Srem(_) => "srem",
+            RequestData::Smembers(_) => "smembers",
+            RequestData::Zadd(_) => "zadd",
+            RequestData::Zrange(_) => "zrange",
+            RequestData::Zrangebyscore(_) => "zrangebyscore",
+            RequestData::JsonGet(_) => "json_get",
+            RequestData::JsonSet(_) => "json_set",
+            RequestData::JsonAppend(_) => "json_append",
+            RequestData::JsonIncr(_) => "json_incr",
+            RequestData::CreateIndex(_) => "create_index",
+            RequestData::DropIndex(_) => "drop_index",
+            RequestData::Hfind(_) => "hfind",
+        };
+        Some(name)
+    }
+
+    // Whether this command modifies the storage
+    pub fn is_mutation(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(
+                RequestData::Hset(_)
+                    | RequestData::Hmset(_)
+                    | RequestData::Hdel(_)
+                    | RequestData::Hmdel(_)
+                    | RequestData::Lpush(_)
+                    | RequestData::Lpop(_)
+                    | RequestData::Sadd(_)
+                    | RequestData::Srem(_)
+                    | RequestData::Zadd(_)
+                    | RequestData::JsonSet(_)
+                    | RequestData::JsonAppend(_)
+                    | RequestData::JsonIncr(_)
+                    | RequestData::CreateIndex(_)
+                    | RequestData::DropIndex(_)
+            )
+        )
+    }
 }
 
 impl Kvpair {
@@ -125,6 +523,39 @@ impl From<i64> for Value {
     }
 }
 
+impl From<f64> for Value {
+    fn from(float: f64) -> Self {
+        Self {
+            value: Some(value::Value::Float(float)),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Self {
+            value: Some(value::Value::Bool(b)),
+        }
+    }
+}
+
+impl Value {
+    // A JSON document, see the Json commands
+    pub fn json(doc: &serde_json::Value) -> Self {
+        Self {
+            value: Some(value::Value::Json(doc.to_string())),
+        }
+    }
+}
+
+impl From<Collection> for Value {
+    fn from(collection: Collection) -> Self {
+        Self {
+            value: Some(value::Value::Collection(collection)),
+        }
+    }
+}
+
 impl TryFrom<&[u8]> for Value {
     type Error = KvError;
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
@@ -157,6 +588,57 @@ impl TryFrom<Value> for Vec<u8> {
     }
 }
 
+// Trans Value to JSON, binary is encoded as {"binary": "<base64>"} and empty value as null
+impl From<Value> for serde_json::Value {
+    fn from(value: Value) -> Self {
+        match value.value {
+            Some(value::Value::String(s)) => s.into(),
+            Some(value::Value::Binary(buf)) => serde_json::json!({ "binary": BASE64.encode(buf) }),
+            Some(value::Value::Integer(i)) => i.into(),
+            Some(value::Value::Float(f)) => f.into(),
+            Some(value::Value::Bool(b)) => b.into(),
+            Some(value::Value::Collection(c)) => {
+                serde_json::json!({ "kind": c.kind, "len": c.len })
+            }
+            // a document is embedded as it is, or as its text when it's not valid
+            Some(value::Value::Json(text)) => {
+                serde_json::from_str(&text).unwrap_or(serde_json::Value::String(text))
+            }
+            None => serde_json::Value::Null,
+        }
+    }
+}
+
+// Trans JSON back to Value, reverse of the conversion above
+impl TryFrom<serde_json::Value> for Value {
+    type Error = KvError;
+
+    fn try_from(json: serde_json::Value) -> Result<Self, Self::Error> {
+        use serde_json::Value as Json;
+
+        match json {
+            Json::String(s) => Ok(s.into()),
+            Json::Bool(b) => Ok(b.into()),
+            Json::Number(n) => match (n.as_i64(), n.as_f64()) {
+                (Some(i), _) => Ok(i.into()),
+                (None, Some(f)) => Ok(f.into()),
+                _ => Err(KvError::InvalidCommand(format!("Unsupported number: {}", n))),
+            },
+            Json::Null => Ok(Value::default()),
+            Json::Object(mut obj) if obj.len() == 1 => match obj.remove("binary") {
+                Some(Json::String(s)) => BASE64
+                    .decode(s)
+                    .map(|buf| Bytes::from(buf).into())
+                    .map_err(|e| KvError::InvalidCommand(format!("Invalid base64: {}", e))),
+                _ => Err(KvError::InvalidCommand(
+                    "Object value must be {\"binary\": \"<base64>\"}".into(),
+                )),
+            },
+            v => Err(KvError::InvalidCommand(format!("Unsupported JSON value: {}", v))),
+        }
+    }
+}
+
 // Trans Value to CommandResponse
 impl From<Value> for CommandResponse {
     fn from(value: Value) -> Self {
@@ -201,7 +683,17 @@ impl From<KvError> for CommandResponse {
 
         match err {
             KvError::NotFound(_, _) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
-            KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::InvalidCommand(_) | KvError::WrongType(..) => {
+                result.status = StatusCode::BAD_REQUEST.as_u16() as _
+            }
+            KvError::PermissionDenied(_) => result.status = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::ReadOnlyReplica(_) | KvError::NotLeader(_) => {
+                result.status = StatusCode::MISDIRECTED_REQUEST.as_u16() as _
+            }
+            KvError::IncompatibleProtocol(_) => {
+                result.status = StatusCode::UPGRADE_REQUIRED.as_u16() as _
+            }
+            KvError::SeqCompacted(_, _) => result.status = StatusCode::GONE.as_u16() as _,
             _ => {}
         }
 
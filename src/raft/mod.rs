@@ -0,0 +1,1414 @@
+// Raft consensus for the cluster mode. Every mutating CommandRequest is appended to a replicated
+// log, and applied to the Storage of each node once a majority of the members have it. The term
+// and the vote are saved to a file, so that a restarted node doesn't vote twice in a term. Like
+// MemTable, the log lives in memory: a node restarted with its state file but without the log
+// neither votes nor campaigns until it has caught up with the commit of a leader, so that it
+// can't help elect a leader which lacks the entries it had. A cluster restarted as a whole has
+// lost its data, and is bootstrapped again without the state files.
+
+mod transport;
+
+pub use transport::{MemoryNetwork, RaftTransport};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+use crate::raft_entry::Payload;
+use crate::raft_message::Body;
+use crate::*;
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// Unique id of the node in the cluster
+    pub id: u64,
+    /// Address the other nodes send RaftMessage to
+    pub addr: String,
+    pub tick_interval: Duration,
+    /// A follower without a leader starts an election after 1-2 times this many ticks
+    pub election_ticks: u64,
+    /// The leader sends heartbeats every this many ticks
+    pub heartbeat_ticks: u64,
+    /// Compact the log into a snapshot of the storage every this many applied entries
+    pub snapshot_threshold: u64,
+    /// Most entries sent in one AppendEntries
+    pub max_append_entries: usize,
+    /// Most kvpairs sent in one chunk of a snapshot
+    pub max_snapshot_pairs: usize,
+    /// A follower forwards the writes to the leader, or refuses them with NotLeader
+    pub forward_writes: bool,
+    /// How long to wait for a write to be committed
+    pub propose_timeout: Duration,
+    /// File the term and the vote are saved in before any message of the term is sent
+    pub state_file: Option<PathBuf>,
+}
+
+impl RaftConfig {
+    pub fn new(id: u64, addr: impl Into<String>) -> Self {
+        Self {
+            id,
+            addr: addr.into(),
+            tick_interval: Duration::from_millis(100),
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            snapshot_threshold: 10000,
+            max_append_entries: 256,
+            max_snapshot_pairs: 1024,
+            forward_writes: true,
+            propose_timeout: Duration::from_secs(5),
+            state_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// What a node knows about the cluster at the moment
+#[derive(Debug, Clone, PartialEq)]
+pub struct RaftStatus {
+    pub id: u64,
+    pub role: RaftRole,
+    pub term: u64,
+    pub leader: Option<u64>,
+    pub commit_index: u64,
+    pub applied_index: u64,
+    /// Entries up to this index are compacted into the snapshot
+    pub snapshot_index: u64,
+    pub members: Vec<RaftPeer>,
+}
+
+/// A member of the raft cluster, which orders the writes to its Service
+pub struct RaftNode<Store = MemTable> {
+    inner: Arc<RaftInner<Store>>,
+}
+
+struct RaftInner<Store> {
+    config: RaftConfig,
+    service: Service<Store>,
+    transport: Box<dyn RaftTransport>,
+    state: Mutex<RaftState>,
+    // held while the service is changed by the committed entries or a snapshot, or while it's
+    // read into a snapshot, never taken with the state locked
+    apply: Mutex<()>,
+    compacting: AtomicBool,
+    // writes forwarded to the leader, waiting for the response
+    forwards: Mutex<HashMap<u64, oneshot::Sender<CommandResponse>>>,
+    next_forward: AtomicU64,
+}
+
+struct RaftState {
+    role: RaftRole,
+    term: u64,
+    voted_for: Option<u64>,
+    leader: Option<u64>,
+    // restarted without the log it had, it doesn't vote until it catches up with a leader
+    recovering: bool,
+    // entries after the snapshot, log[0] has the index snapshot.last_index + 1
+    log: Vec<RaftEntry>,
+    snapshot: InstallSnapshot,
+    // chunks of a snapshot received from the leader, and whether it's being installed
+    incoming: Option<InstallSnapshot>,
+    installing: bool,
+    // kvpairs of the snapshot each follower has received
+    snapshot_offset: HashMap<u64, usize>,
+    commit_index: u64,
+    applied_index: u64,
+    // the latest membership in the log, and the index of its entry
+    members: BTreeMap<u64, String>,
+    members_index: u64,
+    votes: HashSet<u64>,
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    // ticks since the last heartbeat from the leader, or sent by the leader
+    elapsed: u64,
+    election_timeout: u64,
+    rng: u64,
+    // proposals waiting to be applied: index -> (term, response)
+    waiters: HashMap<u64, (u64, oneshot::Sender<CommandResponse>)>,
+}
+
+// Messages to send once the state is unlocked
+type Outbox = Vec<(String, RaftMessage)>;
+
+impl<Store> Clone for RaftNode<Store> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<Store: Storage + Send + Sync + 'static> RaftNode<Store> {
+    /// Start the node, which receives the messages of the other nodes from inbox. Nodes
+    /// bootstrapping a cluster start with the same peers, a node joining later starts with none
+    /// and is added by RaftNode::add_member on the leader
+    pub fn start(
+        config: RaftConfig,
+        peers: Vec<RaftPeer>,
+        service: Service<Store>,
+        transport: impl RaftTransport,
+        mut inbox: mpsc::UnboundedReceiver<RaftMessage>,
+    ) -> Result<Self, KvError> {
+        let (term, voted_for) = match &config.state_file {
+            Some(path) => load_vote(path)?,
+            None => (0, None),
+        };
+        // writes must come through the node from now on
+        service.set_clustered();
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut state = RaftState {
+            role: RaftRole::Follower,
+            term,
+            voted_for,
+            leader: None,
+            recovering: term > 0,
+            log: Vec::new(),
+            snapshot: InstallSnapshot {
+                membership: Some(RaftMembership { peers }),
+                ..Default::default()
+            },
+            incoming: None,
+            installing: false,
+            snapshot_offset: HashMap::new(),
+            commit_index: 0,
+            applied_index: 0,
+            members: BTreeMap::new(),
+            members_index: 0,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            elapsed: 0,
+            election_timeout: 0,
+            rng: (now.as_nanos() as u64 ^ config.id.wrapping_mul(0x9e3779b97f4a7c15)) | 1,
+            waiters: HashMap::new(),
+        };
+        state.reload_members();
+        state.reset_election_timeout(config.election_ticks);
+
+        let interval = config.tick_interval;
+        let node = Self {
+            inner: Arc::new(RaftInner {
+                config,
+                service,
+                transport: Box::new(transport),
+                state: Mutex::new(state),
+                apply: Mutex::new(()),
+                compacting: AtomicBool::new(false),
+                forwards: Mutex::new(HashMap::new()),
+                next_forward: AtomicU64::new(0),
+            }),
+        };
+
+        // the tasks stop once the node is dropped
+        let weak = Arc::downgrade(&node.inner);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match weak.upgrade() {
+                    Some(inner) => Self { inner }.tick(),
+                    None => break,
+                }
+            }
+        });
+        let weak = Arc::downgrade(&node.inner);
+        tokio::spawn(async move {
+            while let Some(msg) = inbox.recv().await {
+                match weak.upgrade() {
+                    Some(inner) => Self { inner }.step(msg),
+                    None => break,
+                }
+            }
+        });
+
+        Ok(node)
+    }
+
+    pub fn service(&self) -> &Service<Store> {
+        &self.inner.service
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        let st = self.inner.state.lock().unwrap();
+        RaftStatus {
+            id: self.inner.config.id,
+            role: st.role,
+            term: st.term,
+            leader: st.leader,
+            commit_index: st.commit_index,
+            applied_index: st.applied_index,
+            snapshot_index: st.snapshot.last_index,
+            members: st.peers(),
+        }
+    }
+
+    /// Execute the command from a client. Reads are served by the local storage, writes are
+    /// committed through the leader before they are applied
+    pub async fn execute(&self, cmd: CommandRequest, ctx: &ConnectionContext) -> CommandResponse {
+        let service = &self.inner.service;
+        if !cmd.is_mutation() {
+            return service.execute(cmd, ctx);
+        }
+
+        let res = match service.admit(&cmd, ctx) {
+            Ok(()) => self.write(cmd).await.unwrap_or_else(Into::into),
+            Err(e) => e.into(),
+        };
+        service.respond(res, ctx)
+    }
+
+    /// Add a node to the cluster, only the leader accepts membership changes
+    pub async fn add_member(&self, id: u64, addr: impl Into<String>) -> Result<(), KvError> {
+        let addr = addr.into();
+        self.change_members(move |members| {
+            members.insert(id, addr);
+        })
+        .await
+    }
+
+    /// Remove a node from the cluster. A leader removing itself steps down once it's committed
+    pub async fn remove_member(&self, id: u64) -> Result<(), KvError> {
+        self.change_members(move |members| {
+            members.remove(&id);
+        })
+        .await
+    }
+
+    async fn change_members(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<u64, String>),
+    ) -> Result<(), KvError> {
+        let res = self
+            .propose(|st| {
+                // one change at a time, so that any majority of the old and the new members overlap
+                if st.members_index > st.commit_index {
+                    let msg = "another membership change is in progress".into();
+                    return Err(KvError::RaftError(msg));
+                }
+                let mut members = st.members.clone();
+                f(&mut members);
+                let peers = members
+                    .into_iter()
+                    .map(|(id, addr)| RaftPeer { id, addr })
+                    .collect();
+                Ok(Payload::Membership(RaftMembership { peers }))
+            })
+            .await?;
+
+        match res.status == http::StatusCode::OK.as_u16() as u32 {
+            true => Ok(()),
+            false => Err(KvError::RaftError(res.message)),
+        }
+    }
+
+    async fn write(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let payload = Payload::Command(cmd.clone());
+        match self.propose(move |_| Ok(payload)).await {
+            Err(KvError::NotLeader(_)) if self.inner.config.forward_writes => self.forward(cmd).await,
+            result => result,
+        }
+    }
+
+    // Append an entry on the leader, and wait until it's applied
+    async fn propose(
+        &self,
+        f: impl FnOnce(&RaftState) -> Result<Payload, KvError>,
+    ) -> Result<CommandResponse, KvError> {
+        let (tx, rx) = oneshot::channel();
+        let mut out = Outbox::new();
+        {
+            let mut st = self.inner.state.lock().unwrap();
+            if st.role != RaftRole::Leader {
+                return Err(KvError::NotLeader(st.leader_addr()));
+            }
+            let payload = f(&st)?;
+            let index = self.append(&mut st, Some(payload));
+            let term = st.term;
+            st.waiters.insert(index, (term, tx));
+            self.broadcast_append(&mut st, &mut out);
+            // a single node cluster commits right away
+            self.maybe_commit(&mut st);
+        }
+        self.send_all(out);
+        self.apply_committed();
+
+        match tokio::time::timeout(self.inner.config.propose_timeout, rx).await {
+            Ok(Ok(res)) => Ok(res),
+            Ok(Err(_)) => Err(KvError::RaftError("the write is dropped".into())),
+            Err(_) => Err(KvError::RaftError("timeout waiting for the write to commit".into())),
+        }
+    }
+
+    // Send the write to the leader, which proposes it for us
+    async fn forward(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let (leader, msg_term) = {
+            let st = self.inner.state.lock().unwrap();
+            let leader = st.leader.and_then(|id| st.members.get(&id).cloned());
+            (leader, st.term)
+        };
+        let leader = leader.ok_or_else(|| KvError::NotLeader("unknown".into()))?;
+
+        let id = self.inner.next_forward.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.inner.forwards.lock().unwrap().insert(id, tx);
+        let body = Body::Forward(ForwardRequest {
+            id,
+            command: Some(cmd),
+        });
+        self.inner.transport.send(&leader, self.message(msg_term, body));
+
+        let result = tokio::time::timeout(self.inner.config.propose_timeout, rx).await;
+        self.inner.forwards.lock().unwrap().remove(&id);
+        match result {
+            Ok(Ok(res)) => Ok(res),
+            _ => Err(KvError::RaftError(format!("no response from the leader {}", leader))),
+        }
+    }
+
+    fn tick(&self) {
+        let mut out = Outbox::new();
+        {
+            let mut st = self.inner.state.lock().unwrap();
+            let vote = (st.term, st.voted_for);
+            st.elapsed += 1;
+            match st.role {
+                RaftRole::Leader if st.elapsed >= self.inner.config.heartbeat_ticks => {
+                    st.elapsed = 0;
+                    self.broadcast_append(&mut st, &mut out);
+                }
+                RaftRole::Leader => {}
+                // only a member with the log it had could be elected
+                _ if st.elapsed >= st.election_timeout
+                    && st.members.contains_key(&self.inner.config.id)
+                    && !st.recovering =>
+                {
+                    self.campaign(&mut st, &mut out);
+                }
+                _ => {}
+            }
+            self.save_vote(&st, vote, &mut out);
+        }
+        self.send_all(out);
+        // entries left by an apply that was running
+        self.apply_committed();
+    }
+
+    /// Handle a message from another node
+    pub fn step(&self, msg: RaftMessage) {
+        let mut out = Outbox::new();
+        let mut install = None;
+        {
+            let mut st = self.inner.state.lock().unwrap();
+            let vote = (st.term, st.voted_for);
+            if msg.term > st.term {
+                st.become_follower(msg.term, None);
+            }
+            let (from, from_addr, term) = (msg.from, msg.from_addr, msg.term);
+            let reply = |st: &RaftState, out: &mut Outbox, body| {
+                out.push((from_addr.clone(), self.message(st.term, body)));
+            };
+
+            match msg.body {
+                Some(Body::Vote(req)) => {
+                    let up_to_date =
+                        (req.last_log_term, req.last_log_index) >= (st.last_term(), st.last_index());
+                    let granted = term == st.term
+                        && (st.voted_for.is_none() || st.voted_for == Some(from))
+                        && up_to_date
+                        && !st.recovering;
+                    if granted {
+                        st.voted_for = Some(from);
+                        st.elapsed = 0;
+                    }
+                    reply(&st, &mut out, Body::VoteResponse(VoteResponse { granted }));
+                }
+                Some(Body::VoteResponse(res)) => {
+                    if st.role == RaftRole::Candidate && term == st.term && res.granted {
+                        st.votes.insert(from);
+                        if st.has_quorum(&st.votes) {
+                            self.become_leader(&mut st, &mut out);
+                        }
+                    }
+                }
+                Some(Body::Append(req)) => {
+                    let (success, match_index) = self.handle_append(&mut st, from, term, req);
+                    let body = Body::AppendResponse(AppendResponse {
+                        success,
+                        match_index,
+                    });
+                    reply(&st, &mut out, body);
+                }
+                Some(Body::AppendResponse(res)) => {
+                    if st.role == RaftRole::Leader && term == st.term {
+                        self.handle_append_response(&mut st, from, res, &mut out);
+                    }
+                }
+                Some(Body::Snapshot(chunk)) => {
+                    let mut snapshot = None;
+                    let res = self.handle_snapshot(&mut st, from, term, chunk, &mut snapshot);
+                    if let Some(res) = res {
+                        reply(&st, &mut out, Body::SnapshotResponse(res));
+                    }
+                    install = snapshot.map(|v| (from_addr.clone(), v));
+                }
+                Some(Body::SnapshotResponse(res)) => {
+                    if st.role == RaftRole::Leader && term == st.term {
+                        self.handle_snapshot_response(&mut st, from, res, &mut out);
+                    }
+                }
+                Some(Body::Forward(req)) => {
+                    let node = self.clone();
+                    tokio::spawn(async move {
+                        let payload = Payload::Command(req.command.unwrap_or_default());
+                        let res = match node.propose(move |_| Ok(payload)).await {
+                            Ok(res) => res,
+                            Err(e) => e.into(),
+                        };
+                        let term = node.inner.state.lock().unwrap().term;
+                        let body = Body::ForwardResponse(ForwardResponse {
+                            id: req.id,
+                            response: Some(res),
+                        });
+                        node.inner.transport.send(&from_addr, node.message(term, body));
+                    });
+                }
+                Some(Body::ForwardResponse(res)) => {
+                    if let Some(tx) = self.inner.forwards.lock().unwrap().remove(&res.id) {
+                        let _ = tx.send(res.response.unwrap_or_default());
+                    }
+                }
+                None => warn!("Got a raft message without body from {}", from),
+            }
+            self.save_vote(&st, vote, &mut out);
+        }
+        self.send_all(out);
+
+        // the service is restored from the snapshot without blocking the messages
+        if let Some((addr, snapshot)) = install {
+            let node = self.clone();
+            tokio::task::spawn_blocking(move || node.install(snapshot, addr));
+        }
+        self.apply_committed();
+    }
+
+    // Save the term and the vote if they are changed, before the messages of the term are sent.
+    // The messages are dropped if it fails, the others retry them
+    fn save_vote(&self, st: &RaftState, vote: (u64, Option<u64>), out: &mut Outbox) {
+        let Some(path) = &self.inner.config.state_file else {
+            return;
+        };
+        if (st.term, st.voted_for) == vote {
+            return;
+        }
+        if let Err(e) = save_vote(path, st.term, st.voted_for) {
+            warn!("Failed to save the term and the vote: {:?}", e);
+            out.clear();
+        }
+    }
+
+    fn campaign(&self, st: &mut RaftState, out: &mut Outbox) {
+        let id = self.inner.config.id;
+        st.role = RaftRole::Candidate;
+        st.term += 1;
+        st.voted_for = Some(id);
+        st.leader = None;
+        st.votes = HashSet::from([id]);
+        st.elapsed = 0;
+        st.reset_election_timeout(self.inner.config.election_ticks);
+        info!("Node {} starts an election for term {}", id, st.term);
+
+        if st.has_quorum(&st.votes) {
+            return self.become_leader(st, out);
+        }
+        let body = Body::Vote(VoteRequest {
+            last_log_index: st.last_index(),
+            last_log_term: st.last_term(),
+        });
+        for (_, addr) in st.members.iter().filter(|(v, _)| **v != id) {
+            out.push((addr.clone(), self.message(st.term, body.clone())));
+        }
+    }
+
+    fn become_leader(&self, st: &mut RaftState, out: &mut Outbox) {
+        info!("Node {} becomes the leader of term {}", self.inner.config.id, st.term);
+        st.role = RaftRole::Leader;
+        st.leader = Some(self.inner.config.id);
+        st.elapsed = 0;
+        st.next_index.clear();
+        st.match_index.clear();
+        // entries of the previous terms are committed along with this one
+        self.append(st, None);
+        self.broadcast_append(st, out);
+        self.maybe_commit(st);
+    }
+
+    fn append(&self, st: &mut RaftState, payload: Option<Payload>) -> u64 {
+        let index = st.last_index() + 1;
+        if let Some(Payload::Membership(membership)) = &payload {
+            st.set_members(index, membership);
+        }
+        st.log.push(RaftEntry {
+            term: st.term,
+            index,
+            payload,
+        });
+        index
+    }
+
+    fn broadcast_append(&self, st: &mut RaftState, out: &mut Outbox) {
+        let peers: Vec<_> = st
+            .members
+            .iter()
+            .filter(|(id, _)| **id != self.inner.config.id)
+            .map(|(id, addr)| (*id, addr.clone()))
+            .collect();
+        for (id, addr) in peers {
+            self.send_append(st, id, addr, out);
+        }
+    }
+
+    fn send_append(&self, st: &mut RaftState, id: u64, addr: String, out: &mut Outbox) {
+        let last_index = st.last_index();
+        let next = *st.next_index.entry(id).or_insert(last_index + 1);
+        // the entries it needs are compacted, send the snapshot from the chunk it's waiting for
+        if next <= st.snapshot.last_index {
+            let snapshot = &st.snapshot;
+            let len = snapshot.pairs.len();
+            let offset = st.snapshot_offset.get(&id).copied().unwrap_or_default().min(len);
+            let end = (offset + self.inner.config.max_snapshot_pairs.max(1)).min(len);
+            let chunk = InstallSnapshot {
+                last_index: snapshot.last_index,
+                last_term: snapshot.last_term,
+                membership: snapshot.membership.clone(),
+                pairs: snapshot.pairs[offset..end].to_vec(),
+                offset: offset as u64,
+                done: end == len,
+            };
+            out.push((addr, self.message(st.term, Body::Snapshot(chunk))));
+            return;
+        }
+
+        let prev_log_index = next - 1;
+        let start = (next - st.snapshot.last_index - 1) as usize;
+        let entries = st.log[start..]
+            .iter()
+            .take(self.inner.config.max_append_entries)
+            .cloned()
+            .collect();
+        let body = Body::Append(AppendEntries {
+            prev_log_index,
+            prev_log_term: st.term_at(prev_log_index).unwrap_or_default(),
+            entries,
+            leader_commit: st.commit_index,
+        });
+        out.push((addr, self.message(st.term, body)));
+    }
+
+    // Returns whether the entries are appended, and the index matched or to retry from
+    fn handle_append(
+        &self,
+        st: &mut RaftState,
+        from: u64,
+        term: u64,
+        req: AppendEntries,
+    ) -> (bool, u64) {
+        if term < st.term {
+            return (false, 0);
+        }
+        st.become_follower(term, Some(from));
+
+        let prev = req.prev_log_index;
+        let end = prev + req.entries.len() as u64;
+        let mut entries = req.entries;
+        // the entries in the snapshot are committed, they must be the same as the leader's
+        if prev < st.snapshot.last_index {
+            let skip = (st.snapshot.last_index - prev) as usize;
+            if skip >= entries.len() {
+                return (true, end);
+            }
+            entries.drain(..skip);
+        } else {
+            match st.term_at(prev) {
+                None => return (false, st.last_index()),
+                Some(v) if v != req.prev_log_term => return (false, prev - 1),
+                _ => {}
+            }
+        }
+
+        for entry in entries {
+            match st.term_at(entry.index) {
+                Some(v) if v == entry.term => continue,
+                Some(_) => st.truncate(entry.index),
+                None => {}
+            }
+            if let Some(Payload::Membership(membership)) = &entry.payload {
+                st.set_members(entry.index, membership);
+            }
+            st.log.push(entry);
+        }
+
+        if req.leader_commit > st.commit_index {
+            // a stale request can't take back what's committed
+            st.commit_index = req.leader_commit.min(end).max(st.commit_index);
+        }
+        if st.recovering && end >= req.leader_commit {
+            info!("Node {} has caught up with the leader", self.inner.config.id);
+            st.recovering = false;
+        }
+        (true, end)
+    }
+
+    fn handle_append_response(
+        &self,
+        st: &mut RaftState,
+        from: u64,
+        res: AppendResponse,
+        out: &mut Outbox,
+    ) {
+        let Some(addr) = st.members.get(&from).cloned() else {
+            return;
+        };
+        if res.success {
+            let matched = st.match_index.entry(from).or_default();
+            *matched = res.match_index.max(*matched);
+            let next = *matched + 1;
+            st.next_index.insert(from, next);
+            self.maybe_commit(st);
+            // keep sending until it catches up
+            if next <= st.last_index() {
+                self.send_append(st, from, addr, out);
+            }
+        } else {
+            // a follower restarted without its log has less than it had acknowledged
+            if let Some(matched) = st.match_index.get_mut(&from) {
+                *matched = res.match_index.min(*matched);
+            }
+            let next = st.next_index.get(&from).copied().unwrap_or(1);
+            let next = (next - 1).min(res.match_index + 1).max(1);
+            st.next_index.insert(from, next);
+            self.send_append(st, from, addr, out);
+        }
+    }
+
+    // The next chunk of the snapshot to send, or the whole snapshot again after a failed install
+    fn handle_snapshot_response(
+        &self,
+        st: &mut RaftState,
+        from: u64,
+        res: SnapshotResponse,
+        out: &mut Outbox,
+    ) {
+        let Some(addr) = st.members.get(&from).cloned() else {
+            return;
+        };
+        if res.last_index > 0 {
+            st.snapshot_offset.remove(&from);
+            let matched = st.match_index.entry(from).or_default();
+            *matched = res.last_index.max(*matched);
+            let next = *matched + 1;
+            st.next_index.insert(from, next);
+            self.maybe_commit(st);
+            if next <= st.last_index() {
+                self.send_append(st, from, addr, out);
+            }
+        } else if res.snapshot_index == st.snapshot.last_index {
+            let received = res.received as usize;
+            let offset = st.snapshot_offset.insert(from, received).unwrap_or_default();
+            // a response to a chunk sent again by a heartbeat is a duplicate
+            if received != offset {
+                self.send_append(st, from, addr, out);
+            }
+        }
+    }
+
+    // Collect the chunks of a snapshot, the response tells the leader which chunk to send next.
+    // Once the last one is received, the snapshot is left in install and the response is sent
+    // after it's installed
+    fn handle_snapshot(
+        &self,
+        st: &mut RaftState,
+        from: u64,
+        term: u64,
+        chunk: InstallSnapshot,
+        install: &mut Option<InstallSnapshot>,
+    ) -> Option<SnapshotResponse> {
+        if term < st.term {
+            return Some(SnapshotResponse::default());
+        }
+        st.become_follower(term, Some(from));
+        let last_index = chunk.last_index;
+        if last_index <= st.commit_index {
+            return Some(SnapshotResponse {
+                last_index,
+                snapshot_index: last_index,
+                received: 0,
+            });
+        }
+        if st.installing {
+            return None;
+        }
+
+        let mut snapshot = match st.incoming.take() {
+            Some(v) if (v.last_index, v.last_term) == (last_index, chunk.last_term) => v,
+            _ => InstallSnapshot {
+                last_index,
+                last_term: chunk.last_term,
+                membership: chunk.membership,
+                ..Default::default()
+            },
+        };
+        // a chunk out of order is dropped, the leader goes on from what's received
+        if chunk.offset == snapshot.pairs.len() as u64 {
+            snapshot.pairs.extend(chunk.pairs);
+            if chunk.done {
+                st.installing = true;
+                *install = Some(snapshot);
+                return None;
+            }
+        }
+        let received = snapshot.pairs.len() as u64;
+        st.incoming = Some(snapshot);
+        Some(SnapshotResponse {
+            last_index: 0,
+            snapshot_index: last_index,
+            received,
+        })
+    }
+
+    // Restore the service from the snapshot received from the leader. The indexes only move once
+    // it's restored, a failed one is rejected and the leader sends it again from the first chunk
+    fn install(&self, snapshot: InstallSnapshot, addr: String) {
+        let _apply = self.inner.apply.lock().unwrap();
+        let last_index = snapshot.last_index;
+        let applied = self.inner.state.lock().unwrap().applied_index;
+        let restored = match last_index > applied {
+            true => Some(self.restore(&snapshot)),
+            false => None,
+        };
+
+        let mut st = self.inner.state.lock().unwrap();
+        st.installing = false;
+        let mut res = SnapshotResponse {
+            last_index,
+            snapshot_index: last_index,
+            received: snapshot.pairs.len() as u64,
+        };
+        match restored {
+            Some(Ok(())) => {
+                info!(
+                    "Node {} installs the snapshot at index {}",
+                    self.inner.config.id, last_index
+                );
+                // keep the entries after the snapshot if the log agrees with it
+                match st.term_at(last_index) {
+                    Some(v) if v == snapshot.last_term => {
+                        let n = (last_index - st.snapshot.last_index) as usize;
+                        st.log.drain(..n);
+                    }
+                    _ => st.log.clear(),
+                }
+                st.waiters.retain(|index, _| *index > last_index);
+                st.snapshot = snapshot;
+                st.commit_index = st.commit_index.max(last_index);
+                st.applied_index = last_index;
+                st.reload_members();
+            }
+            Some(Err(e)) => {
+                warn!("Failed to install the snapshot at index {}: {:?}", last_index, e);
+                res = SnapshotResponse {
+                    snapshot_index: last_index,
+                    ..Default::default()
+                };
+            }
+            None => {}
+        }
+        let msg = self.message(st.term, Body::SnapshotResponse(res));
+        drop(st);
+        self.inner.transport.send(&addr, msg);
+    }
+
+    fn restore(&self, snapshot: &InstallSnapshot) -> Result<(), KvError> {
+        let service = &self.inner.service;
+        service.clear()?;
+        for hset in snapshot.pairs.iter().cloned() {
+            service.apply(CommandRequest {
+                request_data: Some(command_request::RequestData::Hset(hset)),
+            })?;
+        }
+        Ok(())
+    }
+
+    // Commit the entries a majority of the members have, only the ones of the current term are
+    // counted, the previous ones are committed along with them
+    fn maybe_commit(&self, st: &mut RaftState) {
+        let id = self.inner.config.id;
+        let mut matched: Vec<u64> = st
+            .members
+            .keys()
+            .map(|v| match *v == id {
+                true => st.last_index(),
+                false => st.match_index.get(v).copied().unwrap_or_default(),
+            })
+            .collect();
+        if matched.is_empty() {
+            return;
+        }
+        matched.sort_unstable();
+        let index = matched[matched.len() - (matched.len() / 2 + 1)];
+        if index > st.commit_index && st.term_at(index) == Some(st.term) {
+            st.commit_index = index;
+        }
+    }
+
+    // Apply the committed entries without the state lock, so that a slow write doesn't hold up
+    // the messages. The entries are left to the thread already applying, or the next tick
+    fn apply_committed(&self) {
+        let Ok(_apply) = self.inner.apply.try_lock() else {
+            return;
+        };
+        loop {
+            let entries: Vec<RaftEntry> = {
+                let st = self.inner.state.lock().unwrap();
+                let start = (st.applied_index - st.snapshot.last_index) as usize;
+                let end = (st.commit_index - st.snapshot.last_index) as usize;
+                let n = self.inner.config.max_append_entries.max(1);
+                st.log[start..end].iter().take(n).cloned().collect()
+            };
+            if entries.is_empty() {
+                break;
+            }
+            let applied: Vec<_> = entries
+                .into_iter()
+                .map(|entry| {
+                    let res = match entry.payload {
+                        Some(Payload::Command(cmd)) => self.inner.service.dispatch_mutation(cmd),
+                        _ => Value::default().into(),
+                    };
+                    (entry.index, entry.term, res)
+                })
+                .collect();
+
+            let mut st = self.inner.state.lock().unwrap();
+            for (index, entry_term, res) in applied {
+                st.applied_index = index;
+                if let Some((term, tx)) = st.waiters.remove(&index) {
+                    let res = match term == entry_term {
+                        true => res,
+                        false => lost_write(),
+                    };
+                    let _ = tx.send(res);
+                }
+            }
+
+            let id = self.inner.config.id;
+            if st.role == RaftRole::Leader
+                && !st.members.contains_key(&id)
+                && st.members_index <= st.applied_index
+            {
+                info!("Node {} is removed from the cluster, step down", id);
+                let term = st.term;
+                st.become_follower(term, None);
+            }
+        }
+        self.maybe_compact();
+    }
+
+    // Compact the log once enough entries are applied. The service is read into the snapshot on
+    // a blocking thread, which holds the apply lock so that it's the service at applied_index
+    fn maybe_compact(&self) {
+        {
+            let st = self.inner.state.lock().unwrap();
+            if st.applied_index - st.snapshot.last_index < self.inner.config.snapshot_threshold {
+                return;
+            }
+        }
+        if self.inner.compacting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let node = self.clone();
+        tokio::task::spawn_blocking(move || {
+            node.compact();
+            node.inner.compacting.store(false, Ordering::SeqCst);
+        });
+    }
+
+    fn compact(&self) {
+        let _apply = self.inner.apply.lock().unwrap();
+        let last_index = {
+            let st = self.inner.state.lock().unwrap();
+            if st.applied_index <= st.snapshot.last_index {
+                return;
+            }
+            st.applied_index
+        };
+        let pairs = match self.inner.service.snapshot() {
+            Ok(pairs) => pairs,
+            Err(e) => {
+                warn!("Failed to take a snapshot: {:?}", e);
+                return;
+            }
+        };
+
+        let mut st = self.inner.state.lock().unwrap();
+        let membership = st.membership_at(last_index);
+        let last_term = st.term_at(last_index).unwrap_or_default();
+        let n = (last_index - st.snapshot.last_index) as usize;
+        st.log.drain(..n);
+        st.snapshot = InstallSnapshot {
+            last_index,
+            last_term,
+            membership: Some(membership),
+            pairs,
+            ..Default::default()
+        };
+        // the chunks sent so far are of the old snapshot
+        st.snapshot_offset.clear();
+    }
+
+    fn message(&self, term: u64, body: Body) -> RaftMessage {
+        RaftMessage {
+            from: self.inner.config.id,
+            from_addr: self.inner.config.addr.clone(),
+            term,
+            body: Some(body),
+        }
+    }
+
+    fn send_all(&self, out: Outbox) {
+        for (addr, msg) in out {
+            self.inner.transport.send(&addr, msg);
+        }
+    }
+}
+
+impl RaftState {
+    fn last_index(&self) -> u64 {
+        self.snapshot.last_index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.last_term, |v| v.term)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index.checked_sub(self.snapshot.last_index) {
+            Some(0) => Some(self.snapshot.last_term),
+            Some(i) => self.log.get(i as usize - 1).map(|v| v.term),
+            None => None,
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<u64>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = RaftRole::Follower;
+        self.leader = leader;
+        self.elapsed = 0;
+    }
+
+    fn has_quorum(&self, votes: &HashSet<u64>) -> bool {
+        let granted = self.members.keys().filter(|v| votes.contains(v)).count();
+        granted * 2 > self.members.len()
+    }
+
+    fn reset_election_timeout(&mut self, election_ticks: u64) {
+        // xorshift, good enough to spread the elections of the nodes
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.election_timeout = election_ticks + self.rng % election_ticks.max(1);
+    }
+
+    // Drop the entries from index on, which conflict with the leader
+    fn truncate(&mut self, index: u64) {
+        self.log.truncate((index - self.snapshot.last_index - 1) as usize);
+        for index in self.waiters.keys().copied().filter(|v| *v >= index).collect::<Vec<_>>() {
+            if let Some((_, tx)) = self.waiters.remove(&index) {
+                let _ = tx.send(lost_write());
+            }
+        }
+        if self.members_index >= index {
+            self.reload_members();
+        }
+    }
+
+    fn set_members(&mut self, index: u64, membership: &RaftMembership) {
+        self.members = membership
+            .peers
+            .iter()
+            .map(|v| (v.id, v.addr.clone()))
+            .collect();
+        self.members_index = index;
+        self.next_index.retain(|id, _| membership.peers.iter().any(|v| v.id == *id));
+        self.match_index.retain(|id, _| membership.peers.iter().any(|v| v.id == *id));
+    }
+
+    // The latest membership in the log, or the one of the snapshot
+    fn reload_members(&mut self) {
+        let index = self.last_index();
+        let membership = self.membership_at(index);
+        let members_index = self
+            .log
+            .iter()
+            .rev()
+            .find(|v| matches!(v.payload, Some(Payload::Membership(_))))
+            .map_or(self.snapshot.last_index, |v| v.index);
+        self.set_members(members_index, &membership);
+    }
+
+    fn membership_at(&self, index: u64) -> RaftMembership {
+        self.log
+            .iter()
+            .rev()
+            .filter(|v| v.index <= index)
+            .find_map(|v| match &v.payload {
+                Some(Payload::Membership(membership)) => Some(membership.clone()),
+                _ => None,
+            })
+            .or_else(|| self.snapshot.membership.clone())
+            .unwrap_or_default()
+    }
+
+    fn peers(&self) -> Vec<RaftPeer> {
+        self.members
+            .iter()
+            .map(|(id, addr)| RaftPeer {
+                id: *id,
+                addr: addr.clone(),
+            })
+            .collect()
+    }
+
+    fn leader_addr(&self) -> String {
+        self.leader
+            .and_then(|id| self.members.get(&id).cloned())
+            .unwrap_or_else(|| "unknown".into())
+    }
+}
+
+fn lost_write() -> CommandResponse {
+    KvError::RaftError("the write is lost in a leader change".into()).into()
+}
+
+// The term and the vote as "term vote", the vote is "-" when there is none. It's written to
+// another file and renamed, so that a crash leaves the old one or the new one
+fn save_vote(path: &Path, term: u64, voted_for: Option<u64>) -> Result<(), KvError> {
+    let vote = voted_for.map_or_else(|| "-".to_string(), |v| v.to_string());
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(format!("{} {}\n", term, vote).as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn load_vote(path: &Path) -> Result<(u64, Option<u64>), KvError> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, None)),
+        Err(e) => return Err(e.into()),
+    };
+    let invalid = || KvError::RaftError(format!("invalid raft state in {}", path.display()));
+    let mut parts = content.split_whitespace();
+    let term = parts.next().and_then(|v| v.parse().ok()).ok_or_else(invalid)?;
+    let voted_for = match parts.next() {
+        Some("-") => None,
+        Some(v) => Some(v.parse().map_err(|_| invalid())?),
+        None => return Err(invalid()),
+    };
+    Ok((term, voted_for))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok};
+
+    #[tokio::test]
+    async fn cluster_should_elect_leader_and_replicate_writes() {
+        let network = MemoryNetwork::new();
+        let nodes = start_cluster(&network, 3, |_| {});
+        let leader = wait_for_leader(&nodes).await;
+        let follower = nodes.iter().find(|v| v.status().id != leader.status().id).unwrap();
+        let ctx = ConnectionContext::default();
+
+        let res = leader.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &ctx).await;
+        assert_res_ok(res, &[Value::default()], &[]);
+        // the follower forwards the write to the leader
+        let res = follower.execute(CommandRequest::new_hset("t1", "k1", "v2".into()), &ctx).await;
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        wait_for_applied(&nodes).await;
+        for node in &nodes {
+            let res = node.execute(CommandRequest::new_hget("t1", "k1"), &ctx).await;
+            assert_res_ok(res, &["v2".into()], &[]);
+        }
+
+        // writes bypassing raft are refused
+        let res = follower.service().execute(CommandRequest::new_hdel("t1", "k1"), &ctx);
+        assert_res_error(res, 400, "raft cluster");
+    }
+
+    #[tokio::test]
+    async fn cluster_should_survive_leader_failure() {
+        let network = MemoryNetwork::new();
+        let nodes = start_cluster(&network, 3, |_| {});
+        let old = wait_for_leader(&nodes).await;
+        let ctx = ConnectionContext::default();
+        old.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &ctx).await;
+
+        network.isolate(old.inner.config.addr.clone());
+        let id = old.status().id;
+        let others: Vec<_> = nodes.iter().filter(|v| v.status().id != id).cloned().collect();
+        let leader = wait_for_leader(&others).await;
+        assert!(leader.status().term > old.status().term);
+
+        let res = leader.execute(CommandRequest::new_hset("t1", "k2", "v2".into()), &ctx).await;
+        assert_res_ok(res, &[Value::default()], &[]);
+        // the old leader can't commit without a majority
+        let res = old.execute(CommandRequest::new_hset("t1", "k3", "v3".into()), &ctx).await;
+        assert_eq!(res.status, 500);
+
+        network.heal(&old.inner.config.addr);
+        wait_for_applied(&nodes).await;
+        assert_eq!(old.status().role, RaftRole::Follower);
+        let res = old.execute(CommandRequest::new_hget("t1", "k2"), &ctx).await;
+        assert_res_ok(res, &["v2".into()], &[]);
+        let res = old.execute(CommandRequest::new_hget("t1", "k3"), &ctx).await;
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[tokio::test]
+    async fn new_member_should_catch_up_from_snapshot() {
+        let network = MemoryNetwork::new();
+        let mut nodes = start_cluster(&network, 3, |v| {
+            v.snapshot_threshold = 5;
+            // the snapshot is sent in several chunks
+            v.max_snapshot_pairs = 3;
+        });
+        let leader = wait_for_leader(&nodes).await;
+        let ctx = ConnectionContext::default();
+        for i in 0..10 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+            leader.execute(cmd, &ctx).await;
+        }
+        // the snapshot is taken in the background
+        for _ in 0..500 {
+            if leader.status().snapshot_index >= 5 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(leader.status().snapshot_index >= 5);
+
+        // a new node starts without members, and joins the cluster
+        let config = test_config(4);
+        let inbox = network.join(&config.addr);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let node = RaftNode::start(config, vec![], service, network.clone(), inbox).unwrap();
+        leader.add_member(4, "node4").await.unwrap();
+        nodes.push(node.clone());
+        wait_for_applied(&nodes).await;
+
+        assert_eq!(node.status().members.len(), 4);
+        assert_eq!(node.status().leader, Some(leader.status().id));
+        let res = node.execute(CommandRequest::new_hget("t1", "k9"), &ctx).await;
+        assert_res_ok(res, &[9i64.into()], &[]);
+
+        // the leader removes itself, the others elect a new one
+        let id = leader.status().id;
+        leader.remove_member(id).await.unwrap();
+        let others: Vec<_> = nodes.iter().filter(|v| v.status().id != id).cloned().collect();
+        let new_leader = wait_for_leader(&others).await;
+        assert_eq!(new_leader.status().members.len(), 3);
+        assert_ne!(leader.status().role, RaftRole::Leader);
+    }
+
+    #[tokio::test]
+    async fn follower_should_redirect_writes_without_forwarding() {
+        let network = MemoryNetwork::new();
+        let nodes = start_cluster(&network, 3, |v| v.forward_writes = false);
+        let leader = wait_for_leader(&nodes).await;
+        let follower = nodes.iter().find(|v| v.status().id != leader.status().id).unwrap();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = follower.execute(cmd, &ConnectionContext::default()).await;
+        let addr = &leader.inner.config.addr;
+        assert_res_error(res, 421, &format!("the leader is {}", addr));
+    }
+
+    #[tokio::test]
+    async fn restarted_node_should_keep_term_and_vote() {
+        let dir = tempfile::tempdir().unwrap();
+        let start = |network: &MemoryNetwork| {
+            let config = RaftConfig {
+                state_file: Some(dir.path().join("raft.state")),
+                ..test_config(1)
+            };
+            let inbox = network.join(&config.addr);
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            let peers = vec![RaftPeer {
+                id: 1,
+                addr: "node1".into(),
+            }];
+            RaftNode::start(config, peers, service, network.clone(), inbox).unwrap()
+        };
+        let node = start(&MemoryNetwork::new());
+        let term = wait_for_leader(std::slice::from_ref(&node)).await.status().term;
+        drop(node);
+
+        // it voted for itself in the term, another candidate of the term gets no vote
+        let network = MemoryNetwork::new();
+        let node = start(&network);
+        let mut inbox = network.join("node2");
+        assert_eq!(node.status().term, term);
+        node.step(RaftMessage {
+            from: 2,
+            from_addr: "node2".into(),
+            term,
+            body: Some(Body::Vote(VoteRequest {
+                last_log_index: 100,
+                last_log_term: term,
+            })),
+        });
+        let res = inbox.recv().await.unwrap();
+        assert_eq!(res.body, Some(Body::VoteResponse(VoteResponse { granted: false })));
+    }
+
+    #[tokio::test]
+    async fn restarted_node_should_not_vote_until_caught_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let start = |network: &MemoryNetwork| {
+            let config = RaftConfig {
+                state_file: Some(dir.path().join("raft.state")),
+                ..test_config(1)
+            };
+            let inbox = network.join(&config.addr);
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            let peers = vec![RaftPeer {
+                id: 1,
+                addr: "node1".into(),
+            }];
+            RaftNode::start(config, peers, service, network.clone(), inbox).unwrap()
+        };
+        let node = start(&MemoryNetwork::new());
+        let term = wait_for_leader(std::slice::from_ref(&node)).await.status().term;
+        drop(node);
+
+        // its log is gone, a candidate of a later term without the entries gets no vote
+        let network = MemoryNetwork::new();
+        let node = start(&network);
+        let mut inbox = network.join("node2");
+        let vote = |term, last_log_term, last_log_index| RaftMessage {
+            from: 2,
+            from_addr: "node2".into(),
+            term,
+            body: Some(Body::Vote(VoteRequest {
+                last_log_index,
+                last_log_term,
+            })),
+        };
+        node.step(vote(term + 1, 0, 0));
+        let res = inbox.recv().await.unwrap();
+        assert_eq!(res.body, Some(Body::VoteResponse(VoteResponse { granted: false })));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(node.status().role, RaftRole::Follower);
+
+        // it votes again once it has what the leader committed
+        node.step(RaftMessage {
+            from: 2,
+            from_addr: "node2".into(),
+            term: term + 1,
+            body: Some(Body::Append(AppendEntries {
+                prev_log_index: 0,
+                prev_log_term: 0,
+                entries: vec![RaftEntry {
+                    term: term + 1,
+                    index: 1,
+                    payload: None,
+                }],
+                leader_commit: 1,
+            })),
+        });
+        let res = inbox.recv().await.unwrap();
+        let body = Body::AppendResponse(AppendResponse {
+            success: true,
+            match_index: 1,
+        });
+        assert_eq!(res.body, Some(body));
+        node.step(vote(term + 2, term + 1, 1));
+        let res = inbox.recv().await.unwrap();
+        assert_eq!(res.body, Some(Body::VoteResponse(VoteResponse { granted: true })));
+    }
+
+    fn test_config(id: u64) -> RaftConfig {
+        RaftConfig {
+            tick_interval: Duration::from_millis(10),
+            propose_timeout: Duration::from_millis(500),
+            ..RaftConfig::new(id, format!("node{}", id))
+        }
+    }
+
+    fn start_cluster(
+        network: &MemoryNetwork,
+        n: u64,
+        f: impl Fn(&mut RaftConfig),
+    ) -> Vec<RaftNode> {
+        let peers: Vec<_> = (1..=n)
+            .map(|id| RaftPeer {
+                id,
+                addr: format!("node{}", id),
+            })
+            .collect();
+        (1..=n)
+            .map(|id| {
+                let mut config = test_config(id);
+                f(&mut config);
+                let inbox = network.join(&config.addr);
+                let service: Service = ServiceInner::new(MemTable::new()).into();
+                RaftNode::start(config, peers.clone(), service, network.clone(), inbox).unwrap()
+            })
+            .collect()
+    }
+
+    // The leader all the nodes agree on
+    async fn wait_for_leader(nodes: &[RaftNode]) -> RaftNode {
+        for _ in 0..500 {
+            let status: Vec<_> = nodes.iter().map(|v| v.status()).collect();
+            let leader = status.iter().find(|v| v.role == RaftRole::Leader);
+            if let Some(leader) = leader {
+                if status.iter().all(|v| v.leader == Some(leader.id)) {
+                    return nodes.iter().find(|v| v.status().id == leader.id).unwrap().clone();
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no leader is elected");
+    }
+
+    async fn wait_for_applied(nodes: &[RaftNode]) {
+        for _ in 0..500 {
+            let status: Vec<_> = nodes.iter().map(|v| v.status()).collect();
+            let commit = status.iter().map(|v| v.commit_index).max().unwrap_or_default();
+            if status.iter().all(|v| v.applied_index == commit) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("nodes are not caught up");
+    }
+}
@@ -0,0 +1,62 @@
+// How raft messages travel between the nodes. Sending never blocks and a message may get lost,
+// which raft tolerates by retrying on the next heartbeat.
+
+use std::sync::Arc;
+
+use dashmap::{DashMap, DashSet};
+use tokio::sync::mpsc;
+
+use crate::RaftMessage;
+
+pub trait RaftTransport: Send + Sync + 'static {
+    /// Send the message to the node receiving at addr
+    fn send(&self, addr: &str, msg: RaftMessage);
+}
+
+/// Delivers the messages between nodes in the same process. A node could be isolated from the
+/// others to test failures
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    inner: Arc<MemoryNetworkInner>,
+}
+
+#[derive(Default)]
+struct MemoryNetworkInner {
+    nodes: DashMap<String, mpsc::UnboundedSender<RaftMessage>>,
+    isolated: DashSet<String>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive the messages sent to addr, pass the receiver to RaftNode::start
+    pub fn join(&self, addr: impl Into<String>) -> mpsc::UnboundedReceiver<RaftMessage> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.inner.nodes.insert(addr.into(), tx);
+        rx
+    }
+
+    /// Drop all the messages from and to addr
+    pub fn isolate(&self, addr: impl Into<String>) {
+        self.inner.isolated.insert(addr.into());
+    }
+
+    /// Deliver the messages from and to addr again
+    pub fn heal(&self, addr: &str) {
+        self.inner.isolated.remove(addr);
+    }
+}
+
+impl RaftTransport for MemoryNetwork {
+    fn send(&self, addr: &str, msg: RaftMessage) {
+        if self.inner.isolated.contains(addr) || self.inner.isolated.contains(&msg.from_addr) {
+            return;
+        }
+        if let Some(node) = self.inner.nodes.get(addr) {
+            // the node is gone if the receiver is dropped
+            let _ = node.send(msg);
+        }
+    }
+}
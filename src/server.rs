@@ -1,28 +1,774 @@
 use anyhow::Result;
-use kv_store::{MemTable, ProstServerStream, Service, ServiceInner, TlsServerAcceptor};
-use tokio::net::TcpListener;
-use tracing::info;
+use clap::{Parser, Subcommand};
+use kv_store::{
+    cluster_router, follow, grpc_server, http_router, serve_grpc_tls, serve_http_tls, serve_raft,
+    spki_pin, verify_backup, CertAuthority, CommandRequest, ConnectionContext, Encryption,
+    EnvKeyProvider, EvictionPolicy, FileKeyProvider, KvError, MasterKey, MemTable, MigrateProgress,
+    Migration, ProstClientStream, ProstServerStream, QuicServerEndpoint, RECORD_VERSION,
+    RaftConfig, RaftNode, RaftPeer, RocksDB, Service, ServiceInner, SledDb, Storage, TcpTransport,
+    TlsClientConnector, TlsServerAcceptor, UnixSocketListener, YamuxCtrl,
+};
+use std::{net::SocketAddr, path::{Path, PathBuf}, sync::Arc, time::Duration};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+#[derive(Debug, Parser)]
+#[command(name = "kvs", about = "KV server")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Address of the native TLS listener
+    #[arg(long, default_value = "127.0.0.1:9527")]
+    addr: String,
+    /// Address of the HTTP/JSON gateway, over TLS when client certs are verified
+    #[arg(long, default_value = "127.0.0.1:9528")]
+    http_addr: String,
+    /// Address of the gRPC gateway, over TLS when client certs are verified
+    #[arg(long, default_value = "127.0.0.1:9529")]
+    grpc_addr: String,
+    /// Also listen for QUIC on this UDP address, with the same certs as the TLS listener
+    #[arg(long)]
+    quic_addr: Option<String>,
+    /// Also listen on a Unix domain socket at this path, without TLS
+    #[arg(long)]
+    unix: Option<PathBuf>,
+    /// Permission bits (octal) of the Unix domain socket, which is its access control
+    #[arg(long, default_value = "660", value_parser = parse_mode)]
+    unix_mode: u32,
+    /// Multiplex logical streams with yamux on each TLS connection
+    #[arg(long)]
+    yamux: bool,
+    /// Server certificate file, watched and reloaded on change or SIGHUP.
+    /// The bundled fixtures are used when not set
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    /// Server private key file
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+    /// CA certificate file to verify client certificates
+    #[arg(long, requires = "cert")]
+    client_ca: Option<PathBuf>,
+    /// CRL file issued by the client CA, revoked client certificates are rejected
+    #[arg(long, requires = "client_ca")]
+    crl: Option<PathBuf>,
+    /// How often (in seconds) the certificate files are checked for changes
+    #[arg(long, default_value = "60")]
+    cert_check_interval: u64,
+    /// Run as a follower of the primary at this address: replicate its writes over TLS and
+    /// refuse writes from clients
+    #[arg(long)]
+    replica_of: Option<String>,
+    /// Domain name in the certificate of the primary
+    #[arg(long, default_value = "kvserver.acme.inc")]
+    primary_domain: String,
+    /// CA certificate file to verify the primary, the bundled fixture CA is used when not set
+    #[arg(long, requires = "replica_of")]
+    primary_ca: Option<PathBuf>,
+    /// Client certificate file presented to the primary
+    #[arg(long, requires_all = ["replica_of", "primary_key"])]
+    primary_cert: Option<PathBuf>,
+    /// Private key file of the client certificate presented to the primary
+    #[arg(long, requires = "primary_cert")]
+    primary_key: Option<PathBuf>,
+    /// Run as the node with this id of a raft cluster, writes are committed by a majority.
+    /// The nodes authenticate each other with mutual TLS, with --cert, --key and --client-ca
+    #[arg(
+        long,
+        requires_all = ["raft_addr", "raft_state", "cert", "key", "client_ca"],
+        conflicts_with_all = ["replica_of", "yamux", "quic_addr"]
+    )]
+    raft_id: Option<u64>,
+    /// Address the raft messages of the other nodes are received on, over TLS
+    #[arg(long, requires = "raft_id")]
+    raft_addr: Option<String>,
+    /// File the term and the vote of the node are kept in across restarts. A node restarted with
+    /// it doesn't vote until it has caught up with the leader, remove it to bootstrap again
+    #[arg(long, requires = "raft_id")]
+    raft_state: Option<PathBuf>,
+    /// A node bootstrapping the cluster as id=addr, repeated for every node including this one.
+    /// A node joining an existing cluster starts without peers and is added on the leader
+    #[arg(long = "raft-peer", requires = "raft_id", value_parser = parse_peer)]
+    raft_peers: Vec<RaftPeer>,
+    /// Domain name in the certificates of the other nodes
+    #[arg(long, default_value = "kvserver.acme.inc")]
+    raft_domain: String,
+    /// CA certificate file to verify the other nodes, the client CA is used when not set
+    #[arg(long, requires = "raft_id")]
+    raft_ca: Option<PathBuf>,
+    /// Directory the Backup command writes into, the command is refused when not set.
+    /// Only clients with the admin role could back up when client certs are verified.
+    /// The server keeps its data in memory, the backups of sled and rocksdb stores are taken
+    /// through the library
+    #[arg(long)]
+    backup_dir: Option<PathBuf>,
+    /// Start with the data of a backup of a server, after checking it against the manifest of
+    /// the backup
+    #[arg(long, conflicts_with_all = ["replica_of", "raft_id"])]
+    restore: Option<PathBuf>,
+    /// Memory budget of the store in bytes, with an optional K/M/G suffix. Kvpairs are evicted
+    /// by the eviction policy to stay within it
+    #[arg(long, value_parser = parse_size)]
+    max_memory: Option<usize>,
+    /// Memory budget of each table, in bytes with an optional K/M/G suffix
+    #[arg(long, value_parser = parse_size)]
+    max_table_memory: Option<usize>,
+    /// Which kvpairs are evicted first: lru, lfu, random or ttl-first
+    #[arg(long, default_value = "lru")]
+    eviction: EvictionPolicy,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Create the CA and issue server/client certificates
+    #[command(subcommand)]
+    Cert(CertCommand),
+    /// Back up a running server into its backup directory
+    Backup(BackupArgs),
+    /// Copy every table from one store into another, with the server stopped
+    Migrate(MigrateArgs),
+    /// Rewrite the legacy records of a sled or rocksdb store in the current record format, with
+    /// the server stopped. With master keys the records are encrypted with the current one
+    Upgrade {
+        /// The store to upgrade: sled:<path> or rocksdb:<path>
+        #[arg(long, value_parser = parse_store)]
+        store: StoreSpec,
+        #[command(flatten)]
+        keys: KeyArgs,
+    },
+    /// Print a new random master key as a line of a key file, "<id>:<base64>"
+    Keygen {
+        /// Id of the key, larger than the ids of the keys in use
+        id: u32,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct KeyArgs {
+    /// File of the master keys, "<id>:<base64>" per line. The key with the largest id is the
+    /// current one, the older ones are kept to read the records not re-encrypted yet
+    #[arg(long, conflicts_with = "key_env")]
+    key_file: Option<PathBuf>,
+    /// Environment variable with the master keys, "<id>:<base64>" separated by ','
+    #[arg(long)]
+    key_env: Option<String>,
+    /// Encrypt the keys too, with subkeys of the master key of this id. It's chosen when the
+    /// store is created and recorded in it, a store is refused when opened with another choice.
+    /// The ranges and Hfind of a table with encrypted keys read and sort the whole table
+    #[arg(long)]
+    encrypt_keys: Option<u32>,
+}
+
+#[derive(Debug, clap::Args)]
+struct SourceKeyArgs {
+    /// File of the master keys of the store copied from, "<id>:<base64>" per line
+    #[arg(long, conflicts_with = "from_key_env")]
+    from_key_file: Option<PathBuf>,
+    /// Environment variable with the master keys of the store copied from
+    #[arg(long)]
+    from_key_env: Option<String>,
+    /// Id of the master key the keys of the store copied from are encrypted with
+    #[arg(long)]
+    from_encrypt_keys: Option<u32>,
+}
+
+impl SourceKeyArgs {
+    fn encryption(&self) -> Result<Option<Encryption>> {
+        KeyArgs {
+            key_file: self.from_key_file.clone(),
+            key_env: self.from_key_env.clone(),
+            encrypt_keys: self.from_encrypt_keys,
+        }
+        .encryption()
+    }
+}
+
+impl KeyArgs {
+    fn encryption(&self) -> Result<Option<Encryption>> {
+        let encryption = match (&self.key_file, &self.key_env) {
+            (Some(path), _) => Encryption::new(Arc::new(FileKeyProvider::open(path)?)),
+            (None, Some(var)) => Encryption::new(Arc::new(EnvKeyProvider::new(var)?)),
+            (None, None) if self.encrypt_keys.is_some() => {
+                anyhow::bail!("--encrypt-keys needs the master keys, from --key-file or --key-env")
+            }
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(match self.encrypt_keys {
+            Some(id) => encryption.encrypt_keys(id)?,
+            None => encryption,
+        }))
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct MigrateArgs {
+    /// The store to copy from: memtable:<backup dir>, sled:<path> or rocksdb:<path>
+    #[arg(long, value_parser = parse_store)]
+    from: StoreSpec,
+    /// The store to copy into, a memtable is written as a backup into the directory when done
+    #[arg(long, value_parser = parse_store)]
+    to: StoreSpec,
+    /// Checkpoint file of the tables done, a migration stopped halfway resumes from it
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+    /// Report the progress every so many kvpairs
+    #[arg(long, default_value = "10000")]
+    progress_every: u64,
+    /// Master keys to encrypt the store copied into
+    #[command(flatten)]
+    keys: KeyArgs,
+    /// Master keys to read the store copied from
+    #[command(flatten)]
+    source_keys: SourceKeyArgs,
+}
+
+#[derive(Debug, Clone)]
+enum StoreSpec {
+    MemTable(PathBuf),
+    Sled(PathBuf),
+    RocksDB(PathBuf),
+}
+
+impl StoreSpec {
+    fn path(&self) -> &Path {
+        match self {
+            StoreSpec::MemTable(path) | StoreSpec::Sled(path) | StoreSpec::RocksDB(path) => path,
+        }
+    }
+
+    // A store read from must be there, opening a mistyped path would create an empty store
+    fn existing(&self) -> Result<&Self> {
+        if !self.path().exists() {
+            anyhow::bail!("{} doesn't exist", self.path().display());
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct BackupArgs {
+    /// Name of the backup, a new directory under the backup directory of the server
+    name: String,
+    /// Address of the native TLS listener of the server
+    #[arg(long, default_value = "127.0.0.1:9527")]
+    server: String,
+    /// Domain name in the certificate of the server
+    #[arg(long, default_value = "kvserver.acme.inc")]
+    domain: String,
+    /// CA certificate file to verify the server, the bundled fixture CA is used when not set
+    #[arg(long)]
+    ca: Option<PathBuf>,
+    /// Client certificate file, with the admin role when the server verifies client certs
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    /// Private key file of the client certificate
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum CertCommand {
+    /// Create a CA, written as ca.cert/ca.key
+    Ca {
+        #[command(flatten)]
+        opts: CertOpts,
+        #[arg(long, default_value = "Acme CA")]
+        cn: String,
+        #[arg(long, default_value = "3650")]
+        days: u32,
+    },
+    /// Issue a server certificate signed by the CA in the directory
+    Server {
+        #[command(flatten)]
+        opts: CertOpts,
+        #[arg(long, default_value = "Acme KV server")]
+        cn: String,
+        /// DNS name or IP address the server is reached by, could be repeated
+        #[arg(long = "san", required = true)]
+        sans: Vec<String>,
+        #[arg(long, default_value = "1825")]
+        days: u32,
+        /// Base name of the written files
+        #[arg(long, default_value = "server")]
+        name: String,
+    },
+    /// Issue a client certificate signed by the CA in the directory
+    Client {
+        #[command(flatten)]
+        opts: CertOpts,
+        /// Identity of the client
+        #[arg(long)]
+        cn: String,
+        /// Role granted to the client, could be repeated
+        #[arg(long = "role")]
+        roles: Vec<String>,
+        #[arg(long, default_value = "365")]
+        days: u32,
+        /// Base name of the written files
+        #[arg(long, default_value = "client")]
+        name: String,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct CertOpts {
+    /// Directory of the CA and the issued certificates
+    #[arg(long, default_value = "certs")]
+    dir: PathBuf,
+    #[arg(long, default_value = "Acme Inc.")]
+    org: String,
+    /// Overwrite existing files
+    #[arg(long)]
+    force: bool,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let addr = "127.0.0.1:9527";
+    let args = Args::parse();
+    match args.command {
+        Some(Command::Cert(cmd)) => return cert(cmd),
+        Some(Command::Backup(cmd)) => return backup(cmd).await,
+        Some(Command::Migrate(cmd)) => return migrate(cmd),
+        Some(Command::Upgrade { store, keys }) => return upgrade(store, keys),
+        Some(Command::Keygen { id }) => {
+            println!("{}:{}", id, MasterKey::generate()?.to_base64());
+            return Ok(());
+        }
+        None => {}
+    }
 
-    // 以后从配置文件取
-    let server_cert = include_str!("../fixtures/server.cert");
-    let server_key = include_str!("../fixtures/server.key");
+    let addr = args.addr.as_str();
+    let http_addr = args.http_addr.as_str();
+    let grpc_addr = args.grpc_addr.as_str();
 
-    let acceptor = TlsServerAcceptor::new(server_cert, server_key, None)?;
+    let (server_cert, server_key, client_ca, crl, acceptor) = match (&args.cert, &args.key) {
+        (Some(cert), Some(key)) => {
+            let acceptor = TlsServerAcceptor::from_files(
+                cert,
+                key,
+                args.client_ca.as_ref(),
+                args.crl.as_ref(),
+            )?;
+            acceptor.watch(Duration::from_secs(args.cert_check_interval))?;
+            let client_ca = match &args.client_ca {
+                Some(path) => Some(std::fs::read_to_string(path)?),
+                None => None,
+            };
+            let crl = match &args.crl {
+                Some(path) => Some(std::fs::read_to_string(path)?),
+                None => None,
+            };
+            let cert = std::fs::read_to_string(cert)?;
+            let key = std::fs::read_to_string(key)?;
+            (cert, key, client_ca, crl, acceptor)
+        }
+        _ => {
+            let cert = include_str!("../fixtures/server.cert").to_string();
+            let key = include_str!("../fixtures/server.key").to_string();
+            let acceptor = TlsServerAcceptor::new(&cert, &key, None)?;
+            (cert, key, None, None, acceptor)
+        }
+    };
+
+    let store = memtable(&args);
+    let mut inner = match (&args.replica_of, &args.restore) {
+        (Some(primary), _) => ServiceInner::new(store).follower_of(primary),
+        (None, Some(dir)) => {
+            info!("Restore from the backup in {}", dir.display());
+            ServiceInner::restore_into(store, dir)?
+        }
+        (None, None) => ServiceInner::new(store),
+    };
+    if let Some(dir) = &args.backup_dir {
+        inner = inner.backup_dir(dir);
+    }
+    if args.client_ca.is_some() {
+        inner = inner.fn_authorize(admin_backup);
+    }
+    let service: Service = inner.into();
+    if args.max_memory.is_some() || args.max_table_memory.is_some() {
+        let service = service.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                info!("Memory of the store: {:?}", service.store().stats());
+            }
+        });
+    }
+
+    if let Some(primary) = args.replica_of.clone() {
+        let connector = primary_connector(&args)?;
+        info!("Replicate from the primary {}", primary);
+        let connect = move || {
+            let (connector, primary) = (connector.clone(), primary.clone());
+            async move { connector.connect(TcpStream::connect(primary).await?).await }
+        };
+        tokio::spawn(follow(service.clone(), connect));
+    }
+
+    let raft = match (args.raft_id, &args.raft_addr) {
+        (Some(id), Some(raft_addr)) => {
+            // nodes authenticate each other with the server certs, a raft message from a client
+            // without a cert of the client CA could replace the data of the node
+            let client_ca = client_ca.as_deref();
+            let client_ca = client_ca.ok_or_else(|| anyhow::anyhow!("raft needs --client-ca"))?;
+            let acceptor = TlsServerAcceptor::new(&server_cert, &server_key, Some(client_ca))?;
+            let connector = raft_connector(&args, &server_cert, &server_key)?;
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            let listener = TcpListener::bind(raft_addr).await?;
+            info!("Start raft node {} on {}", id, raft_addr);
+            tokio::spawn(serve_raft(listener, Some(acceptor), tx));
+
+            let config = RaftConfig {
+                state_file: args.raft_state.clone(),
+                ..RaftConfig::new(id, raft_addr)
+            };
+            let transport = TcpTransport::new().with_tls(connector);
+            let peers = args.raft_peers.clone();
+            Some(RaftNode::start(config, peers, service.clone(), transport, rx)?)
+        }
+        _ => None,
+    };
+
+    // HTTP/JSON gateway shares the same service with the native listener. With client certs
+    // verified, the gateways take the same TLS config as the native listener, so that they
+    // aren't a way around it
+    let gateway_tls = args.client_ca.as_ref().map(|_| acceptor.clone());
+    let http = TcpListener::bind(http_addr).await?;
+    let router = http_router(service.clone());
+    match &gateway_tls {
+        Some(tls) => {
+            // the members are only changed by clients with a cert of the client CA, which a
+            // raft node always has
+            let router = match &raft {
+                Some(raft) => router.merge(cluster_router(raft.clone())),
+                None => router,
+            };
+            info!("Start HTTP gateway on {} over TLS", http_addr);
+            tokio::spawn(serve_http_tls(http, tls.clone(), router));
+        }
+        None => {
+            info!("Start HTTP gateway on {}", http_addr);
+            let router = router.into_make_service_with_connect_info::<SocketAddr>();
+            tokio::spawn(async move { axum::serve(http, router).await });
+        }
+    }
+
+    // gRPC gateway shares the same service as well
+    match gateway_tls {
+        Some(tls) => {
+            info!("Start gRPC gateway on {} over TLS", grpc_addr);
+            let grpc = TcpListener::bind(grpc_addr).await?;
+            tokio::spawn(serve_grpc_tls(grpc, tls, service.clone()));
+        }
+        None => {
+            info!("Start gRPC gateway on {}", grpc_addr);
+            let grpc = tonic::transport::Server::builder()
+                .add_service(grpc_server(service.clone()))
+                .serve(grpc_addr.parse()?);
+            tokio::spawn(grpc);
+        }
+    }
+
+    if let Some(quic_addr) = args.quic_addr {
+        // QUIC endpoint takes the certs at startup, it is not reloaded
+        let quic = QuicServerEndpoint::bind(
+            quic_addr.parse()?,
+            &server_cert,
+            &server_key,
+            client_ca.as_deref(),
+            crl.as_deref(),
+        )?;
+        info!("Start QUIC listening on {}", quic_addr);
+        tokio::spawn(quic.serve(service.clone()));
+    }
+
+    // Sidecars on the same host talk the same framing over a Unix socket, without TLS
+    if let Some(path) = args.unix {
+        let unix = UnixSocketListener::bind(&path, args.unix_mode)?;
+        info!("Start listening on {}", path.display());
+        let (service, raft) = (service.clone(), raft.clone());
+        tokio::spawn(async move {
+            loop {
+                // A failed accept (e.g. out of file descriptors) only drops that connection
+                let (stream, cred) = match unix.accept().await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("Accept on {} failed: {:?}", unix.path().display(), e);
+                        continue;
+                    }
+                };
+                info!("Client {:?} connected", cred);
+                let mut stream = ProstServerStream::new(stream, service.clone());
+                if let Some(raft) = &raft {
+                    stream = stream.with_raft(raft.clone());
+                }
+                tokio::spawn(async move { stream.process().await });
+            }
+        });
+    }
 
-    let service: Service = ServiceInner::new(MemTable::new()).into();
     let listener = TcpListener::bind(addr).await?;
     info!("Start listening on {}", addr);
     loop {
         let tls = acceptor.clone();
         let (stream, addr) = listener.accept().await?;
         info!("Client {:?} connected", addr);
-        let stream = tls.accept(stream).await?;
-        let stream = ProstServerStream::new(stream, service.clone());
-        tokio::spawn(async move { stream.process().await });
+        // A failed handshake (e.g. a revoked client certificate) only drops that connection
+        let stream = match tls.accept(stream).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("TLS handshake with {:?} failed: {:?}", addr, e);
+                continue;
+            }
+        };
+        let ctx = ConnectionContext::new(Some(addr)).with_tls(&stream);
+        if args.yamux {
+            YamuxCtrl::new_server(stream, None, service.clone(), ctx);
+        } else {
+            let mut stream = ProstServerStream::new(stream, service.clone()).with_context(ctx);
+            if let Some(raft) = &raft {
+                stream = stream.with_raft(raft.clone());
+            }
+            tokio::spawn(async move { stream.process().await });
+        }
     }
-}
\ No newline at end of file
+}
+
+fn primary_connector(args: &Args) -> Result<TlsClientConnector> {
+    let (cert, key) = (&args.primary_cert, &args.primary_key);
+    client_connector(&args.primary_domain, &args.primary_ca, cert, key)
+}
+
+fn client_connector(
+    domain: &str,
+    ca: &Option<PathBuf>,
+    cert: &Option<PathBuf>,
+    key: &Option<PathBuf>,
+) -> Result<TlsClientConnector> {
+    let ca = match ca {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => include_str!("../fixtures/ca.cert").to_string(),
+    };
+    let identity = match (cert, key) {
+        (Some(cert), Some(key)) => {
+            Some((std::fs::read_to_string(cert)?, std::fs::read_to_string(key)?))
+        }
+        _ => None,
+    };
+    let identity = identity.as_ref().map(|(cert, key)| (cert.as_str(), key.as_str()));
+    Ok(TlsClientConnector::new_private(domain, identity, &ca)?)
+}
+
+// With client certs verified, only a client with the admin role could back up the server
+fn admin_backup(cmd: &CommandRequest, ctx: &ConnectionContext) -> Result<(), KvError> {
+    let roles = ctx.identity.as_ref().map(|v| v.roles.as_slice()).unwrap_or_default();
+    match cmd.command() {
+        Some("backup") if !roles.iter().any(|v| v == "admin") => Err(KvError::PermissionDenied(
+            "backup needs the admin role".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+async fn backup(args: BackupArgs) -> Result<()> {
+    let connector = client_connector(&args.domain, &args.ca, &args.cert, &args.key)?;
+    let stream = connector.connect(TcpStream::connect(&args.server).await?).await?;
+    let mut client = ProstClientStream::new(stream);
+    let res = client.execute(CommandRequest::new_backup(&args.name)).await?;
+    if res.status != 200 {
+        anyhow::bail!("Backup failed: {}", res.message);
+    }
+
+    let values: Vec<serde_json::Value> = res.values.into_iter().map(Into::into).collect();
+    match values.as_slice() {
+        [dir, seq] => println!("Wrote {} at seq {}", dir.as_str().unwrap_or_default(), seq),
+        _ => anyhow::bail!("Unexpected response of Backup: {:?}", values),
+    }
+    Ok(())
+}
+
+fn migrate(args: MigrateArgs) -> Result<()> {
+    if let StoreSpec::MemTable(dir) = &args.to {
+        if args.checkpoint.is_some() {
+            anyhow::bail!("A memtable is only written when the migration is done, it can't resume");
+        }
+        if dir.exists() {
+            anyhow::bail!("{} already exists", dir.display());
+        }
+    }
+
+    let encryption = args.keys.encryption()?;
+    if encryption.is_some() && matches!(args.to, StoreSpec::MemTable(_)) {
+        anyhow::bail!("A memtable isn't encrypted, the master keys are for sled or rocksdb");
+    }
+
+    let source_encryption = args.source_keys.encryption()?;
+    if source_encryption.is_some() && matches!(args.from, StoreSpec::MemTable(_)) {
+        anyhow::bail!("A memtable isn't encrypted, the master keys are for sled or rocksdb");
+    }
+
+    let source = open_store(args.from.existing()?, source_encryption)?;
+    let memtable = MemTable::new();
+    let opened;
+    let target: &dyn Storage = match &args.to {
+        StoreSpec::MemTable(_) => &memtable,
+        spec => {
+            opened = open_store(spec, encryption)?;
+            opened.as_ref()
+        }
+    };
+
+    let progress = |v: &MigrateProgress| {
+        let state = if v.done { "done" } else { "copying" };
+        eprintln!(
+            "[{}/{}] {}: {} kvpairs {}, {} in total",
+            v.table_index + 1,
+            v.tables,
+            v.table,
+            v.copied,
+            state,
+            v.total
+        );
+    };
+    let mut migration =
+        Migration::new(source.as_ref(), target).progress(args.progress_every, progress);
+    if let Some(path) = &args.checkpoint {
+        migration = migration.checkpoint(path);
+    }
+    let report = migration.run()?;
+
+    if let StoreSpec::MemTable(dir) = &args.to {
+        let service: Service = ServiceInner::new(memtable).into();
+        service.backup(dir)?;
+    }
+    let pairs: u64 = report.tables.values().sum();
+    println!("Migrated {} tables with {} kvpairs", report.tables.len(), pairs);
+    if !report.resumed.is_empty() {
+        println!("Skipped {} tables done before: {:?}", report.resumed.len(), report.resumed);
+    }
+    Ok(())
+}
+
+fn upgrade(store: StoreSpec, keys: KeyArgs) -> Result<()> {
+    let encryption = keys.encryption()?;
+    let encrypted = match encryption {
+        Some(_) => ", encrypted with the current master key",
+        None => "",
+    };
+    let report = match store.existing()? {
+        StoreSpec::Sled(path) => SledDb::open(path, encryption)?.upgrade()?,
+        StoreSpec::RocksDB(path) => RocksDB::open(path, encryption)?.upgrade()?,
+        StoreSpec::MemTable(_) => anyhow::bail!("A memtable backup has no records to upgrade"),
+    };
+    println!(
+        "Upgraded {} of {} records to format version {}{}",
+        report.upgraded, report.scanned, RECORD_VERSION, encrypted
+    );
+    if !report.corrupt.is_empty() {
+        anyhow::bail!("{} records can't be read: {:?}", report.corrupt.len(), report.corrupt);
+    }
+    Ok(())
+}
+
+// A memtable is read from a backup, after checking it against the manifest of the backup
+fn open_store(spec: &StoreSpec, encryption: Option<Encryption>) -> Result<Box<dyn Storage>> {
+    Ok(match spec {
+        StoreSpec::MemTable(dir) => {
+            let store = MemTable::restore(dir)?;
+            verify_backup(&store, dir)?;
+            Box::new(store)
+        }
+        StoreSpec::Sled(path) => Box::new(SledDb::open(path, encryption)?),
+        StoreSpec::RocksDB(path) => Box::new(RocksDB::open(path, encryption)?),
+    })
+}
+
+// The server serves a MemTable. SledDb and RocksDB, with their backups, record upgrades and
+// encryption, are used through the library, and offline by the migrate and upgrade commands
+fn memtable(args: &Args) -> MemTable {
+    let mut store = MemTable::new().eviction(args.eviction);
+    if let Some(max) = args.max_memory {
+        store = store.max_bytes(max);
+    }
+    if let Some(max) = args.max_table_memory {
+        store = store.max_table_bytes(max);
+    }
+    store
+}
+
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let unit = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("unknown unit {}, expect K, M or G", unit)),
+    };
+    let n: usize = digits.parse().map_err(|e| format!("invalid size {}: {}", s, e))?;
+    n.checked_mul(unit).ok_or_else(|| format!("size {} is too large", s))
+}
+
+fn parse_store(s: &str) -> Result<StoreSpec, String> {
+    let (kind, path) = s.split_once(':').ok_or("expect <backend>:<path>")?;
+    let path = PathBuf::from(path);
+    match kind {
+        "memtable" => Ok(StoreSpec::MemTable(path)),
+        "sled" => Ok(StoreSpec::Sled(path)),
+        "rocksdb" => Ok(StoreSpec::RocksDB(path)),
+        _ => Err(format!("unknown backend {}, expect memtable, sled or rocksdb", kind)),
+    }
+}
+
+fn raft_connector(args: &Args, cert: &str, key: &str) -> Result<TlsClientConnector> {
+    let path = args.raft_ca.as_ref().or(args.client_ca.as_ref());
+    let path = path.ok_or_else(|| anyhow::anyhow!("raft needs --raft-ca or --client-ca"))?;
+    let ca = std::fs::read_to_string(path)?;
+    Ok(TlsClientConnector::new_private(&args.raft_domain, Some((cert, key)), &ca)?)
+}
+
+fn parse_peer(s: &str) -> Result<RaftPeer, String> {
+    let (id, addr) = s.split_once('=').ok_or("expect id=addr")?;
+    let id = id.parse().map_err(|e| format!("invalid id: {}", e))?;
+    Ok(RaftPeer {
+        id,
+        addr: addr.to_string(),
+    })
+}
+
+fn cert(cmd: CertCommand) -> Result<()> {
+    let load_ca = |opts: &CertOpts| -> Result<CertAuthority> {
+        let cert = std::fs::read_to_string(opts.dir.join("ca.cert"))?;
+        let key = std::fs::read_to_string(opts.dir.join("ca.key"))?;
+        Ok(CertAuthority::load(&cert, &key)?)
+    };
+
+    let (pem, opts, name) = match &cmd {
+        CertCommand::Ca { opts, cn, days } => {
+            let ca = CertAuthority::new(&opts.org, cn, *days)?;
+            (ca.pem().clone(), opts, "ca")
+        }
+        CertCommand::Server { opts, cn, sans, days, name } => {
+            let pem = load_ca(opts)?.issue_server(&opts.org, cn, sans, *days)?;
+            println!("SPKI pin: {}", spki_pin(&pem.cert)?);
+            (pem, opts, name.as_str())
+        }
+        CertCommand::Client { opts, cn, roles, days, name } => {
+            let pem = load_ca(opts)?.issue_client(&opts.org, cn, roles, *days)?;
+            (pem, opts, name.as_str())
+        }
+    };
+
+    let (cert, key) = pem.write(&opts.dir, name, opts.force)?;
+    println!("Wrote {} and {}", cert.display(), key.display());
+    Ok(())
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|e| format!("invalid octal mode {}: {}", s, e))
+}
@@ -0,0 +1,277 @@
+// Point-in-time backups. Writes are held off while the store takes a snapshot, which is written
+// and hashed after they go on, so a backup matches one sequence number of the replication log. A
+// store without snapshots is copied and hashed while the writes are held. The manifest records a
+// digest of every table, a store restored from the backup is checked against it.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prost::Message;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::*;
+
+use super::replication;
+
+// Written last, a backup without it is incomplete
+const MANIFEST_FILE: &str = "MANIFEST.json";
+
+// Version of the manifest format
+const BACKUP_VERSION: u64 = 1;
+
+/// What a backup holds, from the MANIFEST.json in its directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupManifest {
+    /// Sequence number of the last mutation in the backup
+    pub seq: u64,
+    /// Unix timestamp in seconds
+    pub created_at: u64,
+    /// Digest of every table which has kvpairs
+    pub tables: BTreeMap<String, TableDigest>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableDigest {
+    pub pairs: u64,
+    /// sha256 of the kvpairs sorted by key, in hex
+    pub sha256: String,
+}
+
+impl<Store: Storage> Service<Store> {
+    /// Back up the store into dir, which must not exist yet
+    pub fn backup(&self, dir: impl AsRef<Path>) -> Result<BackupManifest, KvError> {
+        let dir = dir.as_ref();
+        let store = &self.inner.store;
+        let (seq, tables) = replication::blocking(|| -> Result<_, KvError> {
+            // only the snapshot is taken while the writes are held, when the store has one
+            let (seq, snapshot) = self.hold_writes(|seq| -> Result<_, KvError> {
+                Ok((seq, store.snapshot()?))
+            })?;
+            match snapshot {
+                Some(snapshot) => {
+                    snapshot.backup(dir)?;
+                    Ok((seq, digest(&snapshot)?))
+                }
+                None => self.hold_writes(|seq| {
+                    store.backup(dir)?;
+                    Ok((seq, digest(store)?))
+                }),
+            }
+        })?;
+
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let manifest = BackupManifest {
+            seq,
+            created_at: created_at.as_secs(),
+            tables,
+        };
+        let tables: serde_json::Map<_, _> = manifest
+            .tables
+            .iter()
+            .map(|(k, v)| (k.clone(), json!({"pairs": v.pairs, "sha256": v.sha256})))
+            .collect();
+        let data = json!({
+            "version": BACKUP_VERSION,
+            "seq": manifest.seq,
+            "created_at": manifest.created_at,
+            "tables": tables,
+        });
+        let data =
+            serde_json::to_vec_pretty(&data).map_err(|e| KvError::BackupError(e.to_string()))?;
+        fs::write(dir.join(MANIFEST_FILE), data)?;
+
+        Ok(manifest)
+    }
+
+    // The Backup command writes into a directory named by the client under the backup
+    // directory of the service, and is refused when there is none
+    pub(super) fn backup_command(&self, name: &str) -> CommandResponse {
+        let root = match &self.inner.backup_dir {
+            Some(root) => root,
+            None => {
+                let msg = "Backup is not enabled on this server";
+                return KvError::InvalidCommand(msg.into()).into();
+            }
+        };
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            let msg = format!("Invalid backup name {:?}", name);
+            return KvError::InvalidCommand(msg).into();
+        }
+
+        let dir: PathBuf = root.join(name);
+        match self.backup(&dir) {
+            Ok(manifest) => {
+                let values: Vec<Value> = vec![
+                    dir.display().to_string().into(),
+                    (manifest.seq as i64).into(),
+                ];
+                values.into()
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl ServiceInner<MemTable> {
+    /// Start from a MemTable backup, after checking the data matches its manifest
+    pub fn restore(dir: impl AsRef<Path>) -> Result<Self, KvError> {
+        Self::restore_into(MemTable::new(), dir)
+    }
+
+    /// Restore into a MemTable with memory budgets, which fails if the backup doesn't fit
+    pub fn restore_into(store: MemTable, dir: impl AsRef<Path>) -> Result<Self, KvError> {
+        let store = store.load(dir.as_ref())?;
+        verify_backup(&store, dir)?;
+        Ok(Self::new(store))
+    }
+}
+
+/// Check a store restored from the backup in dir has the data the backup was taken with
+pub fn verify_backup(
+    store: &impl Storage,
+    dir: impl AsRef<Path>,
+) -> Result<BackupManifest, KvError> {
+    let manifest = read_manifest(dir)?;
+    let tables = digest(store)?;
+    if tables != manifest.tables {
+        let mismatched: BTreeSet<_> = manifest
+            .tables
+            .keys()
+            .chain(tables.keys())
+            .filter(|v| manifest.tables.get(*v) != tables.get(*v))
+            .collect();
+        let msg = format!(
+            "restored data doesn't match the backup in tables {:?}",
+            mismatched
+        );
+        return Err(KvError::BackupError(msg));
+    }
+    Ok(manifest)
+}
+
+/// Read the manifest of the backup in dir
+pub fn read_manifest(dir: impl AsRef<Path>) -> Result<BackupManifest, KvError> {
+    let path = dir.as_ref().join(MANIFEST_FILE);
+    let invalid = |msg: &str| KvError::BackupError(format!("{}: {}", path.display(), msg));
+    let data = fs::read(&path)?;
+    let data: serde_json::Value =
+        serde_json::from_slice(&data).map_err(|e| invalid(&e.to_string()))?;
+    if data["version"].as_u64() != Some(BACKUP_VERSION) {
+        return Err(invalid("unsupported version"));
+    }
+
+    let mut tables = BTreeMap::new();
+    for (name, v) in data["tables"]
+        .as_object()
+        .ok_or_else(|| invalid("no tables"))?
+    {
+        let digest = match (v["pairs"].as_u64(), v["sha256"].as_str()) {
+            (Some(pairs), Some(sha256)) => TableDigest {
+                pairs,
+                sha256: sha256.to_string(),
+            },
+            _ => return Err(invalid("invalid table digest")),
+        };
+        tables.insert(name.clone(), digest);
+    }
+
+    match (data["seq"].as_u64(), data["created_at"].as_u64()) {
+        (Some(seq), Some(created_at)) => Ok(BackupManifest {
+            seq,
+            created_at,
+            tables,
+        }),
+        _ => Err(invalid("no seq or created_at")),
+    }
+}
+
+// Digests of the tables which have kvpairs, an empty table is the same as none
+fn digest(store: &impl Storage) -> Result<BTreeMap<String, TableDigest>, KvError> {
+    let mut tables = BTreeMap::new();
+    for table in store.get_tables()? {
+        let mut pairs = store.get_all(&table)?;
+        if pairs.is_empty() {
+            continue;
+        }
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+
+        let mut hasher = Sha256::new();
+        for pair in &pairs {
+            let value = pair.value.clone().unwrap_or_default().encode_to_vec();
+            hasher.update((pair.key.len() as u64).to_be_bytes());
+            hasher.update(pair.key.as_bytes());
+            hasher.update((value.len() as u64).to_be_bytes());
+            hasher.update(&value);
+        }
+        let sha256 = hasher
+            .finalize()
+            .iter()
+            .map(|v| format!("{:02x}", v))
+            .collect();
+        let digest = TableDigest {
+            pairs: pairs.len() as u64,
+            sha256,
+        };
+        tables.insert(table, digest);
+    }
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok};
+    use tempfile::tempdir;
+
+    #[test]
+    fn backup_command_should_write_a_verified_backup() -> Result<(), KvError> {
+        let dir = tempdir()?;
+        let service: Service = ServiceInner::new(MemTable::new())
+            .backup_dir(dir.path())
+            .into();
+        let ctx = ConnectionContext::default();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &ctx);
+        service.execute(CommandRequest::new_hset("t2", "k2", 2.into()), &ctx);
+
+        let res = service.execute(CommandRequest::new_backup("nightly"), &ctx);
+        let path = dir.path().join("nightly").display().to_string();
+        assert_res_ok(res, &[path.into(), 2.into()], &[]);
+
+        let res = service.execute(CommandRequest::new_backup("nightly"), &ctx);
+        assert_eq!(res.status, 500);
+        let res = service.execute(CommandRequest::new_backup("../etc"), &ctx);
+        assert_res_error(res, 400, "Invalid backup name");
+
+        let restored: Service = ServiceInner::restore(dir.path().join("nightly"))?.into();
+        let res = restored.execute(CommandRequest::new_hget("t2", "k2"), &ctx);
+        assert_res_ok(res, &[2.into()], &[]);
+        let manifest = read_manifest(dir.path().join("nightly"))?;
+        assert_eq!(manifest.seq, 2);
+        assert_eq!(manifest.tables.len(), 2);
+
+        // a store which doesn't match the backup is refused
+        let store = MemTable::restore(dir.path().join("nightly"))?;
+        store.set("t1", "k1".into(), "v0".into())?;
+        let result = verify_backup(&store, dir.path().join("nightly"));
+        assert!(matches!(result, Err(KvError::BackupError(msg)) if msg.contains("{\"t1\"}")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn backup_command_should_be_refused_without_backup_dir() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let res = service.execute(CommandRequest::new_backup("nightly"), &Default::default());
+        assert_res_error(res, 400, "not enabled");
+    }
+}
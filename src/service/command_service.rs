@@ -1,9 +1,15 @@
 use crate::*;
+use super::filter::Filter;
+use super::index;
+use super::json::{self, JsonPath};
 
 impl CommandService for Hget {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         match store.get(&self.table, &self.key) {
-            Ok(Some(v)) => v.into(),
+            Ok(Some(v)) => match check_scalar(&self.table, &self.key, &v) {
+                Ok(()) => v.into(),
+                Err(e) => e.into(),
+            },
             Ok(None) => KvError::NotFound(self.table, self.key).into(),
             Err(e) => e.into(),
         }
@@ -12,8 +18,27 @@ impl CommandService for Hget {
 
 impl CommandService for Hgetall {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get_all(&self.table) {
-            Ok(v) => v.into(),
+        let filter = match self.filter {
+            Some(filter) => filter,
+            None => {
+                return match store.get_all(&self.table) {
+                    Ok(v) => v.into(),
+                    Err(e) => e.into(),
+                }
+            }
+        };
+        // filter while iterating, the kvpairs filtered out are never collected
+        let res: Result<CommandResponse, KvError> = Filter::new(filter).and_then(|filter| {
+            let mut pairs = store
+                .get_iter(&self.table)?
+                .filter_map(|v| v.map(|v| filter.apply(v)).transpose());
+            Ok(match filter.count_only {
+                true => Value::from(pairs.try_fold(0i64, |n, v| v.map(|_| n + 1))?).into(),
+                false => pairs.collect::<Result<Vec<_>, _>>()?.into(),
+            })
+        });
+        match res {
+            Ok(res) => res,
             Err(e) => e.into(),
         }
     }
@@ -21,20 +46,25 @@ impl CommandService for Hgetall {
 
 impl CommandService for Hset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        match self.pair {
-            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
-                Ok(Some(v)) => v.into(),
-                Ok(None) => Value::default().into(),
-                Err(e) => e.into(),
-            },
-            None => Value::default().into(),
+        let pair = match self.pair {
+            Some(v) => v,
+            None => return Value::default().into(),
+        };
+        let value = pair.value.unwrap_or_default();
+        let old = check_overwrite(store, &self.table, &pair.key, &value)
+            .and_then(|_| json::check_json(&value))
+            .and_then(|_| index::set(store, &self.table, pair.key, value));
+        match old {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => Value::default().into(),
+            Err(e) => e.into(),
         }
     }
 }
 
 impl CommandService for Hdel {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.del(&self.table, &self.key) {
+        match index::del(store, &self.table, &self.key) {
             Ok(Some(v)) => v.into(),
             Ok(None) => Value::default().into(),
             Err(e) => e.into()
@@ -42,10 +72,328 @@ impl CommandService for Hdel {
     }
 }
 
+impl CommandService for Hmget {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // a missing key gets an empty value, so the values line up with the keys
+        let values: Result<Vec<Value>, KvError> = self
+            .keys
+            .iter()
+            .map(|key| {
+                let value = store.get(&self.table, key)?.unwrap_or_default();
+                check_scalar(&self.table, key, &value)?;
+                Ok(value)
+            })
+            .collect();
+        match values {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmset {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let pairs: Vec<(String, Value)> = self
+            .pairs
+            .into_iter()
+            .map(|pair| (pair.key, pair.value.unwrap_or_default()))
+            .collect();
+        // every pair is checked before the first one is set, a refused Hmset sets none
+        let checked = pairs.iter().try_for_each(|(key, value)| {
+            check_overwrite(store, &self.table, key, value)?;
+            json::check_json(value)
+        });
+        let values: Result<Vec<Value>, KvError> = checked.and_then(|_| {
+            pairs
+                .into_iter()
+                .map(|(key, value)| {
+                    Ok(index::set(store, &self.table, key, value)?.unwrap_or_default())
+                })
+                .collect()
+        });
+        match values {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmdel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let values: Result<Vec<Value>, KvError> = self
+            .keys
+            .iter()
+            .map(|key| Ok(index::del(store, &self.table, key)?.unwrap_or_default()))
+            .collect();
+        match values {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.contains(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let values: Result<Vec<Value>, KvError> = self
+            .keys
+            .iter()
+            .map(|key| Ok(store.contains(&self.table, key)?.into()))
+            .collect();
+        match values {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for ListTables {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get_tables() {
+            Ok(mut tables) => {
+                // the elements of the collections and the indexes are not tables to a client
+                tables.retain(|v| !is_internal_table(v));
+                tables.sort();
+                let values: Vec<Value> = tables.into_iter().map(Into::into).collect();
+                values.into()
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lpush {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.lpush(&self.table, &self.key, self.values) {
+            Ok(len) => Value::from(len as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lpop {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.lpop(&self.table, &self.key, self.count.max(1)) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lrange {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.lrange(&self.table, &self.key, self.start, self.stop) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Sadd {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.sadd(&self.table, &self.key, self.members) {
+            Ok(added) => Value::from(added as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Srem {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.srem(&self.table, &self.key, &self.members) {
+            Ok(removed) => Value::from(removed as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Smembers {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.smembers(&self.table, &self.key) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Zadd {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // a score is a float or an integer
+        let members: Result<Vec<(String, f64)>, KvError> = self
+            .members
+            .into_iter()
+            .map(|pair| match pair.value.and_then(|v| v.value) {
+                Some(value::Value::Float(score)) => Ok((pair.key, score)),
+                Some(value::Value::Integer(score)) => Ok((pair.key, score as f64)),
+                _ => Err(KvError::InvalidCommand(format!(
+                    "Score of {} is not a number",
+                    pair.key
+                ))),
+            })
+            .collect();
+        match members.and_then(|v| store.zadd(&self.table, &self.key, v)) {
+            Ok(added) => Value::from(added as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Zrange {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.zrange(&self.table, &self.key, self.start, self.stop) {
+            Ok(v) => scored_pairs(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Zrangebyscore {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.zrangebyscore(&self.table, &self.key, self.min, self.max) {
+            Ok(v) => scored_pairs(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for JsonGet {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = JsonPath::parse(&self.path).and_then(|path| {
+            let doc = json::load(store, &self.table, &self.key)?
+                .ok_or_else(|| KvError::NotFound(self.table.clone(), self.key.clone()))?;
+            let value = path.get(&doc).ok_or_else(|| path.not_found(&self.table, &self.key))?;
+            Ok(Value::json(value))
+        });
+        match value {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for JsonSet {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let old = JsonPath::parse(&self.path).and_then(|path| {
+            let value = json::parse(&self.value)?;
+            // only the whole document could be set on a new key
+            let (mut doc, existed) = match json::load(store, &self.table, &self.key)? {
+                Some(doc) => (doc, true),
+                None if path.is_root() => (serde_json::Value::Null, false),
+                None => return Err(KvError::NotFound(self.table.clone(), self.key.clone())),
+            };
+            let old = path.set(&mut doc, value)?.filter(|_| existed);
+            index::set(store, &self.table, self.key.clone(), Value::json(&doc))?;
+            Ok(old.map(|v| Value::json(&v)).unwrap_or_default())
+        });
+        match old {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for JsonAppend {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let len = JsonPath::parse(&self.path).and_then(|path| {
+            let values: Result<Vec<_>, _> = self.values.iter().map(|v| json::parse(v)).collect();
+            let values = values?;
+            let mut doc = json::load(store, &self.table, &self.key)?
+                .ok_or_else(|| KvError::NotFound(self.table.clone(), self.key.clone()))?;
+            let len = match path.get_mut(&mut doc) {
+                Some(serde_json::Value::Array(items)) => {
+                    items.extend(values);
+                    items.len()
+                }
+                Some(_) => return Err(path.invalid("not an array")),
+                None => return Err(path.not_found(&self.table, &self.key)),
+            };
+            index::set(store, &self.table, self.key.clone(), Value::json(&doc))?;
+            Ok(len)
+        });
+        match len {
+            Ok(len) => Value::from(len as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for JsonIncr {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = JsonPath::parse(&self.path).and_then(|path| {
+            let mut doc = json::load(store, &self.table, &self.key)?
+                .ok_or_else(|| KvError::NotFound(self.table.clone(), self.key.clone()))?;
+            let number = path
+                .get_mut(&mut doc)
+                .ok_or_else(|| path.not_found(&self.table, &self.key))?;
+            *number = json::incr(number, &self.by.unwrap_or_default())?;
+            let value = Value::json(number);
+            index::set(store, &self.table, self.key.clone(), Value::json(&doc))?;
+            Ok(value)
+        });
+        match value {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for CreateIndex {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match index::create(store, &self.table, &self.name, &self.path) {
+            Ok(count) => Value::from(count as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for DropIndex {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match index::remove(store, &self.table, &self.name) {
+            Ok(count) => Value::from(count as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hfind {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // a value without data is the same as not set
+        let set = |v: Option<Value>| v.filter(|v| v.value.is_some());
+        let (value, min, max) = (set(self.value), set(self.min), set(self.max));
+        let found = match index::find(store, &self.table, &self.index, value, min, max) {
+            Ok(found) => found,
+            Err(e) => return e.into(),
+        };
+        let (order, pairs): (Vec<String>, Vec<Kvpair>) = found.into_iter().unzip();
+        let mut res: CommandResponse = pairs.into();
+        if self.with_order {
+            res.values = order.into_iter().map(Value::from).collect();
+        }
+        res
+    }
+}
+
+// The members of a sorted set as kvpairs of member and score, in order
+fn scored_pairs(members: Vec<(String, f64)>) -> Vec<Kvpair> {
+    members
+        .into_iter()
+        .map(|(member, score)| Kvpair::new(member, score.into()))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::command_request::RequestData;
+    use serde_json::json;
 
     #[test]
     fn hset_should_work() {
@@ -99,6 +447,67 @@ mod tests {
         assert_res_ok(res, &[], pairs);
     }
 
+    #[test]
+    fn hget_all_with_filter_should_work() {
+        let store = MemTable::new();
+        let pairs = vec![
+            Kvpair::new("user:1", 30.into()),
+            Kvpair::new("user:2", 17.5.into()),
+            Kvpair::new("user:3", "old".into()),
+            Kvpair::new("admin:1", 40.into()),
+            Kvpair::new("doc:1", Value::json(&json!({"user": {"age": 21}}))),
+            Kvpair::new("doc:2", Value::json(&json!({"user": {}}))),
+        ];
+        dispatch(CommandRequest::new_hmset("t1", pairs), &store);
+        let hget_all = |filter: ScanFilter| {
+            dispatch(CommandRequest::new_hget_all_filtered("t1", filter), &store)
+        };
+
+        let res = hget_all(ScanFilter {
+            key_glob: "user:*".into(),
+            min: Some(18.into()),
+            ..Default::default()
+        });
+        assert_res_ok(res, &[], &[Kvpair::new("user:1", 30.into())]);
+
+        let res = hget_all(ScanFilter {
+            key_regex: ":[12]$".into(),
+            value_type: "integer".into(),
+            keys_only: true,
+            ..Default::default()
+        });
+        let mut keys: Vec<_> = res.pairs.iter().map(|v| v.key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, ["admin:1", "user:1"]);
+        assert!(res.pairs.iter().all(|v| v.value == Some(Value::default())));
+
+        // the range is on the projected part of the documents
+        let res = hget_all(ScanFilter {
+            path: "$.user.age".into(),
+            max: Some(25.into()),
+            ..Default::default()
+        });
+        assert_res_ok(res, &[], &[Kvpair::new("doc:1", Value::json(&json!(21)))]);
+
+        let res = hget_all(ScanFilter {
+            max: Some(35.0.into()),
+            count_only: true,
+            ..Default::default()
+        });
+        assert_res_ok(res, &[2.into()], &[]);
+
+        let res = hget_all(ScanFilter {
+            key_regex: "(".into(),
+            ..Default::default()
+        });
+        assert_res_error(res, 400, "Invalid pattern");
+        let res = hget_all(ScanFilter {
+            value_type: "number".into(),
+            ..Default::default()
+        });
+        assert_res_error(res, 400, "Unknown value type");
+    }
+
     #[test]
     fn hdel_should_work() {
         let store = MemTable::new();
@@ -112,6 +521,316 @@ mod tests {
         assert_res_ok(res, &[Value::default()], &[]);
     }
 
+    #[test]
+    fn hmset_and_hmget_should_work() {
+        let store = MemTable::new();
+        let pairs = vec![Kvpair::new("u1", 10.into()), Kvpair::new("u2", 8.into())];
+        let res = dispatch(CommandRequest::new_hmset("score", pairs), &store);
+        assert_res_ok(res, &[Value::default(), Value::default()], &[]);
+
+        let pairs = vec![Kvpair::new("u2", 9.into()), Kvpair::new("u3", 11.into())];
+        let res = dispatch(CommandRequest::new_hmset("score", pairs), &store);
+        assert_res_ok(res, &[8.into(), Value::default()], &[]);
+
+        let keys = vec!["u3".into(), "u4".into(), "u1".into()];
+        let res = dispatch(CommandRequest::new_hmget("score", keys), &store);
+        assert_res_ok(res, &[11.into(), Value::default(), 10.into()], &[]);
+    }
+
+    #[test]
+    fn hmdel_and_hexist_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        dispatch(CommandRequest::new_hset("t1", "k2", "v2".into()), &store);
+
+        let res = dispatch(CommandRequest::new_hexist("t1", "k1"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+
+        let keys = vec!["k1".into(), "k3".into()];
+        let res = dispatch(CommandRequest::new_hmdel("t1", keys), &store);
+        assert_res_ok(res, &["v1".into(), Value::default()], &[]);
+
+        let keys = vec!["k1".into(), "k2".into()];
+        let res = dispatch(CommandRequest::new_hmexist("t1", keys), &store);
+        assert_res_ok(res, &[false.into(), true.into()], &[]);
+    }
+
+    #[test]
+    fn list_tables_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t2", "k1", "v1".into()), &store);
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        let res = dispatch(CommandRequest::new_list_tables(), &store);
+        assert_res_ok(res, &["t1".into(), "t2".into()], &[]);
+    }
+
+    #[test]
+    fn list_commands_should_work() {
+        let store = MemTable::new();
+        let values = vec!["a".into(), "b".into(), "c".into()];
+        let res = dispatch(CommandRequest::new_lpush("t1", "l", values), &store);
+        assert_res_ok(res, &[3.into()], &[]);
+        let res = dispatch(CommandRequest::new_lpush("t1", "l", vec![1.into()]), &store);
+        assert_res_ok(res, &[4.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_lrange("t1", "l", 0, -1), &store);
+        assert_res_ok(res, &[1.into(), "c".into(), "b".into(), "a".into()], &[]);
+        let res = dispatch(CommandRequest::new_lrange("t1", "l", -2, 10), &store);
+        assert_res_ok(res, &["b".into(), "a".into()], &[]);
+
+        let res = dispatch(CommandRequest::new_lpop("t1", "l", 0), &store);
+        assert_res_ok(res, &[1.into()], &[]);
+        let res = dispatch(CommandRequest::new_lpop("t1", "l", 5), &store);
+        assert_res_ok(res, &["c".into(), "b".into(), "a".into()], &[]);
+        // an empty list is gone
+        let res = dispatch(CommandRequest::new_hexist("t1", "l"), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+    }
+
+    #[test]
+    fn set_commands_should_work() {
+        let store = MemTable::new();
+        let members = vec!["a".into(), 1.into(), "a".into()];
+        let res = dispatch(CommandRequest::new_sadd("t1", "s", members), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+        let members = vec![1.into(), "1".into()];
+        let res = dispatch(CommandRequest::new_sadd("t1", "s", members), &store);
+        assert_res_ok(res, &[1.into()], &[]);
+
+        let members = vec!["a".into(), "b".into()];
+        let res = dispatch(CommandRequest::new_srem("t1", "s", members), &store);
+        assert_res_ok(res, &[1.into()], &[]);
+
+        let mut res = dispatch(CommandRequest::new_smembers("t1", "s"), &store);
+        res.values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_res_ok(res, &["1".into(), 1.into()], &[]);
+    }
+
+    #[test]
+    fn zset_commands_should_work() {
+        let store = MemTable::new();
+        let members = vec![("c".into(), 2.0), ("a".into(), 3.0), ("b".into(), 1.5)];
+        let res = dispatch(CommandRequest::new_zadd("t1", "z", members), &store);
+        assert_res_ok(res, &[3.into()], &[]);
+        let members = vec![("a".into(), 1.0), ("d".into(), 2.0)];
+        let res = dispatch(CommandRequest::new_zadd("t1", "z", members), &store);
+        assert_res_ok(res, &[1.into()], &[]);
+
+        // in the order of the scores, assert_res_ok would sort them by member
+        let res = dispatch(CommandRequest::new_zrange("t1", "z", 0, -1), &store);
+        let pairs = vec![
+            Kvpair::new("a", 1.0.into()),
+            Kvpair::new("b", 1.5.into()),
+            Kvpair::new("c", 2.0.into()),
+            Kvpair::new("d", 2.0.into()),
+        ];
+        assert_eq!(res.pairs, pairs);
+        let res = dispatch(CommandRequest::new_zrange("t1", "z", -2, -1), &store);
+        assert_eq!(res.pairs, &pairs[2..]);
+
+        let res = dispatch(CommandRequest::new_zrangebyscore("t1", "z", 1.5, 2.0), &store);
+        assert_eq!(res.pairs, &pairs[1..]);
+
+        let mut cmd = CommandRequest::new_zadd("t1", "z", vec![]);
+        if let Some(RequestData::Zadd(v)) = cmd.request_data.as_mut() {
+            v.members.push(Kvpair::new("e", "high".into()));
+        }
+        assert_res_error(dispatch(cmd, &store), 400, "not a number");
+    }
+
+    #[test]
+    fn commands_on_the_wrong_type_should_fail() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k", "v".into()), &store);
+        dispatch(CommandRequest::new_lpush("t1", "l", vec!["v".into()]), &store);
+
+        let res = dispatch(CommandRequest::new_lpush("t1", "k", vec!["v".into()]), &store);
+        assert_res_error(res, 400, "Wrong type");
+        let res = dispatch(CommandRequest::new_smembers("t1", "l"), &store);
+        assert_res_error(res, 400, "Wrong type");
+        let res = dispatch(CommandRequest::new_hget("t1", "l"), &store);
+        assert_res_error(res, 400, "Wrong type");
+        let res = dispatch(CommandRequest::new_hset("t1", "l", "v".into()), &store);
+        assert_res_error(res, 400, "Wrong type");
+
+        // deleting the key drops the elements, the key could hold anything then
+        dispatch(CommandRequest::new_hdel("t1", "l"), &store);
+        let res = dispatch(CommandRequest::new_list_tables(), &store);
+        assert_res_ok(res, &["t1".into()], &[]);
+        let res = dispatch(CommandRequest::new_hset("t1", "l", "v".into()), &store);
+        assert_res_ok(res, &[Value::default()], &[]);
+    }
+
+    #[test]
+    fn refused_writes_should_write_nothing() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_lpush("t1", "l", vec!["v".into()]), &store);
+
+        let pairs = vec![Kvpair::new("k", "v".into()), Kvpair::new("l", "v".into())];
+        let res = dispatch(CommandRequest::new_hmset("t1", pairs), &store);
+        assert_res_error(res, 400, "Wrong type");
+        let header: Value = Collection::default().into();
+        let values = vec!["a".into(), header.clone()];
+        let res = dispatch(CommandRequest::new_lpush("t1", "l2", values), &store);
+        assert_res_error(res, 400, "element of another");
+        let res = dispatch(CommandRequest::new_sadd("t1", "s", vec![1.into(), header]), &store);
+        assert_res_error(res, 400, "element of another");
+        let members = vec![("a".into(), 1.0), ("b".into(), f64::NAN)];
+        let res = dispatch(CommandRequest::new_zadd("t1", "z", members), &store);
+        assert_res_error(res, 400, "NaN");
+
+        let res = dispatch(CommandRequest::new_hget_all("t1"), &store);
+        assert_eq!(res.pairs.len(), 1);
+        let res = dispatch(CommandRequest::new_list_tables(), &store);
+        assert_res_ok(res, &["t1".into()], &[]);
+        assert_eq!(store.get_tables().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn json_commands_should_work() {
+        let store = MemTable::new();
+        let doc = json!({"user": {"email": "a@b.c", "visits": 1, "tags": []}});
+        let res = dispatch(CommandRequest::new_json_set("t1", "doc", "$", &doc), &store);
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = dispatch(CommandRequest::new_json_get("t1", "doc", "$.user.email"), &store);
+        assert_res_ok(res, &[Value::json(&json!("a@b.c"))], &[]);
+
+        let cmd = CommandRequest::new_json_set("t1", "doc", "$.user.email", &json!("x@y.z"));
+        assert_res_ok(dispatch(cmd, &store), &[Value::json(&json!("a@b.c"))], &[]);
+        let cmd = CommandRequest::new_json_incr("t1", "doc", "$.user.visits", 2.into());
+        assert_res_ok(dispatch(cmd, &store), &[Value::json(&json!(3))], &[]);
+        let values = [json!("a"), json!({"b": 1})];
+        let cmd = CommandRequest::new_json_append("t1", "doc", "$.user.tags", &values);
+        assert_res_ok(dispatch(cmd, &store), &[2.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hget("t1", "doc"), &store);
+        let expected = json!({"user": {"email": "x@y.z", "visits": 3, "tags": ["a", {"b": 1}]}});
+        assert_res_ok(res, &[Value::json(&expected)], &[]);
+    }
+
+    #[test]
+    fn json_commands_should_fail_on_bad_path_or_type() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k", "v".into()), &store);
+        let cmd = CommandRequest::new_json_set("t1", "doc", "$", &json!({"a": [1]}));
+        dispatch(cmd, &store);
+
+        let res = dispatch(CommandRequest::new_json_get("t1", "k", "$"), &store);
+        assert_res_error(res, 400, "Wrong type");
+        let res = dispatch(CommandRequest::new_json_get("t1", "doc", "$.b"), &store);
+        assert_res_error(res, 404, "doc at $.b");
+        let res = dispatch(CommandRequest::new_json_get("t1", "doc", "a"), &store);
+        assert_res_error(res, 400, "Invalid path");
+        let cmd = CommandRequest::new_json_set("t1", "new", "$.a", &json!(1));
+        assert_res_error(dispatch(cmd, &store), 404, "Not found");
+        let cmd = CommandRequest::new_json_incr("t1", "doc", "$.a", 1.into());
+        assert_res_error(dispatch(cmd, &store), 400, "not a number");
+        let cmd = CommandRequest::new_json_append("t1", "doc", "$.a[0]", &[json!(1)]);
+        assert_res_error(dispatch(cmd, &store), 400, "not an array");
+
+        let bad = Value {
+            value: Some(value::Value::Json("{".into())),
+        };
+        let res = dispatch(CommandRequest::new_hset("t1", "bad", bad), &store);
+        assert_res_error(res, 400, "Invalid JSON");
+    }
+
+    #[test]
+    fn hfind_should_find_by_value_and_range() {
+        let store = MemTable::new();
+        for (key, age) in [("a", 30), ("b", 25), ("c", 30), ("d", 41)] {
+            dispatch(CommandRequest::new_hset("t1", key, age.into()), &store);
+        }
+        dispatch(CommandRequest::new_hset("t1", "e", "30".into()), &store);
+        let res = dispatch(CommandRequest::new_create_index("t1", "age", ""), &store);
+        assert_res_ok(res, &[5.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hfind("t1", "age", 30.into()), &store);
+        let pairs = [Kvpair::new("a", 30.into()), Kvpair::new("c", 30.into())];
+        assert_res_ok(res, &[], &pairs);
+
+        // in the order of the index, integers and floats compare as numbers
+        let cmd = CommandRequest::new_hfind_range("t1", "age", Some(26.5.into()), None);
+        let res = dispatch(cmd, &store);
+        let keys: Vec<_> = res.pairs.iter().map(|v| v.key.as_str()).collect();
+        assert_eq!(keys, ["a", "c", "d"]);
+        let cmd = CommandRequest::new_hfind_range("t1", "age", None, Some(30.into()));
+        let keys: Vec<_> = dispatch(cmd, &store).pairs.into_iter().map(|v| v.key).collect();
+        assert_eq!(keys, ["b", "a", "c"]);
+
+        // the index follows the writes
+        dispatch(CommandRequest::new_hset("t1", "a", 25.into()), &store);
+        dispatch(CommandRequest::new_hdel("t1", "c"), &store);
+        let res = dispatch(CommandRequest::new_hfind("t1", "age", 30.into()), &store);
+        assert_res_ok(res, &[], &[]);
+        let res = dispatch(CommandRequest::new_hfind("t1", "age", 25.into()), &store);
+        let pairs = [Kvpair::new("a", 25.into()), Kvpair::new("b", 25.into())];
+        assert_res_ok(res, &[], &pairs);
+
+        let cmd = CommandRequest::new_hfind_range("t1", "age", Some(1.into()), Some("z".into()));
+        assert_res_error(dispatch(cmd, &store), 400, "different types");
+        let res = dispatch(CommandRequest::new_create_index("t1", "age", ""), &store);
+        assert_res_error(res, 400, "already exists");
+
+        let res = dispatch(CommandRequest::new_drop_index("t1", "age"), &store);
+        assert_res_ok(res, &[4.into()], &[]);
+        let res = dispatch(CommandRequest::new_hfind("t1", "age", 25.into()), &store);
+        assert_res_error(res, 404, "index age");
+        let res = dispatch(CommandRequest::new_list_tables(), &store);
+        assert_res_ok(res, &["t1".into()], &[]);
+    }
+
+    #[test]
+    fn hfind_should_keep_large_integers_apart() {
+        let store = MemTable::new();
+        let big = 1i64 << 53;
+        for (key, value) in [("a", Value::from(big + 1)), ("b", big.into()), ("c", 1e16.into())] {
+            dispatch(CommandRequest::new_hset("t1", key, value), &store);
+        }
+        dispatch(CommandRequest::new_create_index("t1", "v", ""), &store);
+
+        let res = dispatch(CommandRequest::new_hfind("t1", "v", (big + 1).into()), &store);
+        assert_res_ok(res, &[], &[Kvpair::new("a", (big + 1).into())]);
+        let mut cmd = CommandRequest::new_hfind_range("t1", "v", Some(big.into()), None);
+        if let Some(RequestData::Hfind(v)) = cmd.request_data.as_mut() {
+            v.with_order = true;
+        }
+        let res = dispatch(cmd, &store);
+        let keys: Vec<_> = res.pairs.iter().map(|v| v.key.as_str()).collect();
+        assert_eq!(keys, ["b", "a", "c"]);
+        assert_eq!(res.values.len(), 3);
+        assert!(res.values.windows(2).all(|v| v[0] < v[1]));
+    }
+
+    #[test]
+    fn hfind_should_find_by_json_field() {
+        let store = MemTable::new();
+        let sessions = [("s1", "u1"), ("s2", "u2"), ("s3", "u1")];
+        for (key, user) in sessions {
+            let doc = json!({"user": user, "ttl": 60});
+            dispatch(CommandRequest::new_json_set("t1", key, "$", &doc), &store);
+        }
+        // a document kept as a string is indexed too
+        let doc = json!({"user": "u1"}).to_string();
+        dispatch(CommandRequest::new_hset("t1", "s4", doc.into()), &store);
+        dispatch(CommandRequest::new_hset("t1", "other", "u1".into()), &store);
+        let res = dispatch(CommandRequest::new_create_index("t1", "user", "$.user"), &store);
+        assert_res_ok(res, &[4.into()], &[]);
+
+        let cmd = CommandRequest::new_json_set("t1", "s3", "$.user", &json!("u2"));
+        dispatch(cmd, &store);
+        let res = dispatch(CommandRequest::new_hfind("t1", "user", "u1".into()), &store);
+        let keys: Vec<_> = res.pairs.into_iter().map(|v| v.key).collect();
+        assert_eq!(keys, ["s1", "s4"]);
+        let res = dispatch(CommandRequest::new_hfind("t1", "user", "u2".into()), &store);
+        let keys: Vec<_> = res.pairs.into_iter().map(|v| v.key).collect();
+        assert_eq!(keys, ["s2", "s3"]);
+
+        let res = dispatch(CommandRequest::new_create_index("t1", "bad", "user"), &store);
+        assert_res_error(res, 400, "Invalid path");
+    }
+
     // Get Response from Request, could handle HGET/HGETALL/HSET for now.
     fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
         match cmd.request_data.unwrap() {
@@ -119,6 +838,28 @@ mod tests {
             RequestData::Hgetall(hgetall) => hgetall.execute(store),
             RequestData::Hset(hset) => hset.execute(store),
             RequestData::Hdel(hdel) => hdel.execute(store),
+            RequestData::Hmget(v) => v.execute(store),
+            RequestData::Hmset(v) => v.execute(store),
+            RequestData::Hmdel(v) => v.execute(store),
+            RequestData::Hexist(v) => v.execute(store),
+            RequestData::Hmexist(v) => v.execute(store),
+            RequestData::ListTables(v) => v.execute(store),
+            RequestData::Lpush(v) => v.execute(store),
+            RequestData::Lpop(v) => v.execute(store),
+            RequestData::Lrange(v) => v.execute(store),
+            RequestData::Sadd(v) => v.execute(store),
+            RequestData::Srem(v) => v.execute(store),
+            RequestData::Smembers(v) => v.execute(store),
+            RequestData::Zadd(v) => v.execute(store),
+            RequestData::Zrange(v) => v.execute(store),
+            RequestData::Zrangebyscore(v) => v.execute(store),
+            RequestData::JsonGet(v) => v.execute(store),
+            RequestData::JsonSet(v) => v.execute(store),
+            RequestData::JsonAppend(v) => v.execute(store),
+            RequestData::JsonIncr(v) => v.execute(store),
+            RequestData::CreateIndex(v) => v.execute(store),
+            RequestData::DropIndex(v) => v.execute(store),
+            RequestData::Hfind(v) => v.execute(store),
             _ => todo!()
         }
     }
@@ -0,0 +1,149 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio_rustls::rustls::Session;
+use tokio_rustls::server::TlsStream as ServerTlsStream;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::{KvError, ROLE_URI_PREFIX};
+
+// Connection ids are unique within the process
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Who is on the other side of a connection, passed into Service::execute and its hooks
+/// so that authorization, auditing and metrics could key off the caller.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectionContext {
+    /// Unique id of the connection, 0 for commands not coming from a connection
+    pub id: u64,
+    pub peer_addr: Option<SocketAddr>,
+    /// Identity in the verified client certificate
+    pub identity: Option<PeerIdentity>,
+    /// Negotiated ALPN protocol
+    pub alpn: Option<String>,
+}
+
+/// Subject and SANs of a verified client certificate
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerIdentity {
+    pub subject: String,
+    pub common_name: Option<String>,
+    pub sans: Vec<String>,
+    /// Roles granted by the CA, from the URI SANs issued by `kvs cert client --role`
+    pub roles: Vec<String>,
+}
+
+impl ConnectionContext {
+    // Create a context with a new connection id
+    pub fn new(peer_addr: Option<SocketAddr>) -> Self {
+        Self {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            peer_addr,
+            ..Default::default()
+        }
+    }
+
+    // Fill the identity and ALPN negotiated by the TLS handshake
+    pub fn with_tls<S>(self, stream: &ServerTlsStream<S>) -> Self {
+        let session = stream.get_ref().1;
+        let certs = session.get_peer_certificates().unwrap_or_default();
+        let alpn = session.get_alpn_protocol().map(|v| v.to_vec());
+        self.with_peer(certs.first().map(|c| c.0.as_slice()), alpn)
+    }
+
+    // Fill the identity from the DER of the leaf client cert, and the negotiated ALPN
+    pub fn with_peer(mut self, cert: Option<&[u8]>, alpn: Option<Vec<u8>>) -> Self {
+        // rustls has verified the cert already, a cert we can't parse just has no identity
+        self.identity = cert.and_then(|der| PeerIdentity::from_der(der).ok());
+        self.alpn = alpn.map(|v| String::from_utf8_lossy(&v).into_owned());
+        self
+    }
+}
+
+impl PeerIdentity {
+    // Parse subject and SANs from a DER encoded certificate
+    pub fn from_der(der: &[u8]) -> Result<Self, KvError> {
+        let (_, cert) = X509Certificate::from_der(der)
+            .map_err(|_| KvError::CertifcateParseError("peer", "cert"))?;
+
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(String::from);
+
+        let sans: Vec<_> = match cert.subject_alternative_name() {
+            Ok(Some(ext)) => ext.value.general_names.iter().map(general_name).collect(),
+            _ => Vec::new(),
+        };
+        let roles = sans
+            .iter()
+            .filter_map(|san| san.strip_prefix(ROLE_URI_PREFIX))
+            .map(String::from)
+            .collect();
+
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            common_name,
+            sans,
+            roles,
+        })
+    }
+}
+
+fn general_name(name: &GeneralName) -> String {
+    match name {
+        GeneralName::DNSName(s) | GeneralName::RFC822Name(s) | GeneralName::URI(s) => s.to_string(),
+        GeneralName::IPAddress(&[a, b, c, d]) => IpAddr::from([a, b, c, d]).to_string(),
+        GeneralName::IPAddress(bytes) => match <[u8; 16]>::try_from(*bytes) {
+            Ok(v6) => IpAddr::from(v6).to_string(),
+            Err(_) => name.to_string(),
+        },
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tokio_rustls::rustls::internal::pemfile;
+
+    const CLIENT_CERT: &str = include_str!("../../fixtures/client.cert");
+    const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
+
+    #[test]
+    fn connection_id_should_be_unique() {
+        let ctx1 = ConnectionContext::new(None);
+        let ctx2 = ConnectionContext::new(None);
+        assert_ne!(ctx1.id, ctx2.id);
+        assert_ne!(ctx1.id, ConnectionContext::default().id);
+    }
+
+    #[test]
+    fn peer_identity_should_be_parsed_from_cert() {
+        let identity = PeerIdentity::from_der(&der(CLIENT_CERT)).unwrap();
+        assert_eq!(identity.common_name.as_deref(), Some("awesome-device-id"));
+        assert!(identity.subject.contains("O=Acme Inc."));
+
+        let identity = PeerIdentity::from_der(&der(SERVER_CERT)).unwrap();
+        assert_eq!(identity.common_name.as_deref(), Some("Acme KV server"));
+        assert!(identity.sans.contains(&"kvserver.acme.inc".to_string()));
+    }
+
+    #[test]
+    fn with_peer_should_fill_identity_and_alpn() {
+        let der = der(CLIENT_CERT);
+        let ctx = ConnectionContext::new(None).with_peer(Some(&der), Some(b"kv".to_vec()));
+        assert_eq!(ctx.alpn.as_deref(), Some("kv"));
+        assert_eq!(
+            ctx.identity.unwrap().common_name.as_deref(),
+            Some("awesome-device-id")
+        );
+    }
+
+    fn der(pem: &str) -> Vec<u8> {
+        pemfile::certs(&mut Cursor::new(pem)).unwrap().remove(0).0
+    }
+}
@@ -0,0 +1,166 @@
+// Filtering and projection of the kvpairs of a table, evaluated while Hgetall or a scan of the
+// gRPC gateway iterates the table, so only the kvpairs asked for leave the server. A kvpair is
+// returned when all the conditions set hold: the key matches the glob and the regex, the value
+// is of the type, and the value, or its part at the path, is within min and max as the
+// secondary indexes order them.
+
+use regex::Regex;
+
+use super::index::Range;
+use super::json::{self, JsonPath};
+use crate::*;
+
+// The names of the fields of Value, what value_type is compared with
+const VALUE_TYPES: &[&str] = &["string", "binary", "integer", "float", "bool", "collection", "json"];
+
+pub(crate) struct Filter {
+    glob: Option<Regex>,
+    regex: Option<Regex>,
+    value_type: Option<String>,
+    range: Option<Range>,
+    path: Option<JsonPath>,
+    keys_only: bool,
+    pub(crate) count_only: bool,
+}
+
+impl Filter {
+    pub(crate) fn new(filter: ScanFilter) -> Result<Self, KvError> {
+        let glob = match filter.key_glob.as_str() {
+            "" => None,
+            glob => Some(compile(&glob_to_regex(glob))?),
+        };
+        let regex = match filter.key_regex.as_str() {
+            "" => None,
+            regex => Some(compile(regex)?),
+        };
+        let value_type = match filter.value_type.as_str() {
+            "" => None,
+            ty if VALUE_TYPES.contains(&ty) => Some(ty.to_string()),
+            ty => return Err(KvError::InvalidCommand(format!("Unknown value type {:?}", ty))),
+        };
+        // a value without data is the same as not set
+        let set = |v: Option<Value>| v.filter(|v| v.value.is_some());
+        let (min, max) = (set(filter.min), set(filter.max));
+        let path = match filter.path.as_str() {
+            "" => None,
+            path => Some(JsonPath::parse(path)?),
+        };
+        Ok(Self {
+            glob,
+            regex,
+            value_type,
+            range: Range::new(min.as_ref(), max.as_ref())?,
+            path,
+            keys_only: filter.keys_only,
+            count_only: filter.count_only,
+        })
+    }
+
+    // The kvpair to return for pair, None when it's filtered out
+    pub(crate) fn apply(&self, pair: Kvpair) -> Option<Kvpair> {
+        let key_matches =
+            |re: &Option<Regex>| re.as_ref().is_none_or(|re| re.is_match(&pair.key));
+        if !key_matches(&self.glob) || !key_matches(&self.regex) {
+            return None;
+        }
+        let value = pair.value.unwrap_or_default();
+        if let Some(ty) = &self.value_type {
+            if type_name(&value) != Some(ty.as_str()) {
+                return None;
+            }
+        }
+        let value = match &self.path {
+            Some(path) => Value::json(path.get(&json::document(&value)?)?),
+            None => value,
+        };
+        if self.range.as_ref().is_some_and(|range| !range.contains(&value)) {
+            return None;
+        }
+        let value = match self.keys_only {
+            true => Value::default(),
+            false => value,
+        };
+        Some(Kvpair::new(pair.key, value))
+    }
+}
+
+fn compile(regex: &str) -> Result<Regex, KvError> {
+    Regex::new(regex).map_err(|e| KvError::InvalidCommand(format!("Invalid pattern: {}", e)))
+}
+
+fn type_name(value: &Value) -> Option<&'static str> {
+    let name = match value.value.as_ref()? {
+        value::Value::String(_) => "string",
+        value::Value::Binary(_) => "binary",
+        value::Value::Integer(_) => "integer",
+        value::Value::Float(_) => "float",
+        value::Value::Bool(_) => "bool",
+        value::Value::Collection(_) => "collection",
+        value::Value::Json(_) => "json",
+    };
+    Some(name)
+}
+
+// The regex matching the whole key as the glob does; a '[' without its ']' is itself
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::from("(?s)^");
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                // a class has at least one char, a ']' right after '[' or '[!' is in it
+                let negate = chars.get(i + 1) == Some(&'!');
+                let first = i + 1 + negate as usize;
+                match chars.iter().skip(first + 1).position(|&c| c == ']') {
+                    Some(n) => {
+                        let end = first + 1 + n;
+                        regex.push_str(if negate { "[^" } else { "[" });
+                        for &c in &chars[first..end] {
+                            if matches!(c, '\\' | '[' | ']' | '^' | '&' | '~') {
+                                regex.push('\\');
+                            }
+                            regex.push(c);
+                        }
+                        regex.push(']');
+                        i = end;
+                    }
+                    None => regex.push_str(r"\["),
+                }
+            }
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+        i += 1;
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_should_match_the_whole_key() {
+        let cases = [
+            ("user:*", "user:42", true),
+            ("user:*", "admin:user:42", false),
+            ("user:?", "user:4", true),
+            ("user:?", "user:42", false),
+            ("k[12]", "k2", true),
+            ("k[!12]", "k2", false),
+            ("k[!12]", "k3", true),
+            ("k[a-c]", "kb", true),
+            ("k[]]", "k]", true),
+            ("a.b+", "a.b+", true),
+            ("a.b+", "axbb", false),
+            ("k[", "k[", true),
+        ];
+        for (glob, key, matched) in cases {
+            let re = compile(&glob_to_regex(glob)).unwrap();
+            assert_eq!(re.is_match(key), matched, "{} on {}", glob, key);
+        }
+    }
+}
@@ -0,0 +1,330 @@
+// Secondary indexes on the values of a table. An index keeps one kvpair per indexed key in a
+// table of its own, keyed by the encoded value followed by '\0' and the key, so Hfind reads a
+// range of the index rather than all the table. The writes of the commands go through set and
+// del here, which write the value and the entries of the indexes in one Storage::write_batch, so
+// sled and rocksdb never keep one without the other. Mutations are applied one at a time under
+// the lock of the replication log, and find checks the value of every entry it reads, so a
+// reader running beside a write never gets a key the index no longer holds.
+//
+// The encoding sorts as the values: a type tag, then the bool, the number, the string or the hex
+// of the binary. A number is the bits of the nearest float followed by the difference of an
+// integer from it, so integers beyond 2^53 stay apart and sort among the floats. Collections
+// and values missing the indexed JSON field are not indexed.
+
+use serde_json::Value as Json;
+
+use super::json::{self, JsonPath};
+use crate::*;
+
+// The table with the definitions of the indexes on table, the name to the JSON path
+fn indexes_table(table: &str) -> String {
+    format!("\0\0indexes\0{}", table)
+}
+
+// The table with the entries of the index on table
+fn entries_table(table: &str, name: &str) -> String {
+    format!("\0\0index\0{}\0{}", table, name)
+}
+
+struct Index {
+    entries: String,
+    path: Option<JsonPath>,
+}
+
+impl Index {
+    fn new(table: &str, name: &str, path: &str) -> Result<Self, KvError> {
+        let path = match path {
+            "" => None,
+            _ => Some(JsonPath::parse(path)?),
+        };
+        Ok(Self {
+            entries: entries_table(table, name),
+            path,
+        })
+    }
+
+    // The encoded value this index keeps for value, None when it's not indexed
+    fn encode(&self, value: &Value) -> Option<String> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return encode(value),
+        };
+        encode_json(path.get(&json::document(value)?)?)
+    }
+
+    // The writes moving the entry of key from the old value to the new one
+    fn update(&self, key: &str, old: Option<&Value>, new: Option<&Value>) -> Vec<Write> {
+        let old = old.and_then(|v| self.encode(v));
+        let new = new.and_then(|v| self.encode(v));
+        if old == new {
+            return Vec::new();
+        }
+        let mut writes = Vec::new();
+        if let Some(old) = old {
+            writes.push((self.entries.clone(), entry_key(&old, key), None));
+        }
+        if let Some(new) = new {
+            writes.push((self.entries.clone(), entry_key(&new, key), Some(key.into())));
+        }
+        writes
+    }
+}
+
+// A write of Storage::write_batch, the table, the key and the value or None to delete it
+type Write = (String, String, Option<Value>);
+
+// Set the value of key and update the indexes of table, returns the old value
+pub(crate) fn set(
+    store: &impl Storage,
+    table: &str,
+    key: String,
+    value: Value,
+) -> Result<Option<Value>, KvError> {
+    let indexes = load(store, table)?;
+    if indexes.is_empty() {
+        return store.set(table, key, value);
+    }
+    let old = store.get(table, &key)?;
+    let mut writes = Vec::new();
+    for index in &indexes {
+        writes.extend(index.update(&key, old.as_ref(), Some(&value)));
+    }
+    writes.insert(0, (table.to_string(), key, Some(value)));
+    Ok(store.write_batch(writes)?.swap_remove(0))
+}
+
+// Delete key, with the elements of a collection, and its entries in the indexes of table. A
+// collection isn't indexed
+pub(crate) fn del(store: &impl Storage, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    let indexes = load(store, table)?;
+    let old = match store.get(table, key)? {
+        Some(old) if !indexes.is_empty() && as_collection(&old).is_none() => old,
+        _ => return store.del_value(table, key),
+    };
+    let mut writes = vec![(table.to_string(), key.to_string(), None)];
+    for index in &indexes {
+        writes.extend(index.update(key, Some(&old), None));
+    }
+    Ok(store.write_batch(writes)?.swap_remove(0))
+}
+
+// Create the index and add the values already in table, returns how many are indexed
+pub(crate) fn create(
+    store: &impl Storage,
+    table: &str,
+    name: &str,
+    path: &str,
+) -> Result<u64, KvError> {
+    if name.is_empty() || name.contains('\0') {
+        return Err(KvError::InvalidCommand(format!("Invalid index name {:?}", name)));
+    }
+    let index = Index::new(table, name, path)?;
+    store.check_table(&index.entries)?;
+    if store.contains(&indexes_table(table), name)? {
+        return Err(KvError::InvalidCommand(format!(
+            "Index {} already exists on table {}",
+            name, table
+        )));
+    }
+
+    store.set(&indexes_table(table), name.into(), path.into())?;
+    let mut count = 0;
+    for pair in store.get_iter(table)? {
+        let pair = pair?;
+        let encoded = pair.value.as_ref().and_then(|v| index.encode(v));
+        if let Some(encoded) = encoded {
+            store.set(&index.entries, entry_key(&encoded, &pair.key), pair.key.into())?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Drop the index, returns how many entries are removed
+pub(crate) fn remove(store: &impl Storage, table: &str, name: &str) -> Result<u64, KvError> {
+    if store.del(&indexes_table(table), name)?.is_none() {
+        return Err(not_found(table, name));
+    }
+    let entries = entries_table(table, name);
+    let mut count = 0;
+    for pair in store.get_all(&entries)? {
+        store.del(&entries, &pair.key)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// The kvpairs whose indexed value is value, or between min and max, in the order of the index,
+// with their encoded values
+pub(crate) fn find(
+    store: &impl Storage,
+    table: &str,
+    name: &str,
+    value: Option<Value>,
+    min: Option<Value>,
+    max: Option<Value>,
+) -> Result<Vec<(String, Kvpair)>, KvError> {
+    let path = match store.get(&indexes_table(table), name)? {
+        Some(Value {
+            value: Some(value::Value::String(path)),
+        }) => path,
+        _ => return Err(not_found(table, name)),
+    };
+    let index = Index::new(table, name, &path)?;
+
+    let range = match (value, min, max) {
+        (Some(value), None, None) => Range::new(Some(&value), Some(&value))?,
+        (None, min, max) => Range::new(min.as_ref(), max.as_ref())?,
+        _ => None,
+    };
+    let range =
+        range.ok_or_else(|| KvError::InvalidCommand("Expect a value or a range to find".into()))?;
+    let (start, end) = range.entries();
+
+    let mut pairs = Vec::new();
+    for entry in store.get_range(&index.entries, &start, Some(&end))? {
+        let entry = entry?;
+        let key = match entry.value.and_then(|v| v.value) {
+            Some(value::Value::String(key)) => key,
+            _ => continue,
+        };
+        // the range could hold strings with '\0' out of the bounds, check the value itself
+        let value = match store.get(table, &key)? {
+            Some(value) => value,
+            None => continue,
+        };
+        let encoded = match index.encode(&value) {
+            Some(encoded) => encoded,
+            None => continue,
+        };
+        if range.contains_encoded(&encoded) && entry.key == entry_key(&encoded, &key) {
+            pairs.push((encoded, Kvpair::new(key, value)));
+        }
+    }
+    Ok(pairs)
+}
+
+// A range of values in the order of the indexes, within one type
+pub(crate) struct Range {
+    tag: String,
+    min: Option<String>,
+    max: Option<String>,
+}
+
+impl Range {
+    // None when neither min nor max is set, an open end stops at the next type
+    pub(crate) fn new(min: Option<&Value>, max: Option<&Value>) -> Result<Option<Self>, KvError> {
+        let bound = |value: &Value| {
+            encode(value).ok_or_else(|| {
+                KvError::InvalidCommand("A collection or NaN can't bound a range".into())
+            })
+        };
+        let min = min.map(bound).transpose()?;
+        let max = max.map(bound).transpose()?;
+        let tag = match (&min, &max) {
+            (Some(min), Some(max)) if min[..1] != max[..1] => {
+                return Err(KvError::InvalidCommand("Min and max are of different types".into()));
+            }
+            (Some(bound), _) | (_, Some(bound)) => bound[..1].to_string(),
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Self { tag, min, max }))
+    }
+
+    // Whether value is within the range, a collection or a JSON object never is
+    pub(crate) fn contains(&self, value: &Value) -> bool {
+        encode(value).is_some_and(|encoded| self.contains_encoded(&encoded))
+    }
+
+    fn contains_encoded(&self, encoded: &str) -> bool {
+        encoded.starts_with(&self.tag)
+            && self.min.as_ref().is_none_or(|min| encoded >= min.as_str())
+            && self.max.as_ref().is_none_or(|max| encoded <= max.as_str())
+    }
+
+    // The start and the end (not included) of the entries of an index within the range
+    fn entries(&self) -> (String, String) {
+        let start = self.min.clone().unwrap_or_else(|| self.tag.clone());
+        let end = match &self.max {
+            // the entries of max are max, '\0' and the key
+            Some(max) => format!("{}\u{1}", max),
+            None => ((self.tag.as_bytes()[0] + 1) as char).to_string(),
+        };
+        (start, end)
+    }
+}
+
+// The indexes on table
+fn load(store: &impl Storage, table: &str) -> Result<Vec<Index>, KvError> {
+    store
+        .get_all(&indexes_table(table))?
+        .into_iter()
+        .map(|pair| match pair.value.and_then(|v| v.value) {
+            Some(value::Value::String(path)) => Index::new(table, &pair.key, &path),
+            _ => Err(KvError::Internal(format!("Index {} is broken", pair.key))),
+        })
+        .collect()
+}
+
+fn entry_key(encoded: &str, key: &str) -> String {
+    format!("{}\0{}", encoded, key)
+}
+
+fn not_found(table: &str, name: &str) -> KvError {
+    KvError::NotFound(table.into(), format!("index {}", name))
+}
+
+fn encode(value: &Value) -> Option<String> {
+    match value.value.as_ref()? {
+        value::Value::Bool(v) => Some(format!("b{}", *v as u8)),
+        value::Value::Integer(v) => encode_integer(*v as i128),
+        value::Value::Float(v) => encode_float(*v),
+        value::Value::String(v) => Some(format!("s{}", v)),
+        value::Value::Binary(v) => {
+            let hex: String = v.iter().map(|v| format!("{:02x}", v)).collect();
+            Some(format!("x{}", hex))
+        }
+        value::Value::Json(text) => encode_json(&json::parse(text).ok()?),
+        value::Value::Collection(_) => None,
+    }
+}
+
+fn encode_json(value: &Json) -> Option<String> {
+    match value {
+        Json::Bool(v) => Some(format!("b{}", *v as u8)),
+        Json::Number(v) => match (v.as_i64(), v.as_u64()) {
+            (Some(v), _) => encode_integer(v as i128),
+            (None, Some(v)) => encode_integer(v as i128),
+            (None, None) => encode_float(v.as_f64()?),
+        },
+        Json::String(v) => Some(format!("s{}", v)),
+        _ => None,
+    }
+}
+
+// A float differs by 0 from itself
+fn encode_float(v: f64) -> Option<String> {
+    encode_number(v, 0)
+}
+
+// The nearest float is exact up to 2^53 and within 1024 of the integer up to 2^64, an integer
+// sorts after the floats below it and before the floats above it
+fn encode_integer(v: i128) -> Option<String> {
+    let nearest = v as f64;
+    encode_number(nearest, (v - nearest as i128) as i16)
+}
+
+// The bits of a float sort as the float once the sign bit of a positive one is set and all the
+// bits of a negative one are flipped; -0.0 is indexed as 0.0. The difference of an integer from
+// the float follows, offset to sort as a number
+fn encode_number(v: f64, diff: i16) -> Option<String> {
+    if v.is_nan() {
+        return None;
+    }
+    let bits = (v + 0.0).to_bits();
+    let bits = match bits >> 63 {
+        0 => bits | 1 << 63,
+        _ => !bits,
+    };
+    Some(format!("n{:016x}{:04x}", bits, (diff as i32 + 0x8000) as u16))
+}
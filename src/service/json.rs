@@ -0,0 +1,249 @@
+// JSON documents stored as Value::Json. The Json commands read and change a part of a document
+// on the server, so concurrent clients don't overwrite each other's changes: mutations are
+// applied one at a time under the lock of the replication log.
+//
+// A path starts with '$' for the whole document, followed by .field, ["field"] or [index].
+
+use serde_json::Value as Json;
+
+use crate::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Field(String),
+    Index(usize),
+}
+
+pub(crate) struct JsonPath {
+    path: String,
+    segments: Vec<Segment>,
+}
+
+impl JsonPath {
+    // An empty path is the whole document, the same as "$"
+    pub(crate) fn parse(path: &str) -> Result<Self, KvError> {
+        let invalid =
+            |msg: &str| KvError::InvalidCommand(format!("Invalid path {:?}: {}", path, msg));
+        let rest = match path {
+            "" => "",
+            _ => path.strip_prefix('$').ok_or_else(|| invalid("expect '$' first"))?,
+        };
+
+        let mut segments = Vec::new();
+        let mut chars = rest.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '.' => {
+                    let mut field = String::new();
+                    while let Some(c) = chars.next_if(|v| *v != '.' && *v != '[') {
+                        field.push(c);
+                    }
+                    if field.is_empty() {
+                        return Err(invalid("empty field"));
+                    }
+                    segments.push(Segment::Field(field));
+                }
+                '[' => {
+                    let segment = match chars.next_if_eq(&'"') {
+                        Some(quote) => {
+                            let (mut quoted, mut escaped) = (String::from(quote), false);
+                            for c in chars.by_ref() {
+                                quoted.push(c);
+                                if c == '"' && !escaped {
+                                    break;
+                                }
+                                escaped = c == '\\' && !escaped;
+                            }
+                            let field = serde_json::from_str(&quoted)
+                                .map_err(|_| invalid("invalid quoted field"))?;
+                            Segment::Field(field)
+                        }
+                        None => {
+                            let mut index = String::new();
+                            while let Some(c) = chars.next_if(|v| v.is_ascii_digit()) {
+                                index.push(c);
+                            }
+                            let index = index
+                                .parse()
+                                .map_err(|_| invalid("expect an index or a quoted field"))?;
+                            Segment::Index(index)
+                        }
+                    };
+                    if chars.next() != Some(']') {
+                        return Err(invalid("expect ']'"));
+                    }
+                    segments.push(segment);
+                }
+                _ => return Err(invalid("expect '.' or '['")),
+            }
+        }
+
+        Ok(Self {
+            path: path.into(),
+            segments,
+        })
+    }
+
+    pub(crate) fn is_root(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub(crate) fn get<'a>(&self, doc: &'a Json) -> Option<&'a Json> {
+        self.segments.iter().try_fold(doc, |v, segment| match segment {
+            Segment::Field(field) => v.get(field),
+            Segment::Index(i) => v.get(i),
+        })
+    }
+
+    pub(crate) fn get_mut<'a>(&self, doc: &'a mut Json) -> Option<&'a mut Json> {
+        walk_mut(&self.segments, doc)
+    }
+
+    // Set the value at the path, returns the old one. The parent must exist, a field is added
+    // to an object, an array could grow by one at its end
+    pub(crate) fn set(&self, doc: &mut Json, value: Json) -> Result<Option<Json>, KvError> {
+        let (last, parent) = match self.segments.split_last() {
+            Some((last, parent)) => (last, parent),
+            None => return Ok(Some(std::mem::replace(doc, value))),
+        };
+        let parent = walk_mut(parent, doc).ok_or_else(|| self.invalid("the parent doesn't exist"))?;
+
+        match (parent, last) {
+            (Json::Object(map), Segment::Field(field)) => Ok(map.insert(field.clone(), value)),
+            (Json::Array(items), Segment::Index(i)) if *i < items.len() => {
+                Ok(Some(std::mem::replace(&mut items[*i], value)))
+            }
+            (Json::Array(items), Segment::Index(i)) if *i == items.len() => {
+                items.push(value);
+                Ok(None)
+            }
+            (Json::Array(_), Segment::Index(_)) => Err(self.invalid("index out of range")),
+            _ => Err(self.invalid("the parent is not an object or an array")),
+        }
+    }
+
+    pub(crate) fn not_found(&self, table: &str, key: &str) -> KvError {
+        KvError::NotFound(table.into(), format!("{} at {}", key, self.path))
+    }
+
+    pub(crate) fn invalid(&self, msg: &str) -> KvError {
+        KvError::InvalidCommand(format!("Can't change {}: {}", self.path, msg))
+    }
+}
+
+fn walk_mut<'a>(segments: &[Segment], doc: &'a mut Json) -> Option<&'a mut Json> {
+    segments.iter().try_fold(doc, |v, segment| match segment {
+        Segment::Field(field) => v.get_mut(field),
+        Segment::Index(i) => v.get_mut(i),
+    })
+}
+
+// The document at key, None when there's no key
+pub(crate) fn load(
+    store: &impl Storage,
+    table: &str,
+    key: &str,
+) -> Result<Option<Json>, KvError> {
+    match store.get(table, key)? {
+        None => Ok(None),
+        Some(Value {
+            value: Some(value::Value::Json(text)),
+        }) => Ok(Some(parse(&text)?)),
+        Some(_) => Err(KvError::WrongType(table.into(), key.into(), "json")),
+    }
+}
+
+pub(crate) fn parse(text: &str) -> Result<Json, KvError> {
+    serde_json::from_str(text)
+        .map_err(|e| KvError::InvalidCommand(format!("Invalid JSON: {}", e)))
+}
+
+// The document in value, which could be kept as a json value or as a string
+pub(crate) fn document(value: &Value) -> Option<Json> {
+    match value.value.as_ref()? {
+        value::Value::Json(text) | value::Value::String(text) => parse(text).ok(),
+        _ => None,
+    }
+}
+
+// A json value set by Hset must be a valid document, the Json commands parse it later
+pub(crate) fn check_json(value: &Value) -> Result<(), KvError> {
+    match &value.value {
+        Some(value::Value::Json(text)) => parse(text).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+// Add by to the number, an integer stays one unless by is a float or it overflows
+pub(crate) fn incr(number: &Json, by: &Value) -> Result<Json, KvError> {
+    let by = match &by.value {
+        Some(value::Value::Integer(by)) => {
+            if let Some(sum) = number.as_i64().and_then(|n| n.checked_add(*by)) {
+                return Ok(sum.into());
+            }
+            *by as f64
+        }
+        Some(value::Value::Float(by)) => *by,
+        _ => return Err(KvError::InvalidCommand("Increment must be a number".into())),
+    };
+    let number = number
+        .as_f64()
+        .ok_or_else(|| KvError::InvalidCommand(format!("{} is not a number", number)))?;
+    let sum = number + by;
+    serde_json::Number::from_f64(sum)
+        .map(Json::Number)
+        .ok_or_else(|| KvError::InvalidCommand(format!("{} is not a JSON number", sum)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn path_should_parse() {
+        let path = JsonPath::parse("$.user[\"e.mail\"].items[2]").unwrap();
+        let expected = vec![
+            Segment::Field("user".into()),
+            Segment::Field("e.mail".into()),
+            Segment::Field("items".into()),
+            Segment::Index(2),
+        ];
+        assert_eq!(path.segments, expected);
+        assert!(JsonPath::parse("").unwrap().segments.is_empty());
+        assert!(JsonPath::parse("$").unwrap().segments.is_empty());
+
+        for path in ["user", "$.", "$..a", "$[a]", "$[-1]", "$x"] {
+            assert!(JsonPath::parse(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn path_should_get_and_set() {
+        let mut doc = json!({"user": {"tags": ["a"]}});
+        let path = JsonPath::parse("$.user.tags[0]").unwrap();
+        assert_eq!(path.get(&doc), Some(&json!("a")));
+        assert_eq!(path.set(&mut doc, json!("b")).unwrap(), Some(json!("a")));
+
+        let path = JsonPath::parse("$.user.tags[1]").unwrap();
+        assert_eq!(path.set(&mut doc, json!("c")).unwrap(), None);
+        let path = JsonPath::parse("$.user.email").unwrap();
+        assert_eq!(path.set(&mut doc, json!("x@y.z")).unwrap(), None);
+        assert_eq!(doc, json!({"user": {"tags": ["b", "c"], "email": "x@y.z"}}));
+
+        let path = JsonPath::parse("$.user.tags[5]").unwrap();
+        assert!(path.set(&mut doc, json!(1)).is_err());
+        let path = JsonPath::parse("$.group.name").unwrap();
+        assert!(path.set(&mut doc, json!(1)).is_err());
+    }
+
+    #[test]
+    fn incr_should_keep_integers() {
+        assert_eq!(incr(&json!(1), &2.into()).unwrap(), json!(3));
+        assert_eq!(incr(&json!(1), &0.5.into()).unwrap(), json!(1.5));
+        assert_eq!(incr(&json!(1.5), &1.into()).unwrap(), json!(2.5));
+        assert_eq!(incr(&json!(i64::MAX), &1.into()).unwrap(), json!(i64::MAX as f64 + 1.0));
+        assert!(incr(&json!("1"), &1.into()).is_err());
+        assert!(incr(&json!(1), &"1".into()).is_err());
+    }
+}
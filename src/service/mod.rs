@@ -1,9 +1,30 @@
 use crate::command_request::RequestData;
 use crate::*;
+use std::path::PathBuf;
 use std::sync::Arc;
+use tokio::sync::broadcast;
 use tracing::debug;
 
+mod backup;
 mod command_service;
+mod context;
+mod filter;
+mod index;
+mod json;
+mod replication;
+mod watch;
+
+pub use backup::{read_manifest, verify_backup, BackupManifest, TableDigest};
+pub use context::{ConnectionContext, PeerIdentity};
+pub use replication::{FollowerStats, PrimaryStats, ReplicaSource, ReplicationStats};
+use replication::Replication;
+pub use watch::Watcher;
+
+// How many applied mutations a slow subscriber could lag behind
+const CHANGES_CAPACITY: usize = 1024;
+
+// Hook to allow or reject a command from the connection
+pub type AuthorizeFn = fn(&CommandRequest, &ConnectionContext) -> Result<(), KvError>;
 
 pub trait CommandService {
     // Handle the command and return a Response
@@ -18,9 +39,14 @@ pub struct Service<Store = MemTable> {
 // Inner Struct of Service
 pub struct ServiceInner<Store> {
     store: Store,
-    on_received: Vec<fn(&CommandRequest)>,
-    on_executed: Vec<fn(&CommandResponse)>,
-    on_before_send: Vec<fn(&mut CommandResponse)>,
+    changes: broadcast::Sender<Arc<ReplicaLog>>,
+    replication: Replication,
+    // where the Backup command writes to, it's refused when not set
+    backup_dir: Option<PathBuf>,
+    on_received: Vec<fn(&CommandRequest, &ConnectionContext)>,
+    on_authorize: Vec<AuthorizeFn>,
+    on_executed: Vec<fn(&CommandResponse, &ConnectionContext)>,
+    on_before_send: Vec<fn(&mut CommandResponse, &ConnectionContext)>,
     on_after_send: Vec<fn()>,
 }
 
@@ -33,43 +59,135 @@ impl<Store> Clone for Service<Store> {
 }
 
 impl<Store: Storage> Service<Store> {
-    pub fn execute(&self, cmd: CommandRequest) -> CommandResponse {
-        debug!("Got request: {:?}", cmd);
-        self.inner.on_received.notify(&cmd);
-        let mut res = dispatch(cmd, &self.inner.store);
+    pub fn execute(&self, cmd: CommandRequest, ctx: &ConnectionContext) -> CommandResponse {
+        debug!("Got request: {:?} from {:?}", cmd, ctx);
+        let allowed = self.admit(&cmd, ctx).and_then(|_| self.check_writable(&cmd));
+        let res = match allowed {
+            Ok(()) => self.dispatch(cmd),
+            Err(e) => e.into(),
+        };
+        self.respond(res, ctx)
+    }
+
+    // Run the received and authorize hooks before the command is executed, and refuse what
+    // only the service writes itself
+    pub(crate) fn admit(
+        &self,
+        cmd: &CommandRequest,
+        ctx: &ConnectionContext,
+    ) -> Result<(), KvError> {
+        self.inner.on_received.notify(cmd, ctx);
+        self.authorize(cmd, ctx).and_then(|_| check_client(cmd))
+    }
+
+    // Run the executed and before_send hooks on the response
+    pub(crate) fn respond(
+        &self,
+        mut res: CommandResponse,
+        ctx: &ConnectionContext,
+    ) -> CommandResponse {
         debug!("Executed response: {:?}", res);
-        self.inner.on_executed.notify(&res);
-        self.inner.on_before_send.notify(&mut res);
+        self.inner.on_executed.notify(&res, ctx);
+        self.inner.on_before_send.notify(&mut res, ctx);
         if !self.inner.on_before_send.is_empty() {
             debug!("Modified response: {:?}", res)
         }
 
         res
     }
+
+    // Every authorize hook should allow the command
+    fn authorize(&self, cmd: &CommandRequest, ctx: &ConnectionContext) -> Result<(), KvError> {
+        self.inner.on_authorize.iter().try_for_each(|f| f(cmd, ctx))
+    }
+
+    fn dispatch(&self, cmd: CommandRequest) -> CommandResponse {
+        if let Some(RequestData::Backup(backup)) = &cmd.request_data {
+            return self.backup_command(&backup.name);
+        }
+        match cmd.is_mutation() {
+            true => self.dispatch_mutation(cmd),
+            false => dispatch(cmd, &self.inner.store),
+        }
+    }
+
+    // Iterate the Kvpair of a table, only those matching the filter when it's set
+    pub fn scan(
+        &self,
+        table: &str,
+        filter: Option<ScanFilter>,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError> {
+        check_table(table)?;
+        let filter = match filter {
+            Some(filter) => filter::Filter::new(filter)?,
+            None => return self.inner.store.get_iter(table),
+        };
+        if filter.count_only {
+            return Err(KvError::InvalidCommand(
+                "A scan streams the Kvpair, count them with Hgetall".into(),
+            ));
+        }
+        let iter = self.inner.store.get_iter(table)?;
+        Ok(Box::new(iter.filter_map(move |pair| pair.map(|v| filter.apply(v)).transpose())))
+    }
+
+    // Receive the mutating commands successfully applied from now on, with their sequence numbers
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<ReplicaLog>> {
+        self.inner.changes.subscribe()
+    }
+
+    // The store, for what's particular to its backend. Writing to it directly bypasses the
+    // replication log
+    pub fn store(&self) -> &Store {
+        &self.inner.store
+    }
 }
 
 impl<Store: Storage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
+        let (changes, _) = broadcast::channel(CHANGES_CAPACITY);
         Self {
             store,
+            changes,
+            replication: Replication::new(None),
+            backup_dir: None,
             on_received: Vec::new(),
+            on_authorize: Vec::new(),
             on_executed: Vec::new(),
             on_before_send: Vec::new(),
             on_after_send: Vec::new(),
         }
     }
 
-    pub fn fn_received(mut self, f: fn(&CommandRequest)) -> Self {
+    // Follow the primary at addr: writes from clients are refused, see Service::apply_replica
+    pub fn follower_of(mut self, addr: impl Into<String>) -> Self {
+        self.replication = Replication::new(Some(addr.into()));
+        self
+    }
+
+    // Accept the Backup command, which writes the backups into dir
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    pub fn fn_received(mut self, f: fn(&CommandRequest, &ConnectionContext)) -> Self {
         self.on_received.push(f);
         self
     }
 
-    pub fn fn_executed(mut self, f: fn(&CommandResponse)) -> Self {
+    // Reject the command with the returned error before it is executed
+    pub fn fn_authorize(mut self, f: AuthorizeFn) -> Self {
+        self.on_authorize.push(f);
+        self
+    }
+
+    pub fn fn_executed(mut self, f: fn(&CommandResponse, &ConnectionContext)) -> Self {
         self.on_executed.push(f);
         self
     }
 
-    pub fn fn_before_send(mut self, f: fn(&mut CommandResponse)) -> Self {
+    pub fn fn_before_send(mut self, f: fn(&mut CommandResponse, &ConnectionContext)) -> Self {
         self.on_before_send.push(f);
         self
     }
@@ -89,40 +207,106 @@ impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
 }
 
 pub trait Notify<Arg> {
-    fn notify(&self, arg: &Arg);
+    fn notify(&self, arg: &Arg, ctx: &ConnectionContext);
 }
 
 pub trait NotifyMut<Arg> {
-    fn notify(&self, arg: &mut Arg);
+    fn notify(&self, arg: &mut Arg, ctx: &ConnectionContext);
 }
 
-impl<Arg> Notify<Arg> for Vec<fn(&Arg)> {
+impl<Arg> Notify<Arg> for Vec<fn(&Arg, &ConnectionContext)> {
     #[inline]
-    fn notify(&self, arg: &Arg) {
+    fn notify(&self, arg: &Arg, ctx: &ConnectionContext) {
         for f in self {
-            f(arg)
+            f(arg, ctx)
         }
     }
 }
 
-impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)> {
+impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg, &ConnectionContext)> {
     #[inline]
-    fn notify(&self, arg: &mut Arg) {
+    fn notify(&self, arg: &mut Arg, ctx: &ConnectionContext) {
         for f in self {
-            f(arg)
+            f(arg, ctx)
         }
     }
 }
 
-// Get Response from Request, Handle HGET/HGETALL/HSET for now
+// Commands handled by dispatch, advertised in the Hello handshake
+pub const SUPPORTED_COMMANDS: &[&str] = &[
+    "hget", "hgetall", "hmget", "hset", "hmset", "hdel", "hmdel", "hexist", "hmexist", "backup",
+    "list_tables", "lpush", "lpop", "lrange", "sadd", "srem", "smembers", "zadd", "zrange",
+    "zrangebyscore", "json_get", "json_set", "json_append", "json_incr", "create_index",
+    "drop_index", "hfind",
+];
+
+// The tables starting with '\0' are the elements of collections and the indexes, they're
+// written with their commands only
+fn check_table(table: &str) -> Result<(), KvError> {
+    match table.starts_with('\0') {
+        true => Err(KvError::InvalidCommand(format!("Table {:?} is internal", table))),
+        false => Ok(()),
+    }
+}
+
+// A command of a client can't touch the internal tables, nor set a collection header without
+// its elements. The snapshots, the replication and the imports dispatch the headers directly
+fn check_client(cmd: &CommandRequest) -> Result<(), KvError> {
+    if let Some(table) = cmd.table() {
+        check_table(table)?;
+    }
+    let pairs = match &cmd.request_data {
+        Some(RequestData::Hset(v)) => v.pair.iter().collect(),
+        Some(RequestData::Hmset(v)) => v.pairs.iter().collect(),
+        _ => Vec::new(),
+    };
+    match pairs.iter().any(|v| v.value.as_ref().and_then(as_collection).is_some()) {
+        true => Err(KvError::InvalidCommand(
+            "A collection is written with Lpush, Sadd or Zadd".into(),
+        )),
+        false => Ok(()),
+    }
+}
+
+// Get Response from Request
 pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
     match cmd.request_data {
         Some(RequestData::Hget(hget)) => hget.execute(store),
         Some(RequestData::Hgetall(hget_all)) => hget_all.execute(store),
         Some(RequestData::Hset(hset)) => hset.execute(store),
         Some(RequestData::Hdel(hdel)) => hdel.execute(store),
+        Some(RequestData::Hmget(hmget)) => hmget.execute(store),
+        Some(RequestData::Hmset(hmset)) => hmset.execute(store),
+        Some(RequestData::Hmdel(hmdel)) => hmdel.execute(store),
+        Some(RequestData::Hexist(hexist)) => hexist.execute(store),
+        Some(RequestData::Hmexist(hmexist)) => hmexist.execute(store),
+        Some(RequestData::ListTables(list_tables)) => list_tables.execute(store),
+        Some(RequestData::Lpush(lpush)) => lpush.execute(store),
+        Some(RequestData::Lpop(lpop)) => lpop.execute(store),
+        Some(RequestData::Lrange(lrange)) => lrange.execute(store),
+        Some(RequestData::Sadd(sadd)) => sadd.execute(store),
+        Some(RequestData::Srem(srem)) => srem.execute(store),
+        Some(RequestData::Smembers(smembers)) => smembers.execute(store),
+        Some(RequestData::Zadd(zadd)) => zadd.execute(store),
+        Some(RequestData::Zrange(zrange)) => zrange.execute(store),
+        Some(RequestData::Zrangebyscore(zrangebyscore)) => zrangebyscore.execute(store),
+        Some(RequestData::JsonGet(json_get)) => json_get.execute(store),
+        Some(RequestData::JsonSet(json_set)) => json_set.execute(store),
+        Some(RequestData::JsonAppend(json_append)) => json_append.execute(store),
+        Some(RequestData::JsonIncr(json_incr)) => json_incr.execute(store),
+        Some(RequestData::CreateIndex(create_index)) => create_index.execute(store),
+        Some(RequestData::DropIndex(drop_index)) => drop_index.execute(store),
+        Some(RequestData::Hfind(hfind)) => hfind.execute(store),
+        Some(RequestData::Replicate(_)) => {
+            KvError::InvalidCommand("Replicate is only served by the native listener".into()).into()
+        }
+        Some(RequestData::Watch(_)) => {
+            KvError::InvalidCommand("Watch is only served by the native listener".into()).into()
+        }
+        Some(RequestData::Backup(_)) => {
+            KvError::InvalidCommand("Backup is only served by a Service".into()).into()
+        }
         None => KvError::InvalidCommand("Request has no data".into()).into(),
-        _ => KvError::Internal("Not implemented".into()).into(),
     }
 }
 
@@ -144,25 +328,27 @@ mod tests {
 
         // Create a thread, and insert k1, v1 to table {{t1}}
         let handle = thread::spawn(move || {
-            let res = cloned.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+            let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+            let res = cloned.execute(cmd, &ConnectionContext::default());
             assert_res_ok(res, &[Value::default()], &[]);
         });
         handle.join().unwrap();
 
         // In current thread, read value of key {{k1}} in table {{t1}} , it should return {{v1}}
-        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = service.execute(cmd, &ConnectionContext::default());
         assert_res_ok(res, &["v1".into()], &[]);
     }
 
     #[test]
     fn event_registration_should_work() {
-        fn test_received_request(cmd: &CommandRequest) {
-            info!("Got {:?}", cmd);
+        fn test_received_request(cmd: &CommandRequest, ctx: &ConnectionContext) {
+            info!("Got {:?} from {:?}", cmd, ctx);
         }
-        fn test_execute_request(res: &CommandResponse) {
+        fn test_execute_request(res: &CommandResponse, _ctx: &ConnectionContext) {
             info!("{:?}", res);
         }
-        fn test_modify_response(res: &mut CommandResponse) {
+        fn test_modify_response(res: &mut CommandResponse, _ctx: &ConnectionContext) {
             res.status = StatusCode::CREATED.as_u16() as _;
         }
 
@@ -171,18 +357,77 @@ mod tests {
         }
 
         let service: Service = ServiceInner::new(MemTable::default())
-            .fn_received(|_: &CommandRequest| {})
+            .fn_received(|_: &CommandRequest, _: &ConnectionContext| {})
             .fn_received(test_received_request)
             .fn_executed(test_execute_request)
             .fn_before_send(test_modify_response)
             .fn_after_send(test_send_response)
             .into();
 
-        let res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
-        assert_eq!(res.status, StatusCode::CREATED.as_u16() as _);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = service.execute(cmd, &ConnectionContext::default());
+        assert_eq!(res.status, StatusCode::CREATED.as_u16() as u32);
         assert_eq!(res.message, "");
         assert_eq!(res.values, vec![Value::default()]);
     }
+
+    #[test]
+    fn authorize_should_use_connection_context() {
+        fn only_admin_writes(cmd: &CommandRequest, ctx: &ConnectionContext) -> Result<(), KvError> {
+            let cn = ctx.identity.as_ref().and_then(|v| v.common_name.as_deref());
+            match (cmd.is_mutation(), cn) {
+                (true, Some("admin")) | (false, _) => Ok(()),
+                (true, _) => Err(KvError::PermissionDenied("only admin could write".into())),
+            }
+        }
+
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_authorize(only_admin_writes)
+            .into();
+
+        let anonymous = ConnectionContext::new(None);
+        let admin = ConnectionContext {
+            identity: Some(PeerIdentity {
+                common_name: Some("admin".into()),
+                ..Default::default()
+            }),
+            ..ConnectionContext::new(None)
+        };
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = service.execute(cmd.clone(), &anonymous);
+        assert_res_error(res, 403, "only admin could write");
+
+        let res = service.execute(cmd, &admin);
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"), &anonymous);
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn clients_should_not_write_internal_data() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let ctx = ConnectionContext::default();
+        service.execute(CommandRequest::new_lpush("t1", "l", vec!["a".into()]), &ctx);
+
+        let header = Collection {
+            kind: "list".into(),
+            len: 3,
+            ..Default::default()
+        };
+        let res = service.execute(CommandRequest::new_hset("t1", "k", header.into()), &ctx);
+        assert_res_error(res, 400, "Lpush, Sadd or Zadd");
+        let table = elements_table("t1", "l");
+        let res = service.execute(CommandRequest::new_hset(&table, "-2", "b".into()), &ctx);
+        assert_res_error(res, 400, "internal");
+        let res = service.execute(CommandRequest::new_hget_all(&table), &ctx);
+        assert_res_error(res, 400, "internal");
+        assert!(service.scan(&table, None).is_err());
+
+        let res = service.execute(CommandRequest::new_lrange("t1", "l", 0, -1), &ctx);
+        assert_res_ok(res, &["a".into()], &[]);
+    }
 }
 
 #[cfg(test)]
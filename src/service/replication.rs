@@ -0,0 +1,540 @@
+// Leader-follower replication. Every mutation applied by a Service gets a sequence number and is
+// kept in a bounded log. A follower resumes from the tail of the log after a disconnect, or
+// starts over from a snapshot when the tail it needs is gone.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use http::StatusCode;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::command_request::RequestData;
+use crate::replica_entry::Entry;
+use crate::*;
+use super::watch;
+
+// How many mutations are kept for the followers to catch up from
+const LOG_CAPACITY: usize = 10240;
+
+type ReplicaLogs = VecDeque<Arc<ReplicaLog>>;
+
+// Sequence numbers and recent mutations of a Service, plus the primary it follows if any
+pub(super) struct Replication {
+    // Sequence numbers restart with the process, a follower with another log id needs a snapshot
+    id: u64,
+    log: Mutex<ReplicaLogs>,
+    seq: AtomicU64,
+    followers: DashMap<u64, Arc<FollowerProgress>>,
+    next_follower: AtomicU64,
+    primary: Option<PrimaryProgress>,
+    // set when the mutations are ordered by a RaftNode
+    clustered: AtomicBool,
+}
+
+#[derive(Default)]
+struct FollowerProgress {
+    peer: Option<SocketAddr>,
+    sent_seq: AtomicU64,
+}
+
+#[derive(Default)]
+struct PrimaryProgress {
+    addr: String,
+    connected: AtomicBool,
+    // 0 until the first snapshot is fully applied
+    log_id: AtomicU64,
+    applied_seq: AtomicU64,
+    primary_seq: AtomicU64,
+}
+
+/// Replication state of a Service, served at `GET /stats` of the HTTP gateway
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicationStats {
+    pub log_id: u64,
+    /// Sequence number of the latest mutation applied
+    pub seq: u64,
+    /// Followers streaming from this service
+    pub followers: Vec<FollowerStats>,
+    /// The primary this service follows, None for a primary
+    pub primary: Option<PrimaryStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FollowerStats {
+    pub peer: Option<SocketAddr>,
+    pub sent_seq: u64,
+    /// How many mutations are not sent to the follower yet
+    pub lag: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrimaryStats {
+    pub addr: String,
+    pub connected: bool,
+    pub applied_seq: u64,
+    pub primary_seq: u64,
+    /// How many mutations of the primary are not applied yet
+    pub lag: u64,
+}
+
+// Recent mutations in the log, followed by the ones received from changes
+pub(super) struct LogTail {
+    pub logs: Vec<Arc<ReplicaLog>>,
+    pub changes: broadcast::Receiver<Arc<ReplicaLog>>,
+    // the latest seq when the tail was taken
+    pub seq: u64,
+}
+
+/// Entries streamed to a follower: a snapshot if needed, then the mutations from its next_seq
+pub struct ReplicaSource<Store = MemTable> {
+    service: Service<Store>,
+    id: u64,
+    pending: VecDeque<Entry>,
+    changes: broadcast::Receiver<Arc<ReplicaLog>>,
+    progress: Arc<FollowerProgress>,
+}
+
+impl Replication {
+    pub(super) fn new(primary: Option<String>) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self {
+            id: (now.as_nanos() as u64).max(1),
+            log: Mutex::new(VecDeque::new()),
+            seq: AtomicU64::new(0),
+            followers: DashMap::new(),
+            next_follower: AtomicU64::new(0),
+            primary: primary.map(|addr| PrimaryProgress {
+                addr,
+                ..Default::default()
+            }),
+            clustered: AtomicBool::new(false),
+        }
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    // Apply a mutation and record it in the log. The lock keeps the order of the log the same
+    // as the order the mutations are applied in, and lets a snapshot see the store at a seq. So
+    // the writes of a service go one at a time, a tokio worker waiting for the lock hands its
+    // other tasks over to the rest of the workers
+    pub(crate) fn dispatch_mutation(&self, cmd: CommandRequest) -> CommandResponse {
+        let replication = &self.inner.replication;
+        let mut log = lock_log(&replication.log);
+        let old_values = watch::old_values(&cmd, &self.inner.store);
+        let res = dispatch(cmd.clone(), &self.inner.store);
+        if res.status == StatusCode::OK.as_u16() as u32 {
+            let seq = replication.seq.fetch_add(1, Ordering::SeqCst) + 1;
+            let new_values = watch::new_values(&cmd, &self.inner.store);
+            let entry = Arc::new(ReplicaLog {
+                seq,
+                command: Some(cmd),
+                old_values: old_values.unwrap_or_else(|| res.values.clone()),
+                new_values,
+            });
+            log.push_back(entry.clone());
+            if log.len() > LOG_CAPACITY {
+                log.pop_front();
+            }
+            // No subscriber is not an error
+            let _ = self.inner.changes.send(entry);
+        }
+        res
+    }
+
+    // Run f with the seq of the latest mutation, no mutation is applied until it returns
+    pub(super) fn hold_writes<T>(&self, f: impl FnOnce(u64) -> T) -> T {
+        let _log = lock_log(&self.inner.replication.log);
+        f(self.inner.replication.seq.load(Ordering::SeqCst))
+    }
+
+    // Clients can't write to a follower, the writes would be lost on the next snapshot. In a
+    // raft cluster the writes must go through the RaftNode to be ordered
+    pub(super) fn check_writable(&self, cmd: &CommandRequest) -> Result<(), KvError> {
+        if !cmd.is_mutation() {
+            return Ok(());
+        }
+        if let Some(v) = &self.inner.replication.primary {
+            return Err(KvError::ReadOnlyReplica(v.addr.clone()));
+        }
+        match self.inner.replication.clustered.load(Ordering::SeqCst) {
+            true => Err(KvError::InvalidCommand(
+                "Writes of a raft cluster go through the native listener".into(),
+            )),
+            false => Ok(()),
+        }
+    }
+
+    pub(crate) fn set_clustered(&self) {
+        self.inner.replication.clustered.store(true, Ordering::SeqCst);
+    }
+
+    // Delete everything in the store before a snapshot is applied
+    pub(crate) fn clear(&self) -> Result<(), KvError> {
+        for table in self.inner.store.get_tables()? {
+            for pair in self.inner.store.get_all(&table)? {
+                self.apply(CommandRequest::new_hdel(&table, pair.key))?;
+            }
+        }
+        Ok(())
+    }
+
+    // All the kvpairs in the store as Hset commands, which rebuild the store when applied
+    pub(crate) fn snapshot(&self) -> Result<Vec<Hset>, KvError> {
+        let mut pairs = Vec::new();
+        for table in self.inner.store.get_tables()? {
+            let hsets = self.inner.store.get_all(&table)?.into_iter().map(|pair| Hset {
+                table: table.clone(),
+                pair: Some(pair),
+            });
+            pairs.extend(hsets);
+        }
+        Ok(pairs)
+    }
+
+    /// Start streaming to a follower which sent the Replicate command
+    pub fn replicate(
+        &self,
+        cmd: CommandRequest,
+        ctx: &ConnectionContext,
+    ) -> Result<ReplicaSource<Store>, KvError> {
+        self.admit(&cmd, ctx)?;
+        let req = match cmd.request_data {
+            Some(RequestData::Replicate(req)) => req,
+            _ => return Err(KvError::InvalidCommand("Expect a Replicate command".into())),
+        };
+
+        let replication = &self.inner.replication;
+        let log = lock_log(&replication.log);
+        // subscribe with the lock held so no mutation is missed between the log and the channel
+        let changes = self.inner.changes.subscribe();
+        let seq = replication.seq.load(Ordering::SeqCst);
+        let resumable = req.log_id == replication.id
+            && req.next_seq > 0
+            && req.next_seq <= seq + 1
+            && log.front().map_or(req.next_seq == seq + 1, |v| v.seq <= req.next_seq);
+
+        let pending = match resumable {
+            true => {
+                let tail = log.iter().filter(|v| v.seq >= req.next_seq);
+                tail.map(|v| replica_log(v)).collect()
+            }
+            false => {
+                // read with the log locked, the snapshot is the store at seq and the mutations
+                // from the channel are the ones after it
+                let pairs = self.snapshot()?;
+                drop(log);
+                let snapshot = ReplicaSnapshot {
+                    log_id: replication.id,
+                    seq,
+                };
+                let mut pending = VecDeque::from([Entry::SnapshotBegin(snapshot.clone())]);
+                pending.extend(pairs.into_iter().map(Entry::Snapshot));
+                pending.push_back(Entry::SnapshotEnd(snapshot));
+                pending
+            }
+        };
+
+        let id = replication.next_follower.fetch_add(1, Ordering::SeqCst);
+        let progress = Arc::new(FollowerProgress {
+            peer: ctx.peer_addr,
+            sent_seq: AtomicU64::new(match resumable {
+                true => req.next_seq - 1,
+                false => 0,
+            }),
+        });
+        replication.followers.insert(id, progress.clone());
+
+        Ok(ReplicaSource {
+            service: self.clone(),
+            id,
+            pending,
+            changes,
+            progress,
+        })
+    }
+
+    /// Apply an entry streamed by the primary, only a follower accepts it
+    pub fn apply_replica(&self, entry: ReplicaEntry) -> Result<(), KvError> {
+        let primary = self.primary_progress()?;
+        primary.primary_seq.store(entry.head_seq, Ordering::SeqCst);
+
+        match entry.entry {
+            Some(Entry::SnapshotBegin(_)) => {
+                // an interrupted snapshot can't be resumed
+                primary.log_id.store(0, Ordering::SeqCst);
+                primary.applied_seq.store(0, Ordering::SeqCst);
+                self.clear()?;
+            }
+            Some(Entry::Snapshot(hset)) => {
+                self.apply(CommandRequest {
+                    request_data: Some(RequestData::Hset(hset)),
+                })?;
+            }
+            Some(Entry::SnapshotEnd(snapshot)) => {
+                primary.applied_seq.store(snapshot.seq, Ordering::SeqCst);
+                primary.log_id.store(snapshot.log_id, Ordering::SeqCst);
+            }
+            Some(Entry::Log(log)) => {
+                let applied = primary.applied_seq.load(Ordering::SeqCst);
+                if primary.log_id.load(Ordering::SeqCst) == 0 || log.seq != applied + 1 {
+                    return Err(KvError::ReplicationError(format!(
+                        "expect seq {}, got {}",
+                        applied + 1,
+                        log.seq
+                    )));
+                }
+                let cmd = log.command.ok_or_else(|| {
+                    KvError::ReplicationError(format!("seq {} has no command", log.seq))
+                })?;
+                self.apply(cmd)?;
+                primary.applied_seq.store(log.seq, Ordering::SeqCst);
+            }
+            None => return Err(KvError::ReplicationError("empty replica entry".into())),
+        }
+        Ok(())
+    }
+
+    pub fn replication_stats(&self) -> ReplicationStats {
+        let replication = &self.inner.replication;
+        let seq = replication.seq.load(Ordering::SeqCst);
+        let mut followers: Vec<_> = replication
+            .followers
+            .iter()
+            .map(|v| {
+                let sent_seq = v.sent_seq.load(Ordering::SeqCst);
+                FollowerStats {
+                    peer: v.peer,
+                    sent_seq,
+                    lag: seq.saturating_sub(sent_seq),
+                }
+            })
+            .collect();
+        followers.sort_by_key(|v| v.peer);
+
+        let primary = replication.primary.as_ref().map(|v| {
+            let applied_seq = v.applied_seq.load(Ordering::SeqCst);
+            let primary_seq = v.primary_seq.load(Ordering::SeqCst);
+            PrimaryStats {
+                addr: v.addr.clone(),
+                connected: v.connected.load(Ordering::SeqCst),
+                applied_seq,
+                primary_seq,
+                lag: primary_seq.saturating_sub(applied_seq),
+            }
+        });
+
+        ReplicationStats {
+            log_id: replication.id,
+            seq,
+            followers,
+            primary,
+        }
+    }
+
+    // The Replicate command a follower sends to resume from where it stopped
+    pub(crate) fn replicate_request(&self) -> Result<CommandRequest, KvError> {
+        let primary = self.primary_progress()?;
+        let log_id = primary.log_id.load(Ordering::SeqCst);
+        let next_seq = match log_id {
+            0 => 0,
+            _ => primary.applied_seq.load(Ordering::SeqCst) + 1,
+        };
+        Ok(CommandRequest::new_replicate(log_id, next_seq))
+    }
+
+    pub(crate) fn set_primary_connected(&self, connected: bool) {
+        if let Ok(primary) = self.primary_progress() {
+            primary.connected.store(connected, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn apply(&self, cmd: CommandRequest) -> Result<(), KvError> {
+        let res = self.dispatch_mutation(cmd);
+        match res.status == StatusCode::OK.as_u16() as u32 {
+            true => Ok(()),
+            false => Err(KvError::ReplicationError(res.message)),
+        }
+    }
+
+    // The mutations from from_seq in the log, and the channel of the mutations after them
+    pub(super) fn tail(&self, from_seq: u64) -> Result<LogTail, KvError> {
+        let log = lock_log(&self.inner.replication.log);
+        let changes = self.inner.changes.subscribe();
+        let seq = self.inner.replication.seq.load(Ordering::SeqCst);
+        if from_seq == 0 {
+            return Ok(LogTail {
+                logs: vec![],
+                changes,
+                seq,
+            });
+        }
+        if from_seq > seq + 1 {
+            let msg = format!("seq {} is ahead of the latest seq {}", from_seq, seq);
+            return Err(KvError::InvalidCommand(msg));
+        }
+        let oldest = log.front().map_or(seq + 1, |v| v.seq);
+        if from_seq < oldest {
+            return Err(KvError::SeqCompacted(from_seq, oldest));
+        }
+        let logs = log.iter().filter(|v| v.seq >= from_seq).cloned().collect();
+        Ok(LogTail { logs, changes, seq })
+    }
+
+    fn primary_progress(&self) -> Result<&PrimaryProgress, KvError> {
+        self.inner
+            .replication
+            .primary
+            .as_ref()
+            .ok_or_else(|| KvError::ReplicationError("not a follower".into()))
+    }
+}
+
+impl<Store: Storage> ReplicaSource<Store> {
+    /// The next entry to send, waits for new mutations once the follower is caught up.
+    /// A follower lagging too far behind gets an error, and a snapshot when it reconnects
+    pub async fn next(&mut self) -> Result<ReplicaEntry, KvError> {
+        loop {
+            if let Some(entry) = self.pending.pop_front() {
+                match &entry {
+                    Entry::Log(log) => self.progress.sent_seq.store(log.seq, Ordering::SeqCst),
+                    Entry::SnapshotEnd(v) => self.progress.sent_seq.store(v.seq, Ordering::SeqCst),
+                    _ => {}
+                }
+                let head_seq = self.service.inner.replication.seq.load(Ordering::SeqCst);
+                return Ok(ReplicaEntry {
+                    head_seq,
+                    entry: Some(entry),
+                });
+            }
+
+            match self.changes.recv().await {
+                Ok(log) => self.pending.push_back(replica_log(&log)),
+                Err(RecvError::Lagged(n)) => {
+                    let msg = format!("follower lagged behind by {} commands", n);
+                    return Err(KvError::ReplicationError(msg));
+                }
+                Err(RecvError::Closed) => {
+                    return Err(KvError::ReplicationError("service is closed".into()))
+                }
+            }
+        }
+    }
+}
+
+// Lock the log, on a worker of a multi-thread runtime the worker is blocked in place so that
+// its other tasks go on while it waits
+fn lock_log(log: &Mutex<ReplicaLogs>) -> MutexGuard<'_, ReplicaLogs> {
+    if let Ok(guard) = log.try_lock() {
+        return guard;
+    }
+    blocking(|| log.lock().unwrap())
+}
+
+// Run f, which blocks, handing the other tasks of the worker over to the rest of the workers of
+// a multi-thread runtime. Elsewhere it just runs
+pub(super) fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(v) if v.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+// The old and new values are for the watchers of the primary, the follower has its own
+fn replica_log(log: &ReplicaLog) -> Entry {
+    Entry::Log(ReplicaLog {
+        seq: log.seq,
+        command: log.command.clone(),
+        old_values: vec![],
+        new_values: vec![],
+    })
+}
+
+impl<Store> Drop for ReplicaSource<Store> {
+    fn drop(&mut self) {
+        self.service.inner.replication.followers.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok};
+
+    #[tokio::test]
+    async fn follower_should_catch_up_from_snapshot_and_log() -> Result<(), KvError> {
+        let primary: Service = ServiceInner::new(MemTable::new()).into();
+        let follower: Service = ServiceInner::new(MemTable::new())
+            .follower_of("127.0.0.1:9527")
+            .into();
+        let ctx = ConnectionContext::default();
+
+        primary.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &ctx);
+        primary.execute(CommandRequest::new_hset("t1", "k2", "v2".into()), &ctx);
+        // stale data of the follower is dropped by the snapshot
+        follower.apply(CommandRequest::new_hset("t2", "k0", "v0".into()))?;
+
+        let mut source = primary.replicate(follower.replicate_request()?, &ctx)?;
+        primary.execute(CommandRequest::new_hdel("t1", "k1"), &ctx);
+        // snapshot begin, k1, k2, snapshot end, then the hdel after the snapshot
+        for _ in 0..5 {
+            follower.apply_replica(source.next().await?)?;
+        }
+
+        let res = follower.execute(CommandRequest::new_hget_all("t1"), &ctx);
+        assert_res_ok(res, &[], &[Kvpair::new("k2", "v2".into())]);
+        let res = follower.execute(CommandRequest::new_hget("t2", "k0"), &ctx);
+        assert_res_error(res, 404, "Not found");
+
+        let stats = primary.replication_stats();
+        assert_eq!(stats.seq, 3);
+        assert_eq!(stats.followers[0].lag, 0);
+        let stats = follower.replication_stats().primary.unwrap();
+        assert_eq!((stats.applied_seq, stats.primary_seq, stats.lag), (3, 3, 0));
+
+        // resume from the log tail after a disconnect
+        drop(source);
+        assert!(primary.replication_stats().followers.is_empty());
+        primary.execute(CommandRequest::new_hset("t1", "k3", "v3".into()), &ctx);
+        assert_eq!(follower.replication_stats().primary.unwrap().applied_seq, 3);
+
+        let mut source = primary.replicate(follower.replicate_request()?, &ctx)?;
+        let entry = source.next().await?;
+        assert!(matches!(&entry.entry, Some(Entry::Log(v)) if v.seq == 4));
+        follower.apply_replica(entry)?;
+
+        let res = follower.execute(CommandRequest::new_hget("t1", "k3"), &ctx);
+        assert_res_ok(res, &["v3".into()], &[]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn follower_should_reject_writes_from_clients() {
+        let follower: Service = ServiceInner::new(MemTable::new())
+            .follower_of("127.0.0.1:9527")
+            .into();
+        let ctx = ConnectionContext::default();
+
+        let res = follower.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &ctx);
+        assert_res_error(res, 421, "send writes to the primary 127.0.0.1:9527");
+
+        // a log entry out of order is refused rather than applied
+        let entry = ReplicaEntry {
+            head_seq: 2,
+            entry: Some(Entry::Log(ReplicaLog {
+                seq: 2,
+                command: Some(CommandRequest::new_hset("t1", "k1", "v1".into())),
+                old_values: vec![],
+                new_values: vec![],
+            })),
+        };
+        assert!(follower.apply_replica(entry).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+// Change data capture. The mutations in the replication log are turned into change events of
+// the keys they modified, so a watcher resumes from a sequence number the same way a follower
+// does, as long as the log still has it.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::command_request::RequestData;
+use crate::*;
+
+/// Change events of a table, key prefix or key, from the Watch command
+pub struct Watcher {
+    watch: Watch,
+    seq: u64,
+    pending: VecDeque<ChangeEvent>,
+    changes: broadcast::Receiver<Arc<ReplicaLog>>,
+}
+
+impl<Store: Storage> Service<Store> {
+    /// Start watching the changes, replaying the ones since from_seq if it's not 0
+    pub fn watch(&self, cmd: CommandRequest, ctx: &ConnectionContext) -> Result<Watcher, KvError> {
+        self.admit(&cmd, ctx)?;
+        let watch = match cmd.request_data {
+            Some(RequestData::Watch(watch)) => watch,
+            _ => return Err(KvError::InvalidCommand("Expect a Watch command".into())),
+        };
+
+        let tail = self.tail(watch.from_seq)?;
+        let mut watcher = Watcher {
+            watch,
+            seq: tail.seq,
+            pending: VecDeque::new(),
+            changes: tail.changes,
+        };
+        for log in tail.logs {
+            watcher.push(&log);
+        }
+        Ok(watcher)
+    }
+}
+
+impl Watcher {
+    /// The latest seq when the watch started, the changes after it are all streamed
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The next change, waits for one once the replayed changes are sent. A watcher lagging
+    /// too far behind gets an error, it could watch again from the seq after the last event
+    pub async fn next(&mut self) -> Result<ChangeEvent, KvError> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+
+            match self.changes.recv().await {
+                Ok(log) => self.push(&log),
+                Err(RecvError::Lagged(n)) => {
+                    let msg = format!("watcher lagged behind by {} commands", n);
+                    return Err(KvError::Internal(msg));
+                }
+                Err(RecvError::Closed) => {
+                    return Err(KvError::Internal("service is closed".into()))
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, log: &ReplicaLog) {
+        let watch = &self.watch;
+        let events = change_events(log).into_iter().filter(|v| {
+            v.table == watch.table
+                && (watch.key.is_empty() || v.key == watch.key)
+                && v.key.starts_with(&watch.prefix)
+        });
+        self.pending.extend(events);
+    }
+}
+
+// What the events of a command need that the command and its response don't have, read under
+// the log lock before it's applied: the old document of a JSON command, the old elements of the
+// members of a set or sorted set. None for the rest, their old values are in the response
+pub(super) fn old_values(cmd: &CommandRequest, store: &impl Storage) -> Option<Vec<Value>> {
+    let values = match cmd.request_data.as_ref()? {
+        RequestData::Sadd(v) => set_elements(store, &v.table, &v.key, &v.members),
+        RequestData::Srem(v) => set_elements(store, &v.table, &v.key, &v.members),
+        RequestData::Zadd(v) => zset_scores(store, &v.table, &v.key, &v.members),
+        _ => {
+            let (table, key) = json_key(cmd)?;
+            store.get(table, key).map(|v| vec![v.unwrap_or_default()])
+        }
+    };
+    // a store failing the read fails the command as well, which leaves no events
+    Some(values.unwrap_or_default())
+}
+
+// The new document of a JSON command or the scores of a Zadd, read after it's applied
+pub(super) fn new_values(cmd: &CommandRequest, store: &impl Storage) -> Vec<Value> {
+    let values = match (&cmd.request_data, json_key(cmd)) {
+        (Some(RequestData::Zadd(v)), _) => zset_scores(store, &v.table, &v.key, &v.members),
+        (_, Some((table, key))) => store.get(table, key).map(|v| v.into_iter().collect()),
+        _ => return vec![],
+    };
+    values.unwrap_or_default()
+}
+
+fn json_key(cmd: &CommandRequest) -> Option<(&str, &str)> {
+    match cmd.request_data.as_ref()? {
+        RequestData::JsonSet(v) => Some((&v.table, &v.key)),
+        RequestData::JsonAppend(v) => Some((&v.table, &v.key)),
+        RequestData::JsonIncr(v) => Some((&v.table, &v.key)),
+        _ => None,
+    }
+}
+
+// One event per key modified by the command, or per element added to or removed from a
+// collection. Deleting a missing key or member changes nothing
+fn change_events(log: &ReplicaLog) -> Vec<ChangeEvent> {
+    let old = |i: usize| log.old_values.get(i).filter(|v| v.value.is_some()).cloned();
+    let new = |i: usize| log.new_values.get(i).filter(|v| v.value.is_some()).cloned();
+    let event = |table: &str, key: &str, op: &str, old_value, new_value| ChangeEvent {
+        seq: log.seq,
+        table: table.into(),
+        key: key.into(),
+        op: op.into(),
+        old_value,
+        new_value,
+        member: String::new(),
+    };
+
+    let data = match log.command.as_ref().and_then(|v| v.request_data.as_ref()) {
+        Some(data) => data,
+        None => return vec![],
+    };
+    let mut events = Vec::new();
+    match data {
+        RequestData::Hset(v) => {
+            if let Some(pair) = &v.pair {
+                let value = Some(pair.value.clone().unwrap_or_default());
+                events.push(event(&v.table, &pair.key, "set", old(0), value));
+            }
+        }
+        RequestData::Hmset(v) => {
+            for (i, pair) in v.pairs.iter().enumerate() {
+                let value = Some(pair.value.clone().unwrap_or_default());
+                events.push(event(&v.table, &pair.key, "set", old(i), value));
+            }
+        }
+        RequestData::Hdel(v) => {
+            if let Some(old_value) = old(0) {
+                events.push(event(&v.table, &v.key, "del", Some(old_value), None));
+            }
+        }
+        RequestData::Hmdel(v) => {
+            for (i, key) in v.keys.iter().enumerate() {
+                if let Some(old_value) = old(i) {
+                    events.push(event(&v.table, key, "del", Some(old_value), None));
+                }
+            }
+        }
+        RequestData::JsonSet(_) | RequestData::JsonAppend(_) | RequestData::JsonIncr(_) => {
+            if let Some((table, key)) = log.command.as_ref().and_then(json_key) {
+                events.push(event(table, key, "set", old(0), new(0)));
+            }
+        }
+        RequestData::Lpush(v) => {
+            for value in &v.values {
+                events.push(event(&v.table, &v.key, "lpush", None, Some(value.clone())));
+            }
+        }
+        RequestData::Lpop(v) => {
+            for value in &log.old_values {
+                events.push(event(&v.table, &v.key, "lpop", Some(value.clone()), None));
+            }
+        }
+        RequestData::Sadd(v) => {
+            for (i, member) in v.members.iter().enumerate() {
+                if old(i).is_none() && !v.members[..i].contains(member) {
+                    events.push(event(&v.table, &v.key, "sadd", None, Some(member.clone())));
+                }
+            }
+        }
+        RequestData::Srem(v) => {
+            for (i, member) in v.members.iter().enumerate() {
+                if old(i).is_some() && !v.members[..i].contains(member) {
+                    events.push(event(&v.table, &v.key, "srem", Some(member.clone()), None));
+                }
+            }
+        }
+        RequestData::Zadd(v) => {
+            for (i, pair) in v.members.iter().enumerate() {
+                // a member given twice ends with the last score
+                if v.members[i + 1..].iter().any(|v| v.key == pair.key) {
+                    continue;
+                }
+                events.push(ChangeEvent {
+                    member: pair.key.clone(),
+                    ..event(&v.table, &v.key, "zadd", old(i), new(i))
+                });
+            }
+        }
+        _ => {}
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn watch_should_stream_and_resume_changes() -> Result<(), KvError> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let ctx = ConnectionContext::default();
+        service.execute(CommandRequest::new_hset("t1", "a1", "v1".into()), &ctx);
+
+        let mut table = service.watch(CommandRequest::new_watch("t1", 0), &ctx)?;
+        assert_eq!(table.seq(), 1);
+        let mut prefix = service.watch(CommandRequest::new_watch_prefix("t1", "b", 0), &ctx)?;
+        let mut key = service.watch(CommandRequest::new_watch_key("t1", "a1", 0), &ctx)?;
+
+        service.execute(CommandRequest::new_hset("t2", "a1", "v0".into()), &ctx);
+        service.execute(CommandRequest::new_hset("t1", "a1", "v2".into()), &ctx);
+        let pairs = vec![Kvpair::new("b1", "v3".into()), Kvpair::new("b2", "v4".into())];
+        service.execute(CommandRequest::new_hmset("t1", pairs), &ctx);
+        let keys = vec!["a1".into(), "a2".into()];
+        service.execute(CommandRequest::new_hmdel("t1", keys), &ctx);
+
+        let set = ChangeEvent {
+            seq: 3,
+            table: "t1".into(),
+            key: "a1".into(),
+            op: "set".into(),
+            old_value: Some("v1".into()),
+            new_value: Some("v2".into()),
+            member: String::new(),
+        };
+        let del = ChangeEvent {
+            seq: 5,
+            op: "del".into(),
+            old_value: Some("v2".into()),
+            new_value: None,
+            ..set.clone()
+        };
+        assert_eq!(table.next().await?, set);
+        assert_eq!(table.next().await?.seq, 4);
+        assert_eq!(table.next().await?.seq, 4);
+        assert_eq!(table.next().await?, del);
+
+        let event = prefix.next().await?;
+        assert_eq!((event.key.as_str(), event.old_value), ("b1", None));
+        assert_eq!(prefix.next().await?.key, "b2");
+
+        assert_eq!(key.next().await?, set);
+        assert_eq!(key.next().await?, del);
+
+        // resume from a seq still in the log
+        let mut resumed = service.watch(CommandRequest::new_watch_key("t1", "a1", 4), &ctx)?;
+        assert_eq!(resumed.next().await?, del);
+
+        let result = service.watch(CommandRequest::new_watch("t1", 7), &ctx);
+        assert!(matches!(result, Err(KvError::InvalidCommand(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_should_stream_json_and_collection_changes() -> Result<(), KvError> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let ctx = ConnectionContext::default();
+        let mut watcher = service.watch(CommandRequest::new_watch("t1", 0), &ctx)?;
+
+        let doc = json!({"visits": 1});
+        service.execute(CommandRequest::new_json_set("t1", "doc", "$", &doc), &ctx);
+        let cmd = CommandRequest::new_json_incr("t1", "doc", "$.visits", 2.into());
+        service.execute(cmd, &ctx);
+        let event = watcher.next().await?;
+        assert_eq!((event.op.as_str(), event.old_value), ("set", None));
+        assert_eq!(event.new_value, Some(Value::json(&doc)));
+        let event = watcher.next().await?;
+        assert_eq!(event.old_value, Some(Value::json(&doc)));
+        assert_eq!(event.new_value, Some(Value::json(&json!({"visits": 3}))));
+
+        let values = vec!["a".into(), "b".into()];
+        service.execute(CommandRequest::new_lpush("t1", "list", values), &ctx);
+        service.execute(CommandRequest::new_lpop("t1", "list", 1), &ctx);
+        assert_eq!(watcher.next().await?.new_value, Some("a".into()));
+        assert_eq!(watcher.next().await?.new_value, Some("b".into()));
+        let event = watcher.next().await?;
+        assert_eq!((event.op.as_str(), event.old_value), ("lpop", Some("b".into())));
+
+        // members already in the set, or not in it, change nothing
+        service.execute(CommandRequest::new_sadd("t1", "set", vec!["m1".into()]), &ctx);
+        let members = vec!["m1".into(), "m2".into(), "m2".into()];
+        service.execute(CommandRequest::new_sadd("t1", "set", members.clone()), &ctx);
+        let mut members = members;
+        members.push("m3".into());
+        service.execute(CommandRequest::new_srem("t1", "set", members), &ctx);
+        let ops = [("sadd", "m1"), ("sadd", "m2"), ("srem", "m1"), ("srem", "m2")];
+        for (op, member) in ops {
+            let event = watcher.next().await?;
+            assert_eq!((event.key.as_str(), event.op.as_str()), ("set", op));
+            assert_eq!(event.old_value.or(event.new_value), Some(member.into()));
+        }
+
+        service.execute(CommandRequest::new_zadd("t1", "zset", vec![("m1".into(), 1.0)]), &ctx);
+        let members = vec![("m1".into(), 2.0), ("m2".into(), 3.0)];
+        service.execute(CommandRequest::new_zadd("t1", "zset", members), &ctx);
+        assert_eq!(watcher.next().await?.new_value, Some(1.0.into()));
+        let event = watcher.next().await?;
+        assert_eq!((event.op.as_str(), event.member.as_str()), ("zadd", "m1"));
+        assert_eq!((event.old_value, event.new_value), (Some(1.0.into()), Some(2.0.into())));
+        let event = watcher.next().await?;
+        assert_eq!((event.member.as_str(), event.old_value), ("m2", None));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,409 @@
+// A cache in front of a durable store. Reads are served from the cache, or from the backing
+// store and then cached; writes go to both. The backing store is the truth, so the cache could
+// be a bounded MemTable which evicts whatever it likes.
+//
+// In write-behind mode the writes are kept in a pending map until flushed. The pending map is
+// checked before the cache, so a dirty kvpair evicted from the cache is still read back.
+//
+// A write of a key bumps the version of its stripe, with the stripe locked. A read missing the
+// cache notes the version before reading the backing store and caches what it read only when
+// the version is the same, so a write in between isn't covered by the value it replaced.
+
+use dashmap::{DashMap, DashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use tracing::warn;
+
+use crate::{KvError, Kvpair, Storage, Value};
+
+// The keys share this many versions
+const VERSION_STRIPES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    /// Writes reach the backing store before they return
+    #[default]
+    WriteThrough,
+    /// Writes reach the backing store when flushed, which happens once this many kvpairs are
+    /// pending, on backup and on drop
+    WriteBehind { max_pending: usize },
+}
+
+pub struct CachedStorage<Cache: Storage, Backing: Storage> {
+    cache: Cache,
+    backing: Backing,
+    mode: WriteMode,
+    // kvpairs written but not flushed yet, None for a deleted one, with the tick of the write
+    pending: DashMap<String, DashMap<String, (u64, Option<Value>)>>,
+    pending_len: AtomicUsize,
+    tick: AtomicU64,
+    // keys known to be missing from the backing store, cleared when full
+    missing: DashMap<String, DashSet<String>>,
+    missing_len: AtomicUsize,
+    max_missing: usize,
+    // the versions of the keys, bumped by the writes
+    versions: Vec<Mutex<u64>>,
+    // one flush at a time, an older value flushed late would overwrite a newer one
+    flushing: Mutex<()>,
+}
+
+impl<Cache: Storage, Backing: Storage> CachedStorage<Cache, Backing> {
+    pub fn new(cache: Cache, backing: Backing) -> Self {
+        Self {
+            cache,
+            backing,
+            mode: WriteMode::WriteThrough,
+            pending: DashMap::new(),
+            pending_len: AtomicUsize::new(0),
+            tick: AtomicU64::new(0),
+            missing: DashMap::new(),
+            missing_len: AtomicUsize::new(0),
+            max_missing: 10_000,
+            versions: (0..VERSION_STRIPES).map(|_| Mutex::new(0)).collect(),
+            flushing: Mutex::new(()),
+        }
+    }
+
+    pub fn write_mode(mut self, mode: WriteMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    // Remember up to max_keys missing keys, 0 to not cache misses
+    pub fn max_missing(mut self, max_keys: usize) -> Self {
+        self.max_missing = max_keys;
+        self
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    pub fn backing(&self) -> &Backing {
+        &self.backing
+    }
+
+    /// Write the pending kvpairs to the backing store. A kvpair written again meanwhile stays
+    /// pending with its new value
+    pub fn flush(&self) -> Result<(), KvError> {
+        let _flushing = self.flushing.lock().unwrap();
+        for table in self.pending.iter() {
+            let writes: Vec<_> = table
+                .iter()
+                .map(|v| (v.key().clone(), v.value().clone()))
+                .collect();
+            for (key, (tick, value)) in writes {
+                match value {
+                    Some(value) => self.backing.set(table.key(), key.clone(), value)?,
+                    None => self.backing.del(table.key(), &key)?,
+                };
+                if table.remove_if(&key, |_, v| v.0 == tick).is_some() {
+                    self.pending_len.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_behind(&self) -> bool {
+        matches!(self.mode, WriteMode::WriteBehind { .. })
+    }
+
+    // The version of key, locked until the guard is dropped
+    fn version(&self, table: &str, key: &str) -> MutexGuard<'_, u64> {
+        let mut hasher = DefaultHasher::new();
+        (table, key).hash(&mut hasher);
+        let stripe = hasher.finish() as usize % VERSION_STRIPES;
+        self.versions[stripe].lock().unwrap()
+    }
+
+    // The value of key when it's pending, cached or known to be missing
+    fn cached_get(&self, table: &str, key: &str) -> Result<Option<Option<Value>>, KvError> {
+        if let Some(value) = self.pending_get(table, key) {
+            return Ok(Some(value));
+        }
+        if let Some(value) = self.cache.get(table, key)? {
+            return Ok(Some(Some(value)));
+        }
+        match self.is_missing(table, key) {
+            true => Ok(Some(None)),
+            false => Ok(None),
+        }
+    }
+
+    // The value of key without caching it, for a write holding the version of the key
+    fn read(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        match self.cached_get(table, key)? {
+            Some(value) => Ok(value),
+            None => self.backing.get(table, key),
+        }
+    }
+
+    fn pending_get(&self, table: &str, key: &str) -> Option<Option<Value>> {
+        let table = self.pending.get(table)?;
+        let entry = table.get(key)?;
+        Some(entry.1.clone())
+    }
+
+    fn pend(&self, table: &str, key: String, value: Option<Value>) -> Result<(), KvError> {
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed);
+        let added = self
+            .pending
+            .entry(table.into())
+            .or_default()
+            .insert(key, (tick, value))
+            .is_none();
+        if added {
+            let len = self.pending_len.fetch_add(1, Ordering::Relaxed) + 1;
+            if let WriteMode::WriteBehind { max_pending } = self.mode {
+                if len >= max_pending {
+                    self.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_missing(&self, table: &str, key: &str) -> bool {
+        self.missing.get(table).is_some_and(|v| v.contains(key))
+    }
+
+    fn set_missing(&self, table: &str, key: &str) {
+        if self.max_missing == 0 {
+            return;
+        }
+        if self.missing_len.load(Ordering::Relaxed) >= self.max_missing {
+            self.missing.clear();
+            self.missing_len.store(0, Ordering::Relaxed);
+        }
+        if self
+            .missing
+            .entry(table.into())
+            .or_default()
+            .insert(key.into())
+        {
+            self.missing_len.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn unset_missing(&self, table: &str, key: &str) {
+        if let Some(keys) = self.missing.get(table) {
+            if keys.remove(key).is_some() {
+                self.missing_len.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<Cache: Storage, Backing: Storage> Storage for CachedStorage<Cache, Backing> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        if let Some(value) = self.cached_get(table, key)? {
+            return Ok(value);
+        }
+
+        let version = *self.version(table, key);
+        let value = self.backing.get(table, key)?;
+        // a write since the read has cached its own value
+        let current = self.version(table, key);
+        if *current == version {
+            match &value {
+                Some(value) => {
+                    self.cache.set(table, key.into(), value.clone())?;
+                }
+                None => self.set_missing(table, key),
+            }
+        }
+        Ok(value)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let mut version = self.version(table, &key);
+        *version += 1;
+        let old = match self.write_behind() {
+            true => self.read(table, &key)?,
+            false => self.backing.set(table, key.clone(), value.clone())?,
+        };
+        self.unset_missing(table, &key);
+        self.cache.set(table, key.clone(), value.clone())?;
+        if self.write_behind() {
+            self.pend(table, key, Some(value))?;
+        }
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.get(table, key)?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let mut version = self.version(table, key);
+        *version += 1;
+        let old = match self.write_behind() {
+            true => self.read(table, key)?,
+            false => self.backing.del(table, key)?,
+        };
+        self.cache.del(table, key)?;
+        match self.write_behind() {
+            true => self.pend(table, key.into(), None)?,
+            false => self.set_missing(table, key),
+        }
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.get_iter(table)?.collect()
+    }
+
+    // Written through, the batch reaches the backing store at once. Written behind, the writes
+    // are pending one by one and flushed as usual
+    fn write_batch(
+        &self,
+        writes: Vec<(String, String, Option<Value>)>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        if self.write_behind() {
+            return writes
+                .into_iter()
+                .map(|(table, key, value)| match value {
+                    Some(value) => self.set(&table, key, value),
+                    None => self.del(&table, &key),
+                })
+                .collect();
+        }
+        let olds = self.backing.write_batch(writes.clone())?;
+        for (table, key, value) in writes {
+            let mut version = self.version(&table, &key);
+            *version += 1;
+            match value {
+                Some(value) => {
+                    self.unset_missing(&table, &key);
+                    self.cache.set(&table, key, value)?;
+                }
+                None => {
+                    self.cache.del(&table, &key)?;
+                    self.set_missing(&table, &key);
+                }
+            }
+        }
+        Ok(olds)
+    }
+
+    // The backing store with the pending writes over it, the cache only has some of the kvpairs
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError> {
+        let pending: Vec<(String, Option<Value>)> = match self.pending.get(table) {
+            Some(pending) => pending
+                .iter()
+                .map(|v| (v.key().clone(), v.value().1.clone()))
+                .collect(),
+            None => return self.backing.get_iter(table),
+        };
+        let written: HashSet<String> = pending.iter().map(|v| v.0.clone()).collect();
+        let backing = self
+            .backing
+            .get_iter(table)?
+            .filter(move |v| v.as_ref().map_or(true, |v| !written.contains(&v.key)));
+        let pending = pending
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|v| Ok(Kvpair::new(key, v))));
+        Ok(Box::new(backing.chain(pending)))
+    }
+
+    fn get_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = self.backing.get_tables()?;
+        for table in self.pending.iter() {
+            let written = table.iter().any(|v| v.value().1.is_some());
+            if written && !tables.contains(table.key()) {
+                tables.push(table.key().clone());
+            }
+        }
+        Ok(tables)
+    }
+
+    fn backup(&self, dir: &Path) -> Result<(), KvError> {
+        self.flush()?;
+        self.backing.backup(dir)
+    }
+
+    fn check_table(&self, table: &str) -> Result<(), KvError> {
+        self.backing.check_table(table)
+    }
+}
+
+impl<Cache: Storage, Backing: Storage> Drop for CachedStorage<Cache, Backing> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to flush the pending writes: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb};
+    use tempfile::tempdir;
+
+    #[test]
+    fn reads_should_be_cached_and_misses_remembered() {
+        let store = CachedStorage::new(MemTable::new(), MemTable::new());
+        store.backing().set("t1", "k1".into(), "v1".into()).unwrap();
+
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.cache().get("t1", "k1").unwrap(), Some("v1".into()));
+
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        // written behind its back, the miss is still remembered
+        store.backing().set("t1", "k2".into(), "v2".into()).unwrap();
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        store.set("t1", "k2".into(), "v3".into()).unwrap();
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v3".into()));
+
+        store.del("t1", "k1").unwrap();
+        assert_eq!(store.backing().get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+    }
+
+    #[test]
+    fn write_behind_should_flush_when_pending_is_full() {
+        let dir = tempdir().unwrap();
+        let cache = MemTable::new().max_bytes(200);
+        let store = CachedStorage::new(cache, SledDb::new(dir.path()))
+            .write_mode(WriteMode::WriteBehind { max_pending: 10 });
+
+        for i in 0..9 {
+            store
+                .set("t1", format!("k{}", i), (i as i64).into())
+                .unwrap();
+        }
+        store.del("t1", "k0").unwrap();
+        assert_eq!(store.backing().get("t1", "k1").unwrap(), None);
+        // evicted from the cache, still read from the pending writes
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(1.into()));
+        assert_eq!(store.get_all("t1").unwrap().len(), 8);
+
+        store.set("t1", "k9".into(), 9.into()).unwrap();
+        assert_eq!(store.backing().get("t1", "k1").unwrap(), Some(1.into()));
+        assert_eq!(store.backing().get("t1", "k0").unwrap(), None);
+        assert_eq!(store.backing().get_all("t1").unwrap().len(), 9);
+    }
+
+    #[test]
+    fn write_behind_should_flush_on_drop() {
+        let dir = tempdir().unwrap();
+        let backing = SledDb::new(dir.path());
+        let store = CachedStorage::new(MemTable::new(), backing.clone())
+            .write_mode(WriteMode::WriteBehind { max_pending: 100 });
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(backing.get("t1", "k1").unwrap(), None);
+        drop(store);
+
+        // sled lets go of the lock of the dir in the background, read it through another handle
+        assert_eq!(backing.get("t1", "k1").unwrap(), Some("v1".into()));
+    }
+}
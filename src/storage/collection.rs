@@ -0,0 +1,416 @@
+// Lists, sets and sorted sets on top of the kvpairs of any Storage. A collection keeps a header
+// on its key, a Value with its kind and length, and one kvpair per element in a table of its
+// own, so a mutation writes only the elements it touches and the header rather than the whole
+// collection. The element tables start with '\0', ListTables leaves them out. With encrypted
+// keys, the key in the name of an element table is encrypted by the store, see encryption.rs.
+//
+// A list element is keyed by its index, from head to tail in the header, pushing to the head
+// takes the index before it. A set member is keyed by the hex of its encoded value. A sorted set
+// member is the key with its score as the value, the members are sorted when read.
+
+use std::cmp::Ordering;
+
+use crate::{value, Collection, KvError, Kvpair, Storage, Value};
+
+const LIST: &str = "list";
+const SET: &str = "set";
+const ZSET: &str = "zset";
+
+// The table with the elements of the collection at key
+pub(crate) fn elements_table(table: &str, key: &str) -> String {
+    format!("{}{}", elements_table_of(table), hex(key.as_bytes()))
+}
+
+// The start of the names of the element tables of the collections in table
+pub(crate) fn elements_table_of(table: &str) -> String {
+    format!("\0{}\0", table)
+}
+
+// The table and the hex of the key of the collection an element table belongs to
+pub(crate) fn split_elements_table(name: &str) -> Option<(&str, &str)> {
+    let (table, key) = name.strip_prefix('\0')?.split_once('\0')?;
+    match !key.is_empty() && key.bytes().all(|v| v.is_ascii_hexdigit()) {
+        true => Some((table, key)),
+        false => None,
+    }
+}
+
+pub(crate) fn as_collection(value: &Value) -> Option<&Collection> {
+    match &value.value {
+        Some(value::Value::Collection(c)) => Some(c),
+        _ => None,
+    }
+}
+
+// Refuse a scalar command on a key holding a collection
+pub(crate) fn check_scalar(table: &str, key: &str, value: &Value) -> Result<(), KvError> {
+    match as_collection(value) {
+        Some(_) => Err(KvError::WrongType(table.into(), key.into(), "scalar")),
+        None => Ok(()),
+    }
+}
+
+// Refuse to set a scalar over a collection, which would leave its elements behind. A header
+// could be set, that's how a snapshot or an import brings a collection back, a client can't
+// send one, see Service::admit
+pub(crate) fn check_overwrite<S>(
+    store: &S,
+    table: &str,
+    key: &str,
+    value: &Value,
+) -> Result<(), KvError>
+where
+    S: Storage + ?Sized,
+{
+    if as_collection(value).is_some() {
+        return Ok(());
+    }
+    match store.get(table, key)? {
+        Some(old) => check_scalar(table, key, &old),
+        None => Ok(()),
+    }
+}
+
+// Delete the key, with the elements when it holds a collection
+pub(crate) fn del<S>(store: &S, table: &str, key: &str) -> Result<Option<Value>, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let old = store.del(table, key)?;
+    if old.as_ref().and_then(as_collection).is_some() {
+        let elements = elements_table(table, key);
+        for pair in store.get_all(&elements)? {
+            store.del(&elements, &pair.key)?;
+        }
+    }
+    Ok(old)
+}
+
+pub(crate) fn lpush<S>(
+    store: &S,
+    table: &str,
+    key: &str,
+    values: Vec<Value>,
+) -> Result<u64, KvError>
+where
+    S: Storage + ?Sized,
+{
+    values.iter().try_for_each(check_element)?;
+    let mut header = header(store, table, key, LIST)?;
+    let elements = elements_table(table, key);
+    for value in values {
+        header.head -= 1;
+        store.set(&elements, header.head.to_string(), value)?;
+        header.len += 1;
+    }
+    save_header(store, table, key, &header)?;
+    Ok(header.len)
+}
+
+pub(crate) fn lpop<S>(store: &S, table: &str, key: &str, count: u64) -> Result<Vec<Value>, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let mut header = header(store, table, key, LIST)?;
+    let elements = elements_table(table, key);
+    let mut values = Vec::new();
+    while values.len() < count as usize && header.head < header.tail {
+        // an element evicted by a bounded store is skipped
+        if let Some(value) = store.del(&elements, &header.head.to_string())? {
+            values.push(value);
+        }
+        header.head += 1;
+        header.len -= 1;
+    }
+    save_header(store, table, key, &header)?;
+    Ok(values)
+}
+
+pub(crate) fn lrange<S>(
+    store: &S,
+    table: &str,
+    key: &str,
+    start: i64,
+    stop: i64,
+) -> Result<Vec<Value>, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let header = header(store, table, key, LIST)?;
+    let elements = elements_table(table, key);
+    let mut values = Vec::new();
+    for i in range(header.len, start, stop) {
+        let index = header.head + i as i64;
+        if let Some(value) = store.get(&elements, &index.to_string())? {
+            values.push(value);
+        }
+    }
+    Ok(values)
+}
+
+pub(crate) fn sadd<S>(
+    store: &S,
+    table: &str,
+    key: &str,
+    members: Vec<Value>,
+) -> Result<u64, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let members = members
+        .into_iter()
+        .map(|member| {
+            check_element(&member)?;
+            Ok((member_key(&member)?, member))
+        })
+        .collect::<Result<Vec<_>, KvError>>()?;
+    let mut header = header(store, table, key, SET)?;
+    let elements = elements_table(table, key);
+    let mut added = 0;
+    for (member_key, member) in members {
+        if store.set(&elements, member_key, member)?.is_none() {
+            added += 1;
+        }
+    }
+    header.len += added;
+    save_header(store, table, key, &header)?;
+    Ok(added)
+}
+
+pub(crate) fn srem<S>(store: &S, table: &str, key: &str, members: &[Value]) -> Result<u64, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let mut header = header(store, table, key, SET)?;
+    let elements = elements_table(table, key);
+    let mut removed = 0;
+    for member in members {
+        if store.del(&elements, &member_key(member)?)?.is_some() {
+            removed += 1;
+        }
+    }
+    header.len = header.len.saturating_sub(removed);
+    save_header(store, table, key, &header)?;
+    Ok(removed)
+}
+
+pub(crate) fn smembers<S>(store: &S, table: &str, key: &str) -> Result<Vec<Value>, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let header = header(store, table, key, SET)?;
+    if header.len == 0 {
+        return Ok(Vec::new());
+    }
+    let mut members = Vec::new();
+    for pair in store.get_iter(&elements_table(table, key))? {
+        members.extend(pair?.value);
+    }
+    Ok(members)
+}
+
+// The stored members of the set at key, an empty Value for a missing one
+pub(crate) fn set_elements<S>(
+    store: &S,
+    table: &str,
+    key: &str,
+    members: &[Value],
+) -> Result<Vec<Value>, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let elements = elements_table(table, key);
+    members
+        .iter()
+        .map(|member| Ok(store.get(&elements, &member_key(member)?)?.unwrap_or_default()))
+        .collect()
+}
+
+pub(crate) fn zadd<S>(
+    store: &S,
+    table: &str,
+    key: &str,
+    members: Vec<(String, f64)>,
+) -> Result<u64, KvError>
+where
+    S: Storage + ?Sized,
+{
+    if let Some((member, _)) = members.iter().find(|(_, score)| score.is_nan()) {
+        return Err(KvError::InvalidCommand(format!("Score of {} is NaN", member)));
+    }
+    let mut header = header(store, table, key, ZSET)?;
+    let elements = elements_table(table, key);
+    let mut added = 0;
+    for (member, score) in members {
+        if store.set(&elements, member, score.into())?.is_none() {
+            added += 1;
+        }
+    }
+    header.len += added;
+    save_header(store, table, key, &header)?;
+    Ok(added)
+}
+
+// The scores of the members of the sorted set at key, an empty Value for a missing one
+pub(crate) fn zset_scores<S>(
+    store: &S,
+    table: &str,
+    key: &str,
+    members: &[Kvpair],
+) -> Result<Vec<Value>, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let elements = elements_table(table, key);
+    members
+        .iter()
+        .map(|member| Ok(store.get(&elements, &member.key)?.unwrap_or_default()))
+        .collect()
+}
+
+pub(crate) fn zrange<S>(
+    store: &S,
+    table: &str,
+    key: &str,
+    start: i64,
+    stop: i64,
+) -> Result<Vec<(String, f64)>, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let members = sorted_members(store, table, key)?;
+    let range = range(members.len() as u64, start, stop);
+    Ok(members[range.start as usize..range.end as usize].to_vec())
+}
+
+pub(crate) fn zrangebyscore<S>(
+    store: &S,
+    table: &str,
+    key: &str,
+    min: f64,
+    max: f64,
+) -> Result<Vec<(String, f64)>, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let mut members = sorted_members(store, table, key)?;
+    members.retain(|(_, score)| *score >= min && *score <= max);
+    Ok(members)
+}
+
+// Members of the sorted set by score, then by member
+fn sorted_members<S>(store: &S, table: &str, key: &str) -> Result<Vec<(String, f64)>, KvError>
+where
+    S: Storage + ?Sized,
+{
+    let header = header(store, table, key, ZSET)?;
+    if header.len == 0 {
+        return Ok(Vec::new());
+    }
+    let mut members = Vec::new();
+    for pair in store.get_iter(&elements_table(table, key))? {
+        let pair = pair?;
+        if let Some(value::Value::Float(score)) = pair.value.and_then(|v| v.value) {
+            members.push((pair.key, score));
+        }
+    }
+    members.sort_by(|a, b| match a.1.partial_cmp(&b.1) {
+        Some(Ordering::Equal) | None => a.0.cmp(&b.0),
+        Some(ordering) => ordering,
+    });
+    Ok(members)
+}
+
+// The header of the collection of the kind at key, an empty one when there's none
+fn header<S>(store: &S, table: &str, key: &str, kind: &'static str) -> Result<Collection, KvError>
+where
+    S: Storage + ?Sized,
+{
+    match store.get(table, key)? {
+        None => Ok(Collection {
+            kind: kind.into(),
+            ..Default::default()
+        }),
+        Some(value) => match as_collection(&value) {
+            Some(header) if header.kind == kind => Ok(header.clone()),
+            _ => Err(KvError::WrongType(table.into(), key.into(), kind)),
+        },
+    }
+}
+
+// An empty collection is removed with its key, the same as never created
+fn save_header<S>(store: &S, table: &str, key: &str, header: &Collection) -> Result<(), KvError>
+where
+    S: Storage + ?Sized,
+{
+    match header.len {
+        0 => store.del(table, key)?,
+        _ => store.set(table, key.into(), header.clone().into())?,
+    };
+    Ok(())
+}
+
+fn check_element(value: &Value) -> Result<(), KvError> {
+    match as_collection(value) {
+        Some(_) => Err(KvError::InvalidCommand(
+            "A collection can't be an element of another".into(),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn member_key(member: &Value) -> Result<String, KvError> {
+    let buf: Vec<u8> = member.clone().try_into()?;
+    Ok(hex(&buf))
+}
+
+pub(crate) fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+// Indexes from start to stop of a sequence of len items, negative ones counting from the end
+fn range(len: u64, start: i64, stop: i64) -> std::ops::Range<u64> {
+    let len = len as i64;
+    let index = |i: i64| if i < 0 { len + i } else { i };
+    let (start, stop) = (index(start).max(0), index(stop).min(len - 1));
+    match start <= stop {
+        true => start as u64..stop as u64 + 1,
+        false => 0..0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+
+    #[test]
+    fn range_should_count_negative_indexes_from_the_end() {
+        assert_eq!(range(5, 0, -1), 0..5);
+        assert_eq!(range(5, 1, 2), 1..3);
+        assert_eq!(range(5, -2, 10), 3..5);
+        assert_eq!(range(5, -10, 0), 0..1);
+        assert_eq!(range(5, 0, -10), 0..0);
+        assert_eq!(range(5, 3, 1), 0..0);
+        assert_eq!(range(0, 0, -1), 0..0);
+    }
+
+    #[test]
+    fn collections_should_keep_one_kvpair_per_element() {
+        let store = MemTable::new();
+        lpush(&store, "t1", "l", vec!["a".into(), "b".into()]).unwrap();
+        sadd(&store, "t1", "s", vec![1.into(), 1.into(), 2.into()]).unwrap();
+
+        let pairs = store.get_all(&elements_table("t1", "l")).unwrap();
+        assert_eq!(pairs.len(), 2);
+        let pairs = store.get_all(&elements_table("t1", "s")).unwrap();
+        assert_eq!(pairs.len(), 2);
+
+        // the elements go away with the collection
+        del(&store, "t1", "l").unwrap();
+        assert!(store.get_all(&elements_table("t1", "l")).unwrap().is_empty());
+        lpop(&store, "t1", "s", 1).unwrap_err();
+        assert_eq!(srem(&store, "t1", "s", &[1.into(), 2.into()]).unwrap(), 2);
+        assert_eq!(store.get("t1", "s").unwrap(), None);
+    }
+}
@@ -0,0 +1,528 @@
+// Encryption at rest of the records of sled and rocksdb. A value is sealed in an envelope: it's
+// encrypted by AES-256-GCM with a random data key, and the data key is encrypted (wrapped) with
+// a master key from a KeyProvider. The record keeps the id of the master key, so a value is read
+// with the key it was written with while the store is re-encrypted with the current one after a
+// rotation. The stored key of a record is the associated data of its value, a value copied onto
+// another key can't be read.
+//
+// Keys could be encrypted as well, deterministically so a key is still looked up by its
+// ciphertext: the nonce is the HMAC of the table and the key, with subkeys derived from one
+// master key for the lifetime of the store. A store records whether its keys are encrypted in a
+// marker, and refuses to open with the other setting. Table names stay in the clear so a table
+// is still scanned by its prefix, except for the key of a collection in the name of its element
+// table. The ciphertexts aren't in the order of the keys: a range of keys, and so Hfind on an
+// index, reads and sorts the whole table.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::collection::{elements_table_of, hex, split_elements_table};
+use crate::KvError;
+
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+// The nonce and the wrapped data key, followed by the nonce and the ciphertext of the value
+const WRAPPED_LEN: usize = NONCE_LEN + KEY_LEN + TAG_LEN;
+
+// The stored key of the marker of how the keys of a store are kept, out of any table
+pub(crate) const KEYS_MARKER: &[u8] = b"\0kvs:keys";
+
+/// A 256-bit master key
+#[derive(Clone, PartialEq, Eq)]
+pub struct MasterKey([u8; KEY_LEN]);
+
+impl MasterKey {
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        Self(key)
+    }
+
+    /// A random key, to start a key file or rotate to
+    pub fn generate() -> Result<Self, KvError> {
+        let mut key = [0; KEY_LEN];
+        SystemRandom::new().fill(&mut key).map_err(|_| random_failed())?;
+        Ok(Self(key))
+    }
+
+    /// The key as a key file or the environment keeps it
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(self.0)
+    }
+
+    fn cipher(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.0).expect("a key of 256 bits"))
+    }
+
+    // A subkey for one purpose, so the key of a purpose tells nothing of the others
+    fn derive(&self, purpose: &str) -> [u8; KEY_LEN] {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.0);
+        let mut subkey = [0; KEY_LEN];
+        subkey.copy_from_slice(hmac::sign(&key, purpose.as_bytes()).as_ref());
+        subkey
+    }
+}
+
+// never print the key itself
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+/// Where the master keys come from. A key has to stay available while any record is encrypted
+/// with it, a rotation adds a key rather than replacing one
+pub trait KeyProvider: Send + Sync {
+    /// The id of the key new values are encrypted with
+    fn current(&self) -> Result<u32, KvError>;
+    /// The key with the id
+    fn key(&self, id: u32) -> Result<MasterKey, KvError>;
+}
+
+// The master keys by id, the current one has the largest id
+#[derive(Debug, Clone, Default)]
+struct KeyRing(BTreeMap<u32, MasterKey>);
+
+impl KeyRing {
+    // Entries of "<id>:<base64 of 32 bytes>", separated by new lines or ','. A '#' starts a
+    // comment till the end of the line
+    fn parse(text: &str) -> Result<Self, KvError> {
+        let mut keys = BTreeMap::new();
+        let entries = text
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .flat_map(|line| line.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty());
+        for entry in entries {
+            let invalid = |msg: &str| {
+                // the key itself isn't in the message
+                let id = entry.split(':').next().unwrap_or_default();
+                KvError::EncryptionError(format!("Invalid master key {:?}: {}", id, msg))
+            };
+            let (id, key) = entry.split_once(':').ok_or_else(|| invalid("expect <id>:<base64>"))?;
+            let id: u32 = id.trim().parse().map_err(|_| invalid("the id isn't a number"))?;
+            let key = BASE64.decode(key.trim()).map_err(|_| invalid("the key isn't base64"))?;
+            let key: [u8; KEY_LEN] = key.try_into().map_err(|_| invalid("expect 32 bytes"))?;
+            if keys.insert(id, MasterKey(key)).is_some() {
+                return Err(invalid("the id is repeated"));
+            }
+        }
+        Ok(Self(keys))
+    }
+
+    fn current(&self) -> Result<u32, KvError> {
+        let id = self.0.keys().next_back().copied();
+        id.ok_or_else(|| KvError::EncryptionError("No master key".into()))
+    }
+
+    fn key(&self, id: u32) -> Result<MasterKey, KvError> {
+        let key = self.0.get(&id).cloned();
+        key.ok_or_else(|| KvError::EncryptionError(format!("Master key {} not found", id)))
+    }
+}
+
+/// Master keys from a file, one "<id>:<base64>" per line. The key with the largest id is the
+/// current one: a key is rotated by appending a line with a larger id and reloading
+#[derive(Debug)]
+pub struct FileKeyProvider {
+    path: PathBuf,
+    keys: RwLock<KeyRing>,
+}
+
+impl FileKeyProvider {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let path = path.as_ref().to_path_buf();
+        let keys = RwLock::new(KeyRing::parse(&fs::read_to_string(&path)?)?);
+        Ok(Self { path, keys })
+    }
+
+    /// Read the file again, the keys are kept when it can't be read
+    pub fn reload(&self) -> Result<(), KvError> {
+        let keys = KeyRing::parse(&fs::read_to_string(&self.path)?)?;
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+}
+
+impl KeyProvider for FileKeyProvider {
+    fn current(&self) -> Result<u32, KvError> {
+        self.keys.read().unwrap().current()
+    }
+
+    fn key(&self, id: u32) -> Result<MasterKey, KvError> {
+        self.keys.read().unwrap().key(id)
+    }
+}
+
+/// Master keys from an environment variable, "<id>:<base64>" separated by ','. The key with the
+/// largest id is the current one
+#[derive(Debug)]
+pub struct EnvKeyProvider(KeyRing);
+
+impl EnvKeyProvider {
+    pub fn new(var: &str) -> Result<Self, KvError> {
+        let text = std::env::var(var)
+            .map_err(|e| KvError::EncryptionError(format!("Can't read ${}: {}", var, e)))?;
+        Ok(Self(KeyRing::parse(&text)?))
+    }
+}
+
+impl KeyProvider for EnvKeyProvider {
+    fn current(&self) -> Result<u32, KvError> {
+        self.0.current()
+    }
+
+    fn key(&self, id: u32) -> Result<MasterKey, KvError> {
+        self.0.key(id)
+    }
+}
+
+/// How the records of a store are encrypted, given to SledDb::encryption or RocksDB::encryption
+pub struct Encryption {
+    provider: Arc<dyn KeyProvider>,
+    keys: Option<KeyCipher>,
+    rng: SystemRandom,
+}
+
+// Deterministic encryption of the keys
+struct KeyCipher {
+    id: u32,
+    mac: hmac::Key,
+    cipher: LessSafeKey,
+}
+
+impl Encryption {
+    pub fn new(provider: Arc<dyn KeyProvider>) -> Self {
+        Self {
+            provider,
+            keys: None,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Encrypt the keys as well, with subkeys of the master key id. It's chosen when the store
+    /// is created and never changes: a key is looked up by its ciphertext, a rotation
+    /// re-encrypts the values only
+    pub fn encrypt_keys(mut self, id: u32) -> Result<Self, KvError> {
+        let master = self.provider.key(id)?;
+        let mac = hmac::Key::new(hmac::HMAC_SHA256, &master.derive("kv-store key mac"));
+        let cipher = MasterKey(master.derive("kv-store key cipher")).cipher();
+        self.keys = Some(KeyCipher { id, mac, cipher });
+        Ok(self)
+    }
+
+    pub(crate) fn keys_encrypted(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    // The name table is stored under, the key of a collection in the name of its element table
+    // is encrypted like the key itself
+    pub(crate) fn encrypt_table(&self, table: &str) -> String {
+        let key = split_elements_table(table)
+            .and_then(|(parent, key)| Some((parent, String::from_utf8(unhex(key)?).ok()?)));
+        match (&self.keys, key) {
+            (Some(_), Some((parent, key))) => {
+                format!("{}{}", elements_table_of(parent), self.encrypt_key(parent, &key))
+            }
+            _ => table.to_string(),
+        }
+    }
+
+    // The table stored under the name
+    pub(crate) fn decrypt_table(&self, stored: &str) -> Result<String, KvError> {
+        match (&self.keys, split_elements_table(stored)) {
+            (Some(_), Some((parent, key))) => {
+                let key = self.decrypt_key(parent, key)?;
+                Ok(format!("{}{}", elements_table_of(parent), hex(key.as_bytes())))
+            }
+            _ => Ok(stored.to_string()),
+        }
+    }
+
+    // What seals the values written, with the current master key
+    pub(crate) fn sealer(&self) -> Result<Sealer<'_>, KvError> {
+        let id = self.provider.current()?;
+        Ok(Sealer {
+            id,
+            kek: self.provider.key(id)?.cipher(),
+            rng: &self.rng,
+        })
+    }
+
+    // The data sealed by Sealer::seal with the master key id
+    pub(crate) fn open(&self, id: u32, envelope: &[u8], aad: &[u8]) -> Result<Vec<u8>, KvError> {
+        if envelope.len() < WRAPPED_LEN + NONCE_LEN + TAG_LEN {
+            return Err(KvError::EncryptionError("Truncated envelope".into()));
+        }
+        let failed = |_| KvError::EncryptionError("Can't decrypt the value".into());
+        let (nonce, rest) = envelope.split_at(NONCE_LEN);
+        let (wrapped, rest) = rest.split_at(KEY_LEN + TAG_LEN);
+        let (data_nonce, sealed) = rest.split_at(NONCE_LEN);
+
+        let kek = self.provider.key(id)?.cipher();
+        let mut data_key = wrapped.to_vec();
+        let data_key = kek
+            .open_in_place(nonce_of(nonce), Aad::empty(), &mut data_key)
+            .map_err(failed)?;
+        let data_key = UnboundKey::new(&AES_256_GCM, data_key).map_err(failed)?;
+
+        let mut data = sealed.to_vec();
+        let len = LessSafeKey::new(data_key)
+            .open_in_place(nonce_of(data_nonce), Aad::from(aad), &mut data)
+            .map_err(failed)?
+            .len();
+        data.truncate(len);
+        Ok(data)
+    }
+
+    // The key as it's stored in table, the same key always has the same ciphertext
+    pub(crate) fn encrypt_key(&self, table: &str, key: &str) -> String {
+        let keys = match &self.keys {
+            Some(keys) => keys,
+            None => return key.to_string(),
+        };
+        let siv = siv(&keys.mac, table, key);
+        let mut data = key.as_bytes().to_vec();
+        keys.cipher
+            .seal_in_place_append_tag(nonce_of(&siv), Aad::from(table.as_bytes()), &mut data)
+            .expect("a key is never too long to seal");
+        format!("{}{}", hex(&siv), hex(&data))
+    }
+
+    // The key stored in table as it's set
+    pub(crate) fn decrypt_key(&self, table: &str, stored: &str) -> Result<String, KvError> {
+        let keys = match &self.keys {
+            Some(keys) => keys,
+            None => return Ok(stored.to_string()),
+        };
+        let failed = || KvError::EncryptionError(format!("Can't decrypt a key of {}", table));
+        let buf = unhex(stored).ok_or_else(failed)?;
+        if buf.len() < NONCE_LEN + TAG_LEN {
+            return Err(failed());
+        }
+        let (nonce, sealed) = buf.split_at(NONCE_LEN);
+        let mut data = sealed.to_vec();
+        let key = keys
+            .cipher
+            .open_in_place(nonce_of(nonce), Aad::from(table.as_bytes()), &mut data)
+            .map_err(|_| failed())?;
+        let key = String::from_utf8(key.to_vec()).map_err(|_| failed())?;
+        match siv(&keys.mac, table, &key)[..] == *nonce {
+            true => Ok(key),
+            false => Err(failed()),
+        }
+    }
+}
+
+// What the marker of a store says of its keys with the encryption it's opened with
+fn keys_marker(encryption: Option<&Encryption>) -> String {
+    match encryption.and_then(|v| v.keys.as_ref()) {
+        Some(keys) => format!("encrypted with key {}", keys.id),
+        None => "in the clear".to_string(),
+    }
+}
+
+// Check the marker of a store against the encryption it's opened with, the keys would all be
+// missed with another setting. A store without a marker is empty, or from before the marker with
+// the keys in the clear. Returns the marker to write when it's missing
+pub(crate) fn check_keys_marker(
+    marker: Option<&[u8]>,
+    empty: bool,
+    encryption: Option<&Encryption>,
+) -> Result<Option<Vec<u8>>, KvError> {
+    let expected = keys_marker(encryption);
+    let found = match marker {
+        Some(v) => String::from_utf8_lossy(v).into_owned(),
+        None if empty => return Ok(Some(expected.into_bytes())),
+        None => keys_marker(None),
+    };
+    match (found == expected, marker.is_some()) {
+        (true, true) => Ok(None),
+        (true, false) => Ok(Some(expected.into_bytes())),
+        (false, _) => Err(KvError::EncryptionError(format!(
+            "The keys of the store are {}, it can't be opened with the keys {}",
+            found, expected
+        ))),
+    }
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption")
+            .field("keys_encrypted", &self.keys_encrypted())
+            .finish()
+    }
+}
+
+// Seals values with a master key, which is looked up once for many values
+pub(crate) struct Sealer<'a> {
+    id: u32,
+    kek: LessSafeKey,
+    rng: &'a SystemRandom,
+}
+
+impl Sealer<'_> {
+    // The id of the master key
+    pub(crate) fn id(&self) -> u32 {
+        self.id
+    }
+
+    // The nonce and the data key wrapped by the master key, then the nonce and the data
+    // encrypted by the data key
+    pub(crate) fn seal(&self, data: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut random = [0; NONCE_LEN * 2 + KEY_LEN];
+        self.rng.fill(&mut random).expect("the system random number generator failed");
+        let (nonce, rest) = random.split_at(NONCE_LEN);
+        let (data_key, data_nonce) = rest.split_at(KEY_LEN);
+
+        let mut envelope = Vec::with_capacity(WRAPPED_LEN + NONCE_LEN + data.len() + TAG_LEN);
+        let mut wrapped = data_key.to_vec();
+        self.kek
+            .seal_in_place_append_tag(nonce_of(nonce), Aad::empty(), &mut wrapped)
+            .expect("a data key is never too long to seal");
+        envelope.extend_from_slice(nonce);
+        envelope.extend_from_slice(&wrapped);
+
+        let mut sealed = data.to_vec();
+        let data_key = UnboundKey::new(&AES_256_GCM, data_key).expect("a key of 256 bits");
+        LessSafeKey::new(data_key)
+            .seal_in_place_append_tag(nonce_of(data_nonce), Aad::from(aad), &mut sealed)
+            .expect("a value is never too long to seal");
+        envelope.extend_from_slice(data_nonce);
+        envelope.extend_from_slice(&sealed);
+        envelope
+    }
+}
+
+// The synthetic nonce of a key, the same key of the same table always gets the same one
+fn siv(mac: &hmac::Key, table: &str, key: &str) -> [u8; NONCE_LEN] {
+    let mut ctx = hmac::Context::with_key(mac);
+    ctx.update(table.as_bytes());
+    ctx.update(&[0]);
+    ctx.update(key.as_bytes());
+    let mut siv = [0; NONCE_LEN];
+    siv.copy_from_slice(&ctx.sign().as_ref()[..NONCE_LEN]);
+    siv
+}
+
+fn nonce_of(buf: &[u8]) -> Nonce {
+    Nonce::try_assume_unique_for_key(buf).expect("a nonce of NONCE_LEN bytes")
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn random_failed() -> KvError {
+    KvError::EncryptionError("The system random number generator failed".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(ids: &[u32]) -> KeyRing {
+        let text: Vec<String> = ids
+            .iter()
+            .map(|id| format!("{}:{}", id, MasterKey::generate().unwrap().to_base64()))
+            .collect();
+        KeyRing::parse(&text.join(",")).unwrap()
+    }
+
+    impl KeyProvider for KeyRing {
+        fn current(&self) -> Result<u32, KvError> {
+            KeyRing::current(self)
+        }
+
+        fn key(&self, id: u32) -> Result<MasterKey, KvError> {
+            KeyRing::key(self, id)
+        }
+    }
+
+    #[test]
+    fn key_ring_should_parse_and_pick_the_largest_id() {
+        let (a, b) = (MasterKey::generate().unwrap(), MasterKey::generate().unwrap());
+        let text = format!("# keys\n2:{}\n\n 10 : {} # new\n", a.to_base64(), b.to_base64());
+        let keys = KeyRing::parse(&text).unwrap();
+        assert_eq!(keys.current().unwrap(), 10);
+        assert_eq!(keys.key(2).unwrap(), a);
+        assert!(keys.key(3).is_err());
+
+        assert!(KeyRing::parse("1:aGVsbG8=").is_err());
+        assert!(KeyRing::parse(&format!("x:{}", a.to_base64())).is_err());
+        let repeated = format!("1:{},1:{}", a.to_base64(), b.to_base64());
+        assert!(KeyRing::parse(&repeated).is_err());
+        assert!(KeyRing::default().current().is_err());
+    }
+
+    #[test]
+    fn envelope_should_open_with_the_key_and_data_it_is_sealed_with() {
+        let encryption = Encryption::new(Arc::new(provider(&[1, 2])));
+        let sealer = encryption.sealer().unwrap();
+        assert_eq!(sealer.id(), 2);
+        let envelope = sealer.seal(b"hello", b"t1:k1");
+        assert_ne!(sealer.seal(b"hello", b"t1:k1"), envelope);
+        assert_eq!(encryption.open(2, &envelope, b"t1:k1").unwrap(), b"hello");
+
+        assert!(encryption.open(1, &envelope, b"t1:k1").is_err());
+        assert!(encryption.open(2, &envelope, b"t1:k2").is_err());
+        let mut tampered = envelope.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(encryption.open(2, &tampered, b"t1:k1").is_err());
+    }
+
+    #[test]
+    fn keys_should_be_encrypted_deterministically() {
+        let encryption = Encryption::new(Arc::new(provider(&[1]))).encrypt_keys(1).unwrap();
+        let stored = encryption.encrypt_key("t1", "k1");
+        assert_eq!(encryption.encrypt_key("t1", "k1"), stored);
+        assert_ne!(encryption.encrypt_key("t2", "k1"), stored);
+        assert!(!stored.contains("k1") && !stored.contains(':'));
+        assert_eq!(encryption.decrypt_key("t1", &stored).unwrap(), "k1");
+        assert!(encryption.decrypt_key("t2", &stored).is_err());
+        assert!(encryption.decrypt_key("t1", "k1").is_err());
+
+        let plain = Encryption::new(Arc::new(provider(&[1])));
+        assert_eq!(plain.encrypt_key("t1", "k1"), "k1");
+    }
+
+    #[test]
+    fn key_in_the_name_of_an_element_table_should_be_encrypted() {
+        let encryption = Encryption::new(Arc::new(provider(&[1]))).encrypt_keys(1).unwrap();
+        let table = crate::storage::collection::elements_table("t1", "k1");
+        let stored = encryption.encrypt_table(&table);
+        assert!(stored.starts_with("\0t1\0") && !stored.contains(&hex(b"k1")));
+        assert_eq!(encryption.decrypt_table(&stored).unwrap(), table);
+        assert_eq!(encryption.encrypt_table("t1"), "t1");
+        assert_eq!(encryption.decrypt_table("t1").unwrap(), "t1");
+    }
+
+    #[test]
+    fn store_should_be_refused_with_the_keys_kept_another_way() {
+        let plain = Encryption::new(Arc::new(provider(&[1])));
+        let encrypted = Encryption::new(Arc::new(provider(&[1]))).encrypt_keys(1).unwrap();
+        let marker = check_keys_marker(None, true, Some(&encrypted)).unwrap().unwrap();
+        assert_eq!(marker, b"encrypted with key 1");
+        assert!(check_keys_marker(Some(&marker), false, Some(&encrypted)).unwrap().is_none());
+        assert!(check_keys_marker(Some(&marker), false, Some(&plain)).is_err());
+        assert!(check_keys_marker(Some(&marker), false, None).is_err());
+
+        // a store from before the marker has its keys in the clear
+        let marker = check_keys_marker(None, false, Some(&plain)).unwrap().unwrap();
+        assert_eq!(marker, b"in the clear");
+        assert!(check_keys_marker(None, false, Some(&encrypted)).is_err());
+    }
+}
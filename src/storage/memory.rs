@@ -1,10 +1,130 @@
-use crate::{KvError, Kvpair, Storage, Value, StorageIter};
+use crate::{as_collection, Hset, KvError, Kvpair, Storage, Value, StorageIter};
+use dashmap::mapref::entry::Entry as MapEntry;
 use dashmap::{mapref::one::Ref, DashMap};
+use prost::Message;
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File};
+use std::hash::BuildHasher;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// File of a MemTable backup, Hset messages each prefixed by its length
+const DUMP_FILE: &str = "memtable.dump";
+
+// Approximate bytes a kvpair takes besides its key and value: the entry, its slot in the map and
+// the String/Value headers
+const ENTRY_OVERHEAD: usize = 64;
+
+// An eviction pass frees memory down to this percent of the budget, so it doesn't run on every set
+const EVICT_TO_PERCENT: usize = 90;
+
+/// Which kvpairs a bounded MemTable evicts first when it's over its budget. Expired kvpairs are
+/// always evicted first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Least recently used
+    #[default]
+    Lru,
+    /// Least frequently used, the least recently used first among the same frequency
+    Lfu,
+    Random,
+    /// The kvpairs with a TTL, soonest to expire first, then the least recently used
+    TtlFirst,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Self::Lru),
+            "lfu" => Ok(Self::Lfu),
+            "random" => Ok(Self::Random),
+            "ttl-first" => Ok(Self::TtlFirst),
+            _ => Err(KvError::InvalidCommand(format!("Unknown eviction policy {}", s))),
+        }
+    }
+}
+
+/// Memory and eviction counters of a MemTable
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemTableStats {
+    /// Approximate bytes of all the kvpairs
+    pub used_bytes: u64,
+    /// The budget, None when unbounded
+    pub max_bytes: Option<u64>,
+    pub keys: u64,
+    /// Kvpairs evicted to stay within the budgets
+    pub evictions: u64,
+    pub evicted_bytes: u64,
+    /// Kvpairs removed after their TTL
+    pub expired: u64,
+    /// Times the eviction ran
+    pub eviction_passes: u64,
+}
 
 // Use Dashmap build MemTable, which impled Storage trait
-#[derive(Clone, Debug, Default)]
+#[derive(Debug, Default)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: DashMap<String, Table>,
+    max_bytes: Option<usize>,
+    max_table_bytes: Option<usize>,
+    policy: EvictionPolicy,
+    // logical clock of the sets, and of the reads when bounded
+    clock: AtomicU64,
+    used: AtomicUsize,
+    evictions: AtomicU64,
+    evicted_bytes: AtomicU64,
+    expired: AtomicU64,
+    eviction_passes: AtomicU64,
+    // one eviction pass at a time, the others find the memory freed already
+    evicting: Mutex<()>,
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    entries: DashMap<String, Entry>,
+    used: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    size: usize,
+    // tick of the set, an eviction doesn't remove a kvpair set again since it was picked
+    version: u64,
+    // milliseconds since the epoch
+    expire_at: Option<u64>,
+    // updated under the read lock of the map, reads stay concurrent
+    last_access: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl Clone for Entry {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+            size: self.size,
+            version: self.version,
+            expire_at: self.expire_at,
+            last_access: AtomicU64::new(self.last_access.load(Ordering::Relaxed)),
+            hits: AtomicU64::new(self.hits.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Entry {
+    fn expired(&self, now: u64) -> bool {
+        self.expire_at.is_some_and(|v| v <= now)
+    }
+
+    fn live(&self) -> bool {
+        self.expire_at.is_none() || !self.expired(now_millis())
+    }
 }
 
 impl MemTable {
@@ -13,8 +133,72 @@ impl MemTable {
         Self::default()
     }
 
-    // If hash table {{ name }} not existed, create it. Else return the {{ name }} hash table
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    // Keep the kvpairs within about max_bytes, evicting by the policy
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    // Keep each table within about max_bytes, evicting from that table only
+    pub fn max_table_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_table_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn eviction(mut self, policy: EvictionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // Load a MemTable from the directory written by Storage::backup
+    pub fn restore(dir: impl AsRef<Path>) -> Result<Self, KvError> {
+        Self::new().load(dir)
+    }
+
+    // Add the kvpairs of a backup, a bounded MemTable evicts what's over its budget
+    pub fn load(self, dir: impl AsRef<Path>) -> Result<Self, KvError> {
+        let data = fs::read(dir.as_ref().join(DUMP_FILE))?;
+        let mut buf = &data[..];
+        while !buf.is_empty() {
+            let hset = Hset::decode_length_delimited(&mut buf)?;
+            if let Some(pair) = hset.pair {
+                self.set(&hset.table, pair.key, pair.value.unwrap_or_default())?;
+            }
+        }
+        Ok(self)
+    }
+
+    // Remove the kvpair after ttl, until it's set again. Returns false when there's no such key
+    pub fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let table = match self.tables.get(table) {
+            Some(table) => table,
+            None => return Ok(false),
+        };
+        let result = match table.entries.get_mut(key) {
+            Some(mut entry) if entry.live() => {
+                entry.expire_at = Some(now_millis() + ttl.as_millis() as u64);
+                true
+            }
+            _ => false,
+        };
+        Ok(result)
+    }
+
+    pub fn stats(&self) -> MemTableStats {
+        MemTableStats {
+            used_bytes: self.used.load(Ordering::Relaxed) as u64,
+            max_bytes: self.max_bytes.map(|v| v as u64),
+            keys: self.tables.iter().map(|v| v.entries.len() as u64).sum(),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            evicted_bytes: self.evicted_bytes.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            eviction_passes: self.eviction_passes.load(Ordering::Relaxed),
+        }
+    }
+
+    // If hash table {{ name }} not existed, create it. Else return the {{ name }} hash table.
+    // Only the writes create a table, a read of a missing one finds nothing
+    fn get_or_create_table(&self, name: &str) -> Ref<String, Table> {
         if let Some(table) = self.tables.get(name) {
             table
         } else {
@@ -22,41 +206,428 @@ impl MemTable {
             entry.downgrade()
         }
     }
+
+    fn bounded(&self) -> bool {
+        self.max_bytes.is_some() || self.max_table_bytes.is_some()
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn touch(&self, entry: &Entry) {
+        if self.bounded() {
+            entry.last_access.store(self.tick(), Ordering::Relaxed);
+            entry.hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn account(&self, table: &Table, added: usize, removed: usize) {
+        table.used.fetch_add(added, Ordering::Relaxed);
+        table.used.fetch_sub(removed, Ordering::Relaxed);
+        self.used.fetch_add(added, Ordering::Relaxed);
+        self.used.fetch_sub(removed, Ordering::Relaxed);
+    }
+
+    // Remove the kvpair when f allows it. The bytes are accounted with the entry locked, so the
+    // set of a key is always accounted before its removal and the counters never go below zero
+    fn remove_entry(
+        &self,
+        table: &Table,
+        key: &str,
+        f: impl FnOnce(&Entry) -> bool,
+    ) -> Option<Entry> {
+        match table.entries.entry(key.into()) {
+            MapEntry::Occupied(entry) if f(entry.get()) => {
+                self.account(table, 0, entry.get().size);
+                Some(entry.remove())
+            }
+            _ => None,
+        }
+    }
+
+    // An expired kvpair found by a read is removed, unless it was set again meanwhile
+    fn remove_expired(&self, table: &str, key: &str) {
+        if let Some(table) = self.tables.get(table) {
+            let now = now_millis();
+            if self.remove_entry(&table, key, |v| v.expired(now)).is_some() {
+                self.expired.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn evict_if_needed(&self, table: &str, table_used: usize) {
+        if let Some(max) = self.max_table_bytes {
+            if table_used > max {
+                self.evict(Some(table), max);
+            }
+        }
+        if let Some(max) = self.max_bytes {
+            if self.used.load(Ordering::Relaxed) > max {
+                self.evict(None, max);
+            }
+        }
+    }
+
+    // Evict from one table or all of them down to EVICT_TO_PERCENT of max. The candidates are
+    // ranked in one pass over the entries, which runs once per so many bytes set. The internal
+    // tables and the collection headers are never evicted, a collection would lose its elements
+    // or its header
+    fn evict(&self, only: Option<&str>, max: usize) {
+        let _guard = self.evicting.lock().unwrap();
+        let used = || match only {
+            Some(table) => self
+                .tables
+                .get(table)
+                .map_or(0, |v| v.used.load(Ordering::Relaxed)),
+            None => self.used.load(Ordering::Relaxed),
+        };
+        if used() <= max {
+            return;
+        }
+
+        let now = now_millis();
+        let random = RandomState::new();
+        let mut candidates = Vec::new();
+        for table in self.tables.iter() {
+            if only.is_some_and(|v| v != table.key()) || table.key().starts_with('\0') {
+                continue;
+            }
+            for entry in table.entries.iter() {
+                let v = entry.value();
+                if as_collection(&v.value).is_some() {
+                    continue;
+                }
+                let last_access = v.last_access.load(Ordering::Relaxed);
+                let rank = match self.policy {
+                    _ if v.expired(now) => (0, 0, 0),
+                    EvictionPolicy::Lru => (1, last_access, 0),
+                    EvictionPolicy::Lfu => (1, v.hits.load(Ordering::Relaxed), last_access),
+                    EvictionPolicy::Random => {
+                        (1, random.hash_one((table.key(), entry.key())), 0)
+                    }
+                    EvictionPolicy::TtlFirst => match v.expire_at {
+                        Some(expire_at) => (1, expire_at, last_access),
+                        None => (2, last_access, 0),
+                    },
+                };
+                candidates.push((rank, table.key().clone(), entry.key().clone(), v.version));
+            }
+        }
+        candidates.sort_unstable_by_key(|v| v.0);
+
+        let goal = max * EVICT_TO_PERCENT / 100;
+        for (rank, table, key, version) in candidates {
+            if used() <= goal {
+                break;
+            }
+            let table = match self.tables.get(&table) {
+                Some(table) => table,
+                None => continue,
+            };
+            if let Some(entry) = self.remove_entry(&table, &key, |v| v.version == version) {
+                if rank.0 == 0 {
+                    self.expired.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                    self.evicted_bytes
+                        .fetch_add(entry.size as u64, Ordering::Relaxed);
+                }
+            }
+        }
+        self.eviction_passes.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 impl Storage for MemTable {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.value().clone()))
+        let value = {
+            let table = match self.tables.get(table) {
+                Some(table) => table,
+                None => return Ok(None),
+            };
+            let entry = table.entries.get(key);
+            match entry {
+                Some(entry) if entry.live() => {
+                    self.touch(&entry);
+                    return Ok(Some(entry.value.clone()));
+                }
+                entry => entry.is_some(),
+            }
+        };
+        if value {
+            self.remove_expired(table, key);
+        }
+        Ok(None)
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.insert(key, value))
+        let size = key.len() + value.encoded_len() + ENTRY_OVERHEAD;
+        let tick = self.tick();
+        let entry = Entry {
+            value,
+            size,
+            version: tick,
+            expire_at: None,
+            last_access: AtomicU64::new(tick),
+            hits: AtomicU64::new(0),
+        };
+        let (old, table_used) = {
+            let t = self.get_or_create_table(table);
+            // accounted with the entry locked, see remove_entry
+            let old = match t.entries.entry(key) {
+                MapEntry::Occupied(mut v) => {
+                    self.account(&t, size, v.get().size);
+                    Some(v.insert(entry))
+                }
+                MapEntry::Vacant(v) => {
+                    self.account(&t, size, 0);
+                    v.insert(entry);
+                    None
+                }
+            };
+            let old = old.filter(|v| v.live()).map(|v| v.value);
+            (old, t.used.load(Ordering::Relaxed))
+        };
+        if self.bounded() {
+            self.evict_if_needed(table, table_used);
+        }
+        Ok(old)
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.contains_key(key))
+        let result = self
+            .tables
+            .get(table)
+            .is_some_and(|table| table.entries.get(key).is_some_and(|v| v.live()));
+        Ok(result)
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        // Ok(table.remove(key).map(|v| v.1))
-        Ok(table.remove(key).map(|(_key, value)| value))
+        let table = match self.tables.get(table) {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        match self.remove_entry(&table, key, |_| true) {
+            Some(entry) => {
+                let live = entry.live();
+                Ok(Some(entry.value).filter(|_| live))
+            }
+            None => Ok(None),
+        }
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let table = self.get_or_create_table(table);
+        let table = match self.tables.get(table) {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
         Ok(table
+            .entries
             .iter()
-            .map(|v| Kvpair::new(v.key(), v.value().clone()))
+            .filter(|v| v.live())
+            .map(|v| Kvpair::new(v.key(), v.value.clone()))
             .collect())
     }
 
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let table = self.get_or_create_table(table).clone();
-        let iter = StorageIter::new(table.into_iter());
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError> {
+        let table = self
+            .tables
+            .get(table)
+            .map(|v| v.entries.clone())
+            .unwrap_or_default();
+        let pairs = table
+            .into_iter()
+            .filter(|(_, v)| v.live())
+            .map(|(k, v)| (k, v.value));
+        let iter = StorageIter::new(pairs);
         Ok(Box::new(iter))
     }
+
+    fn get_tables(&self) -> Result<Vec<String>, KvError> {
+        Ok(self.tables.iter().map(|v| v.key().clone()).collect())
+    }
+
+    // The TTLs are not kept in the backup
+    fn backup(&self, dir: &Path) -> Result<(), KvError> {
+        fs::create_dir(dir)?;
+        let mut file = BufWriter::new(File::create(dir.join(DUMP_FILE))?);
+        for table in self.tables.iter() {
+            for pair in table.entries.iter().filter(|v| v.live()) {
+                let hset = Hset {
+                    table: table.key().clone(),
+                    pair: Some(Kvpair::new(pair.key(), pair.value.clone())),
+                };
+                file.write_all(&hset.encode_length_delimited_to_vec())?;
+            }
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    }
+
+    // A copy of the live kvpairs, without budgets or TTLs as in a backup
+    fn snapshot(&self) -> Result<Option<Self>, KvError> {
+        let copy = MemTable::new();
+        for table in self.tables.iter() {
+            for pair in table.entries.iter().filter(|v| v.live()) {
+                copy.set(table.key(), pair.key().clone(), pair.value.clone())?;
+            }
+        }
+        Ok(Some(copy))
+    }
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(store: &MemTable, table: &str, keys: std::ops::Range<usize>) {
+        for i in keys {
+            store.set(table, format!("k{:03}", i), "v".into()).unwrap();
+        }
+    }
+
+    #[test]
+    fn bounded_memtable_should_evict_least_recently_used() {
+        // every kvpair takes 4 + 3 + 64 bytes
+        let store = MemTable::new().max_bytes(71 * 10);
+        fill(&store, "t1", 0..10);
+        assert!(store.get("t1", "k000").unwrap().is_some());
+        fill(&store, "t1", 10..11);
+
+        let stats = store.stats();
+        assert_eq!((stats.keys, stats.evictions, stats.eviction_passes), (9, 2, 1));
+        assert!(stats.used_bytes <= 71 * 9);
+        assert!(store.contains("t1", "k000").unwrap());
+        assert!(!store.contains("t1", "k001").unwrap());
+        assert!(!store.contains("t1", "k002").unwrap());
+        assert!(store.contains("t1", "k010").unwrap());
+    }
+
+    #[test]
+    fn bounded_memtable_should_evict_least_frequently_used() {
+        let store = MemTable::new().max_bytes(71 * 10).eviction(EvictionPolicy::Lfu);
+        fill(&store, "t1", 0..10);
+        for i in 0..9 {
+            store.get("t1", &format!("k{:03}", i)).unwrap();
+        }
+        store.get("t1", "k000").unwrap();
+        fill(&store, "t1", 10..11);
+
+        // k009 and the new k010 were never read
+        assert!(!store.contains("t1", "k009").unwrap());
+        assert!(!store.contains("t1", "k010").unwrap());
+        assert!(store.contains("t1", "k000").unwrap());
+    }
+
+    #[test]
+    fn bounded_memtable_should_evict_expiring_first() {
+        let store = MemTable::new().max_bytes(71 * 10).eviction(EvictionPolicy::TtlFirst);
+        fill(&store, "t1", 0..10);
+        assert!(store.expire("t1", "k005", Duration::from_secs(60)).unwrap());
+        assert!(store.expire("t1", "k007", Duration::from_secs(30)).unwrap());
+        assert!(!store.expire("t1", "k100", Duration::from_secs(30)).unwrap());
+        fill(&store, "t1", 10..11);
+
+        assert!(!store.contains("t1", "k005").unwrap());
+        assert!(!store.contains("t1", "k007").unwrap());
+        assert!(store.contains("t1", "k000").unwrap());
+    }
+
+    #[test]
+    fn bounded_memtable_should_evict_randomly_within_budget() {
+        let store = MemTable::new().max_bytes(71 * 100).eviction(EvictionPolicy::Random);
+        fill(&store, "t1", 0..500);
+        let stats = store.stats();
+        assert!(stats.used_bytes <= 71 * 100);
+        assert_eq!(stats.keys + stats.evictions, 500);
+    }
+
+    #[test]
+    fn table_limit_should_only_evict_from_the_table() {
+        let store = MemTable::new().max_table_bytes(71 * 10);
+        fill(&store, "t1", 0..20);
+        fill(&store, "t2", 0..20);
+        fill(&store, "t2", 0..1);
+        let t1 = store.get_all("t1").unwrap();
+        assert!(t1.len() <= 10);
+        assert!(store.contains("t2", "k000").unwrap());
+        assert!(store.get_all("t2").unwrap().len() <= 10);
+    }
+
+    #[test]
+    fn collections_should_not_be_evicted() {
+        let store = MemTable::new().max_bytes(71 * 10);
+        let values: Vec<Value> = (0..5).map(|i| Value::from(i as i64)).collect();
+        store.lpush("t1", "l", values).unwrap();
+        fill(&store, "t1", 0..20);
+
+        assert_eq!(store.lrange("t1", "l", 0, -1).unwrap().len(), 5);
+        assert!(store.stats().evictions > 0);
+    }
+
+    #[test]
+    fn concurrent_sets_and_dels_should_keep_the_bytes_accounted() {
+        let store = MemTable::new();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for i in 0..1000 {
+                        store.set("t1", "k000".into(), "v".into()).unwrap();
+                        if i % 2 == 0 {
+                            store.del("t1", "k000").unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        let used = store.stats().used_bytes;
+        assert_eq!(used, 71 * store.get_all("t1").unwrap().len() as u64);
+    }
+
+    #[test]
+    fn expired_kvpairs_should_be_gone() {
+        let store = MemTable::new();
+        fill(&store, "t1", 0..2);
+        assert!(store.expire("t1", "k000", Duration::ZERO).unwrap());
+        assert_eq!(store.get("t1", "k000").unwrap(), None);
+        assert_eq!(store.get_all("t1").unwrap().len(), 1);
+        let stats = store.stats();
+        assert_eq!((stats.keys, stats.expired, stats.used_bytes), (1, 1, 71));
+
+        // set again, the TTL is gone
+        store.set("t1", "k001".into(), "v".into()).unwrap();
+        assert!(store.expire("t1", "k001", Duration::ZERO).unwrap());
+        assert_eq!(store.set("t1", "k001".into(), "v1".into()).unwrap(), None);
+        assert_eq!(store.get("t1", "k001").unwrap(), Some("v1".into()));
+        assert_eq!(store.stats().used_bytes, 72);
+    }
+
+    #[test]
+    fn snapshot_should_not_see_later_writes() {
+        let store = MemTable::new().max_bytes(1024);
+        fill(&store, "t1", 0..3);
+        assert!(store.expire("t1", "k002", Duration::ZERO).unwrap());
+
+        let snapshot = store.snapshot().unwrap().unwrap();
+        store.set("t1", "k000".into(), "v1".into()).unwrap();
+        store.del("t1", "k001").unwrap();
+
+        let mut pairs = snapshot.get_all("t1").unwrap();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        let keys: Vec<_> = pairs.iter().map(|v| v.key.as_str()).collect();
+        assert_eq!(keys, ["k000", "k001"]);
+        assert_eq!(snapshot.get("t1", "k000").unwrap(), Some("v".into()));
+        assert_eq!(snapshot.stats().max_bytes, None);
+    }
 }
@@ -0,0 +1,249 @@
+// Offline copy of every table from one Storage into another through get_iter, so a store could
+// move between backends. The tables done are recorded in a checkpoint file, a migration which
+// stopped halfway resumes from the table it was copying, copying a kvpair twice is harmless.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+
+use serde_json::json;
+
+use crate::{KvError, Storage};
+
+/// How far a migration is, reported every few kvpairs and when a table is done
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrateProgress {
+    pub table: String,
+    /// Kvpairs of the table copied so far
+    pub copied: u64,
+    /// Kvpairs of all tables copied so far in this run
+    pub total: u64,
+    /// Index of the table in the tables to migrate
+    pub table_index: usize,
+    pub tables: usize,
+    /// The table is copied and verified
+    pub done: bool,
+}
+
+/// The result of a migration
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrateReport {
+    /// Kvpairs copied of each table, verified by counting them in the target
+    pub tables: BTreeMap<String, u64>,
+    /// Tables done in an earlier run, from the checkpoint
+    pub resumed: Vec<String>,
+}
+
+type ProgressFn<'a> = Box<dyn FnMut(&MigrateProgress) + 'a>;
+
+pub struct Migration<'a, S: ?Sized, T: ?Sized> {
+    source: &'a S,
+    target: &'a T,
+    checkpoint: Option<PathBuf>,
+    progress_every: u64,
+    on_progress: Option<ProgressFn<'a>>,
+}
+
+impl<'a, S, T> Migration<'a, S, T>
+where
+    S: Storage + ?Sized,
+    T: Storage + ?Sized,
+{
+    pub fn new(source: &'a S, target: &'a T) -> Self {
+        Self {
+            source,
+            target,
+            checkpoint: None,
+            progress_every: 10_000,
+            on_progress: None,
+        }
+    }
+
+    // Record the tables done in this file and skip them when it exists, it's removed once the
+    // migration is done
+    pub fn checkpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
+    // Call f every so many kvpairs and when a table is done
+    pub fn progress(mut self, every: u64, f: impl FnMut(&MigrateProgress) + 'a) -> Self {
+        self.progress_every = every.max(1);
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    pub fn run(mut self) -> Result<MigrateReport, KvError> {
+        let mut tables = self.source.get_tables()?;
+        tables.sort();
+        // refuse up front what the key layout of the target can't hold, rather than halfway
+        for table in &tables {
+            self.target.check_table(table)?;
+        }
+
+        let mut done = self.load_checkpoint()?;
+        let mut report = MigrateReport::default();
+        let mut total = 0;
+        for (table_index, table) in tables.iter().enumerate() {
+            if done.contains(table) {
+                report.resumed.push(table.clone());
+                continue;
+            }
+
+            let mut progress = MigrateProgress {
+                table: table.clone(),
+                copied: 0,
+                total,
+                table_index,
+                tables: tables.len(),
+                done: false,
+            };
+            let mut next_report = self.progress_every;
+            for pair in self.source.get_iter(table)? {
+                // a kvpair which can't be read fails the migration rather than being skipped
+                let pair = pair?;
+                self.target
+                    .set(table, pair.key, pair.value.unwrap_or_default())?;
+                progress.copied += 1;
+                progress.total += 1;
+                if progress.copied == next_report {
+                    self.report(&progress);
+                    next_report += self.progress_every;
+                }
+            }
+
+            let mut count = 0;
+            for pair in self.target.get_iter(table)? {
+                pair?;
+                count += 1;
+            }
+            if count != progress.copied {
+                return Err(KvError::MigrationError(format!(
+                    "table {} has {} kvpairs in the target after copying {}, was it empty?",
+                    table, count, progress.copied
+                )));
+            }
+
+            progress.done = true;
+            self.report(&progress);
+            total = progress.total;
+            report.tables.insert(table.clone(), progress.copied);
+            done.insert(table.clone());
+            self.save_checkpoint(&done)?;
+        }
+
+        if let Some(path) = &self.checkpoint {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(report)
+    }
+
+    fn report(&mut self, progress: &MigrateProgress) {
+        if let Some(f) = self.on_progress.as_mut() {
+            f(progress);
+        }
+    }
+
+    fn load_checkpoint(&self) -> Result<BTreeSet<String>, KvError> {
+        let path = match &self.checkpoint {
+            Some(path) if path.exists() => path,
+            _ => return Ok(BTreeSet::new()),
+        };
+        let invalid = || KvError::MigrationError(format!("invalid checkpoint {}", path.display()));
+        let data: serde_json::Value =
+            serde_json::from_slice(&fs::read(path)?).map_err(|_| invalid())?;
+        data["tables"]
+            .as_array()
+            .ok_or_else(invalid)?
+            .iter()
+            .map(|v| v.as_str().map(|v| v.to_string()).ok_or_else(invalid))
+            .collect()
+    }
+
+    // Written to a temporary file first, a checkpoint is never left half written
+    fn save_checkpoint(&self, done: &BTreeSet<String>) -> Result<(), KvError> {
+        let path = match &self.checkpoint {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, json!({ "tables": done }).to_string())?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb};
+    use tempfile::tempdir;
+
+    fn source() -> MemTable {
+        let store = MemTable::new();
+        for i in 0..25 {
+            store.set("t1", format!("k{}", i), (i as i64).into()).unwrap();
+        }
+        store.set("t2", "a:b".into(), "v".into()).unwrap();
+        store
+    }
+
+    #[test]
+    fn migrate_should_copy_and_verify_every_table() {
+        let dir = tempdir().unwrap();
+        let source = source();
+        let target = SledDb::new(dir.path().join("db"));
+
+        let mut events = Vec::new();
+        let report = Migration::new(&source, &target)
+            .progress(10, |v| events.push((v.table.clone(), v.copied, v.done)))
+            .run()
+            .unwrap();
+
+        let expected: BTreeMap<_, _> = [("t1".into(), 25), ("t2".into(), 1)].into();
+        assert_eq!(report.tables, expected);
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[2], ("t1".into(), 25, true));
+        assert_eq!(target.get("t1", "k7").unwrap(), Some(7.into()));
+        let pairs = target.get_all("t2").unwrap();
+        assert_eq!(pairs[0].key, "a:b");
+
+        // a target which already has other kvpairs in the table fails the verification
+        let other = MemTable::new();
+        other.set("t2", "x".into(), "v".into()).unwrap();
+        let result = Migration::new(&target, &other).run();
+        assert!(matches!(result, Err(KvError::MigrationError(_))));
+    }
+
+    #[test]
+    fn migrate_should_refuse_tables_the_target_can_not_hold() {
+        let dir = tempdir().unwrap();
+        let source = source();
+        source.set("t:3", "k".into(), "v".into()).unwrap();
+        let target = SledDb::new(dir.path().join("db"));
+
+        let result = Migration::new(&source, &target).run();
+        assert!(matches!(result, Err(KvError::InvalidCommand(_))));
+        assert!(target.get_tables().unwrap().is_empty());
+    }
+
+    #[test]
+    fn migrate_should_resume_from_checkpoint() {
+        let dir = tempdir().unwrap();
+        let checkpoint = dir.path().join("migrate.checkpoint");
+        fs::write(&checkpoint, r#"{"tables": ["t1"]}"#).unwrap();
+        let source = source();
+        let target = MemTable::new();
+
+        let report = Migration::new(&source, &target)
+            .checkpoint(&checkpoint)
+            .run()
+            .unwrap();
+        assert_eq!(report.resumed, vec!["t1".to_string()]);
+        assert_eq!(report.tables.keys().collect::<Vec<_>>(), vec!["t2"]);
+        assert_eq!(target.get("t1", "k1").unwrap(), None);
+        assert!(!checkpoint.exists());
+    }
+}
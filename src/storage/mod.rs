@@ -1,9 +1,22 @@
+mod cached;
+mod collection;
+mod encryption;
 mod memory;
+mod migrate;
+mod record;
 mod rocks;
 mod sleddb;
 
 use crate::{KvError, Kvpair, Value};
-pub use memory::MemTable;
+use std::path::Path;
+pub use cached::{CachedStorage, WriteMode};
+pub use encryption::{Encryption, EnvKeyProvider, FileKeyProvider, KeyProvider, MasterKey};
+pub(crate) use collection::{
+    as_collection, check_overwrite, check_scalar, elements_table, set_elements, zset_scores,
+};
+pub use memory::{EvictionPolicy, MemTable, MemTableStats};
+pub use migrate::{MigrateProgress, MigrateReport, Migration};
+pub use record::{Record, RecordMeta, UpgradeReport, RECORD_VERSION};
 pub use rocks::RocksDB;
 pub use sleddb::SledDb;
 
@@ -18,8 +31,107 @@ pub trait Storage {
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
     /// 遍历 HashTable，返回所有 kv pair（这个接口不好）
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
-    /// 遍历 HashTable，返回 kv pair 的 Iterator
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    /// 遍历 HashTable，返回 kv pair 的 Iterator，读不出来的 kv pair（比如损坏或者无法解密）是 Err
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError>;
+    /// 返回所有 HashTable 的名字
+    fn get_tables(&self) -> Result<Vec<String>, KvError>;
+    /// 把当前的数据备份到 dir，dir 必须不存在；备份期间的写入不保证包含在备份中
+    fn backup(&self, dir: &Path) -> Result<(), KvError>;
+    /// 当前数据的一致视图，不受之后写入的影响，要能很快取得：备份时在挡住写入期间取得，
+    /// 之后从它写出备份。没有这样的视图时返回 None，备份在挡住写入期间进行
+    fn snapshot(&self) -> Result<Option<Self>, KvError>
+    where
+        Self: Sized,
+    {
+        Ok(None)
+    }
+    /// 检查 table 的名字能否存在这里，把 "table:key" 作为 key 的实现中 table 不能有 ':'
+    fn check_table(&self, _table: &str) -> Result<(), KvError> {
+        Ok(())
+    }
+
+    // 下面是 list、set、zset 的操作，默认把每个元素存成一个 kvpair，见 collection.rs；
+    // key 上存的不是对应的类型时返回 WrongType
+
+    /// 把一组 value 依次插入 key 上的 list 的头部，返回 list 的长度
+    fn lpush(&self, table: &str, key: &str, values: Vec<Value>) -> Result<u64, KvError> {
+        collection::lpush(self, table, key, values)
+    }
+    /// 从 key 上的 list 的头部弹出最多 count 个 value
+    fn lpop(&self, table: &str, key: &str, count: u64) -> Result<Vec<Value>, KvError> {
+        collection::lpop(self, table, key, count)
+    }
+    /// 返回 list 中下标从 start 到 stop（包含 stop）的 value，负数表示从尾部数起
+    fn lrange(&self, table: &str, key: &str, start: i64, stop: i64) -> Result<Vec<Value>, KvError> {
+        collection::lrange(self, table, key, start, stop)
+    }
+    /// 往 key 上的 set 中加入一组 member，返回新加入的个数
+    fn sadd(&self, table: &str, key: &str, members: Vec<Value>) -> Result<u64, KvError> {
+        collection::sadd(self, table, key, members)
+    }
+    /// 从 set 中删除一组 member，返回删除的个数
+    fn srem(&self, table: &str, key: &str, members: &[Value]) -> Result<u64, KvError> {
+        collection::srem(self, table, key, members)
+    }
+    /// 返回 set 中所有的 member
+    fn smembers(&self, table: &str, key: &str) -> Result<Vec<Value>, KvError> {
+        collection::smembers(self, table, key)
+    }
+    /// 往 key 上的 zset 中加入一组 member 和 score，返回新加入的个数
+    fn zadd(&self, table: &str, key: &str, members: Vec<(String, f64)>) -> Result<u64, KvError> {
+        collection::zadd(self, table, key, members)
+    }
+    /// 按 score 排序，返回下标从 start 到 stop（包含 stop）的 member 和 score
+    fn zrange(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        collection::zrange(self, table, key, start, stop)
+    }
+    /// 按 score 排序，返回 score 在 min 和 max 之间（包含两端）的 member 和 score
+    fn zrangebyscore(
+        &self,
+        table: &str,
+        key: &str,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        collection::zrangebyscore(self, table, key, min, max)
+    }
+    /// 按 key 的顺序返回 table 中 key 在 start（包含）和 end（不包含）之间的 kv pair，
+    /// end 为 None 时直到 table 的末尾；默认的实现遍历整个 table 再排序
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError> {
+        sorted_range(self.get_iter(table)?, start, end)
+    }
+    /// 删除一个 key，key 上是 list、set 或 zset 时一起删除它的元素
+    fn del_value(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        collection::del(self, table, key)
+    }
+    /// 一起写入一组 (table, key, value)，value 为 None 时删除，返回每个 key 原来的 value；
+    /// sled 和 rocksdb 原子地写入整组，默认的实现逐个写入
+    fn write_batch(
+        &self,
+        writes: Vec<(String, String, Option<Value>)>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        writes
+            .into_iter()
+            .map(|(table, key, value)| match value {
+                Some(value) => self.set(&table, key, value),
+                None => self.del(&table, &key),
+            })
+            .collect()
+    }
 }
 
 pub struct StorageIter<T> {
@@ -37,11 +149,45 @@ where
     T: Iterator,
     T::Item: Into<Kvpair>,
 {
-    type Item = Kvpair;
+    type Item = Result<Kvpair, KvError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.data.next().map(|v| v.into())
+        self.data.next().map(|v| Ok(v.into()))
+    }
+}
+
+// sled and rocksdb keep a kvpair as "table:key", a table with ':' would be read back as another
+pub(crate) fn check_prefix_table(table: &str) -> Result<(), KvError> {
+    match table.contains(':') {
+        true => Err(KvError::InvalidCommand(format!(
+            "Table name {:?} can't contain ':'",
+            table
+        ))),
+        false => Ok(()),
+    }
+}
+
+// Tables starting with '\0' hold what's derived from the tables of the clients, the elements
+// of the collections and the secondary indexes
+pub(crate) fn is_internal_table(table: &str) -> bool {
+    table.starts_with('\0')
+}
+
+// The pairs with a key from start to end, sorted by the key
+pub(crate) fn sorted_range(
+    pairs: impl Iterator<Item = Result<Kvpair, KvError>>,
+    start: &str,
+    end: Option<&str>,
+) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError> {
+    let mut range = Vec::new();
+    for pair in pairs {
+        let pair = pair?;
+        if pair.key.as_str() >= start && end.is_none_or(|end| pair.key.as_str() < end) {
+            range.push(pair);
+        }
     }
+    range.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(Box::new(range.into_iter().map(Ok)))
 }
 
 pub(crate) fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
@@ -51,6 +197,9 @@ pub(crate) fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::thread;
     use tempfile::tempdir;
 
     #[test]
@@ -113,6 +262,412 @@ mod tests {
         test_get_iter(store);
     }
 
+    #[test]
+    fn memtable_key_layout_should_work() {
+        test_key_layout(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_key_layout_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_key_layout(store);
+        assert!(SledDb::new(tempdir().unwrap()).check_table("a:b").is_err());
+    }
+
+    #[test]
+    fn rocksdb_key_layout_should_work() {
+        let dir = tempdir().unwrap();
+        let store = RocksDB::new(dir);
+        test_key_layout(store);
+        assert!(RocksDB::new(tempdir().unwrap()).check_table("a:b").is_err());
+    }
+
+    #[test]
+    fn memtable_collections_should_work() {
+        test_collections(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_collections_should_work() {
+        let dir = tempdir().unwrap();
+        test_collections(SledDb::new(dir));
+    }
+
+    #[test]
+    fn rocksdb_collections_should_work() {
+        let dir = tempdir().unwrap();
+        test_collections(RocksDB::new(dir));
+    }
+
+    #[test]
+    fn memtable_get_range_should_work() {
+        test_get_range(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_get_range_should_work() {
+        let dir = tempdir().unwrap();
+        test_get_range(SledDb::new(dir));
+    }
+
+    #[test]
+    fn rocksdb_get_range_should_work() {
+        let dir = tempdir().unwrap();
+        test_get_range(RocksDB::new(dir));
+    }
+
+    #[test]
+    fn rocksdb_scans_should_go_on_across_batches() {
+        let dir = tempdir().unwrap();
+        let store = RocksDB::new(dir);
+        for i in 0..600 {
+            store.set("t1", format!("k{:03}", i), (i as i64).into()).unwrap();
+        }
+        store.set("t2", "k000".into(), "v".into()).unwrap();
+
+        let keys: Vec<_> = store.get_iter("t1").unwrap().map(|v| v.unwrap().key).collect();
+        assert_eq!((keys.len(), keys[0].as_str(), keys[599].as_str()), (600, "k000", "k599"));
+        assert_eq!(store.get_all("t1").unwrap().len(), 600);
+        let range = store.get_range("t1", "k100", Some("k500")).unwrap();
+        assert_eq!(range.count(), 400);
+
+        // the records are read a batch at a time, a later batch sees the writes before it
+        let mut iter = store.get_iter("t1").unwrap();
+        iter.next();
+        store.set("t1", "k599".into(), "v".into()).unwrap();
+        assert_eq!(iter.last().unwrap().unwrap().value, Some("v".into()));
+    }
+
+    #[test]
+    fn sleddb_legacy_records_should_be_read_and_upgraded() {
+        let dir = tempdir().unwrap();
+        {
+            let db = sled::open(dir.path()).unwrap();
+            for (key, value) in legacy_records() {
+                db.insert(key, value).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        test_legacy_records(SledDb::new(dir.path()));
+    }
+
+    #[test]
+    fn rocksdb_legacy_records_should_be_read_and_upgraded() {
+        let dir = tempdir().unwrap();
+        {
+            let db = rocksdb::DB::open_default(dir.path()).unwrap();
+            for (key, value) in legacy_records() {
+                db.put(key, value).unwrap();
+            }
+        }
+        test_legacy_records(RocksDB::new(dir.path()));
+    }
+
+    // Two records from before the header and one which is no record at all
+    fn legacy_records() -> Vec<(&'static str, Vec<u8>)> {
+        vec![
+            ("t1:k1", legacy_record("v1")),
+            ("t1:k2", legacy_record("v2")),
+            ("t1:k3", vec![0xff, 0xff, 0xff]),
+        ]
+    }
+
+    fn test_legacy_records(store: impl Records) {
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.set("t1", "k2".into(), "v3".into()).unwrap(), Some("v2".into()));
+        assert_eq!(store.record("t1", "k1").unwrap().unwrap().format, 0);
+        assert!(matches!(store.get("t1", "k3"), Err(KvError::CorruptRecord(_))));
+        assert!(store.get_iter("t1").unwrap().any(|v| v.is_err()));
+        assert!(store.get_all("t1").is_err());
+
+        let report = store.upgrade().unwrap();
+        assert_eq!((report.scanned, report.upgraded), (3, 1));
+        assert_eq!(report.corrupt, vec!["t1:k3".to_string()]);
+        let record = store.record("t1", "k1").unwrap().unwrap();
+        assert_eq!((record.value, record.format), ("v1".into(), RECORD_VERSION));
+        store.set("t1", "k2".into(), "v4".into()).unwrap();
+        assert_eq!(store.record("t1", "k2").unwrap().unwrap().meta.version, Some(2));
+    }
+
+    // The bare protobuf of the value, as it was written before records had a header
+    fn legacy_record(value: &str) -> Vec<u8> {
+        Value::from(value).try_into().unwrap()
+    }
+
+    #[test]
+    fn sleddb_encrypted_should_work() {
+        let dir = tempdir().unwrap();
+        let (_, keys) = key_file(dir.path());
+        let store = |name: &str| {
+            let encryption = Encryption::new(keys.clone()).encrypt_keys(1).unwrap();
+            SledDb::new(dir.path().join(name)).encryption(encryption).unwrap()
+        };
+        test_basic_interface(store("basic"));
+        test_get_all(store("all"));
+        test_get_range(store("range"));
+    }
+
+    #[test]
+    fn rocksdb_encrypted_should_work() {
+        let dir = tempdir().unwrap();
+        let (_, keys) = key_file(dir.path());
+        let store = |name: &str| {
+            let encryption = Encryption::new(keys.clone()).encrypt_keys(1).unwrap();
+            RocksDB::new(dir.path().join(name)).encryption(encryption).unwrap()
+        };
+        test_basic_interface(store("basic"));
+        test_get_all(store("all"));
+        test_get_range(store("range"));
+    }
+
+    #[test]
+    fn sleddb_rotated_key_should_be_reencrypted_in_background() {
+        let dir = tempdir().unwrap();
+        let (path, keys) = key_file(dir.path());
+        let encryption = Encryption::new(keys.clone());
+        let store = SledDb::new(dir.path().join("db")).encryption(encryption).unwrap();
+        test_rotated_key_reencryption(store, &path, &keys);
+    }
+
+    #[test]
+    fn rocksdb_rotated_key_should_be_reencrypted_in_background() {
+        let dir = tempdir().unwrap();
+        let (path, keys) = key_file(dir.path());
+        let encryption = Encryption::new(keys.clone());
+        let store = RocksDB::new(dir.path().join("db")).encryption(encryption).unwrap();
+        test_rotated_key_reencryption(store, &path, &keys);
+    }
+
+    #[test]
+    fn sleddb_keys_marker_should_be_checked() {
+        let dir = tempdir().unwrap();
+        let (_, keys) = key_file(dir.path());
+        test_keys_marker(&keys, |v| SledDb::open(dir.path().join("db"), v));
+    }
+
+    #[test]
+    fn rocksdb_keys_marker_should_be_checked() {
+        let dir = tempdir().unwrap();
+        let (_, keys) = key_file(dir.path());
+        test_keys_marker(&keys, |v| RocksDB::open(dir.path().join("db"), v));
+    }
+
+    // The records of the stores with a record format
+    trait Records: Storage + Sized {
+        fn record(&self, table: &str, key: &str) -> Result<Option<Record>, KvError>;
+        fn upgrade(&self) -> Result<UpgradeReport, KvError>;
+        fn upgrade_in_background(&self) -> thread::JoinHandle<Result<UpgradeReport, KvError>>;
+    }
+
+    macro_rules! impl_records {
+        ($($store:ty),*) => {$(
+            impl Records for $store {
+                fn record(&self, table: &str, key: &str) -> Result<Option<Record>, KvError> {
+                    <$store>::record(self, table, key)
+                }
+
+                fn upgrade(&self) -> Result<UpgradeReport, KvError> {
+                    <$store>::upgrade(self)
+                }
+
+                fn upgrade_in_background(
+                    &self,
+                ) -> thread::JoinHandle<Result<UpgradeReport, KvError>> {
+                    <$store>::upgrade_in_background(self)
+                }
+            }
+        )*};
+    }
+
+    impl_records!(SledDb, RocksDB);
+
+    fn test_rotated_key_reencryption(store: impl Records, path: &Path, keys: &FileKeyProvider) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.record("t1", "k1").unwrap().unwrap().key_id, Some(1));
+
+        add_key(path, 2);
+        keys.reload().unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        let report = store.upgrade_in_background().join().unwrap().unwrap();
+        assert_eq!((report.scanned, report.upgraded), (2, 1));
+        for (key, value) in [("k1", "v1"), ("k2", "v2")] {
+            let record = store.record("t1", key).unwrap().unwrap();
+            assert_eq!((record.value, record.key_id), (value.into(), Some(2)));
+        }
+    }
+
+    // A store keeps the keys the way it's created with, the element tables of its collections
+    // are listed by their names
+    fn test_keys_marker<S: Storage>(
+        keys: &Arc<FileKeyProvider>,
+        open: impl Fn(Option<Encryption>) -> Result<S, KvError>,
+    ) {
+        let encrypted = || Some(Encryption::new(keys.clone()).encrypt_keys(1).unwrap());
+        {
+            let store = open(encrypted()).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.lpush("t1", "l1", vec!["a".into()]).unwrap();
+        }
+        assert!(open(Some(Encryption::new(keys.clone()))).is_err());
+        assert!(open(None).is_err());
+
+        let store = open(encrypted()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        let tables = store.get_tables().unwrap();
+        assert_eq!(tables, vec![elements_table("t1", "l1"), "t1".to_string()]);
+    }
+
+    // A key file with master key 1
+    fn key_file(dir: &Path) -> (std::path::PathBuf, Arc<FileKeyProvider>) {
+        let path = dir.join("keys");
+        add_key(&path, 1);
+        let keys = Arc::new(FileKeyProvider::open(&path).unwrap());
+        (path, keys)
+    }
+
+    fn add_key(path: &Path, id: u32) {
+        let key = MasterKey::generate().unwrap().to_base64();
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path).unwrap();
+        writeln!(file, "{}:{}", id, key).unwrap();
+    }
+
+    fn cached(dir: &Path, mode: WriteMode) -> CachedStorage<MemTable, SledDb> {
+        let cache = MemTable::new().max_bytes(1024);
+        CachedStorage::new(cache, SledDb::new(dir)).write_mode(mode)
+    }
+
+    const WRITE_BEHIND: WriteMode = WriteMode::WriteBehind { max_pending: 2 };
+
+    #[test]
+    fn cached_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        test_basic_interface(cached(&dir.path().join("through"), WriteMode::WriteThrough));
+        test_basic_interface(cached(&dir.path().join("behind"), WRITE_BEHIND));
+    }
+
+    #[test]
+    fn cached_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        test_get_all(cached(&dir.path().join("through"), WriteMode::WriteThrough));
+        test_get_all(cached(&dir.path().join("behind"), WRITE_BEHIND));
+    }
+
+    #[test]
+    fn cached_iter_should_work() {
+        let dir = tempdir().unwrap();
+        test_get_iter(cached(&dir.path().join("through"), WriteMode::WriteThrough));
+        test_get_iter(cached(&dir.path().join("behind"), WRITE_BEHIND));
+    }
+
+    #[test]
+    fn cached_reads_should_not_cache_replaced_values() {
+        let dir = tempdir().unwrap();
+        for (name, mode) in [("through", WriteMode::WriteThrough), ("behind", WRITE_BEHIND)] {
+            let store = cached(&dir.path().join(name), mode);
+            thread::scope(|s| {
+                s.spawn(|| {
+                    for i in 0..500i64 {
+                        match i % 7 {
+                            0 => store.del("t1", "k").unwrap(),
+                            _ => store.set("t1", "k".into(), i.into()).unwrap(),
+                        };
+                    }
+                });
+                for _ in 0..3 {
+                    s.spawn(|| {
+                        for _ in 0..500 {
+                            store.get("t1", "k").unwrap();
+                        }
+                    });
+                }
+            });
+            store.flush().unwrap();
+            let expected = Some(499i64.into());
+            assert_eq!(store.backing().get("t1", "k").unwrap(), expected);
+            assert_eq!(store.get("t1", "k").unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn cached_key_layout_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached(&dir.path().join("behind"), WRITE_BEHIND);
+        test_key_layout(store);
+    }
+
+    #[test]
+    fn cached_backup_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached(&dir.path().join("db"), WRITE_BEHIND);
+        test_backup(store, &dir.path().join("backup"), |backup| {
+            SledDb::restore(backup, dir.path().join("restored")).unwrap()
+        });
+    }
+
+    #[test]
+    fn memtable_backup_should_work() {
+        let dir = tempdir().unwrap();
+        test_backup(MemTable::new(), &dir.path().join("backup"), |backup| {
+            MemTable::restore(backup).unwrap()
+        });
+    }
+
+    #[test]
+    fn sleddb_backup_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path().join("db"));
+        test_backup(store, &dir.path().join("backup"), |backup| {
+            SledDb::restore(backup, dir.path().join("restored")).unwrap()
+        });
+    }
+
+    #[test]
+    fn rocksdb_backup_should_work() {
+        let dir = tempdir().unwrap();
+        let store = RocksDB::new(dir.path().join("db"));
+        test_backup(store, &dir.path().join("backup"), |backup| {
+            RocksDB::restore(backup, dir.path().join("restored")).unwrap()
+        });
+    }
+
+    #[test]
+    fn memtable_write_batch_should_work() {
+        test_write_batch(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_write_batch_should_work() {
+        test_write_batch(SledDb::new(tempdir().unwrap()));
+    }
+
+    #[test]
+    fn rocksdb_write_batch_should_work() {
+        test_write_batch(RocksDB::new(tempdir().unwrap()));
+    }
+
+    #[test]
+    fn cached_write_batch_should_work() {
+        let dir = tempdir().unwrap();
+        test_write_batch(cached(&dir.path().join("through"), WriteMode::WriteThrough));
+        test_write_batch(cached(&dir.path().join("behind"), WRITE_BEHIND));
+    }
+
+    fn test_write_batch(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let writes = vec![
+            ("t1".to_string(), "k1".to_string(), None),
+            ("t1".to_string(), "k2".to_string(), Some("v2".into())),
+            ("t2".to_string(), "k1".to_string(), Some("v3".into())),
+            ("t2".to_string(), "k1".to_string(), Some("v4".into())),
+        ];
+        let olds = store.write_batch(writes).unwrap();
+        assert_eq!(olds, vec![Some("v1".into()), None, None, Some("v3".into())]);
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("t2", "k1").unwrap(), Some("v4".into()));
+    }
+
     fn test_basic_interface(store: impl Storage) {
         // Call set() first time will create table {{t1}}, insert the key and return None since there is no value before.
         // set() will return previous value of the key.
@@ -157,13 +712,82 @@ mod tests {
                 Kvpair::new("k1", "v1".into()),
                 Kvpair::new("k2", "v2".into())
             ]
-        )
+        );
+
+        let mut tables = store.get_tables().unwrap();
+        tables.sort();
+        assert_eq!(tables, vec!["t1", "t2"]);
+    }
+
+    fn test_backup<S: Storage>(store: impl Storage, dir: &Path, restore: impl FnOnce(&Path) -> S) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), 2.into()).unwrap();
+        store.backup(dir).unwrap();
+        // a backup is never overwritten
+        assert!(store.backup(dir).is_err());
+
+        // changes after the backup are not in it
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        let restored = restore(dir);
+        assert_eq!(restored.get_all("t1").unwrap(), vec![Kvpair::new("k1", "v1".into())]);
+        assert_eq!(restored.get("t2", "k2").unwrap(), Some(2.into()));
+    }
+
+    // keys with ':' are read back whole, and a table doesn't run into the tables after it
+    fn test_collections(store: impl Storage) {
+        // a key with ':' is fine, it's hex encoded in the name of the elements table
+        assert_eq!(store.lpush("t1", "l:1", vec![1.into(), 2.into()]).unwrap(), 2);
+        assert_eq!(store.lrange("t1", "l:1", 0, -1).unwrap(), vec![2.into(), 1.into()]);
+        assert_eq!(store.lpop("t1", "l:1", 1).unwrap(), vec![2.into()]);
+
+        assert_eq!(store.sadd("t1", "s", vec!["a".into(), "a".into()]).unwrap(), 1);
+        assert_eq!(store.smembers("t1", "s").unwrap(), vec!["a".into()]);
+        assert_eq!(store.srem("t1", "s", &["a".into()]).unwrap(), 1);
+        assert_eq!(store.get("t1", "s").unwrap(), None);
+
+        let members = vec![("b".into(), 2.0), ("a".into(), -1.0)];
+        assert_eq!(store.zadd("t1", "z", members).unwrap(), 2);
+        let expected = vec![("a".to_string(), -1.0), ("b".to_string(), 2.0)];
+        assert_eq!(store.zrange("t1", "z", 0, -1).unwrap(), expected);
+        assert_eq!(store.zrangebyscore("t1", "z", 0.0, 5.0).unwrap(), &expected[1..]);
+
+        assert!(matches!(
+            store.sadd("t1", "z", vec![1.into()]),
+            Err(KvError::WrongType(_, _, "set"))
+        ));
+        store.del_value("t1", "z").unwrap();
+        assert!(store.zrange("t1", "z", 0, -1).unwrap().is_empty());
+        assert_eq!(store.sadd("t1", "z", vec![1.into()]).unwrap(), 1);
+    }
+
+    // the pairs come in the order of the keys, and stop at the end of the table
+    fn test_get_range(store: impl Storage) {
+        for key in ["b", "a:1", "c", "a"] {
+            store.set("t1", key.into(), key.into()).unwrap();
+        }
+        store.set("t2", "a".into(), "a".into()).unwrap();
+        let keys = |start, end| -> Vec<String> {
+            store.get_range("t1", start, end).unwrap().map(|v| v.unwrap().key).collect()
+        };
+        assert_eq!(keys("", None), ["a", "a:1", "b", "c"]);
+        assert_eq!(keys("a:", Some("c")), ["a:1", "b"]);
+        assert_eq!(keys("b", Some("b")), Vec::<String>::new());
+        assert_eq!(keys("d", None), Vec::<String>::new());
+    }
+
+    fn test_key_layout(store: impl Storage) {
+        store.set("t1", "a:b".into(), "v1".into()).unwrap();
+        store.set("t2", "k1".into(), "v2".into()).unwrap();
+        assert_eq!(store.get_all("t1").unwrap(), vec![Kvpair::new("a:b", "v1".into())]);
+        let data: Vec<_> = store.get_iter("t1").unwrap().map(Result::unwrap).collect();
+        assert_eq!(data, vec![Kvpair::new("a:b", "v1".into())]);
+        assert!(store.check_table("t1").is_ok());
     }
 
     fn test_get_iter(store: impl Storage) {
         store.set("t2", "k1".into(), "v1".into()).unwrap();
         store.set("t2", "k2".into(), "v2".into()).unwrap();
-        let mut data: Vec<_> = store.get_iter("t2").unwrap().collect();
+        let mut data: Vec<_> = store.get_iter("t2").unwrap().map(Result::unwrap).collect();
         data.sort_by(|a, b| a.partial_cmp(b).unwrap());
         assert_eq!(
             data,
@@ -0,0 +1,390 @@
+// The record sled and rocksdb keep for a value:
+//
+//   magic (2 bytes) | format version (1) | flags (1) | metadata | protobuf of Value | crc32 (4)
+//
+// The metadata are u64 slots (big endian) present when their flag is set, in the order of the
+// flags: the unix time in milliseconds the value expires at, the version of the key, counted
+// from 1 by every set, and the unix time in milliseconds it's written at. The CRC covers all
+// the bytes before it.
+//
+// An encrypted record has the id of the master key (u32, big endian) after the metadata, and the
+// envelope sealing the protobuf of the Value in place of it, see encryption.rs.
+//
+// A legacy record is the bare protobuf of the Value, as written before there was a header. It
+// never starts with the magic, field number 0 is invalid in protobuf, so it's read as it is
+// until the upgrade of the store rewrites it.
+
+use prost::Message;
+
+use super::encryption::{Encryption, Sealer};
+use super::memory::now_millis;
+use crate::{KvError, Value};
+
+/// The format version of the records written
+pub const RECORD_VERSION: u8 = 1;
+
+const MAGIC: [u8; 2] = [0x00, 0xcb];
+const HEADER_LEN: usize = 4;
+const CRC_LEN: usize = 4;
+
+const FLAG_EXPIRE_AT: u8 = 1;
+const FLAG_VERSION: u8 = 1 << 1;
+const FLAG_TIMESTAMP: u8 = 1 << 2;
+const FLAG_ENCRYPTED: u8 = 1 << 3;
+const KNOWN_FLAGS: u8 = FLAG_EXPIRE_AT | FLAG_VERSION | FLAG_TIMESTAMP | FLAG_ENCRYPTED;
+
+/// The metadata kept with a value in its record
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecordMeta {
+    /// Unix time in milliseconds the value expires at
+    pub expire_at: Option<u64>,
+    /// How many times the key is set
+    pub version: Option<u64>,
+    /// Unix time in milliseconds the value is written at
+    pub timestamp: Option<u64>,
+}
+
+impl RecordMeta {
+    fn slots(&self) -> [(u8, Option<u64>); 3] {
+        [
+            (FLAG_EXPIRE_AT, self.expire_at),
+            (FLAG_VERSION, self.version),
+            (FLAG_TIMESTAMP, self.timestamp),
+        ]
+    }
+}
+
+/// A value with its metadata, as a persistent backend keeps it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub value: Value,
+    pub meta: RecordMeta,
+    /// Format version the record is read from, 0 for a legacy record
+    pub format: u8,
+    /// Id of the master key the value is encrypted with, None when it's in the clear
+    pub key_id: Option<u32>,
+}
+
+/// What an upgrade, or a re-encryption, of the records of a store did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpgradeReport {
+    /// Records read
+    pub scanned: u64,
+    /// Records rewritten in the current format, or with the current master key
+    pub upgraded: u64,
+    /// Keys of the records which can't be read, they are left as they are
+    pub corrupt: Vec<String>,
+}
+
+impl Record {
+    pub fn new(value: Value, meta: RecordMeta) -> Self {
+        Self {
+            value,
+            meta,
+            format: RECORD_VERSION,
+            key_id: None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with(None, &[])
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, KvError> {
+        Self::decode_with(buf, None, &[])
+    }
+
+    // The record with the value sealed by sealer when it's set, aad is the key it's stored at
+    pub(crate) fn encode_with(&self, sealer: Option<&Sealer>, aad: &[u8]) -> Vec<u8> {
+        let slots = self.meta.slots();
+        let mut flags = slots
+            .iter()
+            .filter(|(_, v)| v.is_some())
+            .fold(0, |acc, (flag, _)| acc | flag);
+        if sealer.is_some() {
+            flags |= FLAG_ENCRYPTED;
+        }
+        let mut buf = Vec::with_capacity(HEADER_LEN + 24 + self.value.encoded_len() + CRC_LEN);
+        buf.extend_from_slice(&MAGIC);
+        buf.push(RECORD_VERSION);
+        buf.push(flags);
+        for v in slots.iter().filter_map(|(_, v)| *v) {
+            buf.extend_from_slice(&v.to_be_bytes());
+        }
+        match sealer {
+            Some(sealer) => {
+                buf.extend_from_slice(&sealer.id().to_be_bytes());
+                buf.extend_from_slice(&sealer.seal(&self.value.encode_to_vec(), aad));
+            }
+            None => buf.extend_from_slice(&self.value.encode_to_vec()),
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_be_bytes());
+        buf
+    }
+
+    // The record with the value opened by encryption when it's sealed, aad is the key it's
+    // stored at
+    pub(crate) fn decode_with(
+        buf: &[u8],
+        encryption: Option<&Encryption>,
+        aad: &[u8],
+    ) -> Result<Self, KvError> {
+        // a payload which isn't a Value is as corrupt as a bad header, legacy or not
+        let corrupt = |e: prost::DecodeError| KvError::CorruptRecord(e.to_string());
+        let header = Header::parse(buf)?;
+        let value = match (header.key_id, encryption) {
+            (None, _) => Value::decode(header.payload).map_err(corrupt)?,
+            (Some(id), Some(encryption)) => {
+                Value::decode(&encryption.open(id, header.payload, aad)?[..]).map_err(corrupt)?
+            }
+            (Some(id), None) => {
+                let msg = format!("The value is encrypted with master key {}, no key is given", id);
+                return Err(KvError::EncryptionError(msg));
+            }
+        };
+        Ok(Self {
+            value,
+            meta: header.meta,
+            format: header.format,
+            key_id: header.key_id,
+        })
+    }
+}
+
+// All of a record but its value, read without decrypting it
+struct Header<'a> {
+    format: u8,
+    meta: RecordMeta,
+    key_id: Option<u32>,
+    payload: &'a [u8],
+}
+
+impl<'a> Header<'a> {
+    fn parse(buf: &'a [u8]) -> Result<Self, KvError> {
+        if !buf.starts_with(&MAGIC) {
+            return Ok(Self {
+                format: 0,
+                meta: RecordMeta::default(),
+                key_id: None,
+                payload: buf,
+            });
+        }
+        if buf.len() < HEADER_LEN + CRC_LEN {
+            return Err(KvError::CorruptRecord("truncated header".into()));
+        }
+        let (data, crc) = buf.split_at(buf.len() - CRC_LEN);
+        if crc32fast::hash(data).to_be_bytes() != crc {
+            return Err(KvError::CorruptRecord("checksum mismatch".into()));
+        }
+        let (format, flags) = (data[2], data[3]);
+        if format == 0 || format > RECORD_VERSION {
+            let msg = format!("unknown format version {}", format);
+            return Err(KvError::CorruptRecord(msg));
+        }
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(KvError::CorruptRecord(format!("unknown flags {:#04x}", flags)));
+        }
+
+        let mut rest = &data[HEADER_LEN..];
+        let mut take = |flag: u8, len: usize| -> Result<Option<&'a [u8]>, KvError> {
+            if flags & flag == 0 {
+                return Ok(None);
+            }
+            if rest.len() < len {
+                return Err(KvError::CorruptRecord("truncated metadata".into()));
+            }
+            let (v, tail) = rest.split_at(len);
+            rest = tail;
+            Ok(Some(v))
+        };
+        let mut slot = |flag: u8| -> Result<Option<u64>, KvError> {
+            Ok(take(flag, 8)?.map(|v| u64::from_be_bytes(v.try_into().unwrap())))
+        };
+        let meta = RecordMeta {
+            expire_at: slot(FLAG_EXPIRE_AT)?,
+            version: slot(FLAG_VERSION)?,
+            timestamp: slot(FLAG_TIMESTAMP)?,
+        };
+        let key_id = take(FLAG_ENCRYPTED, 4)?.map(|v| u32::from_be_bytes(v.try_into().unwrap()));
+        Ok(Self {
+            format,
+            meta,
+            key_id,
+            payload: rest,
+        })
+    }
+}
+
+// The value in a record, legacy or not
+pub(crate) fn decode_value(
+    buf: &[u8],
+    encryption: Option<&Encryption>,
+    aad: &[u8],
+) -> Result<Value, KvError> {
+    Record::decode_with(buf, encryption, aad).map(|v| v.value)
+}
+
+// The record of value set over the record old, the version of the key goes up by one. The old
+// record isn't decrypted for its version
+pub(crate) fn encode_set(
+    old: Option<&[u8]>,
+    value: &Value,
+    sealer: Option<&Sealer>,
+    aad: &[u8],
+) -> Vec<u8> {
+    let version = old
+        .and_then(|v| Header::parse(v).ok())
+        .and_then(|v| v.meta.version)
+        .unwrap_or(0);
+    let meta = RecordMeta {
+        expire_at: None,
+        version: Some(version + 1),
+        timestamp: Some(now_millis()),
+    };
+    Record::new(value.clone(), meta).encode_with(sealer, aad)
+}
+
+// The record rewritten in the current format, and sealed by sealer when it's set and the value
+// isn't encrypted with its master key already. None when there is nothing to rewrite
+pub(crate) fn upgrade(
+    buf: &[u8],
+    encryption: Option<&Encryption>,
+    sealer: Option<&Sealer>,
+    aad: &[u8],
+) -> Result<Option<Vec<u8>>, KvError> {
+    let header = Header::parse(buf)?;
+    let sealed = match sealer {
+        Some(sealer) => header.key_id == Some(sealer.id()),
+        None => true,
+    };
+    if header.format == RECORD_VERSION && sealed {
+        return Ok(None);
+    }
+    let record = Record::decode_with(buf, encryption, aad)?;
+    Ok(Some(Record::new(record.value, record.meta).encode_with(sealer, aad)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyProvider, MasterKey};
+    use std::sync::Arc;
+
+    // master key i has id i
+    struct Keys(Vec<MasterKey>);
+
+    impl KeyProvider for Keys {
+        fn current(&self) -> Result<u32, KvError> {
+            Ok(self.0.len() as u32 - 1)
+        }
+
+        fn key(&self, id: u32) -> Result<MasterKey, KvError> {
+            let key = self.0.get(id as usize).cloned();
+            key.ok_or_else(|| KvError::EncryptionError(format!("no key {}", id)))
+        }
+    }
+
+    fn encryption_of(keys: &[MasterKey]) -> Encryption {
+        Encryption::new(Arc::new(Keys(keys.to_vec())))
+    }
+
+    #[test]
+    fn record_should_roundtrip_with_metadata() {
+        let metas = [
+            RecordMeta::default(),
+            RecordMeta {
+                expire_at: Some(3),
+                version: None,
+                timestamp: Some(u64::MAX),
+            },
+            RecordMeta {
+                expire_at: Some(1),
+                version: Some(2),
+                timestamp: Some(3),
+            },
+        ];
+        for meta in metas {
+            for value in [Value::default(), "hello".into(), 42.into(), b"data".into()] {
+                let record = Record::new(value, meta);
+                assert_eq!(Record::decode(&record.encode()).unwrap(), record);
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_record_should_be_read_and_upgraded() {
+        for value in [Value::default(), "hello".into(), 1.5.into()] {
+            let legacy: Vec<u8> = value.clone().try_into().unwrap();
+            let record = Record::decode(&legacy).unwrap();
+            assert_eq!(record.value, value);
+            assert_eq!(record.format, 0);
+
+            let upgraded = upgrade(&legacy, None, None, &[]).unwrap().unwrap();
+            let record = Record::decode(&upgraded).unwrap();
+            assert_eq!((record.value, record.format), (value, RECORD_VERSION));
+            assert_eq!(upgrade(&upgraded, None, None, &[]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn corrupt_record_should_be_rejected() {
+        let buf = Record::new("hello".into(), RecordMeta::default()).encode();
+        for i in 2..buf.len() {
+            let mut corrupt = buf.clone();
+            corrupt[i] ^= 0x40;
+            assert!(Record::decode(&corrupt).is_err(), "byte {} flipped", i);
+        }
+        assert!(Record::decode(&buf[..HEADER_LEN]).is_err());
+
+        // a newer format isn't guessed at
+        let mut newer = buf[..buf.len() - CRC_LEN].to_vec();
+        newer[2] = RECORD_VERSION + 1;
+        newer.extend_from_slice(&crc32fast::hash(&newer).to_be_bytes());
+        let err = Record::decode(&newer).unwrap_err();
+        assert!(err.to_string().contains("unknown format version"));
+    }
+
+    #[test]
+    fn set_should_count_the_versions() {
+        let first = encode_set(None, &"v1".into(), None, &[]);
+        let second = encode_set(Some(&first), &"v2".into(), None, &[]);
+        let record = Record::decode(&second).unwrap();
+        assert_eq!(record.value, "v2".into());
+        assert_eq!(record.meta.version, Some(2));
+        assert!(record.meta.timestamp.is_some());
+
+        let legacy: Vec<u8> = Value::from("v0").try_into().unwrap();
+        let record = Record::decode(&encode_set(Some(&legacy), &"v1".into(), None, &[])).unwrap();
+        assert_eq!(record.meta.version, Some(1));
+    }
+
+    #[test]
+    fn encrypted_record_should_roundtrip_and_rotate() {
+        let keys = [MasterKey::generate().unwrap(), MasterKey::generate().unwrap()];
+        let encryption = encryption_of(&keys);
+        let sealer = encryption.sealer().unwrap();
+        let first = encode_set(None, &"v1".into(), Some(&sealer), b"t1:k1");
+        let buf = encode_set(Some(&first), &"v2".into(), Some(&sealer), b"t1:k1");
+        assert!(!buf.windows(2).any(|v| v == b"v2"));
+
+        let record = Record::decode_with(&buf, Some(&encryption), b"t1:k1").unwrap();
+        assert_eq!((record.value, record.key_id), ("v2".into(), Some(1)));
+        assert_eq!(record.meta.version, Some(2));
+        assert!(Record::decode(&buf).is_err());
+        assert!(Record::decode_with(&buf, Some(&encryption), b"t1:k2").is_err());
+        assert_eq!(upgrade(&buf, Some(&encryption), Some(&sealer), b"t1:k1").unwrap(), None);
+
+        // a plaintext record, or one of an older master key, is sealed with the current one
+        let older = encryption_of(&keys[..1]);
+        let older = older.sealer().unwrap();
+        let record = Record::new("v0".into(), record.meta);
+        for buf in [record.encode_with(Some(&older), b"t1:k1"), record.encode()] {
+            let sealed = upgrade(&buf, Some(&encryption), Some(&sealer), b"t1:k1");
+            let sealed = sealed.unwrap().unwrap();
+            let record = Record::decode_with(&sealed, Some(&encryption), b"t1:k1").unwrap();
+            assert_eq!((record.value, record.key_id), ("v0".into(), Some(1)));
+            assert_eq!(record.meta.version, Some(2));
+        }
+    }
+}
@@ -1,58 +1,179 @@
 // implementation of using rocksdb
 
-use crate::{flip, KvError, Kvpair, Storage, StorageIter, Value};
-use rocksdb::{Direction, IteratorMode, ReadOptions, DB};
-use std::{convert::TryInto, path::Path, str};
+use super::encryption::{check_keys_marker, Encryption, KEYS_MARKER};
+use super::record::{self, decode_value, Record, UpgradeReport};
+use crate::{check_prefix_table, flip, sorted_range, KvError, Kvpair, Storage, Value};
+use rocksdb::{checkpoint::Checkpoint, Direction, IteratorMode, ReadOptions, WriteBatch, DB};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::{fs, path::Path, str, thread};
 
-#[derive(Debug)]
-pub struct RocksDB(DB);
+// How many records a scan reads at a time
+const SCAN_BATCH: usize = 256;
+
+// The writes of a key read the record they replace, they are serialized by the lock
+#[derive(Debug, Clone)]
+pub struct RocksDB(Arc<DB>, Option<Arc<Encryption>>, Arc<Mutex<()>>);
 
 impl RocksDB {
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(DB::open_default(path).unwrap())
+        Self::from_db(DB::open_default(path).unwrap())
+    }
+
+    // Open the db at path, created when it's missing, with the encryption if any. A store with
+    // the keys kept another way is refused, including one with encrypted keys opened without
+    pub fn open(path: impl AsRef<Path>, encryption: Option<Encryption>) -> Result<Self, KvError> {
+        let mut store = Self::from_db(DB::open_default(path)?);
+        store.1 = encryption.map(Arc::new);
+        store.check_keys()?;
+        Ok(store)
+    }
+
+    // Create a db at path, which must not exist, from a checkpoint written by Storage::backup.
+    // The files of a checkpoint are a db of their own, copying them leaves the backup intact
+    pub fn restore(dir: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<Self, KvError> {
+        fs::create_dir(path.as_ref())?;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            fs::copy(entry.path(), path.as_ref().join(entry.file_name()))?;
+        }
+        Ok(Self::from_db(DB::open_default(path)?))
+    }
+
+    // Encrypt the values written, and the keys when the encryption does, see encryption.rs.
+    // The records written before stay readable as they are until they're re-encrypted. A store
+    // with the keys kept the other way is refused
+    pub fn encryption(mut self, encryption: Encryption) -> Result<Self, KvError> {
+        self.1 = Some(Arc::new(encryption));
+        self.check_keys()?;
+        Ok(self)
+    }
+
+    // Check the marker of the keys, it's written into a store without one
+    fn check_keys(&self) -> Result<(), KvError> {
+        let marker = self.0.get(KEYS_MARKER)?;
+        let empty = self.0.iterator(IteratorMode::Start).next().is_none();
+        if let Some(marker) = check_keys_marker(marker.as_deref(), empty, self.1.as_deref())? {
+            self.0.put(KEYS_MARKER, marker)?;
+        }
+        Ok(())
+    }
+
+    fn from_db(db: DB) -> Self {
+        Self(Arc::new(db), None, Arc::new(Mutex::new(())))
+    }
+
+    // The value of key with its metadata
+    pub fn record(&self, table: &str, key: &str) -> Result<Option<Record>, KvError> {
+        let name = self.get_full_key(table, key);
+        let result = self.0.get(name.as_bytes())?;
+        flip(result.map(|v| Record::decode_with(&v, self.1.as_deref(), name.as_bytes())))
+    }
+
+    // Rewrite the legacy records in the current format, and with encryption the records which
+    // aren't encrypted with the current master key. A record which can't be read is left as it
+    // is and reported. The iterator reads a snapshot, a record is put only when it isn't set
+    // meanwhile: every write takes the lock, which is held from the compare to the put
+    pub fn upgrade(&self) -> Result<UpgradeReport, KvError> {
+        let encryption = self.1.as_deref();
+        let sealer = flip(encryption.map(|v| v.sealer()))?;
+        let mut report = UpgradeReport::default();
+        for item in self.0.iterator(IteratorMode::Start) {
+            let (key, value) = item?;
+            if &*key == KEYS_MARKER {
+                continue;
+            }
+            report.scanned += 1;
+            match record::upgrade(&value, encryption, sealer.as_ref(), &key) {
+                Ok(Some(data)) => {
+                    let _lock = self.2.lock().unwrap();
+                    if self.0.get(&key)?.as_deref() == Some(&value[..]) {
+                        self.0.put(&key, data)?;
+                        report.upgraded += 1;
+                    }
+                }
+                Ok(None) => {}
+                Err(_) => report.corrupt.push(String::from_utf8_lossy(&key).into_owned()),
+            }
+        }
+        self.0.flush()?;
+        Ok(report)
+    }
+
+    // Upgrade in a thread while the store is in use, after a master key is added to the key
+    // provider the records are re-encrypted with it. For an application embedding the store,
+    // the server only serves a MemTable and `kvs upgrade` re-encrypts a store offline
+    pub fn upgrade_in_background(&self) -> thread::JoinHandle<Result<UpgradeReport, KvError>> {
+        let store = self.clone();
+        thread::spawn(move || store.upgrade())
+    }
+
+    fn get_full_key(&self, table: &str, key: &str) -> String {
+        match &self.1 {
+            Some(v) => format!("{}:{}", v.encrypt_table(table), v.encrypt_key(table, key)),
+            None => format!("{}:{}", table, key),
+        }
+    }
+
+    fn get_table_prefix(&self, table: &str) -> String {
+        match &self.1 {
+            Some(encryption) => format!("{}:", encryption.encrypt_table(table)),
+            None => format!("{}:", table),
+        }
     }
 
-    fn get_full_key(table: &str, key: &str) -> String {
-        format!("{}:{}", table, key)
+    fn get_key_only(full_key: &[u8], prefix: &str) -> Result<String, KvError> {
+        let key = full_key.strip_prefix(prefix.as_bytes()).unwrap_or(full_key);
+        let key = str::from_utf8(key)
+            .map_err(|_| KvError::CorruptRecord(format!("key {:?} is not utf-8", full_key)))?;
+        Ok(key.to_string())
     }
 
-    fn get_table_prefix(table: &str) -> String {
-        format!("{}:", table)
+    fn decode(&self, name: &str, value: Option<Vec<u8>>) -> Result<Option<Value>, KvError> {
+        flip(value.map(|v| decode_value(&v, self.1.as_deref(), name.as_bytes())))
     }
 
-    fn get_key_only(full_key: Box<[u8]>, table: &str) -> String {
-        let key = &*full_key;
-        let key = str::from_utf8(key).unwrap();
-        let start_index = key.find(table).unwrap();
-        let end_index = table.len();
-        key[start_index + end_index..].to_string()
+    // The pair of a record of table under prefix, the key is decrypted when the keys are
+    // encrypted and the stored key is the associated data of the value
+    fn to_kvpair(
+        &self,
+        table: &str,
+        prefix: &str,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Kvpair, KvError> {
+        let value = decode_value(value, self.1.as_deref(), key)?;
+        let key = RocksDB::get_key_only(key, prefix)?;
+        let key = match &self.1 {
+            Some(encryption) => encryption.decrypt_key(table, &key)?,
+            None => key,
+        };
+        Ok(Kvpair::new(key, value))
     }
 }
 
 impl Storage for RocksDB {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = RocksDB::get_full_key(table, key);
-        let result = self
-            .0
-            .get(name.as_bytes())?
-            .map(|v| v.as_slice().try_into());
-        flip(result)
+        let name = self.get_full_key(table, key);
+        let result = self.0.get(name.as_bytes())?;
+        self.decode(&name, result)
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let name = RocksDB::get_full_key(table, &key);
-        let data: Vec<u8> = value.try_into()?;
-        let previous_value: Option<Value> = match self.0.get(&name.as_bytes())? {
-            Some(value) => Some(value.as_slice().try_into()?),
-            None => None,
-        };
+        let name = self.get_full_key(table, &key);
+        let sealer = flip(self.1.as_ref().map(|v| v.sealer()))?;
+        let _lock = self.2.lock().unwrap();
+        let previous = self.0.get(name.as_bytes())?;
+        // the version of the key comes from the previous record
+        let data = record::encode_set(previous.as_deref(), &value, sealer.as_ref(), name.as_bytes());
+        let previous_value = self.decode(&name, previous)?;
         self.0.put(&name.as_bytes(), data)?;
         Ok(previous_value)
         // last value is the one before put, not the one currently putting
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let name = RocksDB::get_full_key(table, &key);
+        let name = self.get_full_key(table, key);
         let result = self.0.get(&name.as_bytes())?;
         match result {
             Some(_) => Ok(true),
@@ -61,64 +182,173 @@ impl Storage for RocksDB {
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = RocksDB::get_full_key(table, &key);
+        let name = self.get_full_key(table, key);
 
+        let _lock = self.2.lock().unwrap();
         let value = self.get(table, key)?;
         self.0.delete(&name.as_bytes())?;
         Ok(value)
     }
 
+    // the writes go in one WriteBatch, they're all applied or none
+    fn write_batch(
+        &self,
+        writes: Vec<(String, String, Option<Value>)>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let sealer = flip(self.1.as_ref().map(|v| v.sealer()))?;
+        let _lock = self.2.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        // a key written twice replaces what the batch wrote before
+        let mut written: HashMap<String, Option<Vec<u8>>> = HashMap::new();
+        let mut olds = Vec::with_capacity(writes.len());
+        for (table, key, value) in writes {
+            let name = self.get_full_key(&table, &key);
+            let previous = match written.get(&name) {
+                Some(v) => v.clone(),
+                None => self.0.get(name.as_bytes())?,
+            };
+            let data = value.map(|v| {
+                record::encode_set(previous.as_deref(), &v, sealer.as_ref(), name.as_bytes())
+            });
+            match &data {
+                Some(data) => batch.put(name.as_bytes(), data),
+                None => batch.delete(name.as_bytes()),
+            }
+            olds.push(self.decode(&name, previous)?);
+            written.insert(name, data);
+        }
+        self.0.write(batch)?;
+        Ok(olds)
+    }
+
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let prefix = RocksDB::get_table_prefix(table);
-        let mut get_options = ReadOptions::default();
-        get_options.set_prefix_same_as_start(true);
+        rocks_scan_prefix(self, table).collect()
+    }
 
-        let result = rocks_scan_prefix(&self, &prefix, get_options)?;
-        Ok(result)
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError> {
+        Ok(Box::new(rocks_scan_prefix(self, table)))
     }
 
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let prefix = RocksDB::get_table_prefix(table);
-        let mut get_options = ReadOptions::default();
-        get_options.set_prefix_same_as_start(true);
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError> {
+        // encrypted keys are in the order of their ciphertext, sorted after decrypting the table
+        if self.1.as_ref().is_some_and(|v| v.keys_encrypted()) {
+            return sorted_range(self.get_iter(table)?, start, end);
+        }
+        let start = self.get_full_key(table, start);
+        let end = end.map(|end| self.get_full_key(table, end));
+        Ok(Box::new(RocksScan::new(self, table, start, end)))
+    }
+
+    fn get_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = BTreeSet::new();
+        for item in self.0.iterator(IteratorMode::Start) {
+            let (key, _) = item?;
+            if &*key == KEYS_MARKER {
+                continue;
+            }
+            let table = key.split(|v| *v == b':').next().unwrap_or_default();
+            let table = str::from_utf8(table).map_err(|_| {
+                KvError::CorruptRecord(format!("table of key {:?} is not utf-8", key))
+            })?;
+            tables.insert(match &self.1 {
+                Some(encryption) => encryption.decrypt_table(table)?,
+                None => table.to_string(),
+            });
+        }
+        Ok(tables.into_iter().collect())
+    }
 
-        let result = StorageIter::new(rocks_scan_prefix(&self, &prefix, get_options)?.into_iter());
-        Ok(Box::new(result))
+    // A checkpoint is consistent by itself, the unchanged files are hard links
+    fn backup(&self, dir: &Path) -> Result<(), KvError> {
+        Checkpoint::new(&self.0)?.create_checkpoint(dir)?;
+        Ok(())
     }
-}
 
-impl<T> From<(T, Box<[u8]>)> for Kvpair
-where
-    T: Into<String>,
-{
-    fn from(value: (T, Box<[u8]>)) -> Self {
-        let (key, value) = (value.0, value.1);
-        Kvpair::new(key, (&*value).try_into().unwrap())
+    fn check_table(&self, table: &str) -> Result<(), KvError> {
+        check_prefix_table(table)
     }
 }
 
-fn rocks_scan_prefix(
-    db: &RocksDB,
-    prefix: &str,
-    read_options: ReadOptions,
-) -> Result<Vec<Kvpair>, KvError> {
-    let db_iter = db.0.iterator_opt(
-        IteratorMode::From(prefix.as_bytes(), Direction::Forward),
-        read_options,
-    );
+fn rocks_scan_prefix(db: &RocksDB, table: &str) -> RocksScan {
+    let prefix = db.get_table_prefix(table);
+    RocksScan::new(db, table, prefix, None)
+}
+
+// A lazy scan of the records of a table, from a key up to the end of the table or an end key.
+// The records are read SCAN_BATCH at a time, each batch with an iterator of its own from where
+// the last one stopped, since a rocksdb iterator borrows the db
+struct RocksScan {
+    db: RocksDB,
+    table: String,
+    prefix: String,
+    end: Option<String>,
+    // the key the next batch starts from, None when the scan is done
+    next: Option<Vec<u8>>,
+    batch: VecDeque<Result<Kvpair, KvError>>,
+}
+
+impl RocksScan {
+    fn new(db: &RocksDB, table: &str, start: String, end: Option<String>) -> Self {
+        Self {
+            db: db.clone(),
+            table: table.to_string(),
+            prefix: db.get_table_prefix(table),
+            end,
+            next: Some(start.into_bytes()),
+            batch: VecDeque::new(),
+        }
+    }
 
-    let mut vec: Vec<Kvpair> = Vec::new();
+    fn read_batch(&mut self) {
+        let Some(start) = self.next.take() else {
+            return;
+        };
+        let mut read_options = ReadOptions::default();
+        read_options.set_prefix_same_as_start(true);
+        let db_iter = self.db.0.iterator_opt(
+            IteratorMode::From(&start, Direction::Forward),
+            read_options,
+        );
 
-    for item in db_iter {
-        let (key, value) = item.unwrap();
-        vec.push((RocksDB::get_key_only(key, &prefix), value).into());
+        for item in db_iter {
+            let (key, value) = match item {
+                Ok(v) => v,
+                Err(e) => {
+                    self.batch.push_back(Err(e.into()));
+                    return;
+                }
+            };
+            // without a prefix extractor the iterator runs on into the next tables
+            if !key.starts_with(self.prefix.as_bytes())
+                || self.end.as_ref().is_some_and(|end| &*key >= end.as_bytes())
+            {
+                return;
+            }
+            if self.batch.len() == SCAN_BATCH {
+                self.next = Some(key.into_vec());
+                return;
+            }
+            let pair = self.db.to_kvpair(&self.table, &self.prefix, &key, &value);
+            self.batch.push_back(pair);
+        }
     }
+}
 
-    Ok(vec)
+impl Iterator for RocksScan {
+    type Item = Result<Kvpair, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() {
+            self.read_batch();
+        }
+        self.batch.pop_front()
+    }
 }
-// impl From<(Box<[u8]>, Box<[u8]>)> for Kvpair {
-//     fn from(value: (Box<[u8]>, Box<[u8]>)) -> Self {
-//         let (key, value) = (&*value.0, &*value.1);
-//         Kvpair::new(str::from_utf8(key).unwrap(), value.try_into().unwrap())
-//     }
-// }
@@ -1,86 +1,302 @@
 // implementation of using sleddb
 
-use sled::{Db, Error, IVec};
-use std::{convert::TryInto, path::Path, str};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Db, IVec};
+use std::{collections::BTreeSet, io, path::Path, str, sync::Arc, thread};
 
-use crate::{KvError, Kvpair, Storage, StorageIter, Value, flip};
+use super::encryption::{check_keys_marker, Encryption, KEYS_MARKER};
+use super::record::{self, decode_value, Record, UpgradeReport};
+use crate::{KvError, Kvpair, Storage, Value, check_prefix_table, flip, sorted_range};
 
-#[derive(Debug)]
-pub struct SledDb(Db);
+#[derive(Debug, Clone)]
+pub struct SledDb(Db, Option<Arc<Encryption>>);
 
 impl SledDb {
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        Self(sled::open(path).unwrap(), None)
     }
 
-    fn get_full_key(table: &str, key: &str) -> String {
-        format!("{}:{}", table, key)
+    // Open the db at path, created when it's missing, with the encryption if any. A store with
+    // the keys kept another way is refused, including one with encrypted keys opened without
+    pub fn open(path: impl AsRef<Path>, encryption: Option<Encryption>) -> Result<Self, KvError> {
+        let store = Self(sled::open(path)?, encryption.map(Arc::new));
+        store.check_keys()?;
+        Ok(store)
     }
 
-    fn get_table_prefix(table: &str) -> String {
-        format!("{}:", table)
+    // Create a db at path, which must not exist, with the data of a backup from Storage::backup
+    pub fn restore(dir: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let backup = sled::open(dir)?;
+        Ok(Self(copy_to(&backup, path.as_ref())?, None))
+    }
+
+    // Encrypt the values written, and the keys when the encryption does, see encryption.rs.
+    // The records written before stay readable as they are until they're re-encrypted. A store
+    // with the keys kept the other way is refused
+    pub fn encryption(mut self, encryption: Encryption) -> Result<Self, KvError> {
+        self.1 = Some(Arc::new(encryption));
+        self.check_keys()?;
+        Ok(self)
+    }
+
+    // Check the marker of the keys, it's written into a store without one
+    fn check_keys(&self) -> Result<(), KvError> {
+        let marker = self.0.get(KEYS_MARKER)?;
+        let empty = self.0.is_empty();
+        if let Some(marker) = check_keys_marker(marker.as_deref(), empty, self.1.as_deref())? {
+            self.0.insert(KEYS_MARKER, marker)?;
+        }
+        Ok(())
+    }
+
+    // The value of key with its metadata
+    pub fn record(&self, table: &str, key: &str) -> Result<Option<Record>, KvError> {
+        let name = self.get_full_key(table, key);
+        let result = self.0.get(name.as_bytes())?;
+        flip(result.map(|v| Record::decode_with(&v, self.1.as_deref(), name.as_bytes())))
+    }
+
+    // Rewrite the legacy records in the current format, and with encryption the records which
+    // aren't encrypted with the current master key. A record which can't be read is left as it
+    // is and reported
+    pub fn upgrade(&self) -> Result<UpgradeReport, KvError> {
+        let encryption = self.1.as_deref();
+        let sealer = flip(encryption.map(|v| v.sealer()))?;
+        let mut report = UpgradeReport::default();
+        for item in self.0.iter() {
+            let (key, value) = item?;
+            if key == KEYS_MARKER {
+                continue;
+            }
+            report.scanned += 1;
+            match record::upgrade(&value, encryption, sealer.as_ref(), &key) {
+                // a record set meanwhile is written as it should be already
+                Ok(Some(data)) => {
+                    if self.0.compare_and_swap(&key, Some(&value), Some(data))?.is_ok() {
+                        report.upgraded += 1;
+                    }
+                }
+                Ok(None) => {}
+                Err(_) => report.corrupt.push(String::from_utf8_lossy(&key).into_owned()),
+            }
+        }
+        self.0.flush()?;
+        Ok(report)
+    }
+
+    // Upgrade in a thread while the store is in use, after a master key is added to the key
+    // provider the records are re-encrypted with it. For an application embedding the store,
+    // the server only serves a MemTable and `kvs upgrade` re-encrypts a store offline
+    pub fn upgrade_in_background(&self) -> thread::JoinHandle<Result<UpgradeReport, KvError>> {
+        let store = self.clone();
+        thread::spawn(move || store.upgrade())
+    }
+
+    fn get_full_key(&self, table: &str, key: &str) -> String {
+        match &self.1 {
+            Some(v) => format!("{}:{}", v.encrypt_table(table), v.encrypt_key(table, key)),
+            None => format!("{}:{}", table, key),
+        }
+    }
+
+    fn get_table_prefix(&self, table: &str) -> String {
+        match &self.1 {
+            Some(encryption) => format!("{}:", encryption.encrypt_table(table)),
+            None => format!("{}:", table),
+        }
+    }
+
+    fn decode(&self, name: &str, value: Option<IVec>) -> Result<Option<Value>, KvError> {
+        flip(value.map(|v| decode_value(&v, self.1.as_deref(), name.as_bytes())))
+    }
+
+    // The pairs of the items of a table, in the order of the stored keys
+    fn pairs(
+        &self,
+        table: &str,
+        iter: sled::Iter,
+    ) -> impl Iterator<Item = Result<Kvpair, KvError>> + 'static {
+        let (table, encryption) = (table.to_string(), self.1.clone());
+        iter.map(move |item| to_kvpair(&table, encryption.as_deref(), item))
     }
 }
 
 impl Storage for SledDb {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, key);
-        let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
-        flip(result)
+        let name = self.get_full_key(table, key);
+        let result = self.0.get(name.as_bytes())?;
+        self.decode(&name, result)
     }
 
+    // the version of the key comes from the record replaced, which sled swaps atomically
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, &key);
-        let data: Vec<u8> = value.try_into()?;
+        let name = self.get_full_key(table, &key);
+        let sealer = flip(self.1.as_ref().map(|v| v.sealer()))?;
 
-        let result = self.0.insert(name, data)?.map(|v| v.as_ref().try_into());
-        flip(result)
+        let result = self.0.fetch_and_update(name.as_bytes(), |old| {
+            Some(record::encode_set(old, &value, sealer.as_ref(), name.as_bytes()))
+        })?;
+        self.decode(&name, result)
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let name = SledDb::get_full_key(table, &key);
+        let name = self.get_full_key(table, key);
         Ok(self.0.contains_key(name)?)
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, &key);
+        let name = self.get_full_key(table, key);
 
-        let result = self.0.remove(name)?.map(|v| v.as_ref().try_into());
-        flip(result)
+        let result = self.0.remove(name.as_bytes())?;
+        self.decode(&name, result)
     }
 
-    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let result = self.0.scan_prefix(prefix).map(|v| v.into()).collect();
+    // the writes go in one transaction of the tree, they're all applied or none
+    fn write_batch(
+        &self,
+        writes: Vec<(String, String, Option<Value>)>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let sealer = flip(self.1.as_ref().map(|v| v.sealer()))?;
+        let writes: Vec<(String, Option<Value>)> = writes
+            .into_iter()
+            .map(|(table, key, value)| (self.get_full_key(&table, &key), value))
+            .collect();
+        let olds = self
+            .0
+            .transaction(|tx| {
+                let mut olds = Vec::with_capacity(writes.len());
+                for (name, value) in &writes {
+                    let old = match value {
+                        Some(value) => {
+                            let old = tx.get(name.as_bytes())?;
+                            let data = record::encode_set(
+                                old.as_deref(),
+                                value,
+                                sealer.as_ref(),
+                                name.as_bytes(),
+                            );
+                            tx.insert(name.as_bytes(), data)?;
+                            old
+                        }
+                        None => tx.remove(name.as_bytes())?,
+                    };
+                    olds.push(old);
+                }
+                Ok::<_, ConflictableTransactionError<KvError>>(olds)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.into(),
+            })?;
+        writes
+            .iter()
+            .zip(olds)
+            .map(|((name, _), old)| self.decode(name, old))
+            .collect()
+    }
 
-        Ok(result)
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let prefix = self.get_table_prefix(table);
+        let encryption = self.1.as_deref();
+        self.0
+            .scan_prefix(prefix)
+            .map(|item| to_kvpair(table, encryption, item))
+            .collect()
+    }
 
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError> {
+        let prefix = self.get_table_prefix(table);
+        let iter = self.pairs(table, self.0.scan_prefix(prefix));
+        Ok(Box::new(iter))
     }
 
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let iter = StorageIter::new(self.0.scan_prefix(prefix));
+    // the keys of a table are sorted after "table:", ';' is the byte after ':'. Encrypted keys
+    // are in the order of their ciphertext, the whole table is read and sorted
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError> {
+        if self.1.as_ref().is_some_and(|v| v.keys_encrypted()) {
+            return sorted_range(self.get_iter(table)?, start, end);
+        }
+        let start = self.get_full_key(table, start);
+        let end = match end {
+            Some(end) => self.get_full_key(table, end),
+            None => format!("{};", table),
+        };
+        let iter = self.pairs(table, self.0.range(start..end));
         Ok(Box::new(iter))
     }
 
+    fn get_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = BTreeSet::new();
+        for key in self.0.iter().keys() {
+            let key = key?;
+            if key == KEYS_MARKER {
+                continue;
+            }
+            let table = ivec_to_table(key.as_ref())?;
+            tables.insert(match &self.1 {
+                Some(encryption) => encryption.decrypt_table(table)?,
+                None => table.to_string(),
+            });
+        }
+        Ok(tables.into_iter().collect())
+    }
 
+    fn backup(&self, dir: &Path) -> Result<(), KvError> {
+        copy_to(&self.0, dir)?;
+        Ok(())
+    }
+
+    fn check_table(&self, table: &str) -> Result<(), KvError> {
+        check_prefix_table(table)
+    }
 }
 
-impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
-    fn from(value: Result<(IVec, IVec), Error>) -> Self {
-        match value {
-            Ok((k, v)) => match v.as_ref().try_into() {
-                Ok(v) => Kvpair::new(ivec_to_key(k.as_ref()), v),
-                Err(_) => Kvpair::default(),
-            },
-            _ => Kvpair::default(),
-        }
+// Copy all the trees of db into a new db at path
+fn copy_to(db: &Db, path: &Path) -> Result<Db, KvError> {
+    // import panics on a tree which already exists
+    if path.exists() {
+        let msg = format!("{} already exists", path.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
     }
+    let target = sled::open(path)?;
+    target.import(db.export());
+    target.flush()?;
+    Ok(target)
 }
 
-fn ivec_to_key(ivec: &[u8]) -> &str {
-    let s = str::from_utf8(ivec).unwrap();
-    let mut iter = s.split(":");
-    iter.next();
-    iter.next().unwrap()
-}
\ No newline at end of file
+// The pair of an item of table, the key is decrypted when the keys are encrypted and the
+// stored key is the associated data of the value
+fn to_kvpair(
+    table: &str,
+    encryption: Option<&Encryption>,
+    item: Result<(IVec, IVec), sled::Error>,
+) -> Result<Kvpair, KvError> {
+    let (k, v) = item?;
+    let value = decode_value(&v, encryption, &k)?;
+    let key = match encryption {
+        Some(encryption) => encryption.decrypt_key(table, ivec_to_key(&k)?)?,
+        None => ivec_to_key(&k)?.to_string(),
+    };
+    Ok(Kvpair::new(key, value))
+}
+
+fn ivec_to_key(ivec: &[u8]) -> Result<&str, KvError> {
+    let s = str::from_utf8(ivec)
+        .map_err(|_| KvError::CorruptRecord(format!("key {:?} is not utf-8", ivec)))?;
+    // the key is all after the table, it could have ':' in it
+    Ok(s.split_once(':').map_or("", |(_, key)| key))
+}
+
+fn ivec_to_table(ivec: &[u8]) -> Result<&str, KvError> {
+    let table = ivec.split(|v| *v == b':').next().unwrap_or_default();
+    str::from_utf8(table)
+        .map_err(|_| KvError::CorruptRecord(format!("table of key {:?} is not utf-8", ivec)))
+}